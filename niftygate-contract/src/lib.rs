@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 pub mod command;
+pub mod transport;
 
 pub use command::Command;
 
@@ -15,17 +16,10 @@ impl FromStr for HexData {
 }
 
 mod util {
-  use ethcontract::{
-    dyns::DynWeb3,
-    transport::DynTransport,
-    web3::{error::Result, transports::WebSocket},
-    Web3,
-  };
+  use ethcontract::{dyns::DynWeb3, web3::error::Result, Web3};
   use tide::http::Url;
 
   pub async fn web3_from_url(url: Url) -> Result<DynWeb3> {
-    Ok(Web3::new(DynTransport::new(
-      WebSocket::new(url.as_str()).await?,
-    )))
+    Ok(Web3::new(crate::transport::connect(&url).await?))
   }
 }
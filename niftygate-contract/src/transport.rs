@@ -0,0 +1,1188 @@
+use async_std::{future::timeout, io::prelude::*, os::unix::net::UnixStream, sync::Mutex, task};
+use ethcontract::{
+  futures::{
+    channel::oneshot,
+    future::{join_all, BoxFuture, FutureExt, Shared},
+  },
+  jsonrpc as rpc,
+  transport::DynTransport,
+  web3::{
+    error::{Error, Result, TransportError},
+    helpers,
+    transports::WebSocket,
+    types::U64,
+    BatchTransport, RequestId, Transport,
+  },
+};
+use serde::{de::IgnoredAny, Serialize};
+use std::{
+  collections::HashMap,
+  fmt,
+  future::Future,
+  iter, mem,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
+use tide::{http::Url, log};
+
+// Picks a transport based on the scheme of the URL. IPC sockets are given as
+// `ipc:///path/to/geth.ipc` (or `file://`, or `unix://`).
+pub async fn connect(url: &Url) -> Result<DynTransport> {
+  connect_with_backoff(url, Backoff::default()).await
+}
+
+pub async fn connect_with_backoff(url: &Url, backoff: Backoff) -> Result<DynTransport> {
+  match url.scheme() {
+    "http" | "https" => Ok(DynTransport::new(Http::new(url.clone()))),
+    "ws" | "wss" => Ok(DynTransport::new(
+      ReconnectingWebSocket::new(url.clone(), backoff).await?,
+    )),
+    "ipc" | "file" | "unix" => Ok(DynTransport::new(Ipc::new(url.path()))),
+    scheme => Err(message(format!("unsupported scheme: {}", scheme))),
+  }
+}
+
+// Hosted nodes tend to keep API keys in the path (or the userinfo), so only the
+// scheme, host, and port are logged or reported.
+pub fn redact(url: &Url) -> String {
+  match url.host_str() {
+    Some(host) => match url.port() {
+      Some(port) => format!("{}://{}:{}", url.scheme(), host, port),
+      None => format!("{}://{}", url.scheme(), host),
+    },
+    None => format!("{}://{}", url.scheme(), url.path()),
+  }
+}
+
+fn message(message: impl ToString) -> Error {
+  Error::Transport(TransportError::Message(message.to_string()))
+}
+
+fn single(response: rpc::Response) -> Result<rpc::Value> {
+  match response {
+    rpc::Response::Single(output) => helpers::to_result_from_output(output),
+    rpc::Response::Batch(_) => Err(Error::InvalidResponse(String::from(
+      "expected a single response, got a batch",
+    ))),
+  }
+}
+
+// Batch responses may come back in any order, so they are matched to the
+// requests by id.
+fn batch(ids: &[RequestId], response: rpc::Response) -> Result<Vec<Result<rpc::Value>>> {
+  let mut outputs = match response {
+    rpc::Response::Batch(outputs) => outputs,
+    rpc::Response::Single(output) => vec![output],
+  };
+
+  Ok(
+    ids
+      .iter()
+      .map(|id| {
+        let id = rpc::Id::Num(*id as u64);
+        match outputs.iter().position(|output| *output.id() == id) {
+          Some(index) => helpers::to_result_from_output(outputs.swap_remove(index)),
+          None => Err(Error::InvalidResponse(format!("no response for {:?}", id))),
+        }
+      })
+      .collect(),
+  )
+}
+
+fn batch_request(
+  requests: impl IntoIterator<Item = (RequestId, rpc::Call)>,
+) -> (Vec<RequestId>, rpc::Request) {
+  let (ids, calls) = requests.into_iter().unzip();
+  (ids, rpc::Request::Batch(calls))
+}
+
+#[derive(Clone)]
+pub struct Http {
+  client: surf::Client,
+  id: Arc<AtomicUsize>,
+  url: Url,
+}
+
+impl fmt::Debug for Http {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Http")
+      .field("url", &self.url.as_str())
+      .finish()
+  }
+}
+
+impl Http {
+  pub fn new(url: Url) -> Self {
+    Self {
+      client: surf::client(),
+      id: Arc::new(AtomicUsize::new(1)),
+      url,
+    }
+  }
+
+  fn post(&self, request: rpc::Request) -> BoxFuture<'static, Result<rpc::Response>> {
+    let client = self.client.clone();
+    let url = self.url.clone();
+    async move {
+      let body = helpers::to_string(&request);
+      let post = || {
+        client
+          .post(url.clone())
+          .body(surf::Body::from_string(body.clone()))
+          .content_type("application/json")
+      };
+      // Pooled connections may have been closed by the server while idle, so
+      // a request that fails before getting a response is sent once more.
+      let mut response = match post().await {
+        Ok(response) => response,
+        Err(_) => post().await.map_err(message)?,
+      };
+      if !response.status().is_success() {
+        return Err(Error::Transport(TransportError::Code(
+          response.status() as u16
+        )));
+      }
+      let body = response.body_bytes().await.map_err(message)?;
+      helpers::to_response_from_slice(&body)
+    }
+    .boxed()
+  }
+}
+
+impl Transport for Http {
+  type Out = BoxFuture<'static, Result<rpc::Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, helpers::build_request(id, method, params))
+  }
+
+  fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+    let response = self.post(rpc::Request::Single(request));
+    async move { single(response.await?) }.boxed()
+  }
+}
+
+impl BatchTransport for Http {
+  type Batch = BoxFuture<'static, Result<Vec<Result<rpc::Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, rpc::Call)>,
+  {
+    let (ids, request) = batch_request(requests);
+    let response = self.post(request);
+    async move { batch(&ids, response.await?) }.boxed()
+  }
+}
+
+// Requests are written to the socket one at a time, and the response read
+// back before the next one is sent. If anything goes wrong, the connection is
+// dropped, and a new one is made for the next request.
+#[derive(Clone)]
+pub struct Ipc {
+  id: Arc<AtomicUsize>,
+  path: PathBuf,
+  stream: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl fmt::Debug for Ipc {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Ipc").field("path", &self.path).finish()
+  }
+}
+
+impl Ipc {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      id: Arc::new(AtomicUsize::new(1)),
+      path: path.into(),
+      stream: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  fn call(&self, request: rpc::Request) -> BoxFuture<'static, Result<rpc::Response>> {
+    let path = self.path.clone();
+    let stream = self.stream.clone();
+    async move {
+      let mut stream = stream.lock().await;
+      if stream.is_none() {
+        *stream = Some(UnixStream::connect(&path).await?);
+      }
+      let result = Self::exchange(stream.as_mut().unwrap(), &request).await;
+      if result.is_err() {
+        *stream = None;
+      }
+      result
+    }
+    .boxed()
+  }
+
+  async fn exchange(stream: &mut UnixStream, request: &rpc::Request) -> Result<rpc::Response> {
+    stream
+      .write_all(helpers::to_string(request).as_bytes())
+      .await?;
+
+    let mut buffer = vec![];
+    let mut chunk = [0; 8192];
+    loop {
+      let read = stream.read(&mut chunk).await?;
+      if read == 0 {
+        return Err(Error::Unreachable);
+      }
+      buffer.extend_from_slice(&chunk[..read]);
+
+      let mut values = serde_json::Deserializer::from_slice(&buffer).into_iter::<IgnoredAny>();
+      match values.next() {
+        Some(Ok(_)) => return helpers::to_response_from_slice(&buffer[..values.byte_offset()]),
+        Some(Err(error)) if error.is_eof() => continue,
+        None => continue,
+        Some(Err(error)) => return Err(error.into()),
+      }
+    }
+  }
+}
+
+impl Transport for Ipc {
+  type Out = BoxFuture<'static, Result<rpc::Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, helpers::build_request(id, method, params))
+  }
+
+  fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+    let response = self.call(rpc::Request::Single(request));
+    async move { single(response.await?) }.boxed()
+  }
+}
+
+impl BatchTransport for Ipc {
+  type Batch = BoxFuture<'static, Result<Vec<Result<rpc::Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, rpc::Call)>,
+  {
+    let (ids, request) = batch_request(requests);
+    let response = self.call(request);
+    async move { batch(&ids, response.await?) }.boxed()
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+  pub initial: Duration,
+  pub maximum: Duration,
+  pub attempts: usize,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Self {
+      initial: Duration::from_millis(250),
+      maximum: Duration::from_secs(10),
+      attempts: 10,
+    }
+  }
+}
+
+struct Connection {
+  generation: usize,
+  socket: WebSocket,
+}
+
+// Wraps a WebSocket, replacing it with a new one when the connection is lost.
+// A request that fails because of the connection is retried once on the new
+// socket. Requests that fail while the node is still unreachable fail as
+// usual, and the next request tries to reconnect again.
+#[derive(Clone)]
+pub struct ReconnectingWebSocket {
+  backoff: Backoff,
+  connection: Arc<Mutex<Connection>>,
+  id: Arc<AtomicUsize>,
+  url: Url,
+}
+
+impl fmt::Debug for ReconnectingWebSocket {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ReconnectingWebSocket")
+      .field("url", &self.url.as_str())
+      .finish()
+  }
+}
+
+impl ReconnectingWebSocket {
+  pub async fn new(url: Url, backoff: Backoff) -> Result<Self> {
+    let socket = WebSocket::new(url.as_str()).await?;
+    Ok(Self {
+      backoff,
+      connection: Arc::new(Mutex::new(Connection {
+        generation: 0,
+        socket,
+      })),
+      id: Arc::new(AtomicUsize::new(1)),
+      url,
+    })
+  }
+
+  async fn socket(&self) -> (usize, WebSocket) {
+    let connection = self.connection.lock().await;
+    (connection.generation, connection.socket.clone())
+  }
+
+  // Only the first request to notice a lost connection reconnects. Any others
+  // that were using the same socket pick up the replacement.
+  async fn reconnect(&self, generation: usize) -> Result<WebSocket> {
+    let mut connection = self.connection.lock().await;
+    if connection.generation != generation {
+      return Ok(connection.socket.clone());
+    }
+
+    let mut delay = self.backoff.initial;
+    let mut attempt = 1;
+    loop {
+      match WebSocket::new(self.url.as_str()).await {
+        Ok(socket) => {
+          log::info!("reconnected to {}", redact(&self.url));
+          connection.generation += 1;
+          connection.socket = socket;
+          return Ok(connection.socket.clone());
+        }
+        Err(error) if attempt >= self.backoff.attempts => return Err(error),
+        Err(error) => {
+          log::warn!(
+            "failed to reconnect to {} (attempt {}/{}): {}",
+            redact(&self.url),
+            attempt,
+            self.backoff.attempts,
+            error
+          );
+          task::sleep(delay).await;
+          delay = (delay * 2).min(self.backoff.maximum);
+          attempt += 1;
+        }
+      }
+    }
+  }
+}
+
+fn is_disconnected(error: &Error) -> bool {
+  matches!(
+    error,
+    Error::Transport(_) | Error::Unreachable | Error::Io(_)
+  )
+}
+
+impl Transport for ReconnectingWebSocket {
+  type Out = BoxFuture<'static, Result<rpc::Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, helpers::build_request(id, method, params))
+  }
+
+  fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+    let this = self.clone();
+    async move {
+      let (generation, socket) = this.socket().await;
+      match socket.send(id, request.clone()).await {
+        Err(error) if is_disconnected(&error) => {
+          log::warn!("lost connection to {}: {}", redact(&this.url), error);
+          this.reconnect(generation).await?.send(id, request).await
+        }
+        result => result,
+      }
+    }
+    .boxed()
+  }
+}
+
+impl BatchTransport for ReconnectingWebSocket {
+  type Batch = BoxFuture<'static, Result<Vec<Result<rpc::Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, rpc::Call)>,
+  {
+    let requests = requests.into_iter().collect::<Vec<_>>();
+    let this = self.clone();
+    async move {
+      let (generation, socket) = this.socket().await;
+      match socket.send_batch(requests.clone()).await {
+        Err(error) if is_disconnected(&error) => {
+          log::warn!("lost connection to {}: {}", redact(&this.url), error);
+          this.reconnect(generation).await?.send_batch(requests).await
+        }
+        result => result,
+      }
+    }
+    .boxed()
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BatchOptions {
+  pub window: Duration,
+  pub max_size: usize,
+}
+
+impl Default for BatchOptions {
+  fn default() -> Self {
+    Self {
+      window: Duration::from_millis(2),
+      max_size: 100,
+    }
+  }
+}
+
+// Calls that only read from the chain, so identical calls made at about the
+// same time can share a response.
+const COALESCED_METHODS: &[&str] = &[
+  "eth_blockNumber",
+  "eth_call",
+  "eth_chainId",
+  "eth_getBalance",
+  "eth_getCode",
+  "net_version",
+];
+
+type SharedResponse = Shared<BoxFuture<'static, Result<rpc::Value>>>;
+
+struct Queued {
+  id: RequestId,
+  call: rpc::Call,
+  key: Option<String>,
+  sender: oneshot::Sender<Result<rpc::Value>>,
+}
+
+struct Batcher {
+  inner: DynTransport,
+  options: BatchOptions,
+  queue: std::sync::Mutex<Vec<Queued>>,
+  in_flight: std::sync::Mutex<HashMap<String, SharedResponse>>,
+}
+
+impl Batcher {
+  // A batch of one is sent on its own, as a single request.
+  async fn dispatch(&self, batch: Vec<Queued>) {
+    let results = match batch.as_slice() {
+      [] => return,
+      [queued] => vec![self.inner.send(queued.id, queued.call.clone()).await],
+      _ => match self
+        .inner
+        .send_batch(batch.iter().map(|queued| (queued.id, queued.call.clone())))
+        .await
+      {
+        Ok(results) => results,
+        Err(error) => batch.iter().map(|_| Err(error.clone())).collect(),
+      },
+    };
+
+    {
+      let mut in_flight = self.in_flight.lock().unwrap();
+      for key in batch.iter().filter_map(|queued| queued.key.as_ref()) {
+        in_flight.remove(key);
+      }
+    }
+
+    for (queued, result) in batch.into_iter().zip(results) {
+      let _ = queued.sender.send(result);
+    }
+  }
+}
+
+// Collects the calls made within window of the first into a single batch
+// request (of up to max_size calls). Identical read-only calls that are already
+// waiting for a response share it, rather than being sent again.
+#[derive(Clone)]
+pub struct Batching {
+  batcher: Arc<Batcher>,
+  id: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for Batching {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Batching")
+      .field("inner", &self.batcher.inner)
+      .field("options", &self.batcher.options)
+      .finish()
+  }
+}
+
+impl Batching {
+  pub fn new(inner: DynTransport, options: BatchOptions) -> Self {
+    Self {
+      batcher: Arc::new(Batcher {
+        inner,
+        options,
+        queue: std::sync::Mutex::new(vec![]),
+        in_flight: std::sync::Mutex::new(HashMap::new()),
+      }),
+      id: Arc::new(AtomicUsize::new(1)),
+    }
+  }
+
+  fn enqueue(
+    &self,
+    id: RequestId,
+    call: rpc::Call,
+    key: Option<String>,
+  ) -> BoxFuture<'static, Result<rpc::Value>> {
+    let (sender, receiver) = oneshot::channel();
+    let mut queue = self.batcher.queue.lock().unwrap();
+    queue.push(Queued {
+      id,
+      call,
+      key,
+      sender,
+    });
+
+    if queue.len() >= self.batcher.options.max_size {
+      let batch = mem::take(&mut *queue);
+      let batcher = self.batcher.clone();
+      task::spawn(async move { batcher.dispatch(batch).await });
+    } else if queue.len() == 1 {
+      let batcher = self.batcher.clone();
+      task::spawn(async move {
+        task::sleep(batcher.options.window).await;
+        let batch = mem::take(&mut *batcher.queue.lock().unwrap());
+        batcher.dispatch(batch).await
+      });
+    }
+
+    async move {
+      match receiver.await {
+        Ok(result) => result,
+        Err(_) => Err(message("batch was dropped before it was sent")),
+      }
+    }
+    .boxed()
+  }
+}
+
+fn coalescing_key(call: &rpc::Call) -> Option<String> {
+  match call {
+    rpc::Call::MethodCall(call) if COALESCED_METHODS.contains(&call.method.as_str()) => Some(
+      format!("{}:{}", call.method, helpers::to_string(&call.params)),
+    ),
+    _ => None,
+  }
+}
+
+impl Transport for Batching {
+  type Out = BoxFuture<'static, Result<rpc::Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, helpers::build_request(id, method, params))
+  }
+
+  fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+    let key = match coalescing_key(&request) {
+      Some(key) => key,
+      None => return self.enqueue(id, request, None),
+    };
+
+    let mut in_flight = self.batcher.in_flight.lock().unwrap();
+    if let Some(response) = in_flight.get(&key) {
+      return response.clone().boxed();
+    }
+
+    let response = self.enqueue(id, request, Some(key.clone())).shared();
+    in_flight.insert(key, response.clone());
+    response.boxed()
+  }
+}
+
+impl BatchTransport for Batching {
+  type Batch = BoxFuture<'static, Result<Vec<Result<rpc::Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, rpc::Call)>,
+  {
+    self.batcher.inner.send_batch(requests)
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FailoverOptions {
+  pub check_interval: Duration,
+  pub max_block_lag: u64,
+  pub max_latency: Duration,
+}
+
+impl Default for FailoverOptions {
+  fn default() -> Self {
+    Self {
+      check_interval: Duration::from_secs(10),
+      max_block_lag: 3,
+      max_latency: Duration::from_secs(2),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EndpointStatus {
+  pub url: String,
+  pub active: bool,
+  pub connected: bool,
+  pub healthy: bool,
+  pub block: Option<u64>,
+  pub latency_ms: Option<u64>,
+}
+
+#[derive(Default)]
+struct Health {
+  healthy: bool,
+  block: Option<u64>,
+  latency: Option<Duration>,
+}
+
+struct Endpoint {
+  url: Url,
+  transport: RwLock<Option<DynTransport>>,
+  health: RwLock<Health>,
+}
+
+impl Endpoint {
+  fn new(url: Url) -> Self {
+    Self {
+      url,
+      transport: RwLock::new(None),
+      health: RwLock::new(Health::default()),
+    }
+  }
+
+  fn transport(&self) -> Option<DynTransport> {
+    self.transport.read().unwrap().clone()
+  }
+
+  fn is_healthy(&self) -> bool {
+    self.health.read().unwrap().healthy
+  }
+
+  // The failover transport moves on to the next endpoint rather than waiting
+  // for this one to come back, so WebSockets only get one reconnect attempt.
+  async fn connect(&self, options: FailoverOptions) -> Option<DynTransport> {
+    if let Some(transport) = self.transport() {
+      return Some(transport);
+    }
+
+    let backoff = Backoff {
+      attempts: 1,
+      ..Backoff::default()
+    };
+    match timeout(
+      options.max_latency,
+      connect_with_backoff(&self.url, backoff),
+    )
+    .await
+    {
+      Ok(Ok(transport)) => {
+        *self.transport.write().unwrap() = Some(transport.clone());
+        Some(transport)
+      }
+      Ok(Err(error)) => {
+        log::debug!("failed to connect to {}: {}", redact(&self.url), error);
+        None
+      }
+      Err(_) => {
+        log::debug!("timed out connecting to {}", redact(&self.url));
+        None
+      }
+    }
+  }
+
+  async fn block_number(&self, options: FailoverOptions) -> Option<(u64, Duration)> {
+    let transport = self.connect(options).await?;
+    let started = Instant::now();
+    match timeout(
+      options.max_latency,
+      transport.execute("eth_blockNumber", vec![]),
+    )
+    .await
+    {
+      Ok(Ok(value)) => match serde_json::from_value::<U64>(value) {
+        Ok(block) => Some((block.as_u64(), started.elapsed())),
+        Err(error) => {
+          log::debug!(
+            "unexpected block number from {}: {}",
+            redact(&self.url),
+            error
+          );
+          None
+        }
+      },
+      Ok(Err(error)) => {
+        log::debug!("health check failed for {}: {}", redact(&self.url), error);
+        None
+      }
+      Err(_) => {
+        log::debug!("health check timed out for {}", redact(&self.url));
+        None
+      }
+    }
+  }
+}
+
+struct Endpoints {
+  active: AtomicUsize,
+  endpoints: Vec<Endpoint>,
+  options: FailoverOptions,
+}
+
+impl Endpoints {
+  // An endpoint is healthy if it answers within max_latency, and isn't more
+  // than max_block_lag blocks behind the highest block seen. The first healthy
+  // endpoint (in the configured order) becomes the active one.
+  async fn check(&self) {
+    let results = join_all(
+      self
+        .endpoints
+        .iter()
+        .map(|endpoint| endpoint.block_number(self.options)),
+    )
+    .await;
+
+    let highest = results.iter().flatten().map(|(block, _)| *block).max();
+
+    for (endpoint, result) in self.endpoints.iter().zip(results) {
+      let mut health = endpoint.health.write().unwrap();
+      *health = match (result, highest) {
+        (Some((block, latency)), Some(highest)) => Health {
+          healthy: highest - block <= self.options.max_block_lag,
+          block: Some(block),
+          latency: Some(latency),
+        },
+        _ => Health::default(),
+      };
+    }
+
+    if let Some(index) = self
+      .endpoints
+      .iter()
+      .position(|endpoint| endpoint.is_healthy())
+    {
+      self.activate(index);
+    }
+  }
+
+  fn activate(&self, index: usize) {
+    let previous = self.active.swap(index, Ordering::AcqRel);
+    if previous != index {
+      log::warn!(
+        "switched RPC endpoint from {} to {}",
+        redact(&self.endpoints[previous].url),
+        redact(&self.endpoints[index].url)
+      );
+    }
+  }
+
+  // Calls go to the active endpoint first. If it can't be reached, the others
+  // are tried in order, and the first one to answer becomes active.
+  async fn call<T, F, O>(&self, call: F) -> Result<T>
+  where
+    F: Fn(DynTransport) -> O,
+    O: Future<Output = Result<T>>,
+  {
+    let active = self.active.load(Ordering::Acquire);
+    let others = (0..self.endpoints.len()).filter(|index| *index != active);
+    let mut last = Error::Unreachable;
+
+    for index in iter::once(active).chain(others) {
+      let endpoint = &self.endpoints[index];
+      let transport = match endpoint.transport() {
+        Some(transport) => transport,
+        None => continue,
+      };
+      match call(transport).await {
+        Err(error) if is_disconnected(&error) => {
+          log::warn!("RPC endpoint {} failed: {}", redact(&endpoint.url), error);
+          endpoint.health.write().unwrap().healthy = false;
+          last = error;
+        }
+        result => {
+          self.activate(index);
+          return result;
+        }
+      }
+    }
+
+    Err(last)
+  }
+}
+
+// Routes calls to the best of several endpoints, checking their health in the
+// background every check_interval.
+#[derive(Clone)]
+pub struct Failover {
+  endpoints: Arc<Endpoints>,
+  id: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for Failover {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Failover")
+      .field("active", &redact(&self.active()))
+      .finish()
+  }
+}
+
+impl Failover {
+  pub async fn new(urls: Vec<Url>, options: FailoverOptions) -> Result<Self> {
+    if urls.is_empty() {
+      return Err(message("failover needs at least one endpoint"));
+    }
+
+    let endpoints = Arc::new(Endpoints {
+      active: AtomicUsize::new(0),
+      endpoints: urls.into_iter().map(Endpoint::new).collect(),
+      options,
+    });
+
+    endpoints.check().await;
+    if endpoints
+      .endpoints
+      .iter()
+      .all(|endpoint| endpoint.transport().is_none())
+    {
+      return Err(message("none of the endpoints could be reached"));
+    }
+
+    let weak = Arc::downgrade(&endpoints);
+    task::spawn(async move {
+      loop {
+        task::sleep(options.check_interval).await;
+        match weak.upgrade() {
+          Some(endpoints) => endpoints.check().await,
+          None => break,
+        }
+      }
+    });
+
+    Ok(Self {
+      endpoints,
+      id: Arc::new(AtomicUsize::new(1)),
+    })
+  }
+
+  pub fn active(&self) -> Url {
+    let active = self.endpoints.active.load(Ordering::Acquire);
+    self.endpoints.endpoints[active].url.clone()
+  }
+
+  pub fn status(&self) -> Vec<EndpointStatus> {
+    let active = self.endpoints.active.load(Ordering::Acquire);
+    self
+      .endpoints
+      .endpoints
+      .iter()
+      .enumerate()
+      .map(|(index, endpoint)| {
+        let health = endpoint.health.read().unwrap();
+        EndpointStatus {
+          url: redact(&endpoint.url),
+          active: index == active,
+          connected: endpoint.transport().is_some(),
+          healthy: health.healthy,
+          block: health.block,
+          latency_ms: health.latency.map(|latency| latency.as_millis() as u64),
+        }
+      })
+      .collect()
+  }
+}
+
+impl Transport for Failover {
+  type Out = BoxFuture<'static, Result<rpc::Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, helpers::build_request(id, method, params))
+  }
+
+  fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+    let endpoints = self.endpoints.clone();
+    async move {
+      endpoints
+        .call(|transport| transport.send(id, request.clone()))
+        .await
+    }
+    .boxed()
+  }
+}
+
+impl BatchTransport for Failover {
+  type Batch = BoxFuture<'static, Result<Vec<Result<rpc::Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, rpc::Call)>,
+  {
+    let requests = requests.into_iter().collect::<Vec<_>>();
+    let endpoints = self.endpoints.clone();
+    async move {
+      endpoints
+        .call(|transport| transport.send_batch(requests.clone()))
+        .await
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+  use std::{net::TcpListener, sync::atomic::AtomicBool};
+  use tide::{Body, Request, Response, StatusCode};
+
+  type Handler = dyn Fn(&str, &[Value]) -> Value + Send + Sync;
+
+  // A node that answers each call with a handler (or with 503 Service
+  // Unavailable while it's down), and keeps the bodies of the requests it was
+  // sent.
+  #[derive(Clone)]
+  struct Node {
+    down: Arc<AtomicBool>,
+    handler: Arc<Handler>,
+    requests: Arc<std::sync::Mutex<Vec<Value>>>,
+  }
+
+  impl Node {
+    fn answer(&self, call: &Value) -> Value {
+      let method = call["method"].as_str().unwrap_or_default();
+      let params = call["params"].as_array().cloned().unwrap_or_default();
+      json!({"jsonrpc": "2.0", "id": call["id"], "result": (self.handler)(method, &params)})
+    }
+
+    fn requests(&self) -> Vec<Value> {
+      self.requests.lock().unwrap().clone()
+    }
+  }
+
+  fn serve(handler: impl Fn(&str, &[Value]) -> Value + Send + Sync + 'static) -> (Url, Node) {
+    let node = Node {
+      down: Arc::new(AtomicBool::new(false)),
+      handler: Arc::new(handler),
+      requests: Arc::new(std::sync::Mutex::new(vec![])),
+    };
+
+    let mut server = tide::with_state(node.clone());
+    server
+      .at("/")
+      .post(|mut request: Request<Node>| async move {
+        let body: Value = request.body_json().await?;
+        let node = request.state();
+        if node.down.load(Ordering::Acquire) {
+          return Ok(Response::new(StatusCode::ServiceUnavailable));
+        }
+        node.requests.lock().unwrap().push(body.clone());
+        let response = match &body {
+          Value::Array(calls) => Value::Array(calls.iter().map(|call| node.answer(call)).collect()),
+          call => node.answer(call),
+        };
+        Ok(Response::from(Body::from_json(&response)?))
+      });
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    task::spawn(server.listen(listener));
+    (url, node)
+  }
+
+  // A node at the given block, that answers eth_chainId with its block too, so
+  // tests can tell which node answered.
+  fn at_block(block: u64) -> (Url, Node) {
+    serve(move |_, _| json!(format!("{:#x}", block)))
+  }
+
+  fn options() -> FailoverOptions {
+    FailoverOptions {
+      check_interval: Duration::from_secs(3600),
+      ..FailoverOptions::default()
+    }
+  }
+
+  #[async_std::test]
+  async fn needs_an_endpoint() {
+    assert!(Failover::new(vec![], options()).await.is_err());
+  }
+
+  #[async_std::test]
+  async fn prefers_the_first_healthy_endpoint() {
+    let (behind, _) = at_block(10);
+    let (first, first_node) = at_block(20);
+    let (second, second_node) = at_block(20);
+    let failover = Failover::new(vec![behind, first.clone(), second], options())
+      .await
+      .unwrap();
+
+    assert_eq!(failover.active(), first);
+    let status = failover.status();
+    assert!(!status[0].healthy);
+    assert_eq!(status[0].block, Some(10));
+    assert!(status[1].active && status[1].healthy);
+    assert!(!status[2].active && status[2].healthy);
+
+    failover.execute("eth_chainId", vec![]).await.unwrap();
+    let methods = |node: &Node| {
+      node
+        .requests()
+        .iter()
+        .map(|request| request["method"].clone())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(methods(&first_node), vec!["eth_blockNumber", "eth_chainId"]);
+    assert_eq!(methods(&second_node), vec!["eth_blockNumber"]);
+  }
+
+  #[async_std::test]
+  async fn skips_endpoints_that_cannot_be_reached() {
+    let (url, _) = at_block(5);
+    let unreachable = Url::parse("ws://127.0.0.1:9/").unwrap();
+    let failover = Failover::new(vec![unreachable, url.clone()], options())
+      .await
+      .unwrap();
+
+    assert_eq!(failover.active(), url);
+    assert!(!failover.status()[0].connected);
+    assert!(
+      Failover::new(vec![Url::parse("ws://127.0.0.1:9/").unwrap()], options())
+        .await
+        .is_err()
+    );
+  }
+
+  #[async_std::test]
+  async fn fails_over_when_the_active_endpoint_goes_down() {
+    let (first, first_node) = at_block(7);
+    let (second, _) = serve(|_, _| json!("0x8"));
+    let failover = Failover::new(vec![first.clone(), second.clone()], options())
+      .await
+      .unwrap();
+    assert_eq!(failover.active(), first);
+
+    first_node.down.store(true, Ordering::Release);
+    let answer = failover.execute("eth_chainId", vec![]).await.unwrap();
+    assert_eq!(answer, json!("0x8"));
+    assert_eq!(failover.active(), second);
+    assert!(!failover.status()[0].healthy);
+
+    // Once it's back, and checked, the first endpoint is preferred again.
+    first_node.down.store(false, Ordering::Release);
+    failover.endpoints.check().await;
+    assert_eq!(failover.active(), first);
+  }
+
+  #[async_std::test]
+  async fn fails_when_every_endpoint_is_down() {
+    let (url, node) = at_block(1);
+    let failover = Failover::new(vec![url], options()).await.unwrap();
+    node.down.store(true, Ordering::Release);
+    assert!(failover.execute("eth_chainId", vec![]).await.is_err());
+  }
+
+  fn batching(url: Url, max_size: usize) -> Batching {
+    Batching::new(
+      DynTransport::new(Http::new(url)),
+      BatchOptions {
+        window: Duration::from_millis(20),
+        max_size,
+      },
+    )
+  }
+
+  // The number of calls in each request the node was sent (with 0 for a call
+  // sent on its own).
+  fn sizes(node: &Node) -> Vec<usize> {
+    node
+      .requests()
+      .iter()
+      .map(|request| request.as_array().map_or(0, Vec::len))
+      .collect()
+  }
+
+  fn echo() -> (Url, Node) {
+    serve(|method, params| json!(format!("{}{}", method, Value::from(params))))
+  }
+
+  #[async_std::test]
+  async fn batches_calls_made_together() {
+    let (url, node) = echo();
+    let transport = batching(url, 100);
+    let results =
+      join_all((0..3).map(|n| transport.execute("eth_getBalance", vec![json!(n)]))).await;
+
+    assert_eq!(sizes(&node), vec![3]);
+    for (n, result) in results.into_iter().enumerate() {
+      assert_eq!(result.unwrap(), json!(format!("eth_getBalance[{}]", n)));
+    }
+  }
+
+  #[async_std::test]
+  async fn sends_lone_calls_on_their_own() {
+    let (url, node) = echo();
+    let transport = batching(url, 100);
+    transport.execute("eth_chainId", vec![]).await.unwrap();
+    transport.execute("eth_chainId", vec![]).await.unwrap();
+    assert_eq!(sizes(&node), vec![0, 0]);
+  }
+
+  #[async_std::test]
+  async fn splits_batches_at_the_maximum_size() {
+    let (url, node) = echo();
+    let transport = batching(url, 2);
+    let results =
+      join_all((0..5).map(|n| transport.execute("eth_getBalance", vec![json!(n)]))).await;
+
+    assert!(results.iter().all(Result::is_ok));
+    let mut sizes = sizes(&node);
+    sizes.sort_unstable();
+    assert_eq!(sizes, vec![0, 2, 2]);
+  }
+
+  #[async_std::test]
+  async fn shares_responses_to_identical_reads() {
+    let (url, node) = echo();
+    let transport = batching(url, 100);
+    let results = join_all((0..3).map(|_| transport.execute("eth_blockNumber", vec![]))).await;
+
+    assert_eq!(sizes(&node), vec![0]);
+    assert!(results
+      .iter()
+      .all(|result| result.as_ref().unwrap() == "eth_blockNumber[]"));
+  }
+
+  #[async_std::test]
+  async fn sends_identical_writes_each_time() {
+    let (url, node) = echo();
+    let transport = batching(url, 100);
+    let params = vec![json!("0x00")];
+    join_all((0..2).map(|_| transport.execute("eth_sendRawTransaction", params.clone()))).await;
+    assert_eq!(sizes(&node), vec![2]);
+  }
+
+  #[async_std::test]
+  async fn fails_every_call_in_a_failed_batch() {
+    let (url, node) = echo();
+    node.down.store(true, Ordering::Release);
+    let transport = batching(url, 100);
+    let results =
+      join_all((0..2).map(|n| transport.execute("eth_getBalance", vec![json!(n)]))).await;
+    assert!(results.iter().all(Result::is_err));
+
+    // Nothing is left waiting on the failed responses.
+    node.down.store(false, Ordering::Release);
+    assert!(transport
+      .execute("eth_getBalance", vec![json!(0)])
+      .await
+      .is_ok());
+  }
+}
@@ -0,0 +1,48 @@
+use niftygate::middleware::ethereum::{AssertionVerifier, SessionKey, SessionKeys};
+use secp256k1::PublicKey;
+use std::str::FromStr;
+use tide::{log, Body, Request, Response, StatusCode};
+
+type WrappedError = Box<dyn std::error::Error>;
+
+// A backend that only trusts the identity assertion, not the plain headers.
+// Assertions signed with the proxy's secret key are checked with its public key
+// (ASSERTION_PUBLIC_KEY, as printed by --check-config), and HS256 assertions
+// with a shared key (ASSERTION_KEY, as id:secret).
+#[async_std::main]
+async fn main() -> std::result::Result<(), WrappedError> {
+  log::with_level(log::LevelFilter::Debug);
+
+  let verifier = match std::env::var("ASSERTION_PUBLIC_KEY") {
+    Ok(public_key) => AssertionVerifier::Secp256k1(PublicKey::from_str(&public_key)?),
+    Err(_) => AssertionVerifier::Hmac(SessionKeys(vec![SessionKey::from_str(&std::env::var(
+      "ASSERTION_KEY",
+    )?)?])),
+  };
+
+  let mut server = tide::with_state(verifier);
+  server
+    .at("*")
+    .all(|request: Request<AssertionVerifier>| async move {
+      let token = match request.header("X-Web3-Assertion") {
+        Some(values) => values.last().as_str().to_string(),
+        None => return Ok(Response::new(StatusCode::Unauthorized)),
+      };
+
+      match request.state().verify(&token) {
+        Ok(assertion) if assertion.is_for(request.method().as_ref(), request.url().path()) => {
+          let mut response = Response::new(StatusCode::Ok);
+          response.set_body(Body::from_json(&assertion)?);
+          Ok(response)
+        }
+        Ok(_) => Ok(Response::new(StatusCode::Forbidden)),
+        Err(e) => {
+          log::debug!("Assertion: {}", e);
+          Ok(Response::new(StatusCode::Unauthorized))
+        }
+      }
+    });
+  server.listen("127.0.0.1:8080").await?;
+
+  Ok(())
+}
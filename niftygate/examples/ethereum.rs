@@ -28,17 +28,26 @@ async fn main() -> WrappedResult<()> {
       secret_key: SecretKey::from_slice(&secret_key_data)?,
       web3: web3.clone(),
       challenge: b"totes-legit".to_vec(),
+      nonce_header: HeaderName::from_string(String::from("X-Web3-Nonce"))?,
+      typed_data: None,
     })
     .with(ProvidesAccountVerification {
       signature_header: HeaderName::from_string(String::from("X-Web3-Signature"))?,
       address_header: HeaderName::from_string(String::from("X-Web3-Account-Address"))?,
+      chains: vec![],
+      claimed_address_header: None,
+      code_cache: None,
       status_code: StatusCode::PaymentRequired,
       web3: web3.clone(),
       challenge: b"totes-legit".to_vec(),
+      nonce_header: HeaderName::from_string(String::from("X-Web3-Nonce"))?,
+      nonces: None,
+      typed_data: None,
     })
     .with(ProvidesBalance {
       address_header: HeaderName::from_string(String::from("X-Web3-Account-Address"))?,
       balance_header: HeaderName::from_string(String::from("X-Web3-Account-Balance"))?,
+      cache: None,
       web3: web3.clone(),
     })
     .with(
@@ -0,0 +1,415 @@
+use crate::{
+  application::proxy::Config,
+  middleware::forward_auth::{
+    X_FORWARDED_HOST, X_FORWARDED_METHOD, X_FORWARDED_PROTO, X_FORWARDED_URI,
+  },
+};
+use anyhow::{anyhow, Result};
+use futures::channel::oneshot;
+use proto::{
+  attribute_context::HttpRequest, check_response::HttpResponse, CheckRequest, CheckResponse,
+  DeniedHttpResponse, HeaderValue, HeaderValueOption, HttpStatus, OkHttpResponse, Status,
+};
+use std::{iter, net::ToSocketAddrs, str::FromStr, sync::Arc, thread};
+use tide::{
+  http::{self, headers::HeaderName, Method, StatusCode, Url},
+  Server,
+};
+use tonic::{
+  codec::ProstCodec,
+  codegen::{empty_body, http as grpc_http, BoxFuture, Context, Poll, Service, StdError},
+  server::{Grpc, NamedService, UnaryService},
+  transport,
+};
+
+pub mod proto;
+
+const CHECK_PATH: &str = "/envoy.service.auth.v3.Authorization/Check";
+
+// Headers from a denied response that are passed along to the client, besides
+// identity headers.
+const DENIED_HEADERS: [&str; 3] = ["Content-Type", "Location", "WWW-Authenticate"];
+
+// google.rpc.Code
+const OK: i32 = 0;
+const PERMISSION_DENIED: i32 = 7;
+const UNAUTHENTICATED: i32 = 16;
+
+// Answers Envoy's ext_authz Check calls, by sending the original request
+// through a forward-auth server. Identity headers are added to allowed
+// requests, and any the client sent that weren't added are removed.
+#[derive(Clone)]
+pub struct Authorization {
+  pub server: Server<()>,
+  pub headers: Vec<HeaderName>,
+}
+
+// Every header a forward-auth server for this config can hand back, for any
+// host.
+pub fn identity_headers(config: &Config) -> Vec<HeaderName> {
+  let mut headers: Vec<HeaderName> = vec![];
+  let configs = iter::once(config).chain(config.hosts.iter().map(|host| &host.config));
+  for header in configs.flat_map(Config::identity_headers) {
+    if !headers.contains(header) {
+      headers.push(header.clone());
+    }
+  }
+  headers
+}
+
+impl Authorization {
+  // tonic needs tokio, so the gRPC server gets a runtime (and a thread) of its
+  // own.
+  pub async fn listen(self, listen: &str) -> Result<()> {
+    let address = listen
+      .to_socket_addrs()?
+      .next()
+      .ok_or_else(|| anyhow!("{} is not a valid address", listen))?;
+
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+      let serve = || -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(
+          transport::Server::builder()
+            .add_service(AuthorizationServer(Arc::new(self)))
+            .serve(address),
+        )?;
+        Ok(())
+      };
+      let _ = sender.send(serve());
+    });
+
+    receiver.await?
+  }
+
+  pub async fn check(&self, request: CheckRequest) -> CheckResponse {
+    let http_request = request
+      .attributes
+      .and_then(|attributes| attributes.request)
+      .and_then(|request| request.http)
+      .unwrap_or_default();
+
+    // The forward-auth server rebuilds the URL from the X-Forwarded-* headers.
+    let server = self.server.clone();
+    let request = forwarded_request(http_request);
+    let mut response: http::Response =
+      match async_std::task::spawn(async move { server.respond(request).await }).await {
+        Ok(response) => response,
+        Err(error) => return denied(StatusCode::InternalServerError, error.to_string()),
+      };
+
+    if !response.status().is_success() {
+      let status = response.status();
+      let body = response.body_string().await.unwrap_or_default();
+      let mut check_response = denied(status, body);
+      if let Some(HttpResponse::DeniedResponse(denied)) = &mut check_response.http_response {
+        // Envoy frames the body itself, so only headers that describe it (or
+        // tell the client how to authenticate) are passed along.
+        denied.headers = response
+          .iter()
+          .filter(|(name, _)| {
+            self.headers.contains(name)
+              || DENIED_HEADERS
+                .iter()
+                .any(|header| name.as_str().eq_ignore_ascii_case(header))
+          })
+          .map(|(name, values)| header_value_option(name, values.last().as_str()))
+          .collect();
+      }
+      return check_response;
+    }
+
+    let mut ok = OkHttpResponse::default();
+    for header in &self.headers {
+      match response.header(header) {
+        Some(values) => ok
+          .headers
+          .push(header_value_option(header, values.last().as_str())),
+        None => ok.headers_to_remove.push(header.as_str().to_lowercase()),
+      }
+    }
+
+    CheckResponse {
+      status: Some(Status {
+        code: OK,
+        message: String::new(),
+      }),
+      http_response: Some(HttpResponse::OkResponse(ok)),
+    }
+  }
+}
+
+fn forwarded_request(http_request: HttpRequest) -> http::Request {
+  let mut request = http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+
+  // Envoy includes pseudo-headers like :path, which aren't valid names here.
+  for (name, value) in &http_request.headers {
+    if let Ok(name) = HeaderName::from_str(name) {
+      request.append_header(name, value.as_str());
+    }
+  }
+
+  // These always come from Envoy, never from the client.
+  let forwarded = [
+    (X_FORWARDED_METHOD, http_request.method),
+    (X_FORWARDED_URI, http_request.path),
+    (X_FORWARDED_HOST, http_request.host),
+    (X_FORWARDED_PROTO, http_request.scheme),
+  ];
+  for (name, value) in forwarded.iter() {
+    match value.is_empty() {
+      true => request.remove_header(*name),
+      false => request.insert_header(*name, value.as_str()),
+    };
+  }
+
+  request
+}
+
+fn denied(status: StatusCode, body: String) -> CheckResponse {
+  let code = match status {
+    StatusCode::Unauthorized => UNAUTHENTICATED,
+    _ => PERMISSION_DENIED,
+  };
+
+  CheckResponse {
+    status: Some(Status {
+      code,
+      message: status.canonical_reason().to_owned(),
+    }),
+    http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+      status: Some(HttpStatus {
+        code: status as i32,
+      }),
+      headers: vec![],
+      body,
+    })),
+  }
+}
+
+fn header_value_option(name: &HeaderName, value: &str) -> HeaderValueOption {
+  HeaderValueOption {
+    header: Some(HeaderValue {
+      key: name.as_str().to_lowercase(),
+      value: value.to_owned(),
+    }),
+    // Replaces the header rather than adding to it, so clients can't add
+    // values of their own.
+    append: Some(false),
+  }
+}
+
+// The gRPC service, written the way tonic-build would generate it, so the
+// build doesn't need protoc.
+#[derive(Clone)]
+struct AuthorizationServer(Arc<Authorization>);
+
+struct Check(Arc<Authorization>);
+
+impl UnaryService<CheckRequest> for Check {
+  type Response = CheckResponse;
+  type Future = BoxFuture<tonic::Response<CheckResponse>, tonic::Status>;
+
+  fn call(&mut self, request: tonic::Request<CheckRequest>) -> Self::Future {
+    let authorization = self.0.clone();
+    Box::pin(async move {
+      Ok(tonic::Response::new(
+        authorization.check(request.into_inner()).await,
+      ))
+    })
+  }
+}
+
+impl<B> Service<grpc_http::Request<B>> for AuthorizationServer
+where
+  B: tonic::codegen::Body + Send + 'static,
+  B::Error: Into<StdError> + Send + 'static,
+{
+  type Response = grpc_http::Response<tonic::body::BoxBody>;
+  type Error = std::convert::Infallible;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, request: grpc_http::Request<B>) -> Self::Future {
+    let authorization = self.0.clone();
+    match request.uri().path() {
+      CHECK_PATH => Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::default());
+        Ok(grpc.unary(Check(authorization), request).await)
+      }),
+      _ => Box::pin(async move {
+        Ok(
+          grpc_http::Response::builder()
+            .status(200)
+            .header("grpc-status", "12")
+            .header("content-type", "application/grpc")
+            .body(empty_body())
+            .unwrap(),
+        )
+      }),
+    }
+  }
+}
+
+impl NamedService for AuthorizationServer {
+  const NAME: &'static str = "envoy.service.auth.v3.Authorization";
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::ForwardAuth;
+  use proto::{attribute_context, AttributeContext};
+  use std::{collections::HashMap, net::TcpListener, time::Duration};
+  use tonic::{client, codegen::http::uri::PathAndQuery};
+
+  // Allows requests for /allowed (adding an identity header), and denies the
+  // rest, with headers of every kind.
+  fn forward_auth_server() -> Server<()> {
+    let mut server = tide::new();
+    server.at("*").all(|request: tide::Request<()>| async move {
+      match request.url().path() {
+        "/allowed" => {
+          let mut response = tide::Response::new(StatusCode::Ok);
+          response.insert_header("X-Web3-Account-Address", "01");
+          Ok(response)
+        }
+        _ => {
+          let mut response = tide::Response::new(StatusCode::Unauthorized);
+          response.insert_header("WWW-Authenticate", "Bearer");
+          response.insert_header("Transfer-Encoding", "chunked");
+          response.insert_header("Date", "Tue, 15 Nov 1994 08:12:31 GMT");
+          response.insert_header("X-Internal", "secret");
+          response.insert_header("X-Web3-Account-Balance", "0");
+          response.set_body("denied");
+          Ok(response)
+        }
+      }
+    });
+
+    // Requests are rebuilt from the X-Forwarded-* headers first, as in
+    // forward-auth mode.
+    let mut outer = tide::new();
+    outer.with(ForwardAuth {
+      server,
+      denied_status: StatusCode::Forbidden,
+    });
+    outer
+  }
+
+  fn check_request(path: &str) -> CheckRequest {
+    let mut headers = HashMap::new();
+    headers.insert(String::from(":path"), path.to_owned());
+    headers.insert(String::from("x-web3-account-address"), String::from("02"));
+    CheckRequest {
+      attributes: Some(AttributeContext {
+        request: Some(attribute_context::Request {
+          http: Some(HttpRequest {
+            method: String::from("GET"),
+            headers,
+            path: path.to_owned(),
+            host: String::from("example.com"),
+            scheme: String::from("https"),
+            ..HttpRequest::default()
+          }),
+        }),
+      }),
+    }
+  }
+
+  fn headers(headers: &[HeaderValueOption]) -> Vec<(String, String)> {
+    headers
+      .iter()
+      .filter_map(|option| option.header.as_ref())
+      .map(|header| (header.key.clone(), header.value.clone()))
+      .collect()
+  }
+
+  // Sends Check calls the way Envoy does, with a gRPC client.
+  fn check(address: &str, request: CheckRequest) -> CheckResponse {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+      let endpoint = transport::Endpoint::from_shared(format!("http://{}", address)).unwrap();
+      let mut attempts = 0;
+      let channel = loop {
+        match endpoint.connect().await {
+          Ok(channel) => break channel,
+          Err(_) if attempts < 50 => {
+            attempts += 1;
+            std::thread::sleep(Duration::from_millis(20));
+          }
+          Err(error) => panic!("{}", error),
+        }
+      };
+      let mut grpc = client::Grpc::new(channel);
+      grpc.ready().await.unwrap();
+      grpc
+        .unary(
+          tonic::Request::new(request),
+          PathAndQuery::from_static(CHECK_PATH),
+          ProstCodec::default(),
+        )
+        .await
+        .unwrap()
+        .into_inner()
+    })
+  }
+
+  #[test]
+  fn answers_check_calls() {
+    let address = TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .to_string();
+    let authorization = Authorization {
+      server: forward_auth_server(),
+      headers: vec![
+        HeaderName::from("X-Web3-Account-Address"),
+        HeaderName::from("X-Web3-Account-Balance"),
+      ],
+    };
+    let listen = address.clone();
+    async_std::task::spawn(async move { authorization.listen(&listen).await });
+
+    let allowed = check(&address, check_request("/allowed"));
+    assert_eq!(allowed.status.unwrap().code, OK);
+    match allowed.http_response {
+      Some(HttpResponse::OkResponse(ok)) => {
+        assert_eq!(
+          headers(&ok.headers),
+          vec![(String::from("x-web3-account-address"), String::from("01"))]
+        );
+        assert_eq!(ok.headers_to_remove, vec!["x-web3-account-balance"]);
+      }
+      _ => panic!("expected an ok response"),
+    }
+
+    let denied = check(&address, check_request("/denied"));
+    assert_eq!(denied.status.unwrap().code, UNAUTHENTICATED);
+    match denied.http_response {
+      Some(HttpResponse::DeniedResponse(denied)) => {
+        assert_eq!(denied.status.unwrap().code, 401);
+        assert_eq!(denied.body, "denied");
+        let mut headers = headers(&denied.headers);
+        headers.sort();
+        assert_eq!(
+          headers,
+          vec![
+            (
+              String::from("content-type"),
+              String::from("text/plain;charset=utf-8")
+            ),
+            (String::from("www-authenticate"), String::from("Bearer")),
+            (String::from("x-web3-account-balance"), String::from("0")),
+          ]
+        );
+      }
+      _ => panic!("expected a denied response"),
+    }
+  }
+}
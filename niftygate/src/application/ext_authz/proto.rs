@@ -0,0 +1,114 @@
+// The parts of envoy.service.auth.v3 (and the messages it pulls in) that are
+// needed to answer Check. Field numbers match the upstream .proto files, and
+// fields that aren't used here are left out, which protobuf allows.
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckRequest {
+  #[prost(message, optional, tag = "1")]
+  pub attributes: Option<AttributeContext>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttributeContext {
+  #[prost(message, optional, tag = "4")]
+  pub request: Option<attribute_context::Request>,
+}
+
+pub mod attribute_context {
+  use super::HashMap;
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct Request {
+    #[prost(message, optional, tag = "2")]
+    pub http: Option<HttpRequest>,
+  }
+
+  #[derive(Clone, PartialEq, prost::Message)]
+  pub struct HttpRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub method: String,
+    #[prost(map = "string, string", tag = "3")]
+    pub headers: HashMap<String, String>,
+    // Includes the query string, unlike the URL path.
+    #[prost(string, tag = "4")]
+    pub path: String,
+    #[prost(string, tag = "5")]
+    pub host: String,
+    #[prost(string, tag = "6")]
+    pub scheme: String,
+    #[prost(string, tag = "10")]
+    pub protocol: String,
+  }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckResponse {
+  #[prost(message, optional, tag = "1")]
+  pub status: Option<Status>,
+  #[prost(oneof = "check_response::HttpResponse", tags = "2, 3")]
+  pub http_response: Option<check_response::HttpResponse>,
+}
+
+pub mod check_response {
+  #[derive(Clone, PartialEq, prost::Oneof)]
+  pub enum HttpResponse {
+    #[prost(message, tag = "2")]
+    DeniedResponse(super::DeniedHttpResponse),
+    #[prost(message, tag = "3")]
+    OkResponse(super::OkHttpResponse),
+  }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeniedHttpResponse {
+  #[prost(message, optional, tag = "1")]
+  pub status: Option<HttpStatus>,
+  #[prost(message, repeated, tag = "2")]
+  pub headers: Vec<HeaderValueOption>,
+  #[prost(string, tag = "3")]
+  pub body: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OkHttpResponse {
+  #[prost(message, repeated, tag = "2")]
+  pub headers: Vec<HeaderValueOption>,
+  #[prost(string, repeated, tag = "5")]
+  pub headers_to_remove: Vec<String>,
+}
+
+// envoy.config.core.v3.HeaderValueOption, where append is a BoolValue.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValueOption {
+  #[prost(message, optional, tag = "1")]
+  pub header: Option<HeaderValue>,
+  #[prost(message, optional, tag = "2")]
+  pub append: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValue {
+  #[prost(string, tag = "1")]
+  pub key: String,
+  #[prost(string, tag = "2")]
+  pub value: String,
+}
+
+// envoy.type.v3.HttpStatus, where code is an enum of HTTP status codes.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpStatus {
+  #[prost(int32, tag = "1")]
+  pub code: i32,
+}
+
+// google.rpc.Status
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+  #[prost(int32, tag = "1")]
+  pub code: i32,
+  #[prost(string, tag = "2")]
+  pub message: String,
+}
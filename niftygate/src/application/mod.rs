@@ -1,2 +1,3 @@
 pub mod demo;
+pub mod ext_authz;
 pub mod proxy;
@@ -2,70 +2,103 @@ use crate::middleware::{
   ethereum::{prelude::*, *},
   *,
 };
-use anyhow::Result;
-use tide::Server;
-
-pub struct ERC1155Config {
-  pub contract_address: Address,
-  pub balance_header: HeaderName,
-  pub balance_requirement: Option<BalanceRequirement>,
-  pub provides_balances: bool,
-}
+use anyhow::{bail, Result};
+use ethcontract::{dyns::DynWeb3, transport::DynTransport, Web3};
+use niftygate_contract::transport::{self, redact, Batching, Failover};
+use rand::Rng;
+use std::{collections::HashMap, sync::Arc};
+use tide::{
+  http::cookies::SameSite,
+  sessions::{MemoryStore, SessionMiddleware},
+  utils::async_trait,
+  Endpoint, Middleware, Next, Request, Response, Route, Server,
+};
 
-pub struct ERC20Config {
-  pub contract_address: Address,
-  pub balance_header: HeaderName,
-  pub balance_requirement: Option<BalanceRequirement>,
-  pub name_header: HeaderName,
-  pub symbol_header: HeaderName,
-  pub provides_balances: bool,
-  pub provides_name: bool,
-  pub provides_symbol: bool,
-}
+mod config;
+mod tls;
 
-pub struct ERC721Config {
-  pub contract_address: Address,
-  pub balance_header: HeaderName,
-  pub balance_requirement: Option<BalanceRequirement>,
-  pub name_header: HeaderName,
-  pub symbol_header: HeaderName,
-  pub provides_balances: bool,
-  pub provides_name: bool,
-  pub provides_symbol: bool,
-}
+pub use config::{
+  AssertionConfig, BatchConfig, CacheConfig, ChainConfig, Config, DefaultRoute, ERC1155Config,
+  ERC20Config, ERC721Config, ERC777Config, ExtAuthzConfig, FailoverConfig, ForwardAuthConfig,
+  HostConfig, IndexConfig, NonceConfig, PoolConfig, RouteConfig, SessionTokenConfig, SiweConfig,
+  TlsConfig, TokenRequirement,
+};
+pub use tls::server_config;
 
-pub struct ERC777Config {
-  pub contract_address: Address,
-  pub balance_header: HeaderName,
-  pub balance_requirement: Option<BalanceRequirement>,
-  pub name_header: HeaderName,
-  pub symbol_header: HeaderName,
-  pub provides_balances: bool,
-  pub provides_name: bool,
-  pub provides_symbol: bool,
-}
+async fn connect(
+  server: &mut Server<()>,
+  rpc_url: Url,
+  failover_config: Option<&FailoverConfig>,
+  batch_config: Option<&BatchConfig>,
+) -> Result<DynWeb3> {
+  let transport = match failover_config {
+    None => transport::connect(&rpc_url).await?,
+    Some(failover_config) => {
+      let mut urls = vec![rpc_url];
+      urls.extend(failover_config.urls.iter().cloned());
+      let failover = Failover::new(urls, failover_config.options()).await?;
+
+      if let Some(path) = &failover_config.status_path {
+        server.with(ProvidesRpcStatus {
+          path: path.clone(),
+          failover: failover.clone(),
+        });
+      }
 
-pub struct Config {
-  pub address_header: HeaderName,
-  pub backend: Url,
-  pub balance_header: HeaderName,
-  pub balance_requirement: Option<BalanceRequirement>,
-  pub balance_scale: Option<BalanceScale>,
-  pub challenge: Vec<u8>,
-  pub provides_account_verification: bool,
-  pub provides_balances: bool,
-  pub erc20: ERC20Config,
-  pub erc1155: ERC1155Config,
-  pub erc721: ERC721Config,
-  pub erc777: ERC777Config,
-  pub provides_signatures: bool,
-  pub web3_rpc_url: Url,
-  pub secret_key: Option<SecretKey>,
-  pub signature_header: HeaderName,
+      DynTransport::new(failover)
+    }
+  };
+
+  match batch_config {
+    None => Ok(Web3::new(transport)),
+    Some(batch_config) => Ok(Web3::new(DynTransport::new(Batching::new(
+      transport,
+      batch_config.options(),
+    )))),
+  }
 }
 
-pub async fn server(config: Config) -> Result<Server<()>> {
+pub async fn server(mut config: Config) -> Result<Server<()>> {
+  config.validate()?;
+
+  let forward_auth = config.forward_auth.take();
+
   let mut server = tide::new();
+
+  if !config.hosts.is_empty() {
+    let mut hosts = HashMap::new();
+    for host in config.hosts.drain(..) {
+      let site = site(tide::new(), host.config, forward_auth.is_some()).await?;
+      for name in host.names {
+        hosts.insert(name.to_lowercase(), site.clone());
+      }
+    }
+    server.with(VirtualHosts { hosts });
+  }
+
+  let server = site(server, config, forward_auth.is_some()).await?;
+
+  match forward_auth {
+    None => Ok(server),
+    Some(forward_auth) => {
+      let mut outer = tide::new();
+      outer.with(ForwardAuth {
+        server,
+        denied_status: forward_auth.denied_status,
+      });
+      Ok(outer)
+    }
+  }
+}
+
+async fn site(mut server: Server<()>, config: Config, forward_auth: bool) -> Result<Server<()>> {
+  if config.strip_identity_headers {
+    server.with(RemovesHeaders {
+      headers: config.identity_headers().into_iter().cloned().collect(),
+      trusted_hops: config.trusted_hops.clone(),
+    });
+  }
+
   server.with(ProvidesForwardedHeader);
 
   let cors = tide::security::CorsMiddleware::new()
@@ -74,7 +107,115 @@ pub async fn server(config: Config) -> Result<Server<()>> {
     .allow_methods("GET, POST, OPTIONS".parse::<HeaderValue>().unwrap());
   server.with(cors);
 
-  let web3 = crate::util::web3_from_url(config.web3_rpc_url).await?;
+  let claim_headers: Vec<HeaderName> = config.provided_headers().into_iter().cloned().collect();
+  let assertion_claim_headers = config.assertion_claim_headers();
+  let session_audience = config.session_audience().unwrap_or_default();
+
+  // In forward-auth mode, requests that pass the checks are answered here
+  // rather than proxied.
+  let identity = match forward_auth {
+    true => Some(ProvidesIdentity {
+      headers: config.identity_headers().into_iter().cloned().collect(),
+    }),
+    false => None,
+  };
+
+  let web3 = connect(
+    &mut server,
+    config.web3_rpc_url,
+    config.failover.as_ref(),
+    config.batching.as_ref(),
+  )
+  .await?;
+
+  let mut chains = HashMap::new();
+  let mut chain_ids = HashMap::new();
+  for chain in &config.chains {
+    let chain_web3 = connect(
+      &mut server,
+      chain.rpc_url.clone(),
+      chain.failover.as_ref(),
+      config.batching.as_ref(),
+    )
+    .await?;
+    let chain_id = chain_web3.eth().chain_id().await?;
+    if chain_id != chain.chain_id.into() {
+      bail!(
+        "chain {:?} is configured with chain id {}, but {} reports {}",
+        chain.name,
+        chain.chain_id,
+        redact(&chain.rpc_url),
+        chain_id
+      );
+    }
+    chains.insert(chain.name.clone(), chain_web3);
+    chain_ids.insert(chain.name.clone(), chain.chain_id);
+  }
+
+  let web3_for = |chain: &Option<String>| match chain {
+    Some(name) => chains[name].clone(),
+    None => web3.clone(),
+  };
+
+  // Handles for each chain share the same entries, keyed by chain id.
+  let cache = match &config.cache {
+    None => None,
+    Some(cache_config) => {
+      let cache = BalanceCache::new(cache_config.options());
+      if let Some(path) = &cache_config.status_path {
+        server.with(ProvidesCacheStatus {
+          path: path.clone(),
+          cache: cache.clone(),
+        });
+      }
+      Some(cache.for_chain(web3.eth().chain_id().await?.as_u64()))
+    }
+  };
+
+  let cache_for = |chain: &Option<String>| {
+    cache.as_ref().map(|cache| match chain {
+      Some(name) => cache.for_chain(chain_ids[name]),
+      None => cache.clone(),
+    })
+  };
+
+  if let Some(siwe) = &config.siwe {
+    let secret = match &siwe.session_secret {
+      Some(secret) => secret.clone(),
+      None => rand::thread_rng().gen::<[u8; 32]>().to_vec(),
+    };
+
+    server.with(
+      SessionMiddleware::new(MemoryStore::new(), &secret)
+        .with_cookie_name("niftygate.sid")
+        .with_session_ttl(Some(siwe.session_lifetime))
+        .with_same_site_policy(SameSite::Lax),
+    );
+
+    server.with(ProvidesSignInWithEthereum {
+      address_header: config.address_header.clone(),
+      chain_id: siwe.chain_id,
+      domain: siwe.domain.clone().unwrap_or_default(),
+      uri: siwe.uri.clone(),
+      nonces: siwe.nonces(),
+      session_lifetime: siwe.session_lifetime,
+      web3: web3.clone(),
+    });
+  }
+
+  if let Some(session_tokens) = &config.session_tokens {
+    server.with(ProvidesSessionTokenVerification {
+      address_header: config.address_header.clone(),
+      audience: session_audience.clone(),
+      cookie_name: session_tokens.cookie_name.clone(),
+      keys: SessionKeys(session_tokens.keys.clone()),
+    });
+  }
+
+  let nonce_header = match &config.nonces {
+    Some(nonces) => nonces.header.clone(),
+    None => NonceConfig::default().header,
+  };
 
   if config.provides_signatures {
     server.with(ProvidesSignature {
@@ -84,148 +225,399 @@ pub async fn server(config: Config) -> Result<Server<()>> {
         .expect("Cannot provide signatures without a secret key!"),
       web3: web3.clone(),
       challenge: config.challenge.clone(),
+      nonce_header: nonce_header.clone(),
+      typed_data: config.typed_data.clone(),
     });
   }
 
+  let nonces = config.nonces.as_ref().map(NonceConfig::nonces);
+
+  if let (Some(nonce_config), Some(nonces)) = (&config.nonces, &nonces) {
+    server.with(ProvidesChallenge {
+      path: nonce_config.path.clone(),
+      challenge: config.challenge.clone(),
+      nonces: nonces.clone(),
+      typed_data: config.typed_data.clone(),
+    });
+  }
+
+  let mut checks: Vec<Arc<dyn Middleware<()>>> = vec![];
+
   if config.provides_account_verification {
-    server.with(ProvidesAccountVerification {
+    checks.push(Arc::new(ProvidesAccountVerification {
+      chains: match config.contract_wallets {
+        true => config
+          .chains
+          .iter()
+          .map(|chain| chains[&chain.name].clone())
+          .collect(),
+        false => vec![],
+      },
+      claimed_address_header: match config.contract_wallets {
+        true => Some(config.claimed_address_header.clone()),
+        false => None,
+      },
+      code_cache: match config.contract_wallets {
+        true => Some(CodeCache::default()),
+        false => None,
+      },
       signature_header: config.signature_header.clone(),
       address_header: config.address_header.clone(),
       status_code: StatusCode::PaymentRequired,
       web3: web3.clone(),
       challenge: config.challenge.clone(),
-    });
+      nonce_header: nonce_header.clone(),
+      nonces,
+      typed_data: config.typed_data.clone(),
+    }));
   }
 
+  // Balances are looked up all at once, before any of the requirements are
+  // checked.
+  let mut lookups: Vec<Arc<dyn Lookup<()>>> = vec![];
+  let mut requirements: Vec<Arc<dyn Middleware<()>>> = vec![];
+
   if config.provides_balances {
-    server.with(ProvidesBalance {
+    lookups.push(Arc::new(UnlessSessionToken(ProvidesBalance {
       address_header: config.address_header.clone(),
       balance_header: config.balance_header.clone(),
+      cache: cache_for(&None),
       web3: web3.clone(),
-    });
+    })));
   }
 
   if let Some(requirement) = config.balance_requirement {
-    server.with(
+    requirements.push(Arc::new(
       RequiresBalance {
         header: config.balance_header.clone(),
         requirement,
       }
-      .scale(config.balance_scale.unwrap_or(BalanceScale::Gwei)),
-    );
+      .scale(config.balance_scale),
+    ));
   }
 
-  if !config.erc1155.contract_address.is_zero() {
-    if config.erc1155.provides_balances {
-      server.with(ProvidesERC1155Balance {
+  for erc1155 in config.erc1155 {
+    if erc1155.provides_balances {
+      lookups.push(Arc::new(UnlessSessionToken(ProvidesERC1155Balance {
         address_header: config.address_header.clone(),
-        balance_header: config.erc1155.balance_header.clone(),
-        contract: ERC1155::at(&web3, config.erc1155.contract_address),
-      });
+        balance_header: erc1155.balance_header.clone(),
+        token_balance_header: erc1155.token_balance_header.clone(),
+        token_ids: erc1155.token_ids.iter().collect(),
+        cache: cache_for(&erc1155.chain),
+        index: match &erc1155.index {
+          None => None,
+          Some(index) => Some(
+            HolderIndex::new(
+              TokenStandard::ERC1155,
+              erc1155.contract_address,
+              web3_for(&erc1155.chain),
+              index.options(),
+            )
+            .await?,
+          ),
+        },
+        contract: ERC1155::at(&web3_for(&erc1155.chain), erc1155.contract_address),
+      })));
     }
 
-    if let Some(requirement) = config.erc1155.balance_requirement {
-      server.with(RequiresBalance {
-        header: config.erc1155.balance_header.clone(),
+    if let Some(requirement) = erc1155.balance_requirement {
+      requirements.push(Arc::new(RequiresBalance {
+        header: erc1155.balance_header,
         requirement,
-      });
+      }));
+    }
+
+    for token_requirement in erc1155.token_requirements {
+      requirements.push(Arc::new(RequiresTokenBalance {
+        header: erc1155.token_balance_header.clone(),
+        token_ids: token_requirement.token_ids,
+        requirement: token_requirement.requirement,
+      }));
     }
   }
 
-  if !config.erc20.contract_address.is_zero() {
-    if config.erc20.provides_balances {
-      let name_header = if config.erc20.provides_name {
-        Some(config.erc20.name_header)
+  for erc20 in config.erc20 {
+    if erc20.provides_balances {
+      let name_header = if erc20.provides_name {
+        Some(erc20.name_header)
       } else {
         None
       };
 
-      let symbol_header = if config.erc20.provides_symbol {
-        Some(config.erc20.symbol_header)
+      let symbol_header = if erc20.provides_symbol {
+        Some(erc20.symbol_header)
       } else {
         None
       };
 
-      server.with(ProvidesERC20Balance {
+      lookups.push(Arc::new(UnlessSessionToken(ProvidesERC20Balance {
         address_header: config.address_header.clone(),
-        balance_header: config.erc20.balance_header.clone(),
+        balance_header: erc20.balance_header.clone(),
         name_header,
         symbol_header,
-        contract: ERC20::at(&web3, config.erc20.contract_address),
-      });
+        cache: cache_for(&erc20.chain),
+        index: match &erc20.index {
+          None => None,
+          Some(index) => Some(
+            HolderIndex::new(
+              TokenStandard::ERC20,
+              erc20.contract_address,
+              web3_for(&erc20.chain),
+              index.options(),
+            )
+            .await?,
+          ),
+        },
+        contract: ERC20::at(&web3_for(&erc20.chain), erc20.contract_address),
+      })));
     }
 
-    if let Some(requirement) = config.erc20.balance_requirement {
-      server.with(RequiresBalance {
-        header: config.erc20.balance_header.clone(),
+    if let Some(requirement) = erc20.balance_requirement {
+      requirements.push(Arc::new(RequiresBalance {
+        header: erc20.balance_header,
         requirement,
-      });
+      }));
     }
   }
 
-  if !config.erc721.contract_address.is_zero() {
-    if config.erc721.provides_balances {
-      let name_header = if config.erc721.provides_name {
-        Some(config.erc721.name_header)
+  for erc721 in config.erc721 {
+    if erc721.provides_balances {
+      let name_header = if erc721.provides_name {
+        Some(erc721.name_header)
       } else {
         None
       };
 
-      let symbol_header = if config.erc721.provides_symbol {
-        Some(config.erc721.symbol_header)
+      let symbol_header = if erc721.provides_symbol {
+        Some(erc721.symbol_header)
       } else {
         None
       };
 
-      server.with(ProvidesERC721Balance {
+      lookups.push(Arc::new(UnlessSessionToken(ProvidesERC721Balance {
         address_header: config.address_header.clone(),
-        balance_header: config.erc721.balance_header.clone(),
+        balance_header: erc721.balance_header.clone(),
         name_header,
         symbol_header,
-        contract: ERC721::at(&web3, config.erc721.contract_address),
-      });
+        cache: cache_for(&erc721.chain),
+        index: match &erc721.index {
+          None => None,
+          Some(index) => Some(
+            HolderIndex::new(
+              TokenStandard::ERC721,
+              erc721.contract_address,
+              web3_for(&erc721.chain),
+              index.options(),
+            )
+            .await?,
+          ),
+        },
+        contract: ERC721::at(&web3_for(&erc721.chain), erc721.contract_address),
+      })));
     }
 
-    if let Some(requirement) = config.erc721.balance_requirement {
-      server.with(RequiresBalance {
-        header: config.erc721.balance_header.clone(),
+    if let Some(requirement) = erc721.balance_requirement {
+      requirements.push(Arc::new(RequiresBalance {
+        header: erc721.balance_header,
         requirement,
-      });
+      }));
+    }
+
+    if let Some(ownership) = erc721.ownership {
+      requirements.push(Arc::new(RequiresERC721Ownership {
+        address_header: config.address_header.clone(),
+        token_header: erc721.token_header,
+        ownership,
+        contract: ERC721::at(&web3_for(&erc721.chain), erc721.contract_address),
+      }));
     }
   }
 
-  if !config.erc777.contract_address.is_zero() {
-    if config.erc777.provides_balances {
-      let name_header = if config.erc777.provides_name {
-        Some(config.erc777.name_header)
+  for erc777 in config.erc777 {
+    if erc777.provides_balances {
+      let name_header = if erc777.provides_name {
+        Some(erc777.name_header)
       } else {
         None
       };
 
-      let symbol_header = if config.erc777.provides_symbol {
-        Some(config.erc777.symbol_header)
+      let symbol_header = if erc777.provides_symbol {
+        Some(erc777.symbol_header)
       } else {
         None
       };
 
-      server.with(ProvidesERC777Balance {
+      lookups.push(Arc::new(UnlessSessionToken(ProvidesERC777Balance {
         address_header: config.address_header.clone(),
-        balance_header: config.erc777.balance_header.clone(),
+        balance_header: erc777.balance_header.clone(),
         name_header,
         symbol_header,
-        contract: ERC777::at(&web3, config.erc777.contract_address),
-      });
+        cache: cache_for(&erc777.chain),
+        contract: ERC777::at(&web3_for(&erc777.chain), erc777.contract_address),
+      })));
     }
 
-    if let Some(requirement) = config.erc777.balance_requirement {
-      server.with(RequiresBalance {
-        header: config.erc777.balance_header.clone(),
+    if let Some(requirement) = erc777.balance_requirement {
+      requirements.push(Arc::new(RequiresBalance {
+        header: erc777.balance_header,
         requirement,
-      });
+      }));
+    }
+  }
+
+  if !lookups.is_empty() {
+    checks.push(Arc::new(ProvidesConcurrently(lookups)));
+  }
+  checks.extend(requirements);
+
+  let mut after: Vec<Arc<dyn Middleware<()>>> = vec![];
+
+  if let Some(session_tokens) = config.session_tokens {
+    after.push(Arc::new(ProvidesSessionToken {
+      address_header: config.address_header.clone(),
+      audience: session_audience,
+      claim_headers,
+      cookie_name: session_tokens.cookie_name,
+      keys: SessionKeys(session_tokens.keys),
+      lifetime: session_tokens.lifetime,
+      token_header: session_tokens.header,
+    }));
+  }
+
+  let mut proxied_assertion = None;
+  if let Some(assertions) = config.assertions {
+    let signer = match (assertions.keys.is_empty(), config.secret_key) {
+      (false, _) => AssertionSigner::Hmac(SessionKeys(assertions.keys)),
+      (true, Some(secret_key)) => AssertionSigner::Secp256k1(secret_key),
+      (true, None) => bail!("assertions need keys, or a secret key"),
+    };
+    let assertion = ProvidesIdentityAssertion {
+      address_header: config.address_header.clone(),
+      assertion_header: assertions.header,
+      claim_headers: assertion_claim_headers,
+      lifetime: assertions.lifetime,
+      signer,
+    };
+    // Proxied requests are signed by the proxy, for the path the backend sees.
+    match forward_auth {
+      true => after.push(Arc::new(assertion)),
+      false => proxied_assertion = Some(assertion),
+    }
+  }
+
+  let upstream = |proxy: Proxy| -> Box<dyn Endpoint<()>> {
+    match &identity {
+      Some(identity) => Box::new(identity.clone()),
+      None => Box::new(Proxy {
+        assertion: proxied_assertion.clone(),
+        ..proxy
+      }),
+    }
+  };
+
+  let checks = Checks {
+    before: checks.into_iter().map(Shared).collect(),
+    policy: config.policy,
+    policy_header: config.policy_header,
+    after: after.into_iter().map(Shared).collect(),
+  };
+
+  // Routes without a backend (or pool) of their own share this one, so they
+  // share its health checks and connection counts too.
+  let default_proxy = match &config.pool {
+    Some(pool) => Proxy::pooled(Pool::new(
+      pool.backends.clone(),
+      pool.options(&config.address_header),
+    )?),
+    None => Proxy::new(config.backend.clone()),
+  };
+
+  // Routes are added after the defaults, so a route for / or /* replaces them.
+  for path in &["/", "/*"] {
+    let mut route = server.at(path);
+    match config.default_route {
+      DefaultRoute::Deny => {
+        route.all(|_| async { Ok(Response::new(StatusCode::Forbidden)) });
+      }
+      DefaultRoute::Allow => {
+        checks.apply(&mut route, None);
+        route.all(upstream(default_proxy.clone()));
+      }
+      DefaultRoute::Proxy if forward_auth => {
+        route.all(ProvidesIdentity { headers: vec![] });
+      }
+      DefaultRoute::Proxy => {
+        route.all(default_proxy.clone());
+      }
     }
   }
 
-  server.with(Proxy::new(config.backend));
+  for route_config in &config.routes {
+    let proxy = match (&route_config.backend, &route_config.pool) {
+      (Some(backend), _) => Proxy::new(backend.clone()),
+      (None, Some(pool)) => Proxy::pooled(Pool::new(
+        pool.backends.clone(),
+        pool.options(&config.address_header),
+      )?),
+      (None, None) => default_proxy.clone(),
+    };
+    let proxy = Proxy {
+      strip_prefix: route_config.strip_prefix.clone(),
+      add_prefix: route_config.add_prefix.clone(),
+      ..proxy
+    };
+
+    for path in route_config.paths() {
+      let mut route = server.at(&path);
+      checks.apply(&mut route, route_config.policy.as_ref());
+      if route_config.methods.is_empty() {
+        route.all(upstream(proxy.clone()));
+      }
+      for method in &route_config.methods {
+        route.method(*method, upstream(proxy.clone()));
+      }
+    }
+  }
 
   Ok(server)
 }
+
+// Route middleware belongs to a single route, so checks are shared between
+// routes through an Arc.
+#[derive(Clone)]
+struct Shared(Arc<dyn Middleware<()>>);
+
+#[async_trait]
+impl Middleware<()> for Shared {
+  async fn handle(&self, request: Request<()>, next: Next<'_, ()>) -> tide::Result {
+    self.0.handle(request, next).await
+  }
+}
+
+// The middleware that runs for each route, after the global middleware. Routes
+// without a policy of their own use the top-level one.
+struct Checks {
+  before: Vec<Shared>,
+  policy: Option<Policy>,
+  policy_header: HeaderName,
+  after: Vec<Shared>,
+}
+
+impl Checks {
+  fn apply(&self, route: &mut Route<'_, ()>, policy: Option<&Policy>) {
+    for check in &self.before {
+      route.with(check.clone());
+    }
+
+    if let Some(policy) = policy.or(self.policy.as_ref()) {
+      route.with(RequiresPolicy {
+        decision_header: self.policy_header.clone(),
+        policy: policy.clone(),
+      });
+    }
+
+    for check in &self.after {
+      route.with(check.clone());
+    }
+  }
+}
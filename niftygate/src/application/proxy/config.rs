@@ -0,0 +1,2009 @@
+use crate::middleware::{
+  ethereum::{prelude::*, *},
+  headers::Network,
+  proxy::{PoolOptions, Strategy},
+};
+use anyhow::{bail, Context, Result};
+use niftygate_contract::transport::{BatchOptions, FailoverOptions};
+use secp256k1::{PublicKey, Secp256k1};
+use serde::Deserialize;
+use std::{
+  convert::TryFrom,
+  fs, iter,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+use strum::{AsRefStr, EnumString};
+use tide::http::Method;
+
+const ZERO_ADDRESS: [u8; 20] = [0; 20];
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenRequirement {
+  pub token_ids: TokenIds,
+  pub requirement: BalanceRequirement,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ERC1155Config {
+  pub name: String,
+  pub contract_address: Address,
+  pub chain: Option<String>,
+  pub token_ids: TokenIds,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub balance_header: HeaderName,
+  pub balance_requirement: Option<BalanceRequirement>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub token_balance_header: HeaderName,
+  pub token_requirements: Vec<TokenRequirement>,
+  pub provides_balances: bool,
+  pub index: Option<IndexConfig>,
+}
+
+impl Default for ERC1155Config {
+  fn default() -> Self {
+    Self {
+      name: String::from("erc1155"),
+      contract_address: Address::from_slice(&ZERO_ADDRESS),
+      chain: None,
+      token_ids: TokenIds::default(),
+      balance_header: HeaderName::from("X-Web3-ERC1155-Balance"),
+      balance_requirement: None,
+      token_balance_header: HeaderName::from("X-Web3-ERC1155-Token-Balance"),
+      token_requirements: vec![],
+      provides_balances: false,
+      index: None,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ERC20Config {
+  pub name: String,
+  pub contract_address: Address,
+  pub chain: Option<String>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub balance_header: HeaderName,
+  pub balance_requirement: Option<BalanceRequirement>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub name_header: HeaderName,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub symbol_header: HeaderName,
+  pub provides_balances: bool,
+  pub provides_name: bool,
+  pub provides_symbol: bool,
+  pub index: Option<IndexConfig>,
+}
+
+impl Default for ERC20Config {
+  fn default() -> Self {
+    Self {
+      name: String::from("erc20"),
+      contract_address: Address::from_slice(&ZERO_ADDRESS),
+      chain: None,
+      balance_header: HeaderName::from("X-Web3-ERC20-Balance"),
+      balance_requirement: None,
+      name_header: HeaderName::from("X-Web3-ERC20-Name"),
+      symbol_header: HeaderName::from("X-Web3-ERC20-Symbol"),
+      provides_balances: false,
+      provides_name: false,
+      provides_symbol: false,
+      index: None,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ERC721Config {
+  pub name: String,
+  pub contract_address: Address,
+  pub chain: Option<String>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub balance_header: HeaderName,
+  pub balance_requirement: Option<BalanceRequirement>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub token_header: HeaderName,
+  pub ownership: Option<Ownership>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub name_header: HeaderName,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub symbol_header: HeaderName,
+  pub provides_balances: bool,
+  pub provides_name: bool,
+  pub provides_symbol: bool,
+  pub index: Option<IndexConfig>,
+}
+
+impl Default for ERC721Config {
+  fn default() -> Self {
+    Self {
+      name: String::from("erc721"),
+      contract_address: Address::from_slice(&ZERO_ADDRESS),
+      chain: None,
+      balance_header: HeaderName::from("X-Web3-ERC721-Balance"),
+      balance_requirement: None,
+      token_header: HeaderName::from("X-Web3-ERC721-Token"),
+      ownership: None,
+      name_header: HeaderName::from("X-Web3-ERC721-Name"),
+      symbol_header: HeaderName::from("X-Web3-ERC721-Symbol"),
+      provides_balances: false,
+      provides_name: false,
+      provides_symbol: false,
+      index: None,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ERC777Config {
+  pub name: String,
+  pub contract_address: Address,
+  pub chain: Option<String>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub balance_header: HeaderName,
+  pub balance_requirement: Option<BalanceRequirement>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub name_header: HeaderName,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub symbol_header: HeaderName,
+  pub provides_balances: bool,
+  pub provides_name: bool,
+  pub provides_symbol: bool,
+}
+
+impl Default for ERC777Config {
+  fn default() -> Self {
+    Self {
+      name: String::from("erc777"),
+      contract_address: Address::from_slice(&ZERO_ADDRESS),
+      chain: None,
+      balance_header: HeaderName::from("X-Web3-ERC777-Balance"),
+      balance_requirement: None,
+      name_header: HeaderName::from("X-Web3-ERC777-Name"),
+      symbol_header: HeaderName::from("X-Web3-ERC777-Symbol"),
+      provides_balances: false,
+      provides_name: false,
+      provides_symbol: false,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+  pub certificate_path: PathBuf,
+  pub key_path: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NonceConfig {
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub header: HeaderName,
+  pub path: String,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub lifetime: Duration,
+  pub store_path: Option<PathBuf>,
+  pub capacity: usize,
+}
+
+impl Default for NonceConfig {
+  fn default() -> Self {
+    Self {
+      header: HeaderName::from("X-Web3-Nonce"),
+      path: String::from("/.niftygate/challenge"),
+      lifetime: Duration::from_secs(300),
+      store_path: None,
+      capacity: DEFAULT_NONCE_CAPACITY,
+    }
+  }
+}
+
+impl NonceConfig {
+  pub fn nonces(&self) -> Nonces {
+    nonces(self.lifetime, &self.store_path, self.capacity)
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiweConfig {
+  pub domain: Option<String>,
+  pub uri: Option<String>,
+  pub chain_id: u64,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub nonce_lifetime: Duration,
+  pub nonce_store_path: Option<PathBuf>,
+  pub nonce_capacity: usize,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub session_lifetime: Duration,
+  #[serde(deserialize_with = "crate::de::option_bytes")]
+  pub session_secret: Option<Vec<u8>>,
+}
+
+impl Default for SiweConfig {
+  fn default() -> Self {
+    Self {
+      domain: None,
+      uri: None,
+      chain_id: 1,
+      nonce_lifetime: Duration::from_secs(300),
+      nonce_store_path: None,
+      nonce_capacity: DEFAULT_NONCE_CAPACITY,
+      session_lifetime: Duration::from_secs(86400),
+      session_secret: None,
+    }
+  }
+}
+
+impl SiweConfig {
+  pub fn nonces(&self) -> Nonces {
+    nonces(
+      self.nonce_lifetime,
+      &self.nonce_store_path,
+      self.nonce_capacity,
+    )
+  }
+}
+
+fn nonces(lifetime: Duration, store_path: &Option<PathBuf>, capacity: usize) -> Nonces {
+  let store: Arc<dyn NonceStore> = match store_path {
+    Some(path) => Arc::new(FileNonceStore::new(path, capacity)),
+    None => Arc::new(MemoryNonceStore::new(capacity)),
+  };
+
+  Nonces { lifetime, store }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionTokenConfig {
+  pub keys: Vec<SessionKey>,
+  pub audience: Option<String>,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub lifetime: Duration,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub header: HeaderName,
+  pub cookie_name: Option<String>,
+}
+
+impl Default for SessionTokenConfig {
+  fn default() -> Self {
+    Self {
+      keys: vec![],
+      audience: None,
+      lifetime: Duration::from_secs(900),
+      header: HeaderName::from("X-Web3-Session-Token"),
+      cookie_name: Some(String::from("niftygate.token")),
+    }
+  }
+}
+
+// Endpoints to fail over to, in order of preference, after web3_rpc_url.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailoverConfig {
+  pub urls: Vec<Url>,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub check_interval: Duration,
+  pub max_block_lag: u64,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub max_latency: Duration,
+  pub status_path: Option<String>,
+}
+
+impl Default for FailoverConfig {
+  fn default() -> Self {
+    let options = FailoverOptions::default();
+    Self {
+      urls: vec![],
+      check_interval: options.check_interval,
+      max_block_lag: options.max_block_lag,
+      max_latency: options.max_latency,
+      status_path: None,
+    }
+  }
+}
+
+impl FailoverConfig {
+  fn validate(&self, label: &str, rpc_url: &Url) -> Result<()> {
+    if self.urls.is_empty() {
+      bail!("{} needs at least one url", label);
+    }
+    if self.urls.contains(rpc_url) {
+      bail!("{} urls must not include the primary rpc url", label);
+    }
+    if self.check_interval.as_secs() == 0 {
+      bail!("{} check_interval must be at least one second", label);
+    }
+    if self.max_latency.is_zero() {
+      bail!("{} max_latency must be greater than zero", label);
+    }
+    if let Some(path) = &self.status_path {
+      if !path.starts_with('/') {
+        bail!("{} status_path must start with /", label);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn options(&self) -> FailoverOptions {
+    FailoverOptions {
+      check_interval: self.check_interval,
+      max_block_lag: self.max_block_lag,
+      max_latency: self.max_latency,
+    }
+  }
+}
+
+// Calls made within window of each other are sent to the RPC endpoint as one
+// batch (of up to max_size calls), and identical calls share a response.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub window: Duration,
+  pub max_size: usize,
+}
+
+impl Default for BatchConfig {
+  fn default() -> Self {
+    let options = BatchOptions::default();
+    Self {
+      window: options.window,
+      max_size: options.max_size,
+    }
+  }
+}
+
+impl BatchConfig {
+  fn validate(&self) -> Result<()> {
+    if self.max_size == 0 {
+      bail!("batching max_size must be at least one");
+    }
+    Ok(())
+  }
+
+  pub fn options(&self) -> BatchOptions {
+    BatchOptions {
+      window: self.window,
+      max_size: self.max_size,
+    }
+  }
+}
+
+// Caches balances for ttl, or until blocks new blocks have been seen (or
+// both, whichever comes first).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+  #[serde(deserialize_with = "crate::de::option_duration")]
+  pub ttl: Option<Duration>,
+  pub blocks: Option<u64>,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub block_check_interval: Duration,
+  pub capacity: usize,
+  pub status_path: Option<String>,
+}
+
+impl Default for CacheConfig {
+  fn default() -> Self {
+    let options = CacheOptions::default();
+    Self {
+      ttl: None,
+      blocks: None,
+      block_check_interval: options.block_check_interval,
+      capacity: options.capacity,
+      status_path: None,
+    }
+  }
+}
+
+impl CacheConfig {
+  fn validate(&self) -> Result<()> {
+    if self.ttl.is_none() && self.blocks.is_none() {
+      bail!("cache needs a ttl, or blocks");
+    }
+    if let Some(ttl) = self.ttl {
+      if ttl.is_zero() {
+        bail!("cache ttl must be greater than zero");
+      }
+    }
+    if self.blocks == Some(0) {
+      bail!("cache blocks must be at least one");
+    }
+    if self.block_check_interval.is_zero() {
+      bail!("cache block_check_interval must be greater than zero");
+    }
+    if self.capacity == 0 {
+      bail!("cache capacity must be at least one");
+    }
+    if let Some(path) = &self.status_path {
+      if !path.starts_with('/') {
+        bail!("cache status_path must start with /");
+      }
+    }
+    Ok(())
+  }
+
+  pub fn options(&self) -> CacheOptions {
+    CacheOptions {
+      ttl: self.ttl,
+      blocks: self.blocks,
+      block_check_interval: self.block_check_interval,
+      capacity: self.capacity,
+    }
+  }
+
+  fn summary(&self) -> String {
+    let mut limits = vec![];
+    if let Some(ttl) = self.ttl {
+      limits.push(format!("ttl: {}", humantime::format_duration(ttl)));
+    }
+    if let Some(blocks) = self.blocks {
+      limits.push(format!("blocks: {}", blocks));
+    }
+    format!(", cache: ({})", limits.join(", "))
+  }
+}
+
+// Keeps the balances of every holder of a contract from its transfer events,
+// read from from_block, instead of asking the node for them on each request.
+// With a path, the index is saved there, and picks up where it left off.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+  pub from_block: u64,
+  pub page_size: u64,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub poll_interval: Duration,
+  pub reorg_depth: u64,
+  pub path: Option<PathBuf>,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub save_interval: Duration,
+}
+
+impl Default for IndexConfig {
+  fn default() -> Self {
+    let options = IndexOptions::default();
+    Self {
+      from_block: options.from_block,
+      page_size: options.page_size,
+      poll_interval: options.poll_interval,
+      reorg_depth: options.reorg_depth,
+      path: None,
+      save_interval: options.save_interval,
+    }
+  }
+}
+
+impl IndexConfig {
+  fn validate(&self, label: &str, provides_balances: bool) -> Result<()> {
+    if !provides_balances {
+      bail!("{} index is only used with provides_balances", label);
+    }
+    if self.page_size == 0 {
+      bail!("{} index page_size must be at least one", label);
+    }
+    if self.poll_interval.is_zero() {
+      bail!("{} index poll_interval must be greater than zero", label);
+    }
+    if self.save_interval.is_zero() {
+      bail!("{} index save_interval must be greater than zero", label);
+    }
+    Ok(())
+  }
+
+  pub fn options(&self) -> IndexOptions {
+    IndexOptions {
+      from_block: self.from_block,
+      page_size: self.page_size,
+      poll_interval: self.poll_interval,
+      reorg_depth: self.reorg_depth,
+      path: self.path.clone(),
+      save_interval: self.save_interval,
+    }
+  }
+}
+
+fn index_summary(index: Option<&IndexConfig>) -> String {
+  match index {
+    None => String::new(),
+    Some(IndexConfig {
+      from_block,
+      path: None,
+      ..
+    }) => format!(", index: (from block {})", from_block),
+    Some(IndexConfig {
+      from_block,
+      path: Some(path),
+      ..
+    }) => format!(", index: (from block {}, path: {:?})", from_block, path),
+  }
+}
+
+// Signs the verified address (and provided headers) for the backend. Keys are
+// shared with backends (HS256), or without keys, the secret key signs them
+// (ES256K), and backends verify them with its public key.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssertionConfig {
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub header: HeaderName,
+  pub keys: Vec<SessionKey>,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub lifetime: Duration,
+}
+
+impl Default for AssertionConfig {
+  fn default() -> Self {
+    Self {
+      header: HeaderName::from("X-Web3-Assertion"),
+      keys: vec![],
+      lifetime: Duration::from_secs(60),
+    }
+  }
+}
+
+// Backends to spread requests across, in place of a single backend. Backends
+// are checked every check_interval when check_path is set, and are ejected for
+// ejection_time after max_failures requests to them fail in a row.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+  pub backends: Vec<Url>,
+  pub strategy: Strategy,
+  pub check_path: Option<String>,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub check_interval: Duration,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub check_timeout: Duration,
+  pub max_failures: u32,
+  #[serde(deserialize_with = "crate::de::duration")]
+  pub ejection_time: Duration,
+}
+
+impl Default for PoolConfig {
+  fn default() -> Self {
+    let options = PoolOptions::default();
+    Self {
+      backends: vec![],
+      strategy: options.strategy,
+      check_path: options.check_path,
+      check_interval: options.check_interval,
+      check_timeout: options.check_timeout,
+      max_failures: options.max_failures,
+      ejection_time: options.ejection_time,
+    }
+  }
+}
+
+impl PoolConfig {
+  fn validate(&self, label: &str) -> Result<()> {
+    if self.backends.is_empty() {
+      bail!("{} needs at least one backend", label);
+    }
+    for (index, backend) in self.backends.iter().enumerate() {
+      if self.backends[..index].contains(backend) {
+        bail!("{} backend {} is listed more than once", label, backend);
+      }
+    }
+    if let Some(path) = &self.check_path {
+      if !path.starts_with('/') {
+        bail!("{} check_path must start with /", label);
+      }
+    }
+    if self.check_interval.as_secs() == 0 {
+      bail!("{} check_interval must be at least one second", label);
+    }
+    if self.check_timeout.is_zero() {
+      bail!("{} check_timeout must be greater than zero", label);
+    }
+    if self.max_failures == 0 {
+      bail!("{} max_failures must be at least one", label);
+    }
+    Ok(())
+  }
+
+  // The address header is the one set by account verification, so sessions
+  // stick to a backend by verified address.
+  pub fn options(&self, address_header: &HeaderName) -> PoolOptions {
+    PoolOptions {
+      strategy: self.strategy,
+      address_header: address_header.clone(),
+      check_path: self.check_path.clone(),
+      check_interval: self.check_interval,
+      check_timeout: self.check_timeout,
+      max_failures: self.max_failures,
+      ejection_time: self.ejection_time,
+    }
+  }
+
+  fn summary(&self) -> String {
+    let mut summary = format!(
+      "pool: {:?}, strategy: {}",
+      self.backends.iter().map(Url::as_str).collect::<Vec<_>>(),
+      self.strategy.as_ref()
+    );
+    if let Some(path) = &self.check_path {
+      summary.push_str(&format!(
+        ", check: {} every {}",
+        path,
+        humantime::format_duration(self.check_interval)
+      ));
+    }
+    summary
+  }
+}
+
+// Contracts name the chain they live on, and use its RPC endpoint. Contracts
+// without a chain use web3_rpc_url.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainConfig {
+  pub name: String,
+  pub chain_id: u64,
+  pub rpc_url: Url,
+  pub failover: Option<FailoverConfig>,
+}
+
+// Paths use tide's route syntax, so /premium/* matches everything under
+// /premium (and /premium itself). Routes without methods match any method.
+// Routes without a policy (or backend) use the top-level one. strip_prefix
+// and add_prefix change the path sent to the backend, so /api/* can be served
+// from the root of another backend.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+  pub path: String,
+  #[serde(default, deserialize_with = "crate::de::methods")]
+  pub methods: Vec<Method>,
+  pub policy: Option<Policy>,
+  pub backend: Option<Url>,
+  pub pool: Option<PoolConfig>,
+  pub strip_prefix: Option<String>,
+  pub add_prefix: Option<String>,
+}
+
+impl RouteConfig {
+  // tide's wildcards need at least one character to match, so prefixes are
+  // registered on their own (with and without a trailing slash) as well.
+  pub fn paths(&self) -> Vec<String> {
+    match self.path.strip_suffix("/*") {
+      Some("") => vec![String::from("/"), self.path.clone()],
+      Some(prefix) => vec![
+        String::from(prefix),
+        format!("{}/", prefix),
+        self.path.clone(),
+      ],
+      None => vec![self.path.clone()],
+    }
+  }
+
+  // The first setting that only makes sense when proxying, if any are set.
+  fn proxy_setting(&self) -> Option<&'static str> {
+    match self {
+      Self {
+        backend: Some(_), ..
+      } => Some("backend"),
+      Self { pool: Some(_), .. } => Some("pool"),
+      Self {
+        strip_prefix: Some(_),
+        ..
+      } => Some("strip_prefix"),
+      Self {
+        add_prefix: Some(_),
+        ..
+      } => Some("add_prefix"),
+      _ => None,
+    }
+  }
+}
+
+// What happens to requests that don't match any route. Allow runs the same
+// checks as a route would, with the top-level policy and backend.
+#[derive(AsRefStr, Clone, Copy, Debug, Default, Deserialize, EnumString, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum DefaultRoute {
+  Deny,
+  #[default]
+  Allow,
+  Proxy,
+}
+
+// Answers allow/deny for another proxy (nginx auth_request, Traefik
+// ForwardAuth, Caddy forward_auth) instead of proxying to the backend. nginx
+// only understands 401 and 403, so denied_status can replace the usual 402.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardAuthConfig {
+  pub denied_status: StatusCode,
+}
+
+impl Default for ForwardAuthConfig {
+  fn default() -> Self {
+    Self {
+      denied_status: StatusCode::PaymentRequired,
+    }
+  }
+}
+
+// Answers Envoy's ext_authz Check calls over gRPC, with the same decisions as
+// forward_auth, which it requires.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtAuthzConfig {
+  pub listen: String,
+}
+
+impl Default for ExtAuthzConfig {
+  fn default() -> Self {
+    Self {
+      listen: String::from("0.0.0.0:9001"),
+    }
+  }
+}
+
+// A site served by the same process, for requests with a matching Host header
+// (and TLS connections with a matching SNI name). Everything else about it is
+// configured just like a standalone proxy, and nothing is inherited from the
+// top level.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "serde_json::Map<String, serde_json::Value>")]
+pub struct HostConfig {
+  pub names: Vec<String>,
+  pub config: Config,
+}
+
+// serde ignores deny_unknown_fields on flattened structs, so the names are
+// split off by hand instead.
+impl TryFrom<serde_json::Map<String, serde_json::Value>> for HostConfig {
+  type Error = serde_json::Error;
+
+  fn try_from(mut fields: serde_json::Map<String, serde_json::Value>) -> Result<Self, Self::Error> {
+    let names = match fields.remove("names") {
+      Some(names) => serde_json::from_value(names)?,
+      None => return Err(serde::de::Error::missing_field("names")),
+    };
+    let config = serde_json::from_value(serde_json::Value::Object(fields))?;
+    Ok(Self { names, config })
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub listen: String,
+  pub tls: Option<TlsConfig>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub address_header: HeaderName,
+  pub assertions: Option<AssertionConfig>,
+  pub backend: Url,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub balance_header: HeaderName,
+  pub balance_requirement: Option<BalanceRequirement>,
+  pub balance_scale: BalanceScale,
+  pub batching: Option<BatchConfig>,
+  pub cache: Option<CacheConfig>,
+  pub chains: Vec<ChainConfig>,
+  #[serde(deserialize_with = "crate::de::bytes")]
+  pub challenge: Vec<u8>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub claimed_address_header: HeaderName,
+  pub contract_wallets: bool,
+  pub default_route: DefaultRoute,
+  pub ext_authz: Option<ExtAuthzConfig>,
+  pub failover: Option<FailoverConfig>,
+  pub forward_auth: Option<ForwardAuthConfig>,
+  pub hosts: Vec<HostConfig>,
+  pub nonces: Option<NonceConfig>,
+  pub provides_account_verification: bool,
+  pub provides_balances: bool,
+  pub erc20: Vec<ERC20Config>,
+  pub erc1155: Vec<ERC1155Config>,
+  pub erc721: Vec<ERC721Config>,
+  pub erc777: Vec<ERC777Config>,
+  pub policy: Option<Policy>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub policy_header: HeaderName,
+  pub pool: Option<PoolConfig>,
+  pub provides_signatures: bool,
+  pub routes: Vec<RouteConfig>,
+  pub web3_rpc_url: Url,
+  #[serde(deserialize_with = "crate::de::option_secret_key")]
+  pub secret_key: Option<SecretKey>,
+  pub session_tokens: Option<SessionTokenConfig>,
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub signature_header: HeaderName,
+  pub siwe: Option<SiweConfig>,
+  pub strip_identity_headers: bool,
+  #[serde(deserialize_with = "crate::de::from_strs")]
+  pub trusted_hops: Vec<Network>,
+  pub typed_data: Option<TypedChallenge>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      listen: String::from("0.0.0.0:8000"),
+      tls: None,
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      assertions: None,
+      backend: Url::parse("http://127.0.0.1:8080").unwrap(),
+      balance_header: HeaderName::from("X-Web3-Account-Balance"),
+      balance_requirement: None,
+      balance_scale: BalanceScale::Wei,
+      batching: None,
+      cache: None,
+      chains: vec![],
+      challenge: b"totes-legit".to_vec(),
+      claimed_address_header: HeaderName::from("X-Web3-Claimed-Address"),
+      contract_wallets: false,
+      default_route: DefaultRoute::Allow,
+      ext_authz: None,
+      failover: None,
+      forward_auth: None,
+      hosts: vec![],
+      nonces: None,
+      provides_account_verification: false,
+      provides_balances: false,
+      erc20: vec![],
+      erc1155: vec![],
+      erc721: vec![],
+      erc777: vec![],
+      policy: None,
+      policy_header: HeaderName::from("X-Web3-Policy-Decision"),
+      pool: None,
+      provides_signatures: false,
+      routes: vec![],
+      web3_rpc_url: Url::parse("ws://127.0.0.1:7545").unwrap(),
+      secret_key: None,
+      session_tokens: None,
+      signature_header: HeaderName::from("X-Web3-Signature"),
+      siwe: None,
+      strip_identity_headers: true,
+      trusted_hops: vec![],
+      typed_data: None,
+    }
+  }
+}
+
+impl Config {
+  pub fn from_path(path: &Path) -> Result<Self> {
+    let data =
+      fs::read_to_string(path).with_context(|| format!("failed to read config file {:?}", path))?;
+
+    let config = match path.extension().and_then(|extension| extension.to_str()) {
+      Some("toml") => toml::from_str(&data)?,
+      Some("yaml") | Some("yml") => serde_yaml::from_str(&data)?,
+      _ => bail!(
+        "unsupported config format {:?}, expected .toml, .yaml or .yml",
+        path
+      ),
+    };
+
+    Ok(config)
+  }
+
+  pub fn validate(&self) -> Result<()> {
+    if self.provides_signatures && self.secret_key.is_none() {
+      bail!("provides_signatures requires a secret key");
+    }
+
+    let mut headers = vec![
+      (String::from("address_header"), &self.address_header),
+      (String::from("signature_header"), &self.signature_header),
+    ];
+
+    if self.contract_wallets {
+      if !self.provides_account_verification {
+        bail!("contract_wallets requires provides_account_verification");
+      }
+      headers.push((
+        String::from("claimed_address_header"),
+        &self.claimed_address_header,
+      ));
+    }
+
+    if let Some(batching) = &self.batching {
+      batching.validate()?;
+    }
+    if let Some(failover) = &self.failover {
+      failover.validate("failover", &self.web3_rpc_url)?;
+    }
+
+    if let Some(pool) = &self.pool {
+      pool.validate("pool")?;
+    }
+
+    if let Some(cache) = &self.cache {
+      cache.validate()?;
+    }
+
+    if !self.trusted_hops.is_empty() && !self.strip_identity_headers {
+      bail!("trusted_hops requires strip_identity_headers");
+    }
+
+    for (index, chain) in self.chains.iter().enumerate() {
+      let label = format!("chain {:?}", chain.name);
+      if chain.name.is_empty() {
+        bail!("chains need a name");
+      }
+      if self.chains[..index]
+        .iter()
+        .any(|other| other.name == chain.name)
+      {
+        bail!("{} is defined more than once", label);
+      }
+      if let Some(failover) = &chain.failover {
+        failover.validate(&format!("{} failover", label), &chain.rpc_url)?;
+      }
+    }
+
+    if let Some(nonces) = &self.nonces {
+      if !self.provides_account_verification {
+        bail!("nonces requires provides_account_verification");
+      }
+      if !nonces.path.starts_with('/') {
+        bail!("nonces path must start with /");
+      }
+      if nonces.lifetime.as_secs() == 0 {
+        bail!("nonces lifetime must be at least one second");
+      }
+      if nonces.capacity == 0 {
+        bail!("nonces capacity must be at least one");
+      }
+      headers.push((String::from("nonces header"), &nonces.header));
+    }
+
+    if let Some(siwe) = &self.siwe {
+      // The request's Host is chosen by the client, so it can't stand in for
+      // the domain messages are signed for.
+      if siwe.domain.as_deref().unwrap_or_default().is_empty() {
+        bail!("siwe domain is required");
+      }
+      if let Some(secret) = &siwe.session_secret {
+        if secret.len() < 32 {
+          bail!("siwe session_secret must be at least 32 bytes");
+        }
+      }
+      if siwe.nonce_lifetime.as_secs() == 0 {
+        bail!("siwe nonce_lifetime must be at least one second");
+      }
+      if siwe.nonce_capacity == 0 {
+        bail!("siwe nonce_capacity must be at least one");
+      }
+      if siwe.session_lifetime.as_secs() == 0 {
+        bail!("siwe session_lifetime must be at least one second");
+      }
+    }
+
+    if let Some(typed_data) = &self.typed_data {
+      if typed_data.challenge_field == typed_data.nonce_field {
+        bail!("typed_data challenge_field and nonce_field must be different");
+      }
+    }
+
+    if let Some(session_tokens) = &self.session_tokens {
+      if session_tokens.keys.is_empty() {
+        bail!("session_tokens needs at least one key");
+      }
+      for (index, key) in session_tokens.keys.iter().enumerate() {
+        if key.secret.len() < 32 {
+          bail!("session_tokens key {:?} must be at least 32 bytes", key.id);
+        }
+        if session_tokens.keys[..index]
+          .iter()
+          .any(|other| other.id == key.id)
+        {
+          bail!("session_tokens key {:?} is defined more than once", key.id);
+        }
+      }
+      if session_tokens.lifetime.as_secs() == 0 {
+        bail!("session_tokens lifetime must be at least one second");
+      }
+      if session_tokens.audience.as_deref() == Some("") {
+        bail!("session_tokens audience can't be empty");
+      }
+      headers.push((
+        String::from("session_tokens header"),
+        &session_tokens.header,
+      ));
+    }
+
+    if let Some(assertions) = &self.assertions {
+      if assertions.keys.is_empty() && self.secret_key.is_none() {
+        bail!("assertions need keys, or a secret key");
+      }
+      for (index, key) in assertions.keys.iter().enumerate() {
+        if key.secret.len() < 32 {
+          bail!("assertions key {:?} must be at least 32 bytes", key.id);
+        }
+        if assertions.keys[..index]
+          .iter()
+          .any(|other| other.id == key.id)
+        {
+          bail!("assertions key {:?} is defined more than once", key.id);
+        }
+      }
+      if assertions.lifetime.as_secs() == 0 {
+        bail!("assertions lifetime must be at least one second");
+      }
+      headers.push((String::from("assertions header"), &assertions.header));
+    }
+
+    if self.provides_balances || self.balance_requirement.is_some() {
+      headers.push((String::from("balance_header"), &self.balance_header));
+    }
+
+    let mut names = vec![];
+
+    for erc1155 in &self.erc1155 {
+      let label = format!("erc1155 {:?}", erc1155.name);
+      if erc1155.contract_address.is_zero() {
+        bail!("{} needs a contract_address", label);
+      }
+      self.validate_chain(&label, &erc1155.chain)?;
+      if let Some(index) = &erc1155.index {
+        index.validate(&label, erc1155.provides_balances)?;
+      }
+      if erc1155.provides_balances
+        || erc1155.balance_requirement.is_some()
+        || !erc1155.token_requirements.is_empty()
+      {
+        headers.push((format!("{} balance_header", label), &erc1155.balance_header));
+        headers.push((
+          format!("{} token_balance_header", label),
+          &erc1155.token_balance_header,
+        ));
+      }
+      erc1155
+        .token_ids
+        .validate(&format!("{} token_ids", label))?;
+      for token_requirement in &erc1155.token_requirements {
+        token_requirement
+          .token_ids
+          .validate(&format!("{} token_requirements token_ids", label))?;
+        for id in token_requirement.token_ids.iter() {
+          if !erc1155.token_ids.contains(&id) {
+            bail!(
+              "{} has a requirement for token {}, which is not in token_ids",
+              label,
+              id
+            );
+          }
+        }
+      }
+      names.push(label);
+    }
+
+    for erc20 in &self.erc20 {
+      let label = format!("erc20 {:?}", erc20.name);
+      if erc20.contract_address.is_zero() {
+        bail!("{} needs a contract_address", label);
+      }
+      self.validate_chain(&label, &erc20.chain)?;
+      if let Some(index) = &erc20.index {
+        index.validate(&label, erc20.provides_balances)?;
+      }
+      if erc20.provides_balances || erc20.balance_requirement.is_some() {
+        headers.push((format!("{} balance_header", label), &erc20.balance_header));
+      }
+      if erc20.provides_name {
+        headers.push((format!("{} name_header", label), &erc20.name_header));
+      }
+      if erc20.provides_symbol {
+        headers.push((format!("{} symbol_header", label), &erc20.symbol_header));
+      }
+      names.push(label);
+    }
+
+    for erc721 in &self.erc721 {
+      let label = format!("erc721 {:?}", erc721.name);
+      if erc721.contract_address.is_zero() {
+        bail!("{} needs a contract_address", label);
+      }
+      self.validate_chain(&label, &erc721.chain)?;
+      if let Some(index) = &erc721.index {
+        index.validate(&label, erc721.provides_balances)?;
+      }
+      if erc721.provides_balances || erc721.balance_requirement.is_some() {
+        headers.push((format!("{} balance_header", label), &erc721.balance_header));
+      }
+      if let Some(Ownership::TokenIds(token_ids)) = &erc721.ownership {
+        token_ids.validate(&format!("{} ownership token_ids", label))?;
+      }
+      if erc721.ownership.is_some() {
+        headers.push((format!("{} token_header", label), &erc721.token_header));
+      }
+      if erc721.provides_name {
+        headers.push((format!("{} name_header", label), &erc721.name_header));
+      }
+      if erc721.provides_symbol {
+        headers.push((format!("{} symbol_header", label), &erc721.symbol_header));
+      }
+      names.push(label);
+    }
+
+    for erc777 in &self.erc777 {
+      let label = format!("erc777 {:?}", erc777.name);
+      if erc777.contract_address.is_zero() {
+        bail!("{} needs a contract_address", label);
+      }
+      self.validate_chain(&label, &erc777.chain)?;
+      if erc777.provides_balances || erc777.balance_requirement.is_some() {
+        headers.push((format!("{} balance_header", label), &erc777.balance_header));
+      }
+      if erc777.provides_name {
+        headers.push((format!("{} name_header", label), &erc777.name_header));
+      }
+      if erc777.provides_symbol {
+        headers.push((format!("{} symbol_header", label), &erc777.symbol_header));
+      }
+      names.push(label);
+    }
+
+    let provided = self.provided_headers();
+    let policies = self
+      .policy
+      .iter()
+      .map(|policy| (String::from("policy"), policy))
+      .chain(self.routes.iter().filter_map(|route| {
+        route
+          .policy
+          .as_ref()
+          .map(|policy| (format!("route {:?} policy", route.path), policy))
+      }));
+
+    for (index, (label, policy)) in policies.enumerate() {
+      if index == 0 {
+        headers.push((String::from("policy_header"), &self.policy_header));
+      }
+      for header in policy.headers() {
+        if !provided.contains(&header) {
+          bail!("{} uses header {}, which is not provided", label, header);
+        }
+      }
+    }
+
+    for (index, route) in self.routes.iter().enumerate() {
+      let label = format!("route {:?}", route.path);
+      if !route.path.starts_with('/') {
+        bail!("{} must start with /", label);
+      }
+      if self.routes[..index]
+        .iter()
+        .any(|other| other.path == route.path && other.methods == route.methods)
+      {
+        bail!("{} is defined more than once", label);
+      }
+      if let Some(strip_prefix) = &route.strip_prefix {
+        let prefix = strip_prefix.trim_end_matches('/');
+        let path = route.path.strip_suffix("/*").unwrap_or(&route.path);
+        if !strip_prefix.starts_with('/') {
+          bail!("{} strip_prefix must start with /", label);
+        }
+        if !(path == prefix || path.starts_with(&format!("{}/", prefix))) {
+          bail!(
+            "{} can't strip {:?}, which its path doesn't start with",
+            label,
+            strip_prefix
+          );
+        }
+      }
+      if let Some(add_prefix) = &route.add_prefix {
+        if !add_prefix.starts_with('/') {
+          bail!("{} add_prefix must start with /", label);
+        }
+      }
+      if let Some(pool) = &route.pool {
+        if route.backend.is_some() {
+          bail!("{} can't set both backend and pool", label);
+        }
+        pool.validate(&format!("{} pool", label))?;
+      }
+    }
+
+    for (index, name) in names.iter().enumerate() {
+      if names[..index].contains(name) {
+        bail!("{} is defined more than once", name);
+      }
+    }
+
+    for (index, (label, header)) in headers.iter().enumerate() {
+      if let Some((other, _)) = headers[..index].iter().find(|(_, other)| other == header) {
+        bail!("{} and {} are both set to {}", other, label, header);
+      }
+    }
+
+    if let Some(forward_auth) = &self.forward_auth {
+      if !forward_auth.denied_status.is_client_error() {
+        bail!("forward_auth denied_status must be a 4xx status");
+      }
+      let routes = self
+        .routes
+        .iter()
+        .chain(self.hosts.iter().flat_map(|host| host.config.routes.iter()));
+      if self.pool.is_some() || self.hosts.iter().any(|host| host.config.pool.is_some()) {
+        bail!("pool can't be used with forward_auth");
+      }
+      for route in routes {
+        if let Some(setting) = route.proxy_setting() {
+          bail!(
+            "route {:?} sets {}, which can't be used with forward_auth",
+            route.path,
+            setting
+          );
+        }
+      }
+    }
+
+    if let Some(ext_authz) = &self.ext_authz {
+      if self.forward_auth.is_none() {
+        bail!("ext_authz requires forward_auth");
+      }
+      if ext_authz.listen == self.listen {
+        bail!(
+          "ext_authz and the proxy can't both listen on {}",
+          self.listen
+        );
+      }
+    }
+
+    let mut host_names = vec![];
+
+    for host in &self.hosts {
+      let label = format!("host {:?}", host.names.join(", "));
+      if host.names.is_empty() {
+        bail!("hosts need at least one name");
+      }
+      for name in &host.names {
+        if name.is_empty() || name.contains(':') {
+          bail!("{} has an invalid name {:?}", label, name);
+        }
+        if host_names.contains(&name.to_lowercase()) {
+          bail!("host name {:?} is used more than once", name);
+        }
+        host_names.push(name.to_lowercase());
+      }
+      if host.config.forward_auth.is_some() {
+        bail!("{} can't set forward_auth, it applies to every host", label);
+      }
+      if host.config.ext_authz.is_some() {
+        bail!("{} can't set ext_authz, it applies to every host", label);
+      }
+      if !host.config.hosts.is_empty() {
+        bail!("{} can't have hosts of its own", label);
+      }
+      if host.config.listen != Self::default().listen {
+        bail!(
+          "{} can't set listen, hosts share the top-level listener",
+          label
+        );
+      }
+      if host.config.tls.is_some() && self.tls.is_none() {
+        bail!("{} has tls, which requires tls at the top level", label);
+      }
+      host
+        .config
+        .validate()
+        .with_context(|| format!("{} is invalid", label))?;
+    }
+
+    Ok(())
+  }
+
+  // Session tokens are only accepted by the proxy they were issued for. Unless
+  // an audience is configured, that's the one for the same backend (or pool).
+  pub fn session_audience(&self) -> Option<String> {
+    let session_tokens = self.session_tokens.as_ref()?;
+    Some(match (&session_tokens.audience, &self.pool) {
+      (Some(audience), _) => audience.clone(),
+      (None, Some(pool)) if !pool.backends.is_empty() => pool.backends[0].to_string(),
+      (None, _) => self.backend.to_string(),
+    })
+  }
+
+  fn validate_chain(&self, label: &str, chain: &Option<String>) -> Result<()> {
+    if let Some(chain) = chain {
+      if !self.chains.iter().any(|other| &other.name == chain) {
+        bail!("{} uses chain {:?}, which is not in chains", label, chain);
+      }
+    }
+    Ok(())
+  }
+
+  // Headers handed back to the proxy asking for a forward-auth decision.
+  pub fn identity_headers(&self) -> Vec<&HeaderName> {
+    let mut identity = vec![&self.address_header];
+    identity.extend(self.provided_headers());
+
+    for erc721 in self
+      .erc721
+      .iter()
+      .filter(|erc721| erc721.ownership.is_some())
+    {
+      identity.push(&erc721.token_header);
+    }
+
+    if self.policy.is_some() || self.routes.iter().any(|route| route.policy.is_some()) {
+      identity.push(&self.policy_header);
+    }
+
+    if let Some(assertions) = &self.assertions {
+      identity.push(&assertions.header);
+    }
+
+    identity
+  }
+
+  // Everything an identity assertion vouches for, besides the address.
+  pub fn assertion_claim_headers(&self) -> Vec<HeaderName> {
+    let assertion_header = self
+      .assertions
+      .as_ref()
+      .map(|assertions| &assertions.header);
+    self
+      .identity_headers()
+      .into_iter()
+      .filter(|&header| header != &self.address_header && Some(header) != assertion_header)
+      .cloned()
+      .collect()
+  }
+
+  // Headers added by the Provides* middleware for balances (and token names
+  // and symbols), which is everything a policy or session token can refer to.
+  pub fn provided_headers(&self) -> Vec<&HeaderName> {
+    let mut provided = vec![];
+
+    if self.provides_balances {
+      provided.push(&self.balance_header);
+    }
+
+    for erc1155 in self
+      .erc1155
+      .iter()
+      .filter(|erc1155| erc1155.provides_balances)
+    {
+      provided.push(&erc1155.balance_header);
+      provided.push(&erc1155.token_balance_header);
+    }
+
+    for erc20 in self.erc20.iter().filter(|erc20| erc20.provides_balances) {
+      provided.push(&erc20.balance_header);
+      if erc20.provides_name {
+        provided.push(&erc20.name_header);
+      }
+      if erc20.provides_symbol {
+        provided.push(&erc20.symbol_header);
+      }
+    }
+
+    for erc721 in self.erc721.iter().filter(|erc721| erc721.provides_balances) {
+      provided.push(&erc721.balance_header);
+      if erc721.provides_name {
+        provided.push(&erc721.name_header);
+      }
+      if erc721.provides_symbol {
+        provided.push(&erc721.symbol_header);
+      }
+    }
+
+    for erc777 in self.erc777.iter().filter(|erc777| erc777.provides_balances) {
+      provided.push(&erc777.balance_header);
+      if erc777.provides_name {
+        provided.push(&erc777.name_header);
+      }
+      if erc777.provides_symbol {
+        provided.push(&erc777.symbol_header);
+      }
+    }
+
+    provided
+  }
+
+  pub fn middleware(&self) -> Vec<String> {
+    let forward_auth = self.forward_auth.is_some();
+
+    let chain = match self.hosts.is_empty() {
+      true => self.site_middleware(forward_auth),
+      false => {
+        let mut chain = vec![];
+        let hosts = self
+          .hosts
+          .iter()
+          .map(|host| (format!("names: {}", host.names.join(", ")), &host.config))
+          .chain(iter::once((String::from("default"), self)));
+
+        for (label, config) in hosts {
+          chain.push(format!("Host ({})", label));
+          chain.extend(indent(config.site_middleware(forward_auth)));
+        }
+
+        chain
+      }
+    };
+
+    match &self.forward_auth {
+      None => chain,
+      Some(forward_auth) => {
+        let mut outer = vec![format!(
+          "ForwardAuth (denied: {})",
+          forward_auth.denied_status
+        )];
+        outer.extend(indent(chain));
+
+        match &self.ext_authz {
+          None => outer,
+          Some(ext_authz) => {
+            let mut ext_authz = vec![format!("ExtAuthz (listen: {})", ext_authz.listen)];
+            ext_authz.extend(indent(outer));
+            ext_authz
+          }
+        }
+      }
+    }
+  }
+
+  fn site_middleware(&self, forward_auth: bool) -> Vec<String> {
+    let mut chain = vec![];
+
+    if self.strip_identity_headers {
+      chain.push(format!(
+        "RemovesHeaders (headers: {:?}, trusted: {:?})",
+        self
+          .identity_headers()
+          .iter()
+          .map(|header| header.as_str())
+          .collect::<Vec<_>>(),
+        self
+          .trusted_hops
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<_>>()
+      ));
+    }
+
+    chain.push(String::from("ProvidesForwardedHeader"));
+    chain.push(String::from("CorsMiddleware"));
+
+    let failovers =
+      self
+        .failover
+        .iter()
+        .map(|failover| (None, failover))
+        .chain(self.chains.iter().filter_map(|chain| {
+          chain
+            .failover
+            .as_ref()
+            .map(|failover| (Some(&chain.name), failover))
+        }));
+
+    for (name, failover) in failovers {
+      if let Some(path) = &failover.status_path {
+        chain.push(format!(
+          "ProvidesRpcStatus (path: {}, endpoints: {}{})",
+          path,
+          failover.urls.len() + 1,
+          on_chain(name)
+        ));
+      }
+    }
+
+    if let Some(cache) = &self.cache {
+      if let Some(path) = &cache.status_path {
+        chain.push(format!("ProvidesCacheStatus (path: {})", path));
+      }
+    }
+
+    if let Some(siwe) = &self.siwe {
+      chain.push(format!(
+        "SessionMiddleware (lifetime: {})",
+        humantime::format_duration(siwe.session_lifetime)
+      ));
+      chain.push(format!(
+        "ProvidesSignInWithEthereum (domain: {}, uri: {}, chain_id: {}, address: {})",
+        siwe.domain.as_deref().unwrap_or_default(),
+        siwe.uri.as_deref().unwrap_or("<any>"),
+        siwe.chain_id,
+        self.address_header
+      ));
+    }
+
+    if let Some(session_tokens) = &self.session_tokens {
+      chain.push(format!(
+        "ProvidesSessionTokenVerification (keys: {:?}, audience: {}, cookie: {}, address: {})",
+        session_tokens
+          .keys
+          .iter()
+          .map(|key| key.id.as_str())
+          .collect::<Vec<_>>(),
+        self.session_audience().unwrap_or_default(),
+        session_tokens.cookie_name.as_deref().unwrap_or("<none>"),
+        self.address_header
+      ));
+    }
+
+    let typed_data = self.typed_data_summary();
+
+    if self.provides_signatures {
+      chain.push(format!(
+        "ProvidesSignature (signature: {}{})",
+        self.signature_header, typed_data
+      ));
+    }
+
+    if let Some(nonces) = &self.nonces {
+      chain.push(format!(
+        "ProvidesChallenge (path: {}, lifetime: {}, store: {}, capacity: {})",
+        nonces.path,
+        humantime::format_duration(nonces.lifetime),
+        match &nonces.store_path {
+          Some(path) => format!("{:?}", path),
+          None => String::from("memory"),
+        },
+        nonces.capacity
+      ));
+    }
+
+    if self.routes.is_empty() && self.default_route == DefaultRoute::Allow {
+      chain.extend(self.checks(self.policy.as_ref()));
+      chain.push(self.upstream(None, forward_auth));
+      return chain;
+    }
+
+    for route in &self.routes {
+      chain.push(format!(
+        "Route (path: {}, methods: {})",
+        route.path,
+        match route.methods.is_empty() {
+          true => String::from("<any>"),
+          false => route
+            .methods
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", "),
+        }
+      ));
+      let mut checks = self.checks(route.policy.as_ref().or(self.policy.as_ref()));
+      checks.push(self.upstream(Some(route), forward_auth));
+      chain.extend(indent(checks));
+    }
+
+    chain.push(format!("Default ({})", self.default_route.as_ref()));
+    match self.default_route {
+      DefaultRoute::Deny => chain.push(String::from("    Forbidden")),
+      DefaultRoute::Allow => {
+        let mut checks = self.checks(self.policy.as_ref());
+        checks.push(self.upstream(None, forward_auth));
+        chain.extend(indent(checks));
+      }
+      DefaultRoute::Proxy if forward_auth => {
+        chain.push(String::from("    ProvidesIdentity (headers: [])"))
+      }
+      DefaultRoute::Proxy => chain.push(format!("    Proxy ({})", self.backends(None))),
+    }
+
+    chain
+  }
+
+  fn upstream(&self, route: Option<&RouteConfig>, forward_auth: bool) -> String {
+    match forward_auth {
+      true => format!(
+        "ProvidesIdentity (headers: {:?})",
+        self
+          .identity_headers()
+          .iter()
+          .map(|header| header.as_str())
+          .collect::<Vec<_>>()
+      ),
+      false => {
+        let mut upstream = format!("Proxy ({}", self.backends(route));
+        if let Some(strip_prefix) = route.and_then(|route| route.strip_prefix.as_ref()) {
+          upstream.push_str(&format!(", strip_prefix: {}", strip_prefix));
+        }
+        if let Some(add_prefix) = route.and_then(|route| route.add_prefix.as_ref()) {
+          upstream.push_str(&format!(", add_prefix: {}", add_prefix));
+        }
+        upstream.push(')');
+        upstream
+      }
+    }
+  }
+
+  fn backends(&self, route: Option<&RouteConfig>) -> String {
+    if let Some(backend) = route.and_then(|route| route.backend.as_ref()) {
+      return format!("backend: {}", backend);
+    }
+    match route
+      .and_then(|route| route.pool.as_ref())
+      .or(self.pool.as_ref())
+    {
+      Some(pool) => pool.summary(),
+      None => format!("backend: {}", self.backend),
+    }
+  }
+
+  fn cache_summary(&self) -> String {
+    match &self.cache {
+      Some(cache) => cache.summary(),
+      None => String::new(),
+    }
+  }
+
+  fn typed_data_summary(&self) -> String {
+    match &self.typed_data {
+      Some(typed_data) => format!(", eip712: {:?}", typed_data),
+      None => String::new(),
+    }
+  }
+
+  // The middleware that runs for each route, after the global middleware.
+  fn checks(&self, policy: Option<&Policy>) -> Vec<String> {
+    let mut chain = vec![];
+    let typed_data = self.typed_data_summary();
+    let cache = self.cache_summary();
+
+    let contract_wallets = match (self.contract_wallets, self.chains.is_empty()) {
+      (true, true) => format!(", eip1271: {}", self.claimed_address_header),
+      (true, false) => format!(
+        ", eip1271: {} on chains {:?}",
+        self.claimed_address_header,
+        self
+          .chains
+          .iter()
+          .map(|chain| chain.name.as_str())
+          .collect::<Vec<_>>()
+      ),
+      (false, _) => String::new(),
+    };
+
+    if self.provides_account_verification {
+      match &self.nonces {
+        Some(nonces) => chain.push(format!(
+          "ProvidesAccountVerification (signature: {}, nonce: {}, address: {}{}{})",
+          self.signature_header, nonces.header, self.address_header, typed_data, contract_wallets
+        )),
+        None => chain.push(format!(
+          "ProvidesAccountVerification (signature: {}, address: {}{}{})",
+          self.signature_header, self.address_header, typed_data, contract_wallets
+        )),
+      }
+    }
+
+    // Balances are looked up all at once, before any of the requirements are
+    // checked.
+    let mut lookups = vec![];
+    let mut requirements = vec![];
+
+    if self.provides_balances {
+      lookups.push(format!(
+        "ProvidesBalance (address: {}, balance: {}{})",
+        self.address_header, self.balance_header, cache
+      ));
+    }
+
+    if let Some(requirement) = &self.balance_requirement {
+      requirements.push(format!(
+        "RequiresBalance (header: {}, requirement: {:?}, scale: {:?})",
+        self.balance_header, requirement, self.balance_scale
+      ));
+    }
+
+    for erc1155 in &self.erc1155 {
+      if erc1155.provides_balances {
+        lookups.push(format!(
+          "ProvidesERC1155Balance (name: {}, contract: {:?}, token_ids: {:?}, balance: {}, token_balance: {}{}{}{})",
+          erc1155.name,
+          erc1155.contract_address,
+          erc1155.token_ids,
+          erc1155.balance_header,
+          erc1155.token_balance_header,
+          on_chain(erc1155.chain.as_ref()),
+          cache,
+          index_summary(erc1155.index.as_ref())
+        ));
+      }
+
+      if let Some(requirement) = &erc1155.balance_requirement {
+        requirements.push(format!(
+          "RequiresBalance (header: {}, requirement: {:?})",
+          erc1155.balance_header, requirement
+        ));
+      }
+
+      for token_requirement in &erc1155.token_requirements {
+        requirements.push(format!(
+          "RequiresTokenBalance (header: {}, token_ids: {:?}, requirement: {:?})",
+          erc1155.token_balance_header, token_requirement.token_ids, token_requirement.requirement
+        ));
+      }
+    }
+
+    for erc20 in &self.erc20 {
+      if erc20.provides_balances {
+        lookups.push(format!(
+          "ProvidesERC20Balance (name: {}, contract: {:?}, balance: {}{}{}{})",
+          erc20.name,
+          erc20.contract_address,
+          erc20.balance_header,
+          on_chain(erc20.chain.as_ref()),
+          cache,
+          index_summary(erc20.index.as_ref())
+        ));
+      }
+
+      if let Some(requirement) = &erc20.balance_requirement {
+        requirements.push(format!(
+          "RequiresBalance (header: {}, requirement: {:?})",
+          erc20.balance_header, requirement
+        ));
+      }
+    }
+
+    for erc721 in &self.erc721 {
+      if erc721.provides_balances {
+        lookups.push(format!(
+          "ProvidesERC721Balance (name: {}, contract: {:?}, balance: {}{}{}{})",
+          erc721.name,
+          erc721.contract_address,
+          erc721.balance_header,
+          on_chain(erc721.chain.as_ref()),
+          cache,
+          index_summary(erc721.index.as_ref())
+        ));
+      }
+
+      if let Some(requirement) = &erc721.balance_requirement {
+        requirements.push(format!(
+          "RequiresBalance (header: {}, requirement: {:?})",
+          erc721.balance_header, requirement
+        ));
+      }
+
+      if let Some(ownership) = &erc721.ownership {
+        requirements.push(format!(
+          "RequiresERC721Ownership (name: {}, contract: {:?}, ownership: {:?}, token: {}{})",
+          erc721.name,
+          erc721.contract_address,
+          ownership,
+          erc721.token_header,
+          on_chain(erc721.chain.as_ref())
+        ));
+      }
+    }
+
+    for erc777 in &self.erc777 {
+      if erc777.provides_balances {
+        lookups.push(format!(
+          "ProvidesERC777Balance (name: {}, contract: {:?}, balance: {}{}{})",
+          erc777.name,
+          erc777.contract_address,
+          erc777.balance_header,
+          on_chain(erc777.chain.as_ref()),
+          cache
+        ));
+      }
+
+      if let Some(requirement) = &erc777.balance_requirement {
+        requirements.push(format!(
+          "RequiresBalance (header: {}, requirement: {:?})",
+          erc777.balance_header, requirement
+        ));
+      }
+    }
+
+    if !lookups.is_empty() {
+      chain.push(format!("ProvidesConcurrently ({})", lookups.join(", ")));
+    }
+    chain.extend(requirements);
+
+    if let Some(policy) = policy {
+      chain.push(format!(
+        "RequiresPolicy (decision: {}, policy: {:?})",
+        self.policy_header, policy
+      ));
+    }
+
+    if let Some(session_tokens) = &self.session_tokens {
+      chain.push(format!(
+        "ProvidesSessionToken (key: {}, audience: {}, lifetime: {}, header: {}, cookie: {}, claims: {:?})",
+        session_tokens
+          .keys
+          .first()
+          .map(|key| key.id.as_str())
+          .unwrap_or("<none>"),
+        self.session_audience().unwrap_or_default(),
+        humantime::format_duration(session_tokens.lifetime),
+        session_tokens.header,
+        session_tokens.cookie_name.as_deref().unwrap_or("<none>"),
+        self
+          .provided_headers()
+          .iter()
+          .map(|header| header.as_str())
+          .collect::<Vec<_>>()
+      ));
+    }
+
+    if let Some(assertions) = &self.assertions {
+      let key = match (assertions.keys.first(), &self.secret_key) {
+        (Some(key), _) => format!("HS256, key: {}", key.id),
+        (None, Some(secret_key)) => format!(
+          "ES256K, public_key: {}",
+          PublicKey::from_secret_key(&Secp256k1::signing_only(), secret_key)
+        ),
+        (None, None) => String::from("<none>"),
+      };
+      chain.push(format!(
+        "ProvidesIdentityAssertion (header: {}, alg: {}, lifetime: {}, claims: {:?})",
+        assertions.header,
+        key,
+        humantime::format_duration(assertions.lifetime),
+        self
+          .assertion_claim_headers()
+          .iter()
+          .map(|header| header.as_str())
+          .collect::<Vec<_>>()
+      ));
+    }
+
+    chain
+  }
+}
+
+fn on_chain(chain: Option<&String>) -> String {
+  match chain {
+    Some(chain) => format!(", chain: {}", chain),
+    None => String::new(),
+  }
+}
+
+fn indent(lines: Vec<String>) -> Vec<String> {
+  lines
+    .into_iter()
+    .map(|line| format!("    {}", line))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(toml: &str) -> Config {
+    toml::from_str(toml).unwrap()
+  }
+
+  #[test]
+  fn scales_balances_in_wei_by_default() {
+    assert!(matches!(config("").balance_scale, BalanceScale::Wei));
+    assert!(matches!(
+      config("balance_scale = \"Gwei\"").balance_scale,
+      BalanceScale::Gwei
+    ));
+  }
+
+  #[test]
+  fn rejects_huge_token_id_ranges() {
+    let erc1155 = config(
+      r#"
+      [[erc1155]]
+      contract_address = "0x0000000000000000000000000000000000000002"
+      token_ids = { first = 0, last = 1000000000000000000 }
+      provides_balances = true
+      "#,
+    );
+    assert!(erc1155
+      .validate()
+      .unwrap_err()
+      .to_string()
+      .contains("at most 1000"));
+
+    let erc721 = config(
+      r#"
+      [[erc721]]
+      contract_address = "0x0000000000000000000000000000000000000002"
+      ownership = { token_ids = { first = 0, last = 1000000000000000000 } }
+      "#,
+    );
+    assert!(erc721
+      .validate()
+      .unwrap_err()
+      .to_string()
+      .contains("at most 1000"));
+  }
+
+  #[test]
+  fn rejects_backwards_token_id_ranges() {
+    let erc1155 = config(
+      r#"
+      [[erc1155]]
+      contract_address = "0x0000000000000000000000000000000000000002"
+      token_ids = { first = 9, last = 5 }
+      provides_balances = true
+      "#,
+    );
+    assert!(erc1155
+      .validate()
+      .unwrap_err()
+      .to_string()
+      .contains("after it ends"));
+  }
+
+  #[test]
+  fn accepts_token_id_ranges() {
+    let erc1155 = config(
+      r#"
+      [[erc1155]]
+      contract_address = "0x0000000000000000000000000000000000000002"
+      token_ids = { first = 5, last = 9 }
+      provides_balances = true
+      token_requirements = [
+        { token_ids = { first = 6, last = 7 }, requirement = { at_least = 1 } },
+      ]
+      "#,
+    );
+    erc1155.validate().unwrap();
+  }
+
+  #[test]
+  fn requires_a_siwe_domain() {
+    let error = config("[siwe]").validate().unwrap_err();
+    assert!(error.to_string().contains("siwe domain is required"));
+    config("[siwe]\ndomain = \"example.com\"")
+      .validate()
+      .unwrap();
+  }
+
+  #[test]
+  fn binds_session_tokens_to_the_backend() {
+    let keys = "[[session_tokens.keys]]\nid = \"test\"\nsecret = \"a-secret-that-is-at-least-32-bytes-long\"";
+    assert_eq!(config("").session_audience(), None);
+
+    let backend = config(&format!("backend = \"http://app.test/\"\n{}", keys));
+    assert_eq!(backend.session_audience().unwrap(), "http://app.test/");
+
+    let pool = config(&format!(
+      "[pool]\nbackends = [\"http://one.test/\", \"http://two.test/\"]\n{}",
+      keys
+    ));
+    assert_eq!(pool.session_audience().unwrap(), "http://one.test/");
+
+    let audience = config(&format!(
+      "backend = \"http://app.test/\"\n[session_tokens]\naudience = \"app\"\n{}",
+      keys
+    ));
+    assert_eq!(audience.session_audience().unwrap(), "app");
+  }
+
+  #[async_std::test]
+  async fn matches_route_prefixes_by_segment() {
+    let route: RouteConfig = toml::from_str("path = \"/app/*\"").unwrap();
+    let mut server = tide::new();
+    for path in route.paths() {
+      server.at(&path).all(|_| async { Ok("app") });
+    }
+    server.at("*").all(|_| async { Ok("default") });
+
+    let cases = [
+      ("/app", "app"),
+      ("/app/", "app"),
+      ("/app/things/1", "app"),
+      ("/application", "default"),
+      ("/applications/1", "default"),
+    ];
+    for (path, expected) in cases {
+      let url = Url::parse(&format!("http://localhost{}", path)).unwrap();
+      let request = tide::http::Request::new(Method::Get, url);
+      let mut response: tide::http::Response = server.respond(request).await.unwrap();
+      assert_eq!(response.body_string().await.unwrap(), expected, "{}", path);
+    }
+  }
+}
@@ -0,0 +1,227 @@
+use super::{Config, TlsConfig};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+  fs::File,
+  io::{BufReader, Seek, SeekFrom},
+  sync::Arc,
+};
+use tide_rustls::rustls::{
+  internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+  sign::{self, CertifiedKey},
+  ClientHello, NoClientAuth, ResolvesServerCert, ResolvesServerCertUsingSNI, ServerConfig,
+};
+
+// Picks the certificate for a host by SNI name, or the top-level certificate
+// for clients that don't send one (or send one we don't know).
+struct ResolvesHostCertificates {
+  hosts: ResolvesServerCertUsingSNI,
+  default: CertifiedKey,
+}
+
+impl ResolvesServerCert for ResolvesHostCertificates {
+  fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+    self
+      .hosts
+      .resolve(client_hello)
+      .or_else(|| Some(self.default.clone()))
+  }
+}
+
+pub fn server_config(config: &Config) -> Result<ServerConfig> {
+  let default = match &config.tls {
+    Some(tls) => certified_key(tls)?,
+    None => bail!("tls is not configured"),
+  };
+
+  let mut hosts = ResolvesServerCertUsingSNI::new();
+  for host in &config.hosts {
+    if let Some(tls) = &host.config.tls {
+      let key = certified_key(tls)?;
+      for name in &host.names {
+        hosts
+          .add(&name.to_lowercase(), key.clone())
+          .with_context(|| {
+            format!(
+              "certificate {:?} is not valid for {}",
+              tls.certificate_path, name
+            )
+          })?;
+      }
+    }
+  }
+
+  let mut server_config = ServerConfig::new(NoClientAuth::new());
+  server_config.cert_resolver = Arc::new(ResolvesHostCertificates { hosts, default });
+  Ok(server_config)
+}
+
+fn certified_key(tls: &TlsConfig) -> Result<CertifiedKey> {
+  let certificates = certs(&mut BufReader::new(
+    File::open(&tls.certificate_path)
+      .with_context(|| format!("failed to read certificate {:?}", tls.certificate_path))?,
+  ))
+  .map_err(|_| anyhow!("invalid certificate {:?}", tls.certificate_path))?;
+
+  // Keys can be PKCS8 or RSA, same as TlsListener.
+  let mut reader = BufReader::new(
+    File::open(&tls.key_path).with_context(|| format!("failed to read key {:?}", tls.key_path))?,
+  );
+  let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+  if keys.is_empty() {
+    reader.seek(SeekFrom::Start(0))?;
+    keys = rsa_private_keys(&mut reader).unwrap_or_default();
+  }
+
+  let key = match keys.first() {
+    Some(key) => sign::any_supported_type(key)
+      .map_err(|_| anyhow!("unsupported key type in {:?}", tls.key_path))?,
+    None => bail!("invalid key {:?}", tls.key_path),
+  };
+
+  Ok(CertifiedKey::new(certificates, Arc::new(key)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+  use std::{io::Write, path::PathBuf};
+  use tide_rustls::rustls::{self, ClientConfig, ClientSession, ServerSession, Session};
+
+  // Writes a certificate for names, signed by ca, and its key, where a TlsConfig
+  // can find them.
+  fn tls(ca: &Certificate, label: &str, names: &[&str]) -> TlsConfig {
+    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+    let certificate = Certificate::from_params(CertificateParams::new(names)).unwrap();
+    let path = |extension: &str| -> PathBuf {
+      std::env::temp_dir().join(format!(
+        "niftygate-tls-{}-{}.{}",
+        label,
+        std::process::id(),
+        extension
+      ))
+    };
+
+    let tls = TlsConfig {
+      certificate_path: path("crt"),
+      key_path: path("key"),
+    };
+    let pem = certificate.serialize_pem_with_signer(ca).unwrap();
+    std::fs::write(&tls.certificate_path, pem).unwrap();
+    std::fs::write(&tls.key_path, certificate.serialize_private_key_pem()).unwrap();
+    tls
+  }
+
+  fn remove(tls: TlsConfig) {
+    std::fs::remove_file(tls.certificate_path).unwrap();
+    std::fs::remove_file(tls.key_path).unwrap();
+  }
+
+  fn ca() -> Certificate {
+    let mut params = CertificateParams::new(vec![]);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+  }
+
+  // Runs a handshake in memory, and returns the certificate the server chose.
+  fn handshake(server_config: ServerConfig, ca: &Certificate, name: &str, sni: bool) -> Vec<u8> {
+    let mut client_config = ClientConfig::new();
+    client_config
+      .root_store
+      .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+      .unwrap();
+    client_config.enable_sni = sni;
+
+    let name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+    let mut client = ClientSession::new(&Arc::new(client_config), name);
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    while client.is_handshaking() || server.is_handshaking() {
+      let mut buffer = vec![];
+      while client.wants_write() {
+        client.write_tls(&mut buffer).unwrap();
+      }
+      server.read_tls(&mut buffer.as_slice()).unwrap();
+      server.process_new_packets().unwrap();
+
+      let mut buffer = vec![];
+      while server.wants_write() {
+        server.write_tls(&mut buffer).unwrap();
+      }
+      client.read_tls(&mut buffer.as_slice()).unwrap();
+      client.process_new_packets().unwrap();
+    }
+    client.flush().unwrap();
+
+    client.get_peer_certificates().unwrap()[0].0.clone()
+  }
+
+  fn config(default: TlsConfig, host: TlsConfig) -> Config {
+    let mut config: Config = toml::from_str(
+      r#"
+        [[hosts]]
+        names = ["Site.test"]
+      "#,
+    )
+    .unwrap();
+    config.tls = Some(default);
+    config.hosts[0].config.tls = Some(host);
+    config
+  }
+
+  fn certificate(tls: &TlsConfig) -> Vec<u8> {
+    let mut reader = BufReader::new(File::open(&tls.certificate_path).unwrap());
+    certs(&mut reader).unwrap()[0].0.clone()
+  }
+
+  #[test]
+  fn picks_certificates_by_sni_name() {
+    let ca = ca();
+    let default = tls(&ca, "default", &["default.test", "other.test"]);
+    let site = tls(&ca, "site", &["site.test"]);
+    let server_config = || server_config(&config(default.clone(), site.clone())).unwrap();
+
+    assert_eq!(
+      handshake(server_config(), &ca, "site.test", true),
+      certificate(&site)
+    );
+    assert_eq!(
+      handshake(server_config(), &ca, "default.test", true),
+      certificate(&default)
+    );
+    remove(default);
+    remove(site);
+  }
+
+  #[test]
+  fn falls_back_to_the_default_certificate() {
+    let ca = ca();
+    let default = tls(&ca, "fallback", &["default.test", "other.test"]);
+    let site = tls(&ca, "fallback-site", &["site.test"]);
+    let server_config = || server_config(&config(default.clone(), site.clone())).unwrap();
+
+    // Names without a certificate of their own, and clients without SNI.
+    assert_eq!(
+      handshake(server_config(), &ca, "other.test", true),
+      certificate(&default)
+    );
+    assert_eq!(
+      handshake(server_config(), &ca, "default.test", false),
+      certificate(&default)
+    );
+    remove(default);
+    remove(site);
+  }
+
+  #[test]
+  fn rejects_certificates_for_other_names() {
+    let ca = ca();
+    let default = tls(&ca, "mismatch", &["default.test"]);
+    let site = tls(&ca, "mismatch-site", &["other.test"]);
+    match server_config(&config(default.clone(), site.clone())) {
+      Ok(_) => panic!("expected the site certificate to be rejected"),
+      Err(error) => assert!(error.to_string().contains("is not valid for Site.test")),
+    }
+    remove(default);
+    remove(site);
+  }
+}
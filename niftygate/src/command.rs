@@ -5,6 +5,7 @@ mod demo;
 mod units;
 mod web3;
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 pub enum Command {
   Demo(demo::Command),
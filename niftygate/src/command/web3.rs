@@ -1,114 +1,237 @@
 use crate::{
-  middleware::ethereum::{BalanceRequirement, BalanceScale},
+  application::{
+    ext_authz::{identity_headers, Authorization},
+    proxy::{server_config, Config, DefaultRoute, ExtAuthzConfig, TlsConfig},
+  },
+  middleware::{
+    ethereum::{BalanceRequirement, BalanceScale, Ownership, SessionKey, TokenIds, TokenPath},
+    headers::Network,
+    proxy::Strategy,
+  },
   HexData,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future;
 use secp256k1::SecretKey;
-use std::{fs, path::PathBuf};
+use std::{convert::TryFrom, fs, path::PathBuf};
 use structopt::StructOpt;
 use tide::{
-  http::{headers::HeaderName, Url},
+  http::{headers::HeaderName, StatusCode, Url},
   log,
 };
 use tide_rustls::TlsListener;
 use web3::types::{Address, U256};
 
-const ZERO_ADDRESS: [u8; 20] = [0; 20];
-// const PERSONAL_SIGN_PREFIX: &str = "\x19Ethereum Signed Message:\n";
-
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Runs the proxy service")]
 pub struct Command {
+  #[structopt(env, long, short = "C", value_name = "path")]
+  config: Option<PathBuf>,
+
   #[structopt(
-    env,
     long,
-    short,
-    value_name = "address",
-    default_value = "0.0.0.0:8000"
+    takes_value = false,
+    help = "validate the config and print the middleware chain, then exit"
   )]
-  listen: String,
+  check_config: bool,
+
+  #[structopt(env, long, short, value_name = "address")]
+  listen: Option<String>,
+
+  #[structopt(env, long, short, value_name = "url")]
+  backend: Option<Url>,
 
   #[structopt(
     env,
     long,
-    short,
     value_name = "url",
-    default_value = "http://127.0.0.1:8080"
+    help = "spread requests across these backends, instead of a single backend"
   )]
-  backend: Url,
+  pool_backends: Vec<Url>,
 
   #[structopt(
     env,
-    short,
     long,
-    value_name = "url",
-    default_value = "ws://127.0.0.1:7545"
+    value_name = "strategy",
+    possible_values = &["round_robin", "least_connections", "address_hash"],
+    help = "how to pick a backend from the pool: round_robin, least_connections, or address_hash (by verified address)"
+  )]
+  pool_strategy: Option<Strategy>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "path",
+    help = "check the health of each pool backend at this path"
+  )]
+  pool_check_path: Option<String>,
+
+  #[structopt(env, long, value_name = "duration")]
+  pool_check_interval: Option<humantime::Duration>,
+
+  #[structopt(env, long, value_name = "duration")]
+  pool_check_timeout: Option<humantime::Duration>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "count",
+    help = "eject a pool backend after this many failed requests in a row"
+  )]
+  pool_max_failures: Option<u32>,
+
+  #[structopt(env, long, value_name = "duration")]
+  pool_ejection_time: Option<humantime::Duration>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "action",
+    possible_values = &["deny", "allow", "proxy"],
+    help = "what to do with requests that don't match a route: deny, allow (after checks), or proxy (without checks)"
+  )]
+  default_route: Option<DefaultRoute>,
+
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "answer forward-auth requests (nginx auth_request, Traefik ForwardAuth, Caddy forward_auth) instead of proxying"
   )]
-  web3_rpc_url: Url,
+  forward_auth: bool,
 
   #[structopt(
     env,
     long,
-    value_name = "name",
-    default_value = "X-Web3-Account-Address"
+    value_name = "status",
+    help = "respond to denied forward-auth requests with this status, rather than 402"
   )]
-  address_header: HeaderName,
+  forward_auth_denied_status: Option<u16>,
 
   #[structopt(
     env,
     long,
-    value_name = "name",
-    default_value = "X-Web3-Account-Balance"
+    value_name = "address",
+    help = "also answer Envoy ext_authz Check calls over gRPC on this address (implies --forward-auth)"
+  )]
+  ext_authz_listen: Option<String>,
+
+  #[structopt(env, short, long, value_name = "url")]
+  web3_rpc_url: Option<Url>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "url",
+    help = "fail over to these RPC endpoints (in order) when web3-rpc-url is unhealthy"
   )]
-  balance_header: HeaderName,
+  failover_urls: Vec<Url>,
+
+  #[structopt(env, long, value_name = "duration")]
+  failover_check_interval: Option<humantime::Duration>,
+
+  #[structopt(env, long, value_name = "blocks")]
+  failover_max_block_lag: Option<u64>,
+
+  #[structopt(env, long, value_name = "duration")]
+  failover_max_latency: Option<humantime::Duration>,
 
   #[structopt(
     env,
     long,
-    value_name = "name",
-    default_value = "X-Web3-ERC1155-Balance"
+    value_name = "path",
+    help = "report the health of each RPC endpoint at this path"
   )]
-  erc1155_balance_header: HeaderName,
+  failover_status_path: Option<String>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC20-Balance")]
-  erc20_balance_header: HeaderName,
+  #[structopt(
+    env,
+    long,
+    value_name = "duration",
+    help = "send RPC calls made within this long of each other as one batch"
+  )]
+  batching_window: Option<humantime::Duration>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC20-Name")]
-  erc20_name_header: HeaderName,
+  #[structopt(env, long, value_name = "calls")]
+  batching_max_size: Option<usize>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC20-Symbol")]
-  erc20_symbol_header: HeaderName,
+  #[structopt(
+    env,
+    long,
+    value_name = "duration",
+    help = "cache balances for this long"
+  )]
+  cache_ttl: Option<humantime::Duration>,
 
   #[structopt(
     env,
     long,
-    value_name = "name",
-    default_value = "X-Web3-ERC721-Balance"
+    value_name = "blocks",
+    help = "cache balances until this many new blocks have been seen"
   )]
-  erc721_balance_header: HeaderName,
+  cache_blocks: Option<u64>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC721-Name")]
-  erc721_name_header: HeaderName,
+  #[structopt(env, long, value_name = "duration")]
+  cache_block_check_interval: Option<humantime::Duration>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC721-Symbol")]
-  erc721_symbol_header: HeaderName,
+  #[structopt(env, long, value_name = "entries")]
+  cache_capacity: Option<usize>,
 
   #[structopt(
     env,
     long,
-    value_name = "name",
-    default_value = "X-Web3-ERC777-Balance"
+    value_name = "path",
+    help = "report balance cache hits and misses at this path"
   )]
-  erc777_balance_header: HeaderName,
+  cache_status_path: Option<String>,
+
+  #[structopt(env, long, value_name = "name")]
+  address_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  balance_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc1155_balance_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc1155_token_balance_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc20_balance_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc20_name_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc20_symbol_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc721_balance_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc721_token_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc721_name_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc721_symbol_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc777_balance_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "name")]
+  erc777_name_header: Option<HeaderName>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC777-Name")]
-  erc777_name_header: HeaderName,
+  #[structopt(env, long, value_name = "name")]
+  erc777_symbol_header: Option<HeaderName>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-ERC777-Symbol")]
-  erc777_symbol_header: HeaderName,
+  #[structopt(env, long, value_name = "name")]
+  signature_header: Option<HeaderName>,
 
-  #[structopt(env, long, value_name = "name", default_value = "X-Web3-Signature")]
-  signature_header: HeaderName,
+  #[structopt(env, long, value_name = "name")]
+  claimed_address_header: Option<HeaderName>,
 
   #[structopt(env, long, short = "k", value_name = "path")]
   secret_key_file: Option<PathBuf>,
@@ -116,11 +239,125 @@ pub struct Command {
   #[structopt(env, long, short = "K", value_name = "hex")]
   secret_key_data: Option<HexData>,
 
-  #[structopt(env, long, short, value_name = "phrase", default_value = "totes-legit")]
-  challenge: String,
+  #[structopt(env, long, short, value_name = "phrase")]
+  challenge: Option<String>,
 
-  #[structopt(env, long, short = "u", value_name = "unit", default_value = "Wei")]
-  balance_scale: BalanceScale,
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "require signatures over a single-use nonce, issued by a challenge endpoint"
+  )]
+  requires_nonces: bool,
+
+  #[structopt(env, long, value_name = "name")]
+  nonce_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "path")]
+  nonce_challenge_path: Option<String>,
+
+  #[structopt(env, long, value_name = "duration")]
+  nonce_lifetime: Option<humantime::Duration>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "path",
+    help = "keep issued nonces in this file, rather than in memory"
+  )]
+  nonce_store_path: Option<PathBuf>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "nonces",
+    help = "refuse to issue more nonces while this many are outstanding"
+  )]
+  nonce_capacity: Option<usize>,
+
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "serve Sign-In with Ethereum (EIP-4361) endpoints, and accept the resulting sessions"
+  )]
+  siwe: bool,
+
+  #[structopt(env, long, value_name = "domain")]
+  siwe_domain: Option<String>,
+
+  #[structopt(env, long, value_name = "uri")]
+  siwe_uri: Option<String>,
+
+  #[structopt(env, long, value_name = "id")]
+  siwe_chain_id: Option<u64>,
+
+  #[structopt(env, long, value_name = "duration")]
+  siwe_session_lifetime: Option<humantime::Duration>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "id:secret",
+    help = "issue session tokens signed with the first key, and accept tokens signed with any of them"
+  )]
+  session_token_keys: Vec<SessionKey>,
+
+  #[structopt(env, long, value_name = "duration")]
+  session_token_lifetime: Option<humantime::Duration>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "audience",
+    help = "only accept session tokens issued for this audience (the backend, by default)"
+  )]
+  session_token_audience: Option<String>,
+
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "sign the verified address and provided headers for the backend, with the assertion keys (or the secret key, if there are none)"
+  )]
+  provides_assertions: bool,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "id:secret",
+    help = "sign assertions (HS256) with the first key, for backends that share them"
+  )]
+  assertion_keys: Vec<SessionKey>,
+
+  #[structopt(env, long, value_name = "name")]
+  assertion_header: Option<HeaderName>,
+
+  #[structopt(env, long, value_name = "duration")]
+  assertion_lifetime: Option<humantime::Duration>,
+
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "sign and verify the challenge as EIP-712 typed data"
+  )]
+  eip712: bool,
+
+  #[structopt(env, long, value_name = "name")]
+  eip712_name: Option<String>,
+
+  #[structopt(env, long, value_name = "version")]
+  eip712_version: Option<String>,
+
+  #[structopt(env, long, value_name = "id")]
+  eip712_chain_id: Option<u64>,
+
+  #[structopt(env, long, value_name = "address")]
+  eip712_verifying_contract: Option<Address>,
+
+  #[structopt(env, long, short = "u", value_name = "unit")]
+  balance_scale: Option<BalanceScale>,
 
   #[structopt(env, long, value_name = "amount", parse(try_from_str = U256::from_dec_str))]
   balance_minimum: Option<U256>,
@@ -155,6 +392,15 @@ pub struct Command {
   #[structopt(env, long, value_name = "address")]
   erc1155_contract_address: Option<Address>,
 
+  #[structopt(
+    env,
+    long,
+    value_name = "id",
+    use_delimiter = true,
+    parse(try_from_str = U256::from_dec_str)
+  )]
+  erc1155_token_ids: Vec<U256>,
+
   #[structopt(env, long, value_name = "address")]
   erc20_contract_address: Option<Address>,
 
@@ -164,6 +410,73 @@ pub struct Command {
   #[structopt(env, long, value_name = "address")]
   erc777_contract_address: Option<Address>,
 
+  #[structopt(
+    env,
+    long,
+    value_name = "block",
+    help = "index ERC1155 holders from transfer events since this block"
+  )]
+  erc1155_index_from_block: Option<u64>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "path",
+    help = "save the ERC1155 holder index here"
+  )]
+  erc1155_index_path: Option<PathBuf>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "block",
+    help = "index ERC20 holders from transfer events since this block"
+  )]
+  erc20_index_from_block: Option<u64>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "path",
+    help = "save the ERC20 holder index here"
+  )]
+  erc20_index_path: Option<PathBuf>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "block",
+    help = "index ERC721 holders from transfer events since this block"
+  )]
+  erc721_index_from_block: Option<u64>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "path",
+    help = "save the ERC721 holder index here"
+  )]
+  erc721_index_path: Option<PathBuf>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "id",
+    use_delimiter = true,
+    conflicts_with = "erc721-owned-token-path",
+    parse(try_from_str = U256::from_dec_str),
+    help = "require ownership of any of these ERC721 tokens"
+  )]
+  erc721_owned_token_ids: Vec<U256>,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "path",
+    help = "require ownership of the ERC721 token in matching paths, like /tickets/{tokenId}"
+  )]
+  erc721_owned_token_path: Option<TokenPath>,
+
   #[structopt(
     env,
     long,
@@ -182,6 +495,14 @@ pub struct Command {
   )]
   provides_account_verification: bool,
 
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "verify signatures from contract wallets (EIP-1271) at the claimed address"
+  )]
+  contract_wallets: bool,
+
   #[structopt(
     env,
     long,
@@ -191,6 +512,22 @@ pub struct Command {
   )]
   provides_balances: bool,
 
+  #[structopt(
+    env,
+    long,
+    takes_value = false,
+    help = "keep identity and balance headers sent by clients, rather than removing them (only safe behind a proxy that removes them)"
+  )]
+  keeps_identity_headers: bool,
+
+  #[structopt(
+    env,
+    long,
+    value_name = "network",
+    help = "keep identity and balance headers on requests from these addresses or networks (like 10.0.0.0/8)"
+  )]
+  trusted_hops: Vec<Network>,
+
   #[structopt(env, long, takes_value = false, help = "provide ERC1155 balances")]
   provides_erc1155_balance: bool,
 
@@ -239,123 +576,465 @@ pub struct Command {
 
 impl Command {
   pub async fn execute(self) -> Result<()> {
-    let secret_key = match (self.secret_key_data, self.secret_key_file) {
-      (Some(data), _) => Some(SecretKey::from_slice(&data.0)?),
-      (None, Some(path)) => Some(SecretKey::from_slice(&fs::read(path)?)?),
-      (None, None) => None,
-    };
+    let check_config = self.check_config;
 
-    let balance_requirement = balance_requirement_from(self.balance_minimum, self.balance_maximum);
-
-    let zero_address = Address::from_slice(&ZERO_ADDRESS);
-
-    let erc1155 = crate::application::proxy::ERC1155Config {
-      balance_header: self.erc1155_balance_header,
-      balance_requirement: balance_requirement_from(
-        self.erc1155_balance_minimum,
-        self.erc1155_balance_maximum,
-      ),
-      contract_address: match self.erc1155_contract_address {
-        Some(address) => address,
-        None => zero_address,
-      },
-      provides_balances: self.provides_erc1155_balance,
-    };
+    let config = self.config()?;
+    config.validate()?;
 
-    let erc20 = crate::application::proxy::ERC20Config {
-      balance_header: self.erc20_balance_header,
-      balance_requirement: balance_requirement_from(
-        self.erc20_balance_minimum,
-        self.erc20_balance_maximum,
-      ),
-      contract_address: match self.erc20_contract_address {
-        Some(address) => address,
-        None => zero_address,
-      },
-      name_header: self.erc20_name_header,
-      provides_balances: self.provides_erc20_balance,
-      provides_name: self.provides_erc20_name,
-      provides_symbol: self.provides_erc20_symbol,
-      symbol_header: self.erc20_symbol_header,
-    };
+    if check_config {
+      for (index, middleware) in config.middleware().iter().enumerate() {
+        println!("{:>2}. {}", index + 1, middleware);
+      }
+      return Ok(());
+    }
 
-    let erc721 = crate::application::proxy::ERC721Config {
-      balance_header: self.erc721_balance_header,
-      balance_requirement: balance_requirement_from(
-        self.erc721_balance_minimum,
-        self.erc721_balance_maximum,
-      ),
-      contract_address: match self.erc721_contract_address {
-        Some(address) => address,
-        None => zero_address,
-      },
-      name_header: self.erc721_name_header,
-      provides_balances: self.provides_erc721_balance,
-      provides_name: self.provides_erc721_name,
-      provides_symbol: self.provides_erc721_symbol,
-      symbol_header: self.erc721_symbol_header,
-    };
+    log::with_level(log::LevelFilter::Debug);
 
-    let erc777 = crate::application::proxy::ERC777Config {
-      balance_header: self.erc777_balance_header,
-      balance_requirement: balance_requirement_from(
-        self.erc777_balance_minimum,
-        self.erc777_balance_maximum,
-      ),
-      contract_address: match self.erc777_contract_address {
-        Some(address) => address,
-        None => zero_address,
-      },
-      name_header: self.erc777_name_header,
-      provides_balances: self.provides_erc777_balance,
-      provides_name: self.provides_erc777_name,
-      provides_symbol: self.provides_erc777_symbol,
-      symbol_header: self.erc777_symbol_header,
+    let listen = config.listen.clone();
+    let tls = match config.tls {
+      Some(_) => Some(server_config(&config)?),
+      None => None,
     };
 
-    // let mut challenge = String::from(PERSONAL_SIGN_PREFIX);
-    // challenge.push_str(&self.challenge);
-
-    let challenge = self.challenge;
-
-    let config = crate::application::proxy::Config {
-      address_header: self.address_header,
-      backend: self.backend,
-      balance_header: self.balance_header,
-      balance_requirement,
-      balance_scale: Some(self.balance_scale),
-      challenge: challenge.as_bytes().to_vec(),
-      erc1155,
-      erc20,
-      erc721,
-      erc777,
-      provides_account_verification: self.provides_account_verification,
-      provides_balances: self.provides_balances,
-      provides_signatures: self.provides_signatures,
-      secret_key,
-      signature_header: self.signature_header,
-      web3_rpc_url: self.web3_rpc_url,
+    let ext_authz = config
+      .ext_authz
+      .as_ref()
+      .map(|ext_authz| (ext_authz.listen.clone(), identity_headers(&config)));
+
+    let server = crate::application::proxy::server(config).await?;
+    let authorization = ext_authz.map(|(ext_authz_listen, headers)| {
+      let authorization = Authorization {
+        server: server.clone(),
+        headers,
+      };
+      async move { authorization.listen(&ext_authz_listen).await }
+    });
+
+    let proxy = async {
+      match tls {
+        Some(tls) => {
+          server
+            .listen(TlsListener::build().addrs(&listen).config(tls))
+            .await?
+        }
+        None => server.listen(&listen).await?,
+      }
+      Ok(())
     };
 
-    log::with_level(log::LevelFilter::Debug);
+    match authorization {
+      Some(authorization) => {
+        future::try_join(proxy, authorization).await?;
+      }
+      None => proxy.await?,
+    }
 
-    let server = crate::application::proxy::server(config).await?;
+    Ok(())
+  }
+
+  fn config(self) -> Result<Config> {
+    let mut config = match &self.config {
+      Some(path) => Config::from_path(path)?,
+      None => Config::default(),
+    };
+
+    match (self.secret_key_data, self.secret_key_file) {
+      (Some(data), _) => config.secret_key = Some(SecretKey::from_slice(&data.0)?),
+      (None, Some(path)) => config.secret_key = Some(SecretKey::from_slice(&fs::read(path)?)?),
+      (None, None) => (),
+    };
 
     if self.with_tls {
       match (self.tls_certificate_path, self.tls_key_path) {
-        (Some(tls_certificate_path), Some(tls_key_path)) => {
-          server.listen(TlsListener::build()
-            .addrs(&self.listen)
-            .cert(&tls_certificate_path)
-            .key(&tls_key_path)).await?
-          },
-          _ => panic!("Missing either certificate or key. CLI argument validation should have prevented this. (╯°□°)╯︵ ┻━┻"),
+        (Some(certificate_path), Some(key_path)) => {
+          config.tls = Some(TlsConfig {
+            certificate_path,
+            key_path,
+          })
         }
-    } else {
-      server.listen(&self.listen).await?
+        _ => panic!("Missing either certificate or key. CLI argument validation should have prevented this. (╯°□°)╯︵ ┻━┻"),
+      }
     }
 
-    Ok(())
+    override_with(&mut config.listen, self.listen);
+    override_with(&mut config.backend, self.backend);
+    override_with(&mut config.default_route, self.default_route);
+
+    if !self.pool_backends.is_empty()
+      || self.pool_strategy.is_some()
+      || self.pool_check_path.is_some()
+      || self.pool_check_interval.is_some()
+      || self.pool_check_timeout.is_some()
+      || self.pool_max_failures.is_some()
+      || self.pool_ejection_time.is_some()
+    {
+      let pool = config.pool.get_or_insert_with(Default::default);
+      if !self.pool_backends.is_empty() {
+        pool.backends = self.pool_backends;
+      }
+      override_with(&mut pool.strategy, self.pool_strategy);
+      if self.pool_check_path.is_some() {
+        pool.check_path = self.pool_check_path;
+      }
+      override_with(
+        &mut pool.check_interval,
+        self.pool_check_interval.map(Into::into),
+      );
+      override_with(
+        &mut pool.check_timeout,
+        self.pool_check_timeout.map(Into::into),
+      );
+      override_with(&mut pool.max_failures, self.pool_max_failures);
+      override_with(
+        &mut pool.ejection_time,
+        self.pool_ejection_time.map(Into::into),
+      );
+    }
+
+    if let Some(listen) = self.ext_authz_listen {
+      config.ext_authz = Some(ExtAuthzConfig { listen });
+    }
+
+    if self.forward_auth || self.forward_auth_denied_status.is_some() || config.ext_authz.is_some()
+    {
+      let forward_auth = config.forward_auth.get_or_insert_with(Default::default);
+      if let Some(status) = self.forward_auth_denied_status {
+        forward_auth.denied_status = StatusCode::try_from(status)
+          .map_err(|_| anyhow!("{} is not a valid status code", status))?;
+      }
+    }
+    override_with(&mut config.web3_rpc_url, self.web3_rpc_url);
+    override_with(&mut config.address_header, self.address_header);
+    override_with(&mut config.balance_header, self.balance_header);
+    override_with(&mut config.signature_header, self.signature_header);
+    override_with(
+      &mut config.claimed_address_header,
+      self.claimed_address_header,
+    );
+    override_with(
+      &mut config.challenge,
+      self.challenge.map(String::into_bytes),
+    );
+
+    if !self.failover_urls.is_empty()
+      || self.failover_check_interval.is_some()
+      || self.failover_max_block_lag.is_some()
+      || self.failover_max_latency.is_some()
+      || self.failover_status_path.is_some()
+    {
+      let failover = config.failover.get_or_insert_with(Default::default);
+      if !self.failover_urls.is_empty() {
+        failover.urls = self.failover_urls;
+      }
+      override_with(
+        &mut failover.check_interval,
+        self.failover_check_interval.map(Into::into),
+      );
+      override_with(&mut failover.max_block_lag, self.failover_max_block_lag);
+      override_with(
+        &mut failover.max_latency,
+        self.failover_max_latency.map(Into::into),
+      );
+      if self.failover_status_path.is_some() {
+        failover.status_path = self.failover_status_path;
+      }
+    }
+
+    if self.batching_window.is_some() || self.batching_max_size.is_some() {
+      let batching = config.batching.get_or_insert_with(Default::default);
+      override_with(&mut batching.window, self.batching_window.map(Into::into));
+      override_with(&mut batching.max_size, self.batching_max_size);
+    }
+
+    if self.cache_ttl.is_some()
+      || self.cache_blocks.is_some()
+      || self.cache_block_check_interval.is_some()
+      || self.cache_capacity.is_some()
+      || self.cache_status_path.is_some()
+    {
+      let cache = config.cache.get_or_insert_with(Default::default);
+      if self.cache_ttl.is_some() {
+        cache.ttl = self.cache_ttl.map(Into::into);
+      }
+      if self.cache_blocks.is_some() {
+        cache.blocks = self.cache_blocks;
+      }
+      override_with(
+        &mut cache.block_check_interval,
+        self.cache_block_check_interval.map(Into::into),
+      );
+      override_with(&mut cache.capacity, self.cache_capacity);
+      if self.cache_status_path.is_some() {
+        cache.status_path = self.cache_status_path;
+      }
+    }
+
+    if self.requires_nonces
+      || self.nonce_header.is_some()
+      || self.nonce_challenge_path.is_some()
+      || self.nonce_lifetime.is_some()
+      || self.nonce_store_path.is_some()
+      || self.nonce_capacity.is_some()
+    {
+      let nonces = config.nonces.get_or_insert_with(Default::default);
+      override_with(&mut nonces.header, self.nonce_header);
+      override_with(&mut nonces.path, self.nonce_challenge_path);
+      override_with(&mut nonces.lifetime, self.nonce_lifetime.map(Into::into));
+      if self.nonce_store_path.is_some() {
+        nonces.store_path = self.nonce_store_path;
+      }
+      override_with(&mut nonces.capacity, self.nonce_capacity);
+    }
+
+    if self.siwe
+      || self.siwe_domain.is_some()
+      || self.siwe_uri.is_some()
+      || self.siwe_chain_id.is_some()
+      || self.siwe_session_lifetime.is_some()
+    {
+      let siwe = config.siwe.get_or_insert_with(Default::default);
+      if self.siwe_domain.is_some() {
+        siwe.domain = self.siwe_domain;
+      }
+      if self.siwe_uri.is_some() {
+        siwe.uri = self.siwe_uri;
+      }
+      override_with(&mut siwe.chain_id, self.siwe_chain_id);
+      override_with(
+        &mut siwe.session_lifetime,
+        self.siwe_session_lifetime.map(Into::into),
+      );
+    }
+
+    if !self.session_token_keys.is_empty()
+      || self.session_token_lifetime.is_some()
+      || self.session_token_audience.is_some()
+    {
+      let session_tokens = config.session_tokens.get_or_insert_with(Default::default);
+      if !self.session_token_keys.is_empty() {
+        session_tokens.keys = self.session_token_keys;
+      }
+      override_with(
+        &mut session_tokens.lifetime,
+        self.session_token_lifetime.map(Into::into),
+      );
+      if self.session_token_audience.is_some() {
+        session_tokens.audience = self.session_token_audience;
+      }
+    }
+
+    if self.provides_assertions
+      || !self.assertion_keys.is_empty()
+      || self.assertion_header.is_some()
+      || self.assertion_lifetime.is_some()
+    {
+      let assertions = config.assertions.get_or_insert_with(Default::default);
+      if !self.assertion_keys.is_empty() {
+        assertions.keys = self.assertion_keys;
+      }
+      override_with(&mut assertions.header, self.assertion_header);
+      override_with(
+        &mut assertions.lifetime,
+        self.assertion_lifetime.map(Into::into),
+      );
+    }
+
+    if self.eip712
+      || self.eip712_name.is_some()
+      || self.eip712_version.is_some()
+      || self.eip712_chain_id.is_some()
+      || self.eip712_verifying_contract.is_some()
+    {
+      let typed_data = config.typed_data.get_or_insert_with(Default::default);
+      if self.eip712_name.is_some() {
+        typed_data.name = self.eip712_name;
+      }
+      if self.eip712_version.is_some() {
+        typed_data.version = self.eip712_version;
+      }
+      if self.eip712_chain_id.is_some() {
+        typed_data.chain_id = self.eip712_chain_id;
+      }
+      if self.eip712_verifying_contract.is_some() {
+        typed_data.verifying_contract = self.eip712_verifying_contract;
+      }
+    }
+
+    override_with(&mut config.balance_scale, self.balance_scale);
+
+    if let Some(requirement) = balance_requirement_from(self.balance_minimum, self.balance_maximum)
+    {
+      config.balance_requirement = Some(requirement);
+    }
+
+    config.provides_signatures |= self.provides_signatures;
+    config.provides_account_verification |= self.provides_account_verification;
+    config.contract_wallets |= self.contract_wallets;
+    config.provides_balances |= self.provides_balances;
+    config.strip_identity_headers &= !self.keeps_identity_headers;
+    if !self.trusted_hops.is_empty() {
+      config.trusted_hops = self.trusted_hops;
+    }
+
+    if self.erc1155_contract_address.is_some()
+      || self.erc1155_balance_header.is_some()
+      || self.erc1155_token_balance_header.is_some()
+      || !self.erc1155_token_ids.is_empty()
+      || self.erc1155_balance_minimum.is_some()
+      || self.erc1155_balance_maximum.is_some()
+      || self.provides_erc1155_balance
+      || self.erc1155_index_from_block.is_some()
+      || self.erc1155_index_path.is_some()
+    {
+      let erc1155 = entry(&mut config.erc1155, |erc1155| erc1155.name == "erc1155");
+      override_with(&mut erc1155.contract_address, self.erc1155_contract_address);
+      override_with(&mut erc1155.balance_header, self.erc1155_balance_header);
+      override_with(
+        &mut erc1155.token_balance_header,
+        self.erc1155_token_balance_header,
+      );
+      if !self.erc1155_token_ids.is_empty() {
+        erc1155.token_ids = TokenIds::List(self.erc1155_token_ids);
+      }
+      if let Some(requirement) =
+        balance_requirement_from(self.erc1155_balance_minimum, self.erc1155_balance_maximum)
+      {
+        erc1155.balance_requirement = Some(requirement);
+      }
+      erc1155.provides_balances |= self.provides_erc1155_balance;
+      if self.erc1155_index_from_block.is_some() || self.erc1155_index_path.is_some() {
+        let index = erc1155.index.get_or_insert_with(Default::default);
+        override_with(&mut index.from_block, self.erc1155_index_from_block);
+        if self.erc1155_index_path.is_some() {
+          index.path = self.erc1155_index_path;
+        }
+      }
+    }
+
+    if self.erc20_contract_address.is_some()
+      || self.erc20_balance_header.is_some()
+      || self.erc20_name_header.is_some()
+      || self.erc20_symbol_header.is_some()
+      || self.erc20_balance_minimum.is_some()
+      || self.erc20_balance_maximum.is_some()
+      || self.provides_erc20_balance
+      || self.provides_erc20_name
+      || self.provides_erc20_symbol
+      || self.erc20_index_from_block.is_some()
+      || self.erc20_index_path.is_some()
+    {
+      let erc20 = entry(&mut config.erc20, |erc20| erc20.name == "erc20");
+      override_with(&mut erc20.contract_address, self.erc20_contract_address);
+      override_with(&mut erc20.balance_header, self.erc20_balance_header);
+      override_with(&mut erc20.name_header, self.erc20_name_header);
+      override_with(&mut erc20.symbol_header, self.erc20_symbol_header);
+      if let Some(requirement) =
+        balance_requirement_from(self.erc20_balance_minimum, self.erc20_balance_maximum)
+      {
+        erc20.balance_requirement = Some(requirement);
+      }
+      erc20.provides_balances |= self.provides_erc20_balance;
+      if self.erc20_index_from_block.is_some() || self.erc20_index_path.is_some() {
+        let index = erc20.index.get_or_insert_with(Default::default);
+        override_with(&mut index.from_block, self.erc20_index_from_block);
+        if self.erc20_index_path.is_some() {
+          index.path = self.erc20_index_path;
+        }
+      }
+      erc20.provides_name |= self.provides_erc20_name;
+      erc20.provides_symbol |= self.provides_erc20_symbol;
+    }
+
+    if self.erc721_contract_address.is_some()
+      || self.erc721_balance_header.is_some()
+      || self.erc721_name_header.is_some()
+      || self.erc721_symbol_header.is_some()
+      || self.erc721_token_header.is_some()
+      || !self.erc721_owned_token_ids.is_empty()
+      || self.erc721_owned_token_path.is_some()
+      || self.erc721_balance_minimum.is_some()
+      || self.erc721_balance_maximum.is_some()
+      || self.provides_erc721_balance
+      || self.provides_erc721_name
+      || self.provides_erc721_symbol
+      || self.erc721_index_from_block.is_some()
+      || self.erc721_index_path.is_some()
+    {
+      let erc721 = entry(&mut config.erc721, |erc721| erc721.name == "erc721");
+      override_with(&mut erc721.contract_address, self.erc721_contract_address);
+      override_with(&mut erc721.balance_header, self.erc721_balance_header);
+      override_with(&mut erc721.name_header, self.erc721_name_header);
+      override_with(&mut erc721.symbol_header, self.erc721_symbol_header);
+      override_with(&mut erc721.token_header, self.erc721_token_header);
+      if !self.erc721_owned_token_ids.is_empty() {
+        erc721.ownership = Some(Ownership::TokenIds(TokenIds::List(
+          self.erc721_owned_token_ids,
+        )));
+      }
+      if let Some(path) = self.erc721_owned_token_path {
+        erc721.ownership = Some(Ownership::Path(path));
+      }
+      if let Some(requirement) =
+        balance_requirement_from(self.erc721_balance_minimum, self.erc721_balance_maximum)
+      {
+        erc721.balance_requirement = Some(requirement);
+      }
+      erc721.provides_balances |= self.provides_erc721_balance;
+      if self.erc721_index_from_block.is_some() || self.erc721_index_path.is_some() {
+        let index = erc721.index.get_or_insert_with(Default::default);
+        override_with(&mut index.from_block, self.erc721_index_from_block);
+        if self.erc721_index_path.is_some() {
+          index.path = self.erc721_index_path;
+        }
+      }
+      erc721.provides_name |= self.provides_erc721_name;
+      erc721.provides_symbol |= self.provides_erc721_symbol;
+    }
+
+    if self.erc777_contract_address.is_some()
+      || self.erc777_balance_header.is_some()
+      || self.erc777_name_header.is_some()
+      || self.erc777_symbol_header.is_some()
+      || self.erc777_balance_minimum.is_some()
+      || self.erc777_balance_maximum.is_some()
+      || self.provides_erc777_balance
+      || self.provides_erc777_name
+      || self.provides_erc777_symbol
+    {
+      let erc777 = entry(&mut config.erc777, |erc777| erc777.name == "erc777");
+      override_with(&mut erc777.contract_address, self.erc777_contract_address);
+      override_with(&mut erc777.balance_header, self.erc777_balance_header);
+      override_with(&mut erc777.name_header, self.erc777_name_header);
+      override_with(&mut erc777.symbol_header, self.erc777_symbol_header);
+      if let Some(requirement) =
+        balance_requirement_from(self.erc777_balance_minimum, self.erc777_balance_maximum)
+      {
+        erc777.balance_requirement = Some(requirement);
+      }
+      erc777.provides_balances |= self.provides_erc777_balance;
+      erc777.provides_name |= self.provides_erc777_name;
+      erc777.provides_symbol |= self.provides_erc777_symbol;
+    }
+
+    Ok(config)
+  }
+}
+
+// Flags for a token standard apply to the entry with that standard's default
+// name (e.g. "erc20"), which is created if the config file doesn't have one.
+fn entry<T: Default>(entries: &mut Vec<T>, matches: impl Fn(&T) -> bool) -> &mut T {
+  match entries.iter().position(matches) {
+    Some(index) => &mut entries[index],
+    None => {
+      entries.push(T::default());
+      entries.last_mut().unwrap()
+    }
+  }
+}
+
+fn override_with<T>(value: &mut T, replacement: Option<T>) {
+  if let Some(replacement) = replacement {
+    *value = replacement;
   }
 }
 
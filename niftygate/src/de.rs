@@ -0,0 +1,320 @@
+use ethcontract::U256;
+use secp256k1::SecretKey;
+use serde::{
+  de::{self, Deserializer, Visitor},
+  Deserialize,
+};
+use std::{convert::TryFrom, fmt, str::FromStr, time::Duration};
+use tide::http::{headers::HeaderName, Method};
+
+pub fn header_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderName, D::Error> {
+  let name = String::deserialize(deserializer)?;
+  HeaderName::from_string(name).map_err(de::Error::custom)
+}
+
+pub fn methods<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Method>, D::Error> {
+  Vec::<String>::deserialize(deserializer)?
+    .iter()
+    .map(|method| Method::from_str(method).map_err(de::Error::custom))
+    .collect()
+}
+
+pub fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+  D: Deserializer<'de>,
+  T: FromStr,
+  T::Err: fmt::Display,
+{
+  let s = String::deserialize(deserializer)?;
+  T::from_str(&s).map_err(de::Error::custom)
+}
+
+pub fn from_strs<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+  D: Deserializer<'de>,
+  T: FromStr,
+  T::Err: fmt::Display,
+{
+  Vec::<String>::deserialize(deserializer)?
+    .iter()
+    .map(|s| T::from_str(s).map_err(de::Error::custom))
+    .collect()
+}
+
+pub fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+  let s = String::deserialize(deserializer)?;
+  humantime::parse_duration(&s).map_err(de::Error::custom)
+}
+
+pub fn option_duration<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+  match Option::<String>::deserialize(deserializer)? {
+    None => Ok(None),
+    Some(s) => humantime::parse_duration(&s)
+      .map(Some)
+      .map_err(de::Error::custom),
+  }
+}
+
+pub fn bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+  Ok(String::deserialize(deserializer)?.into_bytes())
+}
+
+pub fn option_bytes<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+  Ok(Option::<String>::deserialize(deserializer)?.map(String::into_bytes))
+}
+
+pub fn option_secret_key<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Option<SecretKey>, D::Error> {
+  match Option::<String>::deserialize(deserializer)? {
+    None => Ok(None),
+    Some(data) => {
+      let data = hex::decode(data).map_err(de::Error::custom)?;
+      SecretKey::from_slice(&data)
+        .map(Some)
+        .map_err(de::Error::custom)
+    }
+  }
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+  type Value = U256;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("an unsigned integer, or a string of decimal digits")
+  }
+
+  fn visit_u64<E: de::Error>(self, value: u64) -> Result<U256, E> {
+    Ok(U256::from(value))
+  }
+
+  fn visit_i64<E: de::Error>(self, value: i64) -> Result<U256, E> {
+    if value < 0 {
+      Err(E::invalid_value(de::Unexpected::Signed(value), &self))
+    } else {
+      Ok(U256::from(value))
+    }
+  }
+
+  fn visit_str<E: de::Error>(self, value: &str) -> Result<U256, E> {
+    U256::from_dec_str(value).map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+  }
+}
+
+pub struct Amount(pub U256);
+
+impl<'de> Deserialize<'de> for Amount {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(AmountVisitor).map(Self)
+  }
+}
+
+// Some formats (like TOML) can't express tuple variants as arrays, so ranges
+// are deserialized as a pair wrapped in a newtype variant instead.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceRequirement {
+  AtLeast(Amount),
+  AtMost(Amount),
+  Between((Amount, Amount)),
+}
+
+impl From<BalanceRequirement> for crate::middleware::ethereum::BalanceRequirement {
+  fn from(requirement: BalanceRequirement) -> Self {
+    match requirement {
+      BalanceRequirement::AtLeast(min) => Self::AtLeast(min.0),
+      BalanceRequirement::AtMost(max) => Self::AtMost(max.0),
+      BalanceRequirement::Between((min, max)) => Self::Between(min.0, max.0),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TokenIds {
+  Single(Amount),
+  List(Vec<Amount>),
+  Range { first: Amount, last: Amount },
+}
+
+impl From<TokenIds> for crate::middleware::ethereum::TokenIds {
+  fn from(token_ids: TokenIds) -> Self {
+    match token_ids {
+      TokenIds::Single(id) => Self::Single(id.0),
+      TokenIds::List(ids) => Self::List(ids.into_iter().map(|id| id.0).collect()),
+      TokenIds::Range { first, last } => Self::Range(first.0, last.0),
+    }
+  }
+}
+
+// TOML can only deserialize an enum from a table header when the variant's
+// content is itself a table, so policies are read as a table with exactly one
+// known key instead.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+  all: Option<Vec<crate::middleware::ethereum::Policy>>,
+  any: Option<Vec<crate::middleware::ethereum::Policy>>,
+  not: Option<Box<crate::middleware::ethereum::Policy>>,
+  balance: Option<crate::middleware::ethereum::BalancePolicy>,
+  token_balance: Option<crate::middleware::ethereum::TokenBalancePolicy>,
+}
+
+impl TryFrom<Policy> for crate::middleware::ethereum::Policy {
+  type Error = String;
+
+  fn try_from(policy: Policy) -> Result<Self, Self::Error> {
+    let mut policies = vec![];
+    policies.extend(policy.all.map(Self::All));
+    policies.extend(policy.any.map(Self::Any));
+    policies.extend(policy.not.map(Self::Not));
+    policies.extend(policy.balance.map(Self::Balance));
+    policies.extend(policy.token_balance.map(Self::TokenBalance));
+
+    match policies.len() {
+      1 => Ok(policies.remove(0)),
+      _ => Err(String::from(
+        "a policy must have exactly one of all, any, not, balance, or token_balance",
+      )),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::ethereum;
+  use serde_json::json;
+
+  #[derive(Deserialize)]
+  struct Header {
+    #[serde(deserialize_with = "header_name")]
+    header: HeaderName,
+  }
+
+  #[derive(Deserialize)]
+  struct Fields {
+    #[serde(default, deserialize_with = "methods")]
+    methods: Vec<Method>,
+    #[serde(default, deserialize_with = "duration")]
+    duration: Duration,
+    #[serde(default, deserialize_with = "option_duration")]
+    option_duration: Option<Duration>,
+    #[serde(default, deserialize_with = "option_secret_key")]
+    secret_key: Option<SecretKey>,
+    #[serde(default, deserialize_with = "from_strs")]
+    numbers: Vec<u8>,
+  }
+
+  fn fields(value: serde_json::Value) -> Result<Fields, serde_json::Error> {
+    serde_json::from_value(value)
+  }
+
+  #[test]
+  fn reads_header_names() {
+    let header = |value| serde_json::from_value::<Header>(value).map(|parsed| parsed.header);
+    assert_eq!(
+      header(json!({"header": "X-Web3-Account-Address"})).unwrap(),
+      "x-web3-account-address"
+    );
+    assert!(header(json!({"header": "Ünicode"})).is_err());
+  }
+
+  #[test]
+  fn reads_methods() {
+    let parsed = fields(json!({"methods": ["GET", "POST"]})).unwrap();
+    assert_eq!(parsed.methods, vec![Method::Get, Method::Post]);
+    assert!(fields(json!({"methods": ["FETCH"]})).is_err());
+  }
+
+  #[test]
+  fn reads_durations() {
+    let parsed = fields(json!({"duration": "1m 30s", "option_duration": "2h"})).unwrap();
+    assert_eq!(parsed.duration, Duration::from_secs(90));
+    assert_eq!(parsed.option_duration, Some(Duration::from_secs(7200)));
+    assert_eq!(fields(json!({})).unwrap().option_duration, None);
+    assert!(fields(json!({"duration": "soon"})).is_err());
+  }
+
+  #[test]
+  fn reads_secret_keys() {
+    let parsed = fields(json!({"secret_key": "01".repeat(32)})).unwrap();
+    assert_eq!(
+      parsed.secret_key,
+      Some(SecretKey::from_slice(&[1; 32]).unwrap())
+    );
+    assert!(fields(json!({"secret_key": "01"})).is_err());
+    assert!(fields(json!({"secret_key": "zz".repeat(32)})).is_err());
+  }
+
+  #[test]
+  fn reads_lists_of_strings() {
+    assert_eq!(
+      fields(json!({"numbers": ["1", "2"]})).unwrap().numbers,
+      vec![1, 2]
+    );
+    assert!(fields(json!({"numbers": ["one"]})).is_err());
+  }
+
+  #[test]
+  fn reads_amounts() {
+    let amount = |value| serde_json::from_value::<Amount>(value).map(|amount| amount.0);
+    assert_eq!(amount(json!(42)).unwrap(), U256::from(42));
+    assert_eq!(
+      amount(json!("1000000000000000000000")).unwrap(),
+      U256::exp10(21)
+    );
+    assert!(amount(json!(-1)).is_err());
+    assert!(amount(json!("1e18")).is_err());
+  }
+
+  #[test]
+  fn reads_balance_requirements() {
+    #[derive(Deserialize)]
+    struct Requirement {
+      balance: BalanceRequirement,
+    }
+    let requirement = |toml: &str| {
+      toml::from_str::<Requirement>(toml)
+        .map(|parsed| format!("{:?}", ethereum::BalanceRequirement::from(parsed.balance)))
+    };
+    assert_eq!(
+      requirement("balance = { at_least = 5 }").unwrap(),
+      "AtLeast(5)"
+    );
+    assert_eq!(
+      requirement("balance = { at_most = \"7\" }").unwrap(),
+      "AtMost(7)"
+    );
+    assert_eq!(
+      requirement("balance = { between = [1, 9] }").unwrap(),
+      "Between(1, 9)"
+    );
+    assert!(requirement("balance = { exactly = 5 }").is_err());
+  }
+
+  #[test]
+  fn reads_token_ids() {
+    #[derive(Deserialize)]
+    struct Ids {
+      token_ids: ethereum::TokenIds,
+    }
+    let ids = |toml: &str| {
+      toml::from_str::<Ids>(toml)
+        .unwrap()
+        .token_ids
+        .iter()
+        .map(|id| id.as_u64())
+        .collect::<Vec<u64>>()
+    };
+    assert_eq!(ids("token_ids = 7"), vec![7]);
+    assert_eq!(ids("token_ids = [7, \"9\"]"), vec![7, 9]);
+    assert_eq!(ids("token_ids = { first = 5, last = 7 }"), vec![5, 6, 7]);
+  }
+}
@@ -4,6 +4,10 @@ pub mod application;
 pub mod command;
 pub mod middleware;
 
+mod de;
+#[cfg(test)]
+mod mock_rpc;
+
 pub use command::Command;
 pub use niftygate_bindings::openzeppelin;
 
@@ -21,21 +25,6 @@ pub type WrappedError = Box<dyn std::error::Error>;
 #[deprecated(since = "0.7.1", note = "use anyhow::Result instead")]
 pub type WrappedResult<T> = std::result::Result<T, WrappedError>;
 
-mod util {
-  use ethcontract::{
-    dyns::DynWeb3,
-    transport::DynTransport,
-    web3::{error::Result, transports::WebSocket},
-    Web3,
-  };
-  use tide::http::Url;
-
-  pub async fn web3_from_url(url: Url) -> Result<DynWeb3> {
-    Ok(Web3::new(DynTransport::new(
-      WebSocket::new(url.as_str()).await?,
-    )))
-  }
-}
 #[derive(Debug)]
 pub struct HexData(Vec<u8>);
 
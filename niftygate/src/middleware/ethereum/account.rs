@@ -12,27 +12,161 @@ pub mod prelude {
 
 use prelude::*;
 
-use std::result;
+use super::{
+  nonce::{self, Nonces},
+  typed_data::TypedChallenge,
+};
+use crate::openzeppelin::contracts::interfaces::IERC1271;
+use ethcontract::{
+  errors::{ExecutionError, MethodError},
+  tokens::Bytes,
+  web3::{
+    signing::hash_message,
+    types::{RecoveryMessage, H256},
+  },
+};
+use std::{
+  collections::HashMap,
+  iter, result,
+  sync::{Arc, RwLock},
+  time::{Duration, Instant},
+};
 use tide::{utils::async_trait, Middleware, Next, Request, Response, Result};
 
+// Set by middleware that has already verified the account by other means (like
+// a session), so the signature check can be skipped.
+#[derive(Clone, Copy, Debug)]
+pub struct VerifiedAccount(pub Address);
+
 #[derive(Clone)]
 pub struct ProvidesAccountVerification {
   pub address_header: HeaderName,
   pub challenge: Vec<u8>,
+  // Other chains to look for contract wallets on, besides the one web3 is for.
+  pub chains: Vec<DynWeb3>,
+  pub claimed_address_header: Option<HeaderName>,
+  pub code_cache: Option<CodeCache>,
+  pub nonce_header: HeaderName,
+  pub nonces: Option<Nonces>,
   pub signature_header: HeaderName,
   pub status_code: StatusCode,
+  pub typed_data: Option<TypedChallenge>,
   pub web3: DynWeb3,
 }
 
+// Whether claimed addresses have code on each chain (by position, with web3
+// first), so requests claiming a contract wallet don't each cost an eth_getCode
+// call per chain. Wallets can be deployed at any time, so answers are only kept
+// for ttl. When full, expired answers make room, and new ones aren't kept until
+// some have.
+#[derive(Clone)]
+pub struct CodeCache {
+  entries: Arc<RwLock<HashMap<(usize, Address), CodeEntry>>>,
+  ttl: Duration,
+  capacity: usize,
+}
+
+// Whether the address has code, and when that was looked up.
+type CodeEntry = (bool, Instant);
+
+impl CodeCache {
+  pub fn new(ttl: Duration, capacity: usize) -> Self {
+    Self {
+      entries: Arc::new(RwLock::new(HashMap::new())),
+      ttl,
+      capacity,
+    }
+  }
+
+  fn get(&self, chain: usize, address: Address) -> Option<bool> {
+    self
+      .entries
+      .read()
+      .unwrap()
+      .get(&(chain, address))
+      .filter(|(_, cached_at)| cached_at.elapsed() < self.ttl)
+      .map(|(deployed, _)| *deployed)
+  }
+
+  fn insert(&self, chain: usize, address: Address, deployed: bool) {
+    let mut entries = self.entries.write().unwrap();
+    if entries.len() >= self.capacity && !entries.contains_key(&(chain, address)) {
+      entries.retain(|_, (_, cached_at)| cached_at.elapsed() < self.ttl);
+      if entries.len() >= self.capacity {
+        return;
+      }
+    }
+    entries.insert((chain, address), (deployed, Instant::now()));
+  }
+}
+
+impl Default for CodeCache {
+  fn default() -> Self {
+    Self::new(Duration::from_secs(60), 10_000)
+  }
+}
+
+const EIP1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+// Contract wallets can't produce a recoverable signature, so they're asked
+// whether any of the signatures is valid instead (per EIP-1271).
+async fn is_valid_signature(
+  web3: &DynWeb3,
+  address: Address,
+  hash: H256,
+  raw_signatures: Vec<Vec<u8>>,
+) -> anyhow::Result<bool> {
+  let contract = IERC1271::at(web3, address);
+  for raw_signature in raw_signatures {
+    match contract
+      .is_valid_signature(Bytes(hash.0), Bytes(raw_signature))
+      .call()
+      .await
+    {
+      Ok(Bytes(magic_value)) if magic_value == EIP1271_MAGIC_VALUE => return Ok(true),
+      Ok(_)
+      | Err(MethodError {
+        inner: ExecutionError::Revert(_),
+        ..
+      }) => continue,
+      Err(e) => return Err(e.into()),
+    }
+  }
+  Ok(false)
+}
+
 #[async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesAccountVerification {
   async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    if request.ext::<VerifiedAccount>().is_some() {
+      return Ok(next.run(request).await);
+    }
+
     let mut addresses: Vec<Address> = vec![];
     for header in request.header_names() {
       tide::log::debug!("Header: {:?}", &header);
     }
 
-    match request.header(&self.signature_header) {
+    let nonce = match &self.nonces {
+      None => None,
+      Some(_) => match request.header(&self.nonce_header) {
+        None => {
+          tide::log::debug!("Header ({:?}): Missing", &self.nonce_header);
+          return Ok(Response::new(self.status_code));
+        }
+        Some(header_values) => Some(header_values.last().as_str().to_string()),
+      },
+    };
+
+    let challenge = match (&self.typed_data, &nonce) {
+      (Some(typed_data), _) => {
+        RecoveryMessage::Hash(typed_data.hash(&self.challenge, nonce.as_deref()))
+      }
+      (None, None) => RecoveryMessage::Data(self.challenge.clone()),
+      (None, Some(nonce)) => RecoveryMessage::Data(nonce::message(&self.challenge, nonce)),
+    };
+
+    let raw_signatures = match request.header(&self.signature_header) {
       None => {
         tide::log::debug!("Header ({:?}): Missing", &self.signature_header);
         return Ok(Response::new(self.status_code));
@@ -52,42 +186,255 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesAccount
             &self.signature_header,
             raw_signatures.clone()
           );
-          match raw_signatures
-            .into_iter()
-            .map(|raw_signature| {
-              Recovery::from_raw_signature(self.challenge.clone(), raw_signature)
-            })
-            .collect::<result::Result<Vec<Recovery>, _>>()
-          {
+          raw_signatures
+        }
+      },
+    };
+
+    let claimed = match self
+      .claimed_address_header
+      .as_ref()
+      .and_then(|header| request.header(header))
+    {
+      None => None,
+      Some(header_values) => {
+        match hex::decode(header_values.last().as_str().trim_start_matches("0x")) {
+          Ok(raw_address) if raw_address.len() == 20 => Some(Address::from_slice(&raw_address)),
+          _ => return Ok(Response::new(StatusCode::BadRequest)),
+        }
+      }
+    };
+
+    // A contract wallet may only be deployed on some of the chains, and is
+    // accepted if any of those considers the signature valid.
+    let mut deployed_on = vec![];
+    if let Some(address) = claimed {
+      for (chain, web3) in iter::once(&self.web3).chain(&self.chains).enumerate() {
+        let cached = self
+          .code_cache
+          .as_ref()
+          .and_then(|cache| cache.get(chain, address));
+        let deployed = match cached {
+          Some(deployed) => deployed,
+          None => match web3.eth().code(address, None).await {
             Err(e) => {
-              tide::log::debug!("Header ({:?}): Invalid Signature", &self.signature_header);
               tide::log::error!("{:?}", &e);
-              return Ok(Response::new(StatusCode::UnsupportedMediaType));
+              return Ok(Response::new(StatusCode::InternalServerError));
             }
-            Ok(recovery_messages) => match recovery_messages
-              .into_iter()
-              .map(|recovery| self.web3.accounts().recover(recovery))
-              .collect::<result::Result<Vec<Address>, _>>()
-            {
-              Err(e) => {
-                tide::log::error!("{:?}", &e);
-                return Ok(Response::new(StatusCode::UnprocessableEntity));
+            Ok(code) => {
+              if let Some(cache) = &self.code_cache {
+                cache.insert(chain, address, !code.0.is_empty());
               }
-              Ok(recovered) => {
-                for address in recovered {
-                  addresses.push(address)
-                }
-              }
-            },
+              !code.0.is_empty()
+            }
+          },
+        };
+        if deployed {
+          deployed_on.push(web3);
+        }
+      }
+    }
+
+    match claimed.filter(|_| !deployed_on.is_empty()) {
+      Some(address) => {
+        let hash = match &challenge {
+          RecoveryMessage::Data(data) => hash_message(data),
+          RecoveryMessage::Hash(hash) => *hash,
+        };
+        let mut valid = false;
+        for web3 in deployed_on {
+          match is_valid_signature(web3, address, hash, raw_signatures.clone()).await {
+            Err(e) => {
+              tide::log::error!("{:?}", &e);
+              return Ok(Response::new(StatusCode::InternalServerError));
+            }
+            Ok(false) => continue,
+            Ok(true) => {
+              valid = true;
+              break;
+            }
           }
         }
+        if !valid {
+          tide::log::debug!("EIP-1271 ({:?}): Invalid Signature", &address);
+          return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        addresses.push(address);
+      }
+      None => match raw_signatures
+        .into_iter()
+        .map(|raw_signature| Recovery::from_raw_signature(challenge.clone(), raw_signature))
+        .collect::<result::Result<Vec<Recovery>, _>>()
+      {
+        Err(e) => {
+          tide::log::debug!("Header ({:?}): Invalid Signature", &self.signature_header);
+          tide::log::error!("{:?}", &e);
+          return Ok(Response::new(StatusCode::UnsupportedMediaType));
+        }
+        Ok(recovery_messages) => match recovery_messages
+          .into_iter()
+          .map(|recovery| self.web3.accounts().recover(recovery))
+          .collect::<result::Result<Vec<Address>, _>>()
+        {
+          Err(e) => {
+            tide::log::error!("{:?}", &e);
+            return Ok(Response::new(StatusCode::UnprocessableEntity));
+          }
+          Ok(recovered) => {
+            for address in recovered {
+              addresses.push(address)
+            }
+          }
+        },
       },
     }
 
-    for address in addresses {
+    if let (Some(nonces), Some(nonce)) = (&self.nonces, &nonce) {
+      match nonces.redeem(nonce).await {
+        Err(e) => {
+          tide::log::error!("{:?}", &e);
+          return Ok(Response::new(StatusCode::InternalServerError));
+        }
+        Ok(false) => {
+          tide::log::debug!(
+            "Header ({:?}): Unknown, Used, or Expired",
+            &self.nonce_header
+          );
+          return Ok(Response::new(StatusCode::Unauthorized));
+        }
+        Ok(true) => (),
+      }
+    }
+
+    for address in &addresses {
       request.append_header(&self.address_header, hex::encode(address))
     }
 
+    // Later middleware (like session tokens) reads the last address header, so
+    // the last address is the one that was verified.
+    if let Some(address) = addresses.last() {
+      request.set_ext(VerifiedAccount(*address));
+    }
+
     Ok(next.run(request).await)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mock_rpc::{self, MockRpc};
+  use ethcontract::transport::DynTransport;
+  use niftygate_contract::transport::Http;
+  use serde_json::{json, Value};
+  use tide::http::{self, Method};
+
+  const WALLET: &str = "0x000000000000000000000000000000000000c0de";
+
+  // A node with a contract wallet at WALLET, answering isValidSignature calls
+  // with the given result.
+  fn node(result: result::Result<Value, Value>) -> MockRpc {
+    mock_rpc::serve(Duration::default(), move |method, _| match method {
+      "eth_getCode" => Ok(json!("0x6000")),
+      "eth_call" => result.clone(),
+      _ => Err(json!({"code": -32601, "message": "method not found"})),
+    })
+  }
+
+  fn server(node: &MockRpc) -> tide::Server<()> {
+    let mut server = tide::new();
+    server.with(ProvidesAccountVerification {
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      challenge: b"challenge".to_vec(),
+      chains: vec![],
+      claimed_address_header: Some(HeaderName::from("X-Web3-Claimed-Address")),
+      code_cache: Some(CodeCache::default()),
+      nonce_header: HeaderName::from("X-Web3-Nonce"),
+      nonces: None,
+      signature_header: HeaderName::from("X-Web3-Signature"),
+      status_code: StatusCode::PaymentRequired,
+      typed_data: None,
+      web3: Web3::new(DynTransport::new(Http::new(node.url.clone()))),
+    });
+    server.at("/").get(|request: Request<()>| async move {
+      Ok(
+        request
+          .header("X-Web3-Account-Address")
+          .map(|values| values.last().to_string())
+          .unwrap_or_default(),
+      )
+    });
+    server
+  }
+
+  async fn claim(server: &tide::Server<()>) -> http::Response {
+    let mut request = http::Request::new(Method::Get, Url::parse("http://test/").unwrap());
+    request.insert_header("X-Web3-Claimed-Address", WALLET);
+    request.insert_header("X-Web3-Signature", base64::encode([1; 65]));
+    server.respond(request).await.unwrap()
+  }
+
+  // bytes4 is returned left-aligned in its word.
+  fn bytes4(value: [u8; 4]) -> Value {
+    json!(format!("0x{:0<64}", hex::encode(value)))
+  }
+
+  #[async_std::test]
+  async fn accepts_the_magic_value() {
+    let node = node(Ok(bytes4(EIP1271_MAGIC_VALUE)));
+    let mut response = claim(&server(&node)).await;
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(
+      response.body_string().await.unwrap(),
+      WALLET.trim_start_matches("0x")
+    );
+  }
+
+  #[async_std::test]
+  async fn rejects_other_values() {
+    let node = node(Ok(bytes4([0xff; 4])));
+    assert_eq!(
+      claim(&server(&node)).await.status(),
+      StatusCode::Unauthorized
+    );
+  }
+
+  #[async_std::test]
+  async fn rejects_signatures_that_revert() {
+    let node = node(Err(
+      json!({"code": 3, "message": "execution reverted", "data": "0x"}),
+    ));
+    assert_eq!(
+      claim(&server(&node)).await.status(),
+      StatusCode::Unauthorized
+    );
+  }
+
+  #[async_std::test]
+  async fn fails_when_the_node_does() {
+    let node = node(Err(json!({"code": -32000, "message": "header not found"})));
+    assert_eq!(
+      claim(&server(&node)).await.status(),
+      StatusCode::InternalServerError
+    );
+  }
+
+  #[async_std::test]
+  async fn caches_code_lookups() {
+    let node = node(Ok(bytes4(EIP1271_MAGIC_VALUE)));
+    let server = server(&node);
+    for _ in 0..3 {
+      assert_eq!(claim(&server).await.status(), StatusCode::Ok);
+    }
+    let methods = node.log.lock().unwrap().methods.clone();
+    let lookups = methods.iter().filter(|method| *method == "eth_getCode");
+    assert_eq!(lookups.count(), 1);
+    assert_eq!(
+      methods
+        .iter()
+        .filter(|method| *method == "eth_call")
+        .count(),
+      3
+    );
+  }
+}
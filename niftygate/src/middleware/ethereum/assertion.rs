@@ -0,0 +1,369 @@
+use super::session::SessionKeys;
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+  collections::BTreeMap,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tide::{
+  http::{self, headers::HeaderName},
+  log,
+  utils::async_trait,
+  Middleware, Next, Request, Response, Result, StatusCode,
+};
+
+// RFC 8812, ECDSA over secp256k1 with SHA-256.
+pub const ES256K: &str = "ES256K";
+
+// Assertions can be signed with the same keys as session tokens, so they have
+// an audience (aud) of their own, which session tokens never have.
+pub const ASSERTION_AUDIENCE: &str = "niftygate:assertion";
+
+// Same as jsonwebtoken's default, so both kinds of assertion expire alike.
+const LEEWAY: u64 = 60;
+
+// What the proxy vouches for: the verified address (as it appears in the
+// address header), the request it was verified for, and the headers provided
+// along with it (like balances).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IdentityAssertion {
+  pub sub: String,
+  pub aud: String,
+  pub iat: u64,
+  pub exp: u64,
+  pub method: String,
+  pub path: String,
+  pub headers: BTreeMap<String, Vec<String>>,
+}
+
+impl IdentityAssertion {
+  // When proxied, the path is the one the backend is sent, after any
+  // strip_prefix (or add_prefix).
+  pub fn is_for(&self, method: &str, path: &str) -> bool {
+    self.method.eq_ignore_ascii_case(method) && self.path == path
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JoseHeader {
+  alg: String,
+  typ: String,
+}
+
+// Assertions are compact JWS tokens, signed with shared secrets (HS256), or
+// with the proxy's secret key (ES256K), so backends only need its public key.
+#[derive(Clone)]
+pub enum AssertionSigner {
+  Hmac(SessionKeys),
+  Secp256k1(SecretKey),
+}
+
+impl AssertionSigner {
+  pub fn algorithm(&self) -> &'static str {
+    match self {
+      Self::Hmac(_) => "HS256",
+      Self::Secp256k1(_) => ES256K,
+    }
+  }
+
+  pub fn sign(&self, assertion: &IdentityAssertion) -> anyhow::Result<String> {
+    match self {
+      Self::Hmac(keys) => keys.sign(assertion),
+      Self::Secp256k1(secret_key) => {
+        let header = JoseHeader {
+          alg: String::from(ES256K),
+          typ: String::from("JWT"),
+        };
+        let input = format!(
+          "{}.{}",
+          encode(&serde_json::to_vec(&header)?),
+          encode(&serde_json::to_vec(assertion)?)
+        );
+        let signature = Secp256k1::signing_only().sign_ecdsa(&digest(&input)?, secret_key);
+        Ok(format!(
+          "{}.{}",
+          input,
+          encode(&signature.serialize_compact())
+        ))
+      }
+    }
+  }
+
+  pub fn verifier(&self) -> AssertionVerifier {
+    match self {
+      Self::Hmac(keys) => AssertionVerifier::Hmac(keys.clone()),
+      Self::Secp256k1(secret_key) => AssertionVerifier::Secp256k1(PublicKey::from_secret_key(
+        &Secp256k1::signing_only(),
+        secret_key,
+      )),
+    }
+  }
+}
+
+// For backends (written in Rust) to check the assertions they're given.
+#[derive(Clone)]
+pub enum AssertionVerifier {
+  Hmac(SessionKeys),
+  Secp256k1(PublicKey),
+}
+
+impl AssertionVerifier {
+  pub fn verify(&self, token: &str) -> anyhow::Result<IdentityAssertion> {
+    let public_key = match self {
+      Self::Hmac(keys) => return keys.verify(token, ASSERTION_AUDIENCE),
+      Self::Secp256k1(public_key) => public_key,
+    };
+
+    let (input, signature) = token
+      .rsplit_once('.')
+      .ok_or_else(|| anyhow::anyhow!("malformed assertion"))?;
+    let (header, payload) = input
+      .split_once('.')
+      .ok_or_else(|| anyhow::anyhow!("malformed assertion"))?;
+
+    let header: JoseHeader = serde_json::from_slice(&decode(header)?)?;
+    if header.alg != ES256K {
+      anyhow::bail!("expected an {} assertion, not {}", ES256K, header.alg);
+    }
+
+    let signature = Signature::from_compact(&decode(signature)?)?;
+    Secp256k1::verification_only().verify_ecdsa(&digest(input)?, &signature, public_key)?;
+
+    let assertion: IdentityAssertion = serde_json::from_slice(&decode(payload)?)?;
+    if assertion.aud != ASSERTION_AUDIENCE {
+      anyhow::bail!("expected an assertion, not a token for {:?}", assertion.aud);
+    }
+    if assertion.exp + LEEWAY < now().as_secs() {
+      anyhow::bail!("assertion has expired");
+    }
+    Ok(assertion)
+  }
+}
+
+fn encode(data: &[u8]) -> String {
+  base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> anyhow::Result<Vec<u8>> {
+  Ok(base64::decode_config(data, base64::URL_SAFE_NO_PAD)?)
+}
+
+fn digest(input: &str) -> anyhow::Result<Message> {
+  Ok(Message::from_slice(&Sha256::digest(input.as_bytes()))?)
+}
+
+fn now() -> Duration {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+}
+
+// Signs the verified address (and the headers provided with it) for the
+// backend, so it doesn't have to trust plain headers. The proxy signs requests
+// itself, once it knows the path the backend will be sent, so this is only
+// used as middleware when requests are answered here (like with forward-auth).
+#[derive(Clone)]
+pub struct ProvidesIdentityAssertion {
+  pub address_header: HeaderName,
+  pub assertion_header: HeaderName,
+  pub claim_headers: Vec<HeaderName>,
+  pub lifetime: Duration,
+  pub signer: AssertionSigner,
+}
+
+impl ProvidesIdentityAssertion {
+  pub fn assert(&self, request: &mut http::Request) -> anyhow::Result<()> {
+    request.remove_header(&self.assertion_header);
+
+    let address = match request.header(&self.address_header) {
+      None => return Ok(()),
+      Some(values) => values.last().as_str().to_string(),
+    };
+
+    let mut headers = BTreeMap::new();
+    for name in &self.claim_headers {
+      if let Some(values) = request.header(name) {
+        headers.insert(
+          name.to_string(),
+          values.iter().map(|value| value.to_string()).collect(),
+        );
+      }
+    }
+
+    let now = now();
+    let assertion = IdentityAssertion {
+      sub: address,
+      aud: String::from(ASSERTION_AUDIENCE),
+      iat: now.as_secs(),
+      exp: (now + self.lifetime).as_secs(),
+      method: request.method().to_string(),
+      path: request.url().path().to_string(),
+      headers,
+    };
+
+    request.insert_header(&self.assertion_header, self.signer.sign(&assertion)?);
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesIdentityAssertion {
+  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    if let Err(e) = self.assert(request.as_mut()) {
+      log::error!("{:?}", &e);
+      return Ok(Response::new(StatusCode::InternalServerError));
+    }
+
+    Ok(next.run(request).await)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::ethereum::{SessionClaims, SessionKey};
+  use tide::http::{Method, Url};
+
+  fn hmac() -> AssertionSigner {
+    AssertionSigner::Hmac(SessionKeys(vec![SessionKey {
+      id: String::from("test"),
+      secret: b"a-secret-that-is-at-least-32-bytes-long".to_vec(),
+    }]))
+  }
+
+  fn secp256k1() -> AssertionSigner {
+    AssertionSigner::Secp256k1(SecretKey::from_slice(&[1; 32]).unwrap())
+  }
+
+  fn assertion(exp: u64) -> IdentityAssertion {
+    IdentityAssertion {
+      sub: String::from("0000000000000000000000000000000000000001"),
+      aud: String::from(ASSERTION_AUDIENCE),
+      iat: now().as_secs(),
+      exp,
+      method: String::from("GET"),
+      path: String::from("/things"),
+      headers: BTreeMap::new(),
+    }
+  }
+
+  #[test]
+  fn verifies_what_it_signs() {
+    let assertion = assertion(now().as_secs() + 60);
+    for signer in [hmac(), secp256k1()] {
+      let token = signer.sign(&assertion).unwrap();
+      assert_eq!(signer.verifier().verify(&token).unwrap(), assertion);
+    }
+  }
+
+  #[test]
+  fn rejects_expired_assertions() {
+    let assertion = assertion(now().as_secs() - LEEWAY - 60);
+    for signer in [hmac(), secp256k1()] {
+      let token = signer.sign(&assertion).unwrap();
+      assert!(signer.verifier().verify(&token).is_err());
+    }
+  }
+
+  #[test]
+  fn rejects_tampered_assertions() {
+    let token = secp256k1().sign(&assertion(now().as_secs() + 60)).unwrap();
+    let (input, signature) = token.rsplit_once('.').unwrap();
+    let (header, _) = input.split_once('.').unwrap();
+    let mut forged = assertion(now().as_secs() + 60);
+    forged.sub = String::from("0000000000000000000000000000000000000002");
+    let forged = format!(
+      "{}.{}.{}",
+      header,
+      encode(&serde_json::to_vec(&forged).unwrap()),
+      signature
+    );
+    assert!(secp256k1().verifier().verify(&forged).is_err());
+  }
+
+  #[test]
+  fn rejects_assertions_from_other_keys() {
+    let token = secp256k1().sign(&assertion(now().as_secs() + 60)).unwrap();
+    let other = AssertionSigner::Secp256k1(SecretKey::from_slice(&[2; 32]).unwrap());
+    assert!(other.verifier().verify(&token).is_err());
+    assert!(hmac().verifier().verify(&token).is_err());
+  }
+
+  #[test]
+  fn rejects_assertions_for_other_audiences() {
+    let mut assertion = assertion(now().as_secs() + 60);
+    assertion.aud = String::from("http://localhost/");
+    for signer in [hmac(), secp256k1()] {
+      let token = signer.sign(&assertion).unwrap();
+      assert!(signer.verifier().verify(&token).is_err());
+    }
+  }
+
+  #[test]
+  fn keeps_assertions_and_session_tokens_apart() {
+    let keys = match hmac() {
+      AssertionSigner::Hmac(keys) => keys,
+      _ => unreachable!(),
+    };
+
+    let assertion = hmac().sign(&assertion(now().as_secs() + 60)).unwrap();
+    assert!(keys
+      .verify::<SessionClaims>(&assertion, "http://localhost/")
+      .is_err());
+
+    let claims = SessionClaims {
+      sub: String::from("0000000000000000000000000000000000000001"),
+      aud: String::from("http://localhost/"),
+      iat: now().as_secs(),
+      exp: now().as_secs() + 60,
+      headers: BTreeMap::new(),
+    };
+    let token = keys.sign(&claims).unwrap();
+    assert!(hmac().verifier().verify(&token).is_err());
+  }
+
+  #[test]
+  fn asserts_verified_addresses() {
+    let provider = ProvidesIdentityAssertion {
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      assertion_header: HeaderName::from("X-Web3-Assertion"),
+      claim_headers: vec![HeaderName::from("X-Web3-Account-Balance")],
+      lifetime: Duration::from_secs(60),
+      signer: secp256k1(),
+    };
+
+    let mut request =
+      http::Request::new(Method::Post, Url::parse("http://localhost/things").unwrap());
+    request.insert_header("X-Web3-Assertion", "forged");
+    request.insert_header("X-Web3-Account-Address", "01");
+    request.insert_header("X-Web3-Account-Balance", "42");
+    provider.assert(&mut request).unwrap();
+
+    let token = request.header("X-Web3-Assertion").unwrap().last().as_str();
+    let assertion = provider.signer.verifier().verify(token).unwrap();
+    assert_eq!(assertion.sub, "01");
+    assert!(assertion.is_for("post", "/things"));
+    assert!(!assertion.is_for("GET", "/things"));
+    assert_eq!(
+      assertion.headers["x-web3-account-balance"],
+      vec![String::from("42")]
+    );
+  }
+
+  #[test]
+  fn removes_assertions_without_an_address() {
+    let provider = ProvidesIdentityAssertion {
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      assertion_header: HeaderName::from("X-Web3-Assertion"),
+      claim_headers: vec![],
+      lifetime: Duration::from_secs(60),
+      signer: hmac(),
+    };
+
+    let mut request = http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+    request.insert_header("X-Web3-Assertion", "forged");
+    provider.assert(&mut request).unwrap();
+    assert!(request.header("X-Web3-Assertion").is_none());
+  }
+}
@@ -15,11 +15,17 @@ pub mod prelude {
 
 use prelude::*;
 
+use super::{
+  lookup::{provide, Lookup, Provided},
+  BalanceCache, BalanceKey,
+};
+use futures::future::try_join_all;
+use serde::Deserialize;
 use std::{result, str::FromStr};
 use strum::{AsRefStr, EnumString, EnumVariantNames};
-use tide::{utils::async_trait, Middleware, Next, Request, Response, Result};
+use tide::{log, utils::async_trait, Middleware, Next, Request, Response, Result};
 
-#[derive(AsRefStr, Clone, Copy, Debug, EnumString, EnumVariantNames)]
+#[derive(AsRefStr, Clone, Copy, Debug, Deserialize, EnumString, EnumVariantNames)]
 pub enum BalanceScale {
   Wei,
   Kwei,
@@ -50,31 +56,59 @@ impl BalanceScale {
   }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "crate::de::BalanceRequirement")]
 pub enum BalanceRequirement {
   AtLeast(U256),
   AtMost(U256),
   Between(U256, U256),
 }
 
+impl BalanceRequirement {
+  pub fn is_met_by(&self, balance: &U256) -> bool {
+    match self {
+      BalanceRequirement::AtLeast(min) => balance.ge(min),
+      BalanceRequirement::AtMost(max) => balance.le(max),
+      BalanceRequirement::Between(min, max) => balance.ge(min) && balance.le(max),
+    }
+  }
+
+  pub fn scale(self, unit: BalanceScale) -> Self {
+    let scale = unit.scale();
+    match self {
+      BalanceRequirement::AtLeast(min) => {
+        BalanceRequirement::AtLeast(U256::saturating_mul(min, scale))
+      }
+      BalanceRequirement::AtMost(max) => {
+        BalanceRequirement::AtMost(U256::saturating_mul(max, scale))
+      }
+      BalanceRequirement::Between(min, max) => BalanceRequirement::Between(
+        U256::saturating_mul(min, scale),
+        U256::saturating_mul(max, scale),
+      ),
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct ProvidesBalance {
   pub address_header: HeaderName,
   pub balance_header: HeaderName,
+  pub cache: Option<BalanceCache>,
   pub web3: DynWeb3,
 }
 
 #[async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesBalance {
-  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+impl<State: Clone + Send + Sync + 'static> Lookup<State> for ProvidesBalance {
+  async fn lookup(&self, request: &Request<State>) -> result::Result<Provided, StatusCode> {
     let addresses = match request.header(&self.address_header) {
-      None => return Ok(Response::new(StatusCode::NetworkAuthenticationRequired)),
+      None => return Err(StatusCode::NetworkAuthenticationRequired),
       Some(header_values) => match header_values
         .into_iter()
         .map(|input| hex::decode(input.as_str()))
         .collect::<result::Result<Vec<Vec<u8>>, hex::FromHexError>>()
       {
-        Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+        Err(_) => return Err(StatusCode::BadRequest),
         Ok(raw_addresses) => raw_addresses
           .into_iter()
           .map(|src| Address::from_slice(&src))
@@ -82,23 +116,76 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesBalance
       },
     };
 
-    let block = match self.web3.eth().block_number().await {
-      Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-      Ok(block) => BlockNumber::Number(block),
+    let head = match &self.cache {
+      None => None,
+      Some(cache) => match cache.head(&self.web3).await {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(head) => head,
+      },
     };
 
-    for address in addresses {
-      match self.web3.eth().balance(address, Some(block)).await {
-        // match self.web3.eth().balance(address, None).await {
-        Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-        Ok(balance) => match HeaderValue::from_str(&balance.to_string()) {
-          Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-          Ok(value) => request.append_header(&self.balance_header, value),
-        },
+    let keys: Vec<BalanceKey> = addresses
+      .into_iter()
+      .map(|address| BalanceKey {
+        contract: None,
+        address,
+        token_id: None,
+      })
+      .collect();
+
+    let cached: Vec<Option<U256>> = keys
+      .iter()
+      .map(|key| self.cache.as_ref().and_then(|cache| cache.get(key, head)))
+      .collect();
+
+    // Only looked up when a balance isn't cached, so that the rest are all
+    // read at the same block.
+    let block = match (head, cached.iter().any(Option::is_none)) {
+      (Some(head), _) => Some(BlockNumber::Number(head.into())),
+      (None, true) => match self.web3.eth().block_number().await {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(number) => Some(BlockNumber::Number(number)),
+      },
+      (None, false) => None,
+    };
+
+    let balances = try_join_all(
+      keys
+        .into_iter()
+        .zip(cached)
+        .map(|(key, cached)| async move {
+          if let Some(balance) = cached {
+            return Ok(balance);
+          }
+          match self.web3.eth().balance(key.address, block).await {
+            Err(_) => Err(StatusCode::InternalServerError),
+            Ok(balance) => {
+              if let Some(cache) = &self.cache {
+                cache.insert(key, balance, head);
+              }
+              Ok(balance)
+            }
+          }
+        }),
+    )
+    .await?;
+
+    let mut provided = vec![];
+    for balance in balances {
+      match HeaderValue::from_str(&balance.to_string()) {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(value) => provided.push((self.balance_header.clone(), value)),
       }
     }
 
-    Ok(next.run(request).await)
+    Ok(provided)
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesBalance {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    provide(self, request, next).await
   }
 }
 
@@ -110,21 +197,7 @@ pub struct RequiresBalance {
 
 impl RequiresBalance {
   pub fn scale(mut self, unit: BalanceScale) -> Self {
-    let scale = unit.scale();
-    let new = match self.requirement {
-      BalanceRequirement::AtLeast(min) => {
-        BalanceRequirement::AtLeast(U256::saturating_mul(min, scale))
-      }
-      BalanceRequirement::AtMost(max) => {
-        BalanceRequirement::AtMost(U256::saturating_mul(max, scale))
-      }
-      BalanceRequirement::Between(min, max) => BalanceRequirement::Between(
-        U256::saturating_mul(min, scale),
-        U256::saturating_mul(max, scale),
-      ),
-    };
-
-    self.requirement = new;
+    self.requirement = self.requirement.scale(unit);
     self
   }
 }
@@ -137,17 +210,16 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequiresBalance
       Some(header_values) => {
         match header_values
           .into_iter()
-          .map(|value| U256::from_str(value.as_str()))
+          .map(|value| U256::from_dec_str(value.as_str()))
           .collect::<result::Result<Vec<U256>, _>>()
         {
           Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
           Ok(balances) => {
-            if balances.into_iter().any(|balance| match self.requirement {
-              BalanceRequirement::AtLeast(min) => balance.ge(&min),
-              BalanceRequirement::AtMost(max) => balance.le(&max),
-              BalanceRequirement::Between(min, max) => balance.ge(&min) && balance.le(&max),
-            }) {
-              println!("Balance meets requirement of {:?}", self.requirement);
+            if balances
+              .iter()
+              .any(|balance| self.requirement.is_met_by(balance))
+            {
+              log::debug!("Balance meets requirement of {:?}", self.requirement);
               return Ok(next.run(request).await);
             } else {
               return Ok(Response::new(StatusCode::PaymentRequired));
@@ -158,3 +230,44 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequiresBalance
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tide::http::{self, Method};
+
+  async fn require(requirement: BalanceRequirement, balances: &[&str]) -> StatusCode {
+    let mut server = tide::new();
+    server.with(RequiresBalance {
+      header: HeaderName::from("X-Balance"),
+      requirement,
+    });
+    server.at("*").get(|_| async { Ok("ok") });
+
+    let mut request = http::Request::new(Method::Get, Url::parse("http://test/a").unwrap());
+    for balance in balances {
+      request.append_header("X-Balance", *balance);
+    }
+    let response: http::Response = server.respond(request).await.unwrap();
+    response.status()
+  }
+
+  #[async_std::test]
+  async fn reads_balances_as_decimal() {
+    let at_least = || BalanceRequirement::AtLeast(U256::from(200));
+    assert_eq!(
+      require(at_least(), &["150"]).await,
+      StatusCode::PaymentRequired
+    );
+    assert_eq!(require(at_least(), &["250"]).await, StatusCode::Ok);
+    assert_eq!(require(at_least(), &["150", "200"]).await, StatusCode::Ok);
+  }
+
+  #[async_std::test]
+  async fn rejects_missing_or_malformed_balances() {
+    let at_least = || BalanceRequirement::AtLeast(U256::from(1));
+    assert_eq!(require(at_least(), &[]).await, StatusCode::BadRequest);
+    assert_eq!(require(at_least(), &["0x10"]).await, StatusCode::BadRequest);
+    assert_eq!(require(at_least(), &["lots"]).await, StatusCode::BadRequest);
+  }
+}
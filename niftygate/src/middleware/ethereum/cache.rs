@@ -0,0 +1,312 @@
+use ethcontract::{
+  dyns::DynWeb3,
+  web3::types::{Address, U256},
+};
+use serde::Serialize;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
+use tide::{
+  http::Method, utils::async_trait, Body, Middleware, Next, Request, Response, Result, StatusCode,
+};
+
+// Balances are cached per chain (given by the cache handle), contract (None for
+// ether), address, and token id (for ERC1155).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct BalanceKey {
+  pub contract: Option<Address>,
+  pub address: Address,
+  pub token_id: Option<U256>,
+}
+
+// Entries expire after ttl, or once blocks new blocks have been seen, whichever
+// comes first. The latest block is checked at most once per
+// block_check_interval, so requests served from the cache don't need to ask for
+// it every time.
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+  pub ttl: Option<Duration>,
+  pub blocks: Option<u64>,
+  pub block_check_interval: Duration,
+  pub capacity: usize,
+}
+
+impl Default for CacheOptions {
+  fn default() -> Self {
+    Self {
+      ttl: Some(Duration::from_secs(15)),
+      blocks: None,
+      block_check_interval: Duration::from_secs(1),
+      capacity: 10_000,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct CacheStats {
+  pub entries: usize,
+  pub hits: u64,
+  pub misses: u64,
+}
+
+struct Entry {
+  balance: U256,
+  cached_at: Instant,
+  block: Option<u64>,
+}
+
+struct Shared {
+  entries: RwLock<HashMap<(u64, BalanceKey), Entry>>,
+  heads: RwLock<HashMap<u64, (u64, Instant)>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  options: CacheOptions,
+}
+
+impl Shared {
+  fn is_fresh(&self, entry: &Entry, head: Option<u64>, now: Instant) -> bool {
+    let expired = match self.options.ttl {
+      Some(ttl) => now.duration_since(entry.cached_at) >= ttl,
+      None => false,
+    };
+    let stale = match (self.options.blocks, entry.block, head) {
+      (Some(blocks), Some(block), Some(head)) => head.saturating_sub(block) >= blocks,
+      _ => false,
+    };
+    !expired && !stale
+  }
+}
+
+// A cache shared by the balance middlewares, so repeated requests from the same
+// address don't each cost a round of RPC calls. Clones share the same entries
+// and counters, and for_chain gives a handle for another chain.
+#[derive(Clone)]
+pub struct BalanceCache {
+  shared: Arc<Shared>,
+  pub chain_id: u64,
+}
+
+impl BalanceCache {
+  pub fn new(options: CacheOptions) -> Self {
+    Self {
+      shared: Arc::new(Shared {
+        entries: RwLock::new(HashMap::new()),
+        heads: RwLock::new(HashMap::new()),
+        hits: AtomicU64::new(0),
+        misses: AtomicU64::new(0),
+        options,
+      }),
+      chain_id: 0,
+    }
+  }
+
+  pub fn for_chain(&self, chain_id: u64) -> Self {
+    Self {
+      shared: self.shared.clone(),
+      chain_id,
+    }
+  }
+
+  // The latest block on this chain, if entries expire by block.
+  pub async fn head(&self, web3: &DynWeb3) -> anyhow::Result<Option<u64>> {
+    if self.shared.options.blocks.is_none() {
+      return Ok(None);
+    }
+
+    let now = Instant::now();
+    if let Some((head, checked_at)) = self.shared.heads.read().unwrap().get(&self.chain_id) {
+      if now.duration_since(*checked_at) < self.shared.options.block_check_interval {
+        return Ok(Some(*head));
+      }
+    }
+
+    let head = web3.eth().block_number().await?.as_u64();
+    self
+      .shared
+      .heads
+      .write()
+      .unwrap()
+      .insert(self.chain_id, (head, now));
+    Ok(Some(head))
+  }
+
+  pub fn get(&self, key: &BalanceKey, head: Option<u64>) -> Option<U256> {
+    let balance = self
+      .shared
+      .entries
+      .read()
+      .unwrap()
+      .get(&(self.chain_id, *key))
+      .filter(|entry| self.shared.is_fresh(entry, head, Instant::now()))
+      .map(|entry| entry.balance);
+
+    match balance {
+      Some(_) => self.shared.hits.fetch_add(1, Ordering::Relaxed),
+      None => self.shared.misses.fetch_add(1, Ordering::Relaxed),
+    };
+    balance
+  }
+
+  pub fn insert(&self, key: BalanceKey, balance: U256, head: Option<u64>) {
+    let now = Instant::now();
+    let key = (self.chain_id, key);
+    let mut entries = self.shared.entries.write().unwrap();
+
+    // When full, entries that have expired make room. If none have, the new
+    // entry isn't cached until some do.
+    if entries.len() >= self.shared.options.capacity && !entries.contains_key(&key) {
+      let heads = self.shared.heads.read().unwrap();
+      entries.retain(|(chain_id, _), entry| {
+        let head = heads.get(chain_id).map(|(head, _)| *head);
+        self.shared.is_fresh(entry, head, now)
+      });
+      if entries.len() >= self.shared.options.capacity {
+        return;
+      }
+    }
+
+    entries.insert(
+      key,
+      Entry {
+        balance,
+        cached_at: now,
+        block: head,
+      },
+    );
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      entries: self.shared.entries.read().unwrap().len(),
+      hits: self.shared.hits.load(Ordering::Relaxed),
+      misses: self.shared.misses.load(Ordering::Relaxed),
+    }
+  }
+}
+
+// Reports how many balances are cached, and how often the cache was used.
+#[derive(Clone)]
+pub struct ProvidesCacheStatus {
+  pub path: String,
+  pub cache: BalanceCache,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesCacheStatus {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    if request.method() != Method::Get || request.url().path() != self.path {
+      return Ok(next.run(request).await);
+    }
+
+    let mut response = Response::new(StatusCode::Ok);
+    response.insert_header("Cache-Control", "no-store");
+    response.set_body(Body::from_json(&self.cache.stats())?);
+    Ok(response)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mock_rpc;
+  use ethcontract::{transport::DynTransport, Web3};
+  use niftygate_contract::transport::Http;
+  use serde_json::json;
+
+  fn key(address: u64) -> BalanceKey {
+    BalanceKey {
+      contract: None,
+      address: Address::from_low_u64_be(address),
+      token_id: None,
+    }
+  }
+
+  fn cache(ttl: Option<Duration>, blocks: Option<u64>, capacity: usize) -> BalanceCache {
+    BalanceCache::new(CacheOptions {
+      ttl,
+      blocks,
+      capacity,
+      ..CacheOptions::default()
+    })
+  }
+
+  #[async_std::test]
+  async fn expires_entries_after_the_ttl() {
+    let cache = cache(Some(Duration::from_millis(50)), None, 10);
+    cache.insert(key(1), U256::from(5), None);
+    assert_eq!(cache.get(&key(1), None), Some(U256::from(5)));
+    async_std::task::sleep(Duration::from_millis(60)).await;
+    assert_eq!(cache.get(&key(1), None), None);
+  }
+
+  #[test]
+  fn expires_entries_after_blocks() {
+    let cache = cache(None, Some(2), 10);
+    cache.insert(key(1), U256::from(5), Some(10));
+    assert_eq!(cache.get(&key(1), Some(11)), Some(U256::from(5)));
+    assert_eq!(cache.get(&key(1), Some(12)), None);
+  }
+
+  #[test]
+  fn keeps_chains_apart() {
+    let cache = cache(None, None, 10);
+    cache.insert(key(1), U256::from(5), None);
+    cache.for_chain(1).insert(key(1), U256::from(7), None);
+    assert_eq!(cache.get(&key(1), None), Some(U256::from(5)));
+    assert_eq!(cache.for_chain(1).get(&key(1), None), Some(U256::from(7)));
+    assert_eq!(cache.for_chain(2).get(&key(1), None), None);
+  }
+
+  #[test]
+  fn counts_hits_and_misses() {
+    let cache = cache(None, None, 10);
+    cache.get(&key(1), None);
+    cache.insert(key(1), U256::from(5), None);
+    cache.get(&key(1), None);
+    cache.for_chain(1).get(&key(1), None);
+
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 2));
+  }
+
+  #[async_std::test]
+  async fn makes_room_by_removing_expired_entries() {
+    let cache = cache(Some(Duration::from_millis(50)), None, 2);
+    cache.insert(key(1), U256::from(1), None);
+    cache.insert(key(2), U256::from(2), None);
+    cache.insert(key(3), U256::from(3), None);
+    assert_eq!(cache.get(&key(3), None), None);
+
+    async_std::task::sleep(Duration::from_millis(60)).await;
+    cache.insert(key(3), U256::from(3), None);
+    assert_eq!(cache.get(&key(3), None), Some(U256::from(3)));
+    assert_eq!(cache.stats().entries, 1);
+  }
+
+  #[async_std::test]
+  async fn checks_the_head_once_per_interval() {
+    let node = mock_rpc::serve(Duration::default(), |_, _| Ok(json!("0x2a")));
+    let web3 = Web3::new(DynTransport::new(Http::new(node.url.clone())));
+
+    assert_eq!(cache(None, None, 10).head(&web3).await.unwrap(), None);
+    assert!(node.log.lock().unwrap().methods.is_empty());
+
+    let cache = BalanceCache::new(CacheOptions {
+      blocks: Some(1),
+      block_check_interval: Duration::from_secs(3600),
+      ..CacheOptions::default()
+    });
+    assert_eq!(cache.head(&web3).await.unwrap(), Some(42));
+    assert_eq!(cache.head(&web3).await.unwrap(), Some(42));
+    assert_eq!(node.log.lock().unwrap().methods, vec!["eth_blockNumber"]);
+
+    // Each chain has a head of its own.
+    cache.for_chain(1).head(&web3).await.unwrap();
+    assert_eq!(node.log.lock().unwrap().methods.len(), 2);
+  }
+}
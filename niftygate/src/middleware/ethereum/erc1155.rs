@@ -13,6 +13,10 @@ pub mod prelude {
 
 use prelude::*;
 
+use super::{
+  lookup::{provide, Lookup, Provided},
+  BalanceCache, BalanceKey, BalanceRequirement, HolderIndex, TokenIds,
+};
 use std::{result, str::FromStr};
 use tide::{utils::async_trait, Middleware, Next, Request, Response, Result};
 
@@ -20,20 +24,24 @@ use tide::{utils::async_trait, Middleware, Next, Request, Response, Result};
 pub struct ProvidesERC1155Balance {
   pub address_header: HeaderName,
   pub balance_header: HeaderName,
+  pub token_balance_header: HeaderName,
+  pub token_ids: Vec<U256>,
+  pub cache: Option<BalanceCache>,
+  pub index: Option<HolderIndex>,
   pub contract: ERC1155,
 }
 
 #[async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC1155Balance {
-  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+impl<State: Clone + Send + Sync + 'static> Lookup<State> for ProvidesERC1155Balance {
+  async fn lookup(&self, request: &Request<State>) -> result::Result<Provided, StatusCode> {
     let addresses = match request.header(&self.address_header) {
-      None => return Ok(Response::new(StatusCode::NetworkAuthenticationRequired)),
+      None => return Err(StatusCode::NetworkAuthenticationRequired),
       Some(header_values) => match header_values
         .into_iter()
         .map(|input| hex::decode(input.as_str()))
         .collect::<result::Result<Vec<Vec<u8>>, hex::FromHexError>>()
       {
-        Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+        Err(_) => return Err(StatusCode::BadRequest),
         Ok(raw_addresses) => raw_addresses
           .into_iter()
           .map(|src| Address::from_slice(&src))
@@ -41,16 +49,132 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC1155
       },
     };
 
+    // Once the index has caught up, balances come from it instead.
+    let index = self.index.as_ref().filter(|index| index.is_ready());
+    let head = match (&self.cache, index) {
+      (Some(cache), None) => match cache.head(&self.contract.raw_instance().web3()).await {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(head) => head,
+      },
+      _ => None,
+    };
+
+    let mut keys = vec![];
     for account in addresses {
-      match self.contract.balance_of(account, U256::zero()).call().await {
-        Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-        Ok(balance) => match HeaderValue::from_str(&balance.to_string()) {
-          Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-          Ok(value) => request.append_header(&self.balance_header, value),
-        },
+      for id in &self.token_ids {
+        keys.push(BalanceKey {
+          contract: Some(self.contract.address()),
+          address: account,
+          token_id: Some(*id),
+        });
+      }
+    }
+
+    let mut cached: Vec<Option<U256>> = keys
+      .iter()
+      .map(|key| {
+        index
+          .and_then(|index| index.balance(key.address, key.token_id))
+          .or_else(|| self.cache.as_ref().and_then(|cache| cache.get(key, head)))
+      })
+      .collect();
+
+    // Only balances that aren't indexed or cached are asked for.
+    let missing: Vec<&BalanceKey> = keys
+      .iter()
+      .zip(&cached)
+      .filter(|(_, balance)| balance.is_none())
+      .map(|(key, _)| key)
+      .collect();
+
+    if !missing.is_empty() {
+      let accounts = missing.iter().map(|key| key.address).collect();
+      let ids = missing.iter().filter_map(|key| key.token_id).collect();
+      let mut fetched = match self.contract.balance_of_batch(accounts, ids).call().await {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(balances) => balances.into_iter(),
+      };
+
+      for (key, balance) in keys.iter().zip(cached.iter_mut()) {
+        if balance.is_none() {
+          let fetched = match fetched.next() {
+            None => return Err(StatusCode::InternalServerError),
+            Some(fetched) => fetched,
+          };
+          if let Some(cache) = &self.cache {
+            cache.insert(*key, fetched, head);
+          }
+          *balance = Some(fetched);
+        }
+      }
+    }
+
+    let ids = keys.iter().filter_map(|key| key.token_id);
+    let balances = cached.into_iter().flatten();
+
+    let mut provided = vec![];
+
+    for (id, balance) in ids.zip(balances) {
+      match HeaderValue::from_str(&balance.to_string()) {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(value) => provided.push((self.balance_header.clone(), value)),
+      }
+
+      match HeaderValue::from_str(&format!("{}={}", id, balance)) {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(value) => provided.push((self.token_balance_header.clone(), value)),
       }
     }
 
-    Ok(next.run(request).await)
+    Ok(provided)
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC1155Balance {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    provide(self, request, next).await
+  }
+}
+
+#[derive(Clone)]
+pub struct RequiresTokenBalance {
+  pub header: HeaderName,
+  pub token_ids: TokenIds,
+  pub requirement: BalanceRequirement,
+}
+
+pub(crate) fn parse_token_balance(value: &str) -> Option<(U256, U256)> {
+  let (id, balance) = value.split_once('=')?;
+  Some((
+    U256::from_dec_str(id).ok()?,
+    U256::from_dec_str(balance).ok()?,
+  ))
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequiresTokenBalance {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    match request.header(&self.header) {
+      None => Ok(Response::new(StatusCode::BadRequest)),
+      Some(header_values) => {
+        match header_values
+          .into_iter()
+          .map(|value| parse_token_balance(value.as_str()))
+          .collect::<Option<Vec<(U256, U256)>>>()
+        {
+          None => Ok(Response::new(StatusCode::BadRequest)),
+          Some(balances) => {
+            if balances.iter().any(|(id, balance)| {
+              self.token_ids.contains(id) && self.requirement.is_met_by(balance)
+            }) {
+              Ok(next.run(request).await)
+            } else {
+              Ok(Response::new(StatusCode::PaymentRequired))
+            }
+          }
+        }
+      }
+    }
   }
 }
@@ -13,8 +13,13 @@ pub mod prelude {
 
 use prelude::*;
 
+use super::{
+  lookup::{provide, Lookup, Provided},
+  BalanceCache, BalanceKey, HolderIndex,
+};
+use futures::{future::try_join_all, join};
 use std::{result, str::FromStr};
-use tide::{utils::async_trait, Middleware, Next, Request, Response, Result};
+use tide::{utils::async_trait, Middleware, Next, Request, Result};
 
 #[derive(Clone)]
 pub struct ProvidesERC20Balance {
@@ -22,20 +27,54 @@ pub struct ProvidesERC20Balance {
   pub balance_header: HeaderName,
   pub name_header: Option<HeaderName>,
   pub symbol_header: Option<HeaderName>,
+  pub cache: Option<BalanceCache>,
+  pub index: Option<HolderIndex>,
   pub contract: ERC20,
 }
 
+impl ProvidesERC20Balance {
+  async fn balance(
+    &self,
+    account: Address,
+    index: Option<&HolderIndex>,
+    head: Option<u64>,
+  ) -> result::Result<U256, StatusCode> {
+    let key = BalanceKey {
+      contract: Some(self.contract.address()),
+      address: account,
+      token_id: None,
+    };
+
+    let indexed = index.and_then(|index| index.balance(account, None));
+    if let Some(balance) =
+      indexed.or_else(|| self.cache.as_ref().and_then(|cache| cache.get(&key, head)))
+    {
+      return Ok(balance);
+    }
+
+    match self.contract.balance_of(account).call().await {
+      Err(_) => Err(StatusCode::InternalServerError),
+      Ok(balance) => {
+        if let Some(cache) = &self.cache {
+          cache.insert(key, balance, head);
+        }
+        Ok(balance)
+      }
+    }
+  }
+}
+
 #[async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC20Balance {
-  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+impl<State: Clone + Send + Sync + 'static> Lookup<State> for ProvidesERC20Balance {
+  async fn lookup(&self, request: &Request<State>) -> result::Result<Provided, StatusCode> {
     let addresses = match request.header(&self.address_header) {
-      None => return Ok(Response::new(StatusCode::NetworkAuthenticationRequired)),
+      None => return Err(StatusCode::NetworkAuthenticationRequired),
       Some(header_values) => match header_values
         .into_iter()
         .map(|input| hex::decode(input.as_str()))
         .collect::<result::Result<Vec<Vec<u8>>, hex::FromHexError>>()
       {
-        Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+        Err(_) => return Err(StatusCode::BadRequest),
         Ok(raw_addresses) => raw_addresses
           .into_iter()
           .map(|src| Address::from_slice(&src))
@@ -43,28 +82,71 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC20Ba
       },
     };
 
-    if let Some(name) = &self.name_header {
-      if let Ok(values) = self.contract.name().call().await {
-        request.append_header(name, values)
+    // Once the index has caught up, balances come from it instead.
+    let index = self.index.as_ref().filter(|index| index.is_ready());
+    let head = match (&self.cache, index) {
+      (Some(cache), None) => match cache.head(&self.contract.raw_instance().web3()).await {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(head) => head,
+      },
+      _ => None,
+    };
+
+    let name = async {
+      match &self.name_header {
+        None => None,
+        Some(header) => self
+          .contract
+          .name()
+          .call()
+          .await
+          .ok()
+          .map(|name| (header, name)),
       }
-    }
+    };
 
-    if let Some(name) = &self.symbol_header {
-      if let Ok(values) = self.contract.symbol().call().await {
-        request.append_header(name, values)
+    let symbol = async {
+      match &self.symbol_header {
+        None => None,
+        Some(header) => self
+          .contract
+          .symbol()
+          .call()
+          .await
+          .ok()
+          .map(|symbol| (header, symbol)),
+      }
+    };
+
+    let balances = try_join_all(
+      addresses
+        .into_iter()
+        .map(|account| self.balance(account, index, head)),
+    );
+
+    let (name, symbol, balances) = join!(name, symbol, balances);
+
+    let mut provided = vec![];
+    for (header, value) in name.into_iter().chain(symbol) {
+      if let Ok(value) = HeaderValue::from_str(&value) {
+        provided.push((header.clone(), value));
       }
     }
 
-    for account in addresses {
-      match self.contract.balance_of(account).call().await {
-        Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-        Ok(balance) => match HeaderValue::from_str(&balance.to_string()) {
-          Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-          Ok(value) => request.append_header(&self.balance_header, value),
-        },
+    for balance in balances? {
+      match HeaderValue::from_str(&balance.to_string()) {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(value) => provided.push((self.balance_header.clone(), value)),
       }
     }
 
-    Ok(next.run(request).await)
+    Ok(provided)
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC20Balance {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    provide(self, request, next).await
   }
 }
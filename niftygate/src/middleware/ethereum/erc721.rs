@@ -13,7 +13,17 @@ pub mod prelude {
 
 use prelude::*;
 
-use std::{result, str::FromStr};
+use super::{
+  lookup::{provide, Lookup, Provided},
+  BalanceCache, BalanceKey, HolderIndex, TokenIds,
+};
+use ethcontract::errors::{ExecutionError, MethodError};
+use futures::{
+  future::{join_all, try_join_all},
+  join,
+};
+use serde::Deserialize;
+use std::{fmt, result, str::FromStr};
 use tide::{utils::async_trait, Middleware, Next, Request, Response, Result};
 
 #[derive(Clone)]
@@ -22,12 +32,235 @@ pub struct ProvidesERC721Balance {
   pub balance_header: HeaderName,
   pub name_header: Option<HeaderName>,
   pub symbol_header: Option<HeaderName>,
+  pub cache: Option<BalanceCache>,
+  pub index: Option<HolderIndex>,
   pub contract: ERC721,
 }
 
+impl ProvidesERC721Balance {
+  async fn balance(
+    &self,
+    account: Address,
+    index: Option<&HolderIndex>,
+    head: Option<u64>,
+  ) -> result::Result<U256, StatusCode> {
+    let key = BalanceKey {
+      contract: Some(self.contract.address()),
+      address: account,
+      token_id: None,
+    };
+
+    let indexed = index.and_then(|index| index.balance(account, None));
+    if let Some(balance) =
+      indexed.or_else(|| self.cache.as_ref().and_then(|cache| cache.get(&key, head)))
+    {
+      return Ok(balance);
+    }
+
+    match self.contract.balance_of(account).call().await {
+      Err(_) => Err(StatusCode::InternalServerError),
+      Ok(balance) => {
+        if let Some(cache) = &self.cache {
+          cache.insert(key, balance, head);
+        }
+        Ok(balance)
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Lookup<State> for ProvidesERC721Balance {
+  async fn lookup(&self, request: &Request<State>) -> result::Result<Provided, StatusCode> {
+    let addresses = match request.header(&self.address_header) {
+      None => return Err(StatusCode::NetworkAuthenticationRequired),
+      Some(header_values) => match header_values
+        .into_iter()
+        .map(|input| hex::decode(input.as_str()))
+        .collect::<result::Result<Vec<Vec<u8>>, hex::FromHexError>>()
+      {
+        Err(_) => return Err(StatusCode::BadRequest),
+        Ok(raw_addresses) => raw_addresses
+          .into_iter()
+          .map(|src| Address::from_slice(&src))
+          .collect::<Vec<Address>>(),
+      },
+    };
+
+    // Once the index has caught up, balances come from it instead.
+    let index = self.index.as_ref().filter(|index| index.is_ready());
+    let head = match (&self.cache, index) {
+      (Some(cache), None) => match cache.head(&self.contract.raw_instance().web3()).await {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(head) => head,
+      },
+      _ => None,
+    };
+
+    let name = async {
+      match &self.name_header {
+        None => None,
+        Some(header) => self
+          .contract
+          .name()
+          .call()
+          .await
+          .ok()
+          .map(|name| (header, name)),
+      }
+    };
+
+    let symbol = async {
+      match &self.symbol_header {
+        None => None,
+        Some(header) => self
+          .contract
+          .symbol()
+          .call()
+          .await
+          .ok()
+          .map(|symbol| (header, symbol)),
+      }
+    };
+
+    let balances = try_join_all(
+      addresses
+        .into_iter()
+        .map(|account| self.balance(account, index, head)),
+    );
+
+    let (name, symbol, balances) = join!(name, symbol, balances);
+
+    let mut provided = vec![];
+    for (header, value) in name.into_iter().chain(symbol) {
+      if let Ok(value) = HeaderValue::from_str(&value) {
+        provided.push((header.clone(), value));
+      }
+    }
+
+    for balance in balances? {
+      match HeaderValue::from_str(&balance.to_string()) {
+        Err(_) => return Err(StatusCode::InternalServerError),
+        Ok(value) => provided.push((self.balance_header.clone(), value)),
+      }
+    }
+
+    Ok(provided)
+  }
+}
+
 #[async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC721Balance {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    provide(self, request, next).await
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+  Literal(String),
+  TokenId,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct TokenPath(Vec<Segment>);
+
+impl TokenPath {
+  const PLACEHOLDER: &'static str = "{tokenId}";
+
+  pub fn token_id<'a>(&self, path: &'a str) -> Option<&'a str> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    let mut token_id = None;
+
+    for expected in &self.0 {
+      let segment = segments.next()?;
+      match expected {
+        Segment::Literal(literal) if literal != segment => return None,
+        Segment::Literal(_) => (),
+        Segment::TokenId => token_id = Some(segment),
+      }
+    }
+
+    token_id
+  }
+}
+
+impl fmt::Display for TokenPath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for segment in &self.0 {
+      match segment {
+        Segment::Literal(literal) => write!(f, "/{}", literal)?,
+        Segment::TokenId => write!(f, "/{}", Self::PLACEHOLDER)?,
+      }
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for TokenPath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "TokenPath({:?})", self.to_string())
+  }
+}
+
+impl FromStr for TokenPath {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+    let segments = s
+      .trim_start_matches('/')
+      .split('/')
+      .map(|segment| match segment {
+        Self::PLACEHOLDER => Segment::TokenId,
+        _ => Segment::Literal(segment.to_string()),
+      })
+      .collect::<Vec<Segment>>();
+
+    match segments
+      .iter()
+      .filter(|&segment| segment == &Segment::TokenId)
+      .count()
+    {
+      1 => Ok(Self(segments)),
+      _ => anyhow::bail!(
+        "token path {:?} must contain {} exactly once",
+        s,
+        Self::PLACEHOLDER
+      ),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ownership {
+  TokenIds(TokenIds),
+  #[serde(deserialize_with = "crate::de::from_str")]
+  Path(TokenPath),
+}
+
+#[derive(Clone)]
+pub struct RequiresERC721Ownership {
+  pub address_header: HeaderName,
+  pub token_header: HeaderName,
+  pub ownership: Ownership,
+  pub contract: ERC721,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequiresERC721Ownership {
   async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    let token_ids = match &self.ownership {
+      Ownership::TokenIds(token_ids) => token_ids.iter().collect(),
+      Ownership::Path(path) => match path.token_id(request.url().path()) {
+        None => return Ok(next.run(request).await),
+        Some(token_id) => match U256::from_dec_str(token_id) {
+          Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+          Ok(token_id) => vec![token_id],
+        },
+      },
+    };
+
     let addresses = match request.header(&self.address_header) {
       None => return Ok(Response::new(StatusCode::NetworkAuthenticationRequired)),
       Some(header_values) => match header_values
@@ -43,28 +276,139 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesERC721B
       },
     };
 
-    if let Some(name) = &self.name_header {
-      if let Ok(values) = self.contract.name().call().await {
-        request.append_header(name, values)
+    // Owners are looked up all at once, so they can share a batch.
+    let owners = join_all(
+      token_ids
+        .iter()
+        .map(|token_id| self.contract.owner_of(*token_id).call()),
+    )
+    .await;
+
+    let mut owned = vec![];
+    for (token_id, owner) in token_ids.into_iter().zip(owners) {
+      match owner {
+        Ok(owner) => {
+          if addresses.contains(&owner) {
+            owned.push(token_id)
+          }
+        }
+        // ownerOf reverts for tokens that don't exist, which nobody owns.
+        Err(MethodError {
+          inner: ExecutionError::Revert(_),
+          ..
+        }) => (),
+        Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
       }
     }
 
-    if let Some(name) = &self.symbol_header {
-      if let Ok(values) = self.contract.symbol().call().await {
-        request.append_header(name, values)
-      }
+    if owned.is_empty() {
+      return Ok(Response::new(StatusCode::PaymentRequired));
     }
 
-    for account in addresses {
-      match self.contract.balance_of(account).call().await {
-        Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-        Ok(balance) => match HeaderValue::from_str(&balance.to_string()) {
-          Err(_) => return Ok(Response::new(StatusCode::InternalServerError)),
-          Ok(value) => request.append_header(&self.balance_header, value),
-        },
-      }
+    for token_id in owned {
+      request.append_header(&self.token_header, token_id.to_string());
     }
 
     Ok(next.run(request).await)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mock_rpc;
+  use ethcontract::{transport::DynTransport, Web3};
+  use niftygate_contract::transport::Http;
+  use serde_json::json;
+  use std::time::Duration;
+  use tide::http::{Method, Request as HttpRequest, Response as HttpResponse};
+
+  #[test]
+  fn parses_token_paths() {
+    let path: TokenPath = "/tokens/{tokenId}/metadata".parse().unwrap();
+    assert_eq!(path.to_string(), "/tokens/{tokenId}/metadata");
+    assert_eq!(path, "tokens/{tokenId}/metadata".parse().unwrap());
+  }
+
+  #[test]
+  fn rejects_token_paths_without_exactly_one_placeholder() {
+    assert!("/tokens".parse::<TokenPath>().is_err());
+    assert!("/{tokenId}/{tokenId}".parse::<TokenPath>().is_err());
+  }
+
+  #[test]
+  fn finds_token_ids_in_matching_paths() {
+    let path: TokenPath = "/tokens/{tokenId}/metadata".parse().unwrap();
+    assert_eq!(path.token_id("/tokens/42/metadata"), Some("42"));
+    assert_eq!(path.token_id("/tokens/42/metadata/extra"), Some("42"));
+    assert_eq!(path.token_id("/tokens/42"), None);
+    assert_eq!(path.token_id("/other/42/metadata"), None);
+  }
+
+  #[async_std::test]
+  async fn checks_owners_concurrently() {
+    let holder = Address::from_low_u64_be(1);
+    let node = mock_rpc::serve(Duration::from_millis(50), move |method, params| {
+      assert_eq!(method, "eth_call");
+      let data = params[0]["data"].as_str().unwrap();
+      assert!(data.starts_with("0x6352211e"));
+      match u64::from_str_radix(&data[data.len() - 16..], 16).unwrap() {
+        3 => Ok(json!(mock_rpc::word(holder.as_bytes()))),
+        4 => Err(json!({"code": -32000, "message": "execution reverted"})),
+        _ => Ok(json!(mock_rpc::word(
+          Address::from_low_u64_be(2).as_bytes()
+        ))),
+      }
+    });
+    let web3 = Web3::new(DynTransport::new(Http::new(node.url.clone())));
+
+    let mut server = tide::new();
+    server.with(RequiresERC721Ownership {
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      token_header: HeaderName::from("X-Web3-ERC721-Token-Id"),
+      ownership: Ownership::TokenIds(TokenIds::Range(1.into(), 5.into())),
+      contract: ERC721::at(&web3, Address::from_low_u64_be(99)),
+    });
+    server.at("/").get(|request: Request<()>| async move {
+      let owned = request.header("X-Web3-ERC721-Token-Id").unwrap();
+      Ok(owned.last().to_string())
+    });
+
+    let mut request = HttpRequest::new(Method::Get, Url::parse("http://localhost/").unwrap());
+    request.insert_header("X-Web3-Account-Address", hex::encode(holder));
+    let mut response: HttpResponse = server.respond(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(response.body_string().await.unwrap(), "3");
+
+    let log = node.log.lock().unwrap();
+    assert_eq!(log.methods.len(), 5);
+    assert!(log.max_in_flight > 1);
+  }
+
+  #[async_std::test]
+  async fn requires_owning_one_of_the_tokens() {
+    let node = mock_rpc::serve(Duration::default(), |_, _| {
+      Ok(json!(mock_rpc::word(
+        Address::from_low_u64_be(2).as_bytes()
+      )))
+    });
+    let web3 = Web3::new(DynTransport::new(Http::new(node.url.clone())));
+
+    let mut server = tide::new();
+    server.with(RequiresERC721Ownership {
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      token_header: HeaderName::from("X-Web3-ERC721-Token-Id"),
+      ownership: Ownership::TokenIds(TokenIds::List(vec![1.into(), 2.into()])),
+      contract: ERC721::at(&web3, Address::from_low_u64_be(99)),
+    });
+    server.at("/").get(|_| async { Ok("ok") });
+
+    let mut request = HttpRequest::new(Method::Get, Url::parse("http://localhost/").unwrap());
+    request.insert_header(
+      "X-Web3-Account-Address",
+      hex::encode(Address::from_low_u64_be(1)),
+    );
+    let response: HttpResponse = server.respond(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PaymentRequired);
+  }
+}
@@ -0,0 +1,704 @@
+use crate::openzeppelin::contracts::token::{erc1155::erc1155, erc20::erc20, erc721::erc721};
+use async_std::{fs, task};
+use ethcontract::{
+  contract::ParseLog,
+  dyns::DynWeb3,
+  web3::types::{Address, BlockNumber, FilterBuilder, Log, H256, U256},
+  EventStatus, RawLog,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{HashMap, VecDeque},
+  io,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
+use tide::log;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenStandard {
+  ERC20,
+  ERC721,
+  ERC1155,
+}
+
+impl TokenStandard {
+  fn signatures(&self) -> Vec<H256> {
+    match self {
+      Self::ERC20 => vec![erc20::event_data::Transfer::signature()],
+      Self::ERC721 => vec![erc721::event_data::Transfer::signature()],
+      Self::ERC1155 => vec![
+        erc1155::event_data::TransferSingle::signature(),
+        erc1155::event_data::TransferBatch::signature(),
+      ],
+    }
+  }
+
+  // ERC721 balances count tokens, so each transfer is worth one.
+  fn transfers(&self, log: RawLog) -> anyhow::Result<Vec<Transfer>> {
+    let transfers = match self {
+      Self::ERC20 => match erc20::Event::parse_log(log)? {
+        erc20::Event::Transfer(event) => vec![Transfer {
+          from: event.from,
+          to: event.to,
+          token_id: None,
+          value: event.value,
+        }],
+        _ => vec![],
+      },
+      Self::ERC721 => match erc721::Event::parse_log(log)? {
+        erc721::Event::Transfer(event) => vec![Transfer {
+          from: event.from,
+          to: event.to,
+          token_id: None,
+          value: U256::one(),
+        }],
+        _ => vec![],
+      },
+      Self::ERC1155 => match erc1155::Event::parse_log(log)? {
+        erc1155::Event::TransferSingle(event) => vec![Transfer {
+          from: event.from,
+          to: event.to,
+          token_id: Some(event.id),
+          value: event.value,
+        }],
+        erc1155::Event::TransferBatch(event) => {
+          let (from, to) = (event.from, event.to);
+          event
+            .ids
+            .into_iter()
+            .zip(event.values)
+            .map(|(id, value)| Transfer {
+              from,
+              to,
+              token_id: Some(id),
+              value,
+            })
+            .collect()
+        }
+        _ => vec![],
+      },
+    };
+    Ok(transfers)
+  }
+}
+
+// Events are read from from_block in pages of page_size blocks, then followed
+// with a log filter polled every poll_interval. Transfers from the last
+// reorg_depth blocks are kept, so they can be undone when their blocks are
+// reorganized away, or when the index has to catch up again (after the filter
+// fails, or on restart, when it's kept at path).
+#[derive(Clone, Debug)]
+pub struct IndexOptions {
+  pub from_block: u64,
+  pub page_size: u64,
+  pub poll_interval: Duration,
+  pub reorg_depth: u64,
+  pub path: Option<PathBuf>,
+  pub save_interval: Duration,
+}
+
+impl Default for IndexOptions {
+  fn default() -> Self {
+    Self {
+      from_block: 0,
+      page_size: 10_000,
+      poll_interval: Duration::from_secs(5),
+      reorg_depth: 64,
+      path: None,
+      save_interval: Duration::from_secs(30),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Transfer {
+  from: Address,
+  to: Address,
+  token_id: Option<U256>,
+  value: U256,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Applied {
+  block: u64,
+  block_hash: H256,
+  log_index: U256,
+  transfers: Vec<Transfer>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Holding {
+  address: Address,
+  token_id: Option<U256>,
+  balance: U256,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Snapshot {
+  block: Option<u64>,
+  holdings: Vec<Holding>,
+  recent: Vec<Applied>,
+}
+
+// Balances include every transfer up to block, and recent holds the transfers
+// from the last reorg_depth blocks before it.
+#[derive(Default)]
+struct State {
+  block: Option<u64>,
+  balances: HashMap<(Address, Option<U256>), U256>,
+  recent: VecDeque<Applied>,
+}
+
+impl State {
+  // Mints and burns come from (and go to) the zero address, which isn't
+  // counted as a holder.
+  fn adjust(&mut self, address: Address, token_id: Option<U256>, f: impl FnOnce(U256) -> U256) {
+    if address.is_zero() {
+      return;
+    }
+    let key = (address, token_id);
+    let balance = f(self.balances.get(&key).copied().unwrap_or_default());
+    if balance.is_zero() {
+      self.balances.remove(&key);
+    } else {
+      self.balances.insert(key, balance);
+    }
+  }
+
+  fn transfer(&mut self, transfers: &[Transfer], undo: bool) {
+    for transfer in transfers {
+      let (from, to) = match undo {
+        false => (transfer.from, transfer.to),
+        true => (transfer.to, transfer.from),
+      };
+      self.adjust(from, transfer.token_id, |balance| {
+        balance.saturating_sub(transfer.value)
+      });
+      self.adjust(to, transfer.token_id, |balance| {
+        balance.saturating_add(transfer.value)
+      });
+    }
+  }
+
+  // Logs can be seen twice, when the filter picks up blocks that were also
+  // read while catching up.
+  fn add(&mut self, applied: Applied, depth: u64) {
+    if self.recent.iter().any(|recent| {
+      recent.block_hash == applied.block_hash && recent.log_index == applied.log_index
+    }) {
+      return;
+    }
+    self.transfer(&applied.transfers, false);
+    self.advance(applied.block, depth);
+    self.recent.push_back(applied);
+  }
+
+  fn remove(&mut self, removed: &Applied) {
+    let position = self.recent.iter().position(|recent| {
+      recent.block_hash == removed.block_hash && recent.log_index == removed.log_index
+    });
+    if let Some(position) = position {
+      if let Some(applied) = self.recent.remove(position) {
+        self.transfer(&applied.transfers, true);
+      }
+    }
+  }
+
+  fn advance(&mut self, block: u64, depth: u64) {
+    let current = self.block.map_or(block, |current| current.max(block));
+    self.block = Some(current);
+    while let Some(oldest) = self.recent.front() {
+      if oldest.block + depth > current {
+        break;
+      }
+      self.recent.pop_front();
+    }
+  }
+
+  // Undoes the recent transfers, leaving balances as of reorg_depth blocks ago,
+  // where it's safe to start reading again.
+  fn rewind(&mut self, depth: u64) {
+    while let Some(applied) = self.recent.pop_back() {
+      self.transfer(&applied.transfers, true);
+    }
+    self.block = match self.block {
+      Some(block) if block >= depth => Some(block - depth),
+      _ => None,
+    };
+  }
+
+  fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      block: self.block,
+      holdings: self
+        .balances
+        .iter()
+        .map(|((address, token_id), balance)| Holding {
+          address: *address,
+          token_id: *token_id,
+          balance: *balance,
+        })
+        .collect(),
+      recent: self.recent.iter().cloned().collect(),
+    }
+  }
+
+  fn restore(snapshot: Snapshot) -> Self {
+    Self {
+      block: snapshot.block,
+      balances: snapshot
+        .holdings
+        .into_iter()
+        .map(|holding| ((holding.address, holding.token_id), holding.balance))
+        .collect(),
+      recent: snapshot.recent.into(),
+    }
+  }
+}
+
+struct Shared {
+  standard: TokenStandard,
+  address: Address,
+  web3: DynWeb3,
+  options: IndexOptions,
+  state: RwLock<State>,
+  ready: AtomicBool,
+  complete: bool,
+}
+
+// Balances of every holder of a token contract, kept up to date from its
+// transfer events, so they can be provided without asking the node for them.
+// Until the index has caught up, balances aren't available from it.
+//
+// An index that starts after the contract was deployed would miss earlier
+// transfers, so that's refused. If the node can't say whether the contract was
+// deployed (like a node without old state), holders the index hasn't seen are
+// left for the node, rather than taken to have nothing.
+#[derive(Clone)]
+pub struct HolderIndex {
+  shared: Arc<Shared>,
+}
+
+impl HolderIndex {
+  pub async fn new(
+    standard: TokenStandard,
+    address: Address,
+    web3: DynWeb3,
+    options: IndexOptions,
+  ) -> anyhow::Result<Self> {
+    let state = match &options.path {
+      None => State::default(),
+      Some(path) => match fs::read(path).await {
+        Ok(data) => State::restore(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => State::default(),
+        Err(error) => return Err(error.into()),
+      },
+    };
+
+    let complete = match options.from_block.checked_sub(1) {
+      None => true,
+      Some(before) => match web3
+        .eth()
+        .code(address, Some(BlockNumber::Number(before.into())))
+        .await
+      {
+        Ok(code) if code.0.is_empty() => true,
+        Ok(_) => anyhow::bail!(
+          "contract {:?} was deployed before block {}, so an index from there would miss transfers",
+          address,
+          options.from_block
+        ),
+        Err(error) => {
+          log::warn!(
+            "can't tell whether {:?} was deployed before block {}, so holders the index hasn't seen are looked up as usual: {}",
+            address,
+            options.from_block,
+            error
+          );
+          false
+        }
+      },
+    };
+
+    let index = Self {
+      shared: Arc::new(Shared {
+        standard,
+        address,
+        web3,
+        options,
+        state: RwLock::new(state),
+        ready: AtomicBool::new(false),
+        complete,
+      }),
+    };
+
+    let syncing = index.clone();
+    task::spawn(async move {
+      loop {
+        if let Err(error) = syncing.sync().await {
+          syncing.shared.ready.store(false, Ordering::Release);
+          log::warn!(
+            "holder index for {:?} stopped, catching up again: {}",
+            syncing.shared.address,
+            error
+          );
+        }
+        task::sleep(syncing.shared.options.poll_interval).await;
+      }
+    });
+
+    Ok(index)
+  }
+
+  pub fn is_ready(&self) -> bool {
+    self.shared.ready.load(Ordering::Acquire)
+  }
+
+  pub fn balance(&self, address: Address, token_id: Option<U256>) -> Option<U256> {
+    if !self.is_ready() {
+      return None;
+    }
+    let state = self.shared.state.read().unwrap();
+    match state.balances.get(&(address, token_id)) {
+      Some(balance) => Some(*balance),
+      None if self.shared.complete => Some(U256::zero()),
+      None => None,
+    }
+  }
+
+  fn filter(&self) -> FilterBuilder {
+    FilterBuilder::default()
+      .address(vec![self.shared.address])
+      .topics(Some(self.shared.standard.signatures()), None, None, None)
+  }
+
+  // Pending logs (without a block) are left for when they're mined.
+  fn parse(&self, log: Log) -> anyhow::Result<Option<Applied>> {
+    let (block, block_hash, log_index) = match (log.block_number, log.block_hash, log.log_index) {
+      (Some(block), Some(block_hash), Some(log_index)) => (block.as_u64(), block_hash, log_index),
+      _ => return Ok(None),
+    };
+    Ok(Some(Applied {
+      block,
+      block_hash,
+      log_index,
+      transfers: self.shared.standard.transfers(RawLog::from(log))?,
+    }))
+  }
+
+  // The filter is created before the latest block is read, so nothing is
+  // missed between catching up and following it.
+  async fn sync(&self) -> anyhow::Result<()> {
+    let shared = &self.shared;
+    let depth = shared.options.reorg_depth;
+    let filter = shared
+      .web3
+      .eth_filter()
+      .create_logs_filter(self.filter().build())
+      .await?;
+    let head = shared.web3.eth().block_number().await?.as_u64();
+
+    let mut from = {
+      let mut state = shared.state.write().unwrap();
+      state.rewind(depth);
+      match state.block {
+        Some(block) => block + 1,
+        None => shared.options.from_block,
+      }
+    };
+
+    while from <= head {
+      let to = head.min(from.saturating_add(shared.options.page_size - 1));
+      let logs = shared
+        .web3
+        .eth()
+        .logs(
+          self
+            .filter()
+            .from_block(from.into())
+            .to_block(to.into())
+            .build(),
+        )
+        .await?;
+
+      let mut applied = vec![];
+      for log in logs {
+        applied.extend(self.parse(log)?);
+      }
+
+      let mut state = shared.state.write().unwrap();
+      for applied in applied {
+        state.add(applied, depth);
+      }
+      state.advance(to, depth);
+      from = to + 1;
+    }
+
+    shared.ready.store(true, Ordering::Release);
+    log::info!(
+      "holder index for {:?} has {} balances, up to block {}",
+      shared.address,
+      shared.state.read().unwrap().balances.len(),
+      head
+    );
+    self.save().await;
+
+    let mut saved = Instant::now();
+    let mut logs = Box::pin(filter.stream(shared.options.poll_interval));
+    while let Some(log) = logs.next().await {
+      let log = log?;
+      let event = match log.removed {
+        Some(true) => self.parse(log)?.map(EventStatus::Removed),
+        _ => self.parse(log)?.map(EventStatus::Added),
+      };
+
+      match event {
+        None => (),
+        Some(EventStatus::Added(applied)) => shared.state.write().unwrap().add(applied, depth),
+        Some(EventStatus::Removed(applied)) => shared.state.write().unwrap().remove(&applied),
+      }
+
+      if saved.elapsed() >= shared.options.save_interval {
+        self.save().await;
+        saved = Instant::now();
+      }
+    }
+
+    anyhow::bail!("log filter ended")
+  }
+
+  async fn save(&self) {
+    let path = match &self.shared.options.path {
+      Some(path) => path,
+      None => return,
+    };
+
+    let snapshot = self.shared.state.read().unwrap().snapshot();
+    let write = async {
+      let temporary = path.with_extension("tmp");
+      fs::write(&temporary, serde_json::to_vec(&snapshot)?).await?;
+      fs::rename(&temporary, path).await?;
+      Ok::<_, anyhow::Error>(())
+    };
+
+    if let Err(error) = write.await {
+      log::warn!("failed to save holder index to {:?}: {}", path, error);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mock_rpc;
+  use ethcontract::{transport::DynTransport, Web3};
+  use niftygate_contract::transport::Http;
+  use serde_json::{json, Value};
+
+  fn address(n: u64) -> Address {
+    Address::from_low_u64_be(n)
+  }
+
+  fn applied(block: u64, log_index: u64, from: u64, to: u64, value: u64) -> Applied {
+    Applied {
+      block,
+      block_hash: H256::from_low_u64_be(block),
+      log_index: log_index.into(),
+      transfers: vec![Transfer {
+        from: address(from),
+        to: address(to),
+        token_id: None,
+        value: value.into(),
+      }],
+    }
+  }
+
+  fn balance(state: &State, n: u64) -> u64 {
+    state
+      .balances
+      .get(&(address(n), None))
+      .copied()
+      .unwrap_or_default()
+      .as_u64()
+  }
+
+  #[test]
+  fn follows_transfers() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 64);
+    state.add(applied(2, 0, 1, 2, 4), 64);
+    assert_eq!((balance(&state, 1), balance(&state, 2)), (6, 4));
+    assert_eq!(state.block, Some(2));
+    // The zero address mints and burns, but doesn't hold anything.
+    assert!(!state.balances.contains_key(&(address(0), None)));
+  }
+
+  #[test]
+  fn ignores_logs_seen_twice() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 64);
+    state.add(applied(1, 0, 0, 1, 10), 64);
+    assert_eq!(balance(&state, 1), 10);
+  }
+
+  #[test]
+  fn undoes_removed_transfers() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 64);
+    state.add(applied(2, 0, 1, 2, 4), 64);
+    state.remove(&applied(2, 0, 1, 2, 4));
+    assert_eq!((balance(&state, 1), balance(&state, 2)), (10, 0));
+    assert!(!state.balances.contains_key(&(address(2), None)));
+    // Removing it again (or something never added) changes nothing.
+    state.remove(&applied(2, 0, 1, 2, 4));
+    state.remove(&applied(3, 0, 1, 2, 4));
+    assert_eq!(balance(&state, 1), 10);
+  }
+
+  #[test]
+  fn forgets_transfers_deeper_than_reorg_depth() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 2);
+    state.add(applied(2, 0, 1, 2, 4), 2);
+    state.advance(3, 2);
+    assert_eq!(state.recent.len(), 1);
+    // Too deep to be undone.
+    state.remove(&applied(1, 0, 0, 1, 10));
+    assert_eq!(balance(&state, 1), 6);
+  }
+
+  #[test]
+  fn rewinds_to_reorg_depth() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 2);
+    state.add(applied(5, 0, 1, 2, 4), 2);
+    state.add(applied(6, 0, 2, 3, 1), 2);
+    state.rewind(2);
+    assert_eq!(state.block, Some(4));
+    assert!(state.recent.is_empty());
+    assert_eq!(
+      (balance(&state, 1), balance(&state, 2), balance(&state, 3)),
+      (10, 0, 0)
+    );
+
+    // Catching up again applies them once more.
+    state.add(applied(5, 0, 1, 2, 4), 2);
+    state.add(applied(6, 0, 2, 3, 1), 2);
+    assert_eq!(
+      (balance(&state, 1), balance(&state, 2), balance(&state, 3)),
+      (6, 3, 1)
+    );
+  }
+
+  #[test]
+  fn rewinds_past_the_first_block() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 64);
+    state.rewind(64);
+    assert_eq!(state.block, None);
+    assert!(state.balances.is_empty());
+  }
+
+  #[test]
+  fn restores_snapshots() {
+    let mut state = State::default();
+    state.add(applied(1, 0, 0, 1, 10), 64);
+    state.add(applied(2, 0, 1, 2, 4), 64);
+    let json = serde_json::to_vec(&state.snapshot()).unwrap();
+    let mut restored = State::restore(serde_json::from_slice(&json).unwrap());
+    assert_eq!(restored.block, Some(2));
+    assert_eq!((balance(&restored, 1), balance(&restored, 2)), (6, 4));
+    restored.remove(&applied(2, 0, 1, 2, 4));
+    assert_eq!(balance(&restored, 1), 10);
+  }
+
+  // A node at block 100, where address 1 was given 10 tokens in block 5, and
+  // that answers eth_getCode with code.
+  fn node(code: Result<Value, Value>) -> mock_rpc::MockRpc {
+    let signature = erc20::event_data::Transfer::signature();
+    mock_rpc::serve(Duration::default(), move |method, _| match method {
+      "eth_getCode" => code.clone(),
+      "eth_blockNumber" => Ok(json!("0x64")),
+      "eth_newFilter" => Ok(json!("0x1")),
+      "eth_getFilterChanges" => Ok(json!([])),
+      "eth_getLogs" => Ok(json!([{
+        "address": format!("{:?}", address(99)),
+        "topics": [
+          format!("{:?}", signature),
+          mock_rpc::word(address(0).as_bytes()),
+          mock_rpc::word(address(1).as_bytes()),
+        ],
+        "data": mock_rpc::word(&[10]),
+        "blockNumber": "0x5",
+        "blockHash": format!("{:?}", H256::from_low_u64_be(5)),
+        "logIndex": "0x0",
+      }])),
+      _ => Err(json!({"code": -32601, "message": "method not found"})),
+    })
+  }
+
+  async fn index(node: &mock_rpc::MockRpc, from_block: u64) -> anyhow::Result<HolderIndex> {
+    let web3 = Web3::new(DynTransport::new(Http::new(node.url.clone())));
+    let index = HolderIndex::new(
+      TokenStandard::ERC20,
+      address(99),
+      web3,
+      IndexOptions {
+        from_block,
+        poll_interval: Duration::from_millis(10),
+        ..IndexOptions::default()
+      },
+    )
+    .await?;
+    for _ in 0..100 {
+      if index.is_ready() {
+        break;
+      }
+      task::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(index.is_ready());
+    Ok(index)
+  }
+
+  #[async_std::test]
+  async fn indexes_from_the_first_block() {
+    let node = node(Ok(json!("0x")));
+    let index = index(&node, 0).await.unwrap();
+    assert_eq!(index.balance(address(1), None), Some(10.into()));
+    assert_eq!(index.balance(address(2), None), Some(U256::zero()));
+    assert!(!node
+      .log
+      .lock()
+      .unwrap()
+      .methods
+      .contains(&String::from("eth_getCode")));
+  }
+
+  #[async_std::test]
+  async fn indexes_from_before_deployment() {
+    let node = node(Ok(json!("0x")));
+    let index = index(&node, 3).await.unwrap();
+    assert_eq!(index.balance(address(1), None), Some(10.into()));
+    assert_eq!(index.balance(address(2), None), Some(U256::zero()));
+  }
+
+  #[async_std::test]
+  async fn refuses_to_index_from_after_deployment() {
+    let node = node(Ok(json!("0x6080")));
+    assert!(index(&node, 3).await.is_err());
+  }
+
+  #[async_std::test]
+  async fn leaves_unseen_holders_for_the_node_when_deployment_is_unknown() {
+    let node = node(Err(json!({"code": -32000, "message": "missing trie node"})));
+    let index = index(&node, 3).await.unwrap();
+    assert_eq!(index.balance(address(1), None), Some(10.into()));
+    assert_eq!(index.balance(address(2), None), None);
+  }
+}
@@ -0,0 +1,160 @@
+use futures::future::join_all;
+use std::{result, sync::Arc};
+use tide::{
+  http::headers::{HeaderName, HeaderValue},
+  utils::async_trait,
+  Middleware, Next, Request, Response, Result, StatusCode,
+};
+
+pub type Provided = Vec<(HeaderName, HeaderValue)>;
+
+// Looks up the headers a middleware provides, without adding them to the
+// request, so that lookups for several middlewares can run at once. A status
+// means the request is answered with it instead.
+#[async_trait]
+pub trait Lookup<State>: Send + Sync + 'static {
+  async fn lookup(&self, request: &Request<State>) -> result::Result<Provided, StatusCode>;
+}
+
+pub(crate) async fn provide<State, L>(
+  lookup: &L,
+  mut request: Request<State>,
+  next: Next<'_, State>,
+) -> Result
+where
+  State: Clone + Send + Sync + 'static,
+  L: Lookup<State>,
+{
+  match lookup.lookup(&request).await {
+    Err(status) => Ok(Response::new(status)),
+    Ok(provided) => {
+      for (name, value) in provided {
+        request.append_header(name, value);
+      }
+      Ok(next.run(request).await)
+    }
+  }
+}
+
+// Runs several lookups at once (so their RPC calls can share batches), and adds
+// their headers in order. If any of them fail, the first (in order) decides the
+// status.
+pub struct ProvidesConcurrently<State>(pub Vec<Arc<dyn Lookup<State>>>);
+
+impl<State> Clone for ProvidesConcurrently<State> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesConcurrently<State> {
+  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    let results = join_all(self.0.iter().map(|lookup| lookup.lookup(&request))).await;
+
+    let mut headers = vec![];
+    for result in results {
+      match result {
+        Err(status) => return Ok(Response::new(status)),
+        Ok(provided) => headers.extend(provided),
+      }
+    }
+
+    for (name, value) in headers {
+      request.append_header(name, value);
+    }
+    Ok(next.run(request).await)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+  };
+  use tide::http::{self, Method, Url};
+
+  #[derive(Default)]
+  struct Running {
+    now: AtomicUsize,
+    most: AtomicUsize,
+  }
+
+  // Provides its header after a moment, or answers with its status, keeping
+  // track of how many lookups were running at once.
+  struct Slow {
+    header: &'static str,
+    status: Option<StatusCode>,
+    running: Arc<Running>,
+  }
+
+  #[async_trait]
+  impl Lookup<()> for Slow {
+    async fn lookup(&self, _: &Request<()>) -> result::Result<Provided, StatusCode> {
+      let now = self.running.now.fetch_add(1, Ordering::SeqCst) + 1;
+      self.running.most.fetch_max(now, Ordering::SeqCst);
+      async_std::task::sleep(Duration::from_millis(20)).await;
+      self.running.now.fetch_sub(1, Ordering::SeqCst);
+      match self.status {
+        Some(status) => Err(status),
+        None => Ok(vec![(
+          HeaderName::from(self.header),
+          HeaderValue::from_bytes(b"1".to_vec()).unwrap(),
+        )]),
+      }
+    }
+  }
+
+  async fn provide(lookups: &[(&'static str, Option<StatusCode>)]) -> (StatusCode, String, usize) {
+    let running = Arc::new(Running::default());
+    let mut server = tide::new();
+    server.with(ProvidesConcurrently(
+      lookups
+        .iter()
+        .map(|(header, status)| {
+          Arc::new(Slow {
+            header,
+            status: *status,
+            running: running.clone(),
+          }) as Arc<dyn Lookup<()>>
+        })
+        .collect(),
+    ));
+    server.at("*").get(|request: Request<()>| async move {
+      let names = request
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| name.starts_with("x-"))
+        .collect::<Vec<_>>();
+      Ok(names.join(","))
+    });
+
+    let request = http::Request::new(Method::Get, Url::parse("http://test/a").unwrap());
+    let mut response: http::Response = server.respond(request).await.unwrap();
+    let body = response.body_string().await.unwrap();
+    (response.status(), body, running.most.load(Ordering::SeqCst))
+  }
+
+  #[async_std::test]
+  async fn runs_lookups_at_once() {
+    let (status, headers, most) = provide(&[("X-A", None), ("X-B", None), ("X-C", None)]).await;
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(most, 3);
+    let mut headers = headers.split(',').collect::<Vec<_>>();
+    headers.sort_unstable();
+    assert_eq!(headers, vec!["x-a", "x-b", "x-c"]);
+  }
+
+  #[async_std::test]
+  async fn answers_with_the_first_failure() {
+    let (status, _, _) = provide(&[
+      ("X-A", None),
+      ("X-B", Some(StatusCode::PaymentRequired)),
+      ("X-C", Some(StatusCode::InternalServerError)),
+    ])
+    .await;
+    assert_eq!(status, StatusCode::PaymentRequired);
+  }
+}
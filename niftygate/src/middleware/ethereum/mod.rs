@@ -1,6 +1,17 @@
 pub mod account;
+pub mod assertion;
 pub mod balance;
+pub mod cache;
+pub mod holders;
+pub mod lookup;
+pub mod nonce;
+pub mod policy;
+pub mod rpc;
+pub mod session;
 pub mod signature;
+pub mod siwe;
+pub mod token;
+pub mod typed_data;
 
 pub mod erc1155;
 pub mod erc20;
@@ -17,10 +28,30 @@ pub mod prelude {
   pub use super::signature::prelude::*;
 }
 
-pub use account::ProvidesAccountVerification;
+pub use account::{CodeCache, ProvidesAccountVerification, VerifiedAccount};
+pub use assertion::{
+  AssertionSigner, AssertionVerifier, IdentityAssertion, ProvidesIdentityAssertion,
+  ASSERTION_AUDIENCE,
+};
 pub use balance::{BalanceRequirement, BalanceScale, ProvidesBalance, RequiresBalance};
-pub use erc1155::ProvidesERC1155Balance;
+pub use cache::{BalanceCache, BalanceKey, CacheOptions, CacheStats, ProvidesCacheStatus};
+pub use erc1155::{ProvidesERC1155Balance, RequiresTokenBalance};
 pub use erc20::ProvidesERC20Balance;
-pub use erc721::ProvidesERC721Balance;
+pub use erc721::{Ownership, ProvidesERC721Balance, RequiresERC721Ownership, TokenPath};
 pub use erc777::ProvidesERC777Balance;
+pub use holders::{HolderIndex, IndexOptions, TokenStandard};
+pub use lookup::{Lookup, Provided, ProvidesConcurrently};
+pub use nonce::{
+  FileNonceStore, MemoryNonceStore, Nonce, NonceStore, Nonces, ProvidesChallenge,
+  DEFAULT_NONCE_CAPACITY,
+};
+pub use policy::{BalancePolicy, Decision, Policy, RequiresPolicy, TokenBalancePolicy};
+pub use rpc::ProvidesRpcStatus;
+pub use session::{
+  ProvidesSessionToken, ProvidesSessionTokenVerification, SessionClaims, SessionKey, SessionKeys,
+  UnlessSessionToken,
+};
 pub use signature::ProvidesSignature;
+pub use siwe::{ProvidesSignInWithEthereum, SiweMessage};
+pub use token::TokenIds;
+pub use typed_data::TypedChallenge;
@@ -0,0 +1,479 @@
+use super::typed_data::TypedChallenge;
+use async_std::{fs, io::prelude::WriteExt, sync::Mutex};
+use rand::Rng;
+use serde::Serialize;
+use std::{
+  collections::HashMap,
+  io,
+  path::PathBuf,
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tide::{
+  http::Method, log, utils::async_trait, Body, Middleware, Next, Request, Response, Result,
+  StatusCode,
+};
+
+// Anyone can ask for a nonce, so stores hold at most this many outstanding
+// nonces by default, rather than growing until they expire.
+pub const DEFAULT_NONCE_CAPACITY: usize = 10_000;
+
+// Stores refuse new nonces (returning false) while they hold capacity nonces
+// that haven't expired.
+#[async_trait]
+pub trait NonceStore: Send + Sync {
+  async fn insert(&self, nonce: String, expires: SystemTime) -> anyhow::Result<bool>;
+  async fn remove(&self, nonce: &str) -> anyhow::Result<Option<SystemTime>>;
+}
+
+pub struct MemoryNonceStore {
+  capacity: usize,
+  nonces: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl MemoryNonceStore {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      nonces: Mutex::new(HashMap::new()),
+    }
+  }
+}
+
+impl Default for MemoryNonceStore {
+  fn default() -> Self {
+    Self::new(DEFAULT_NONCE_CAPACITY)
+  }
+}
+
+#[async_trait]
+impl NonceStore for MemoryNonceStore {
+  async fn insert(&self, nonce: String, expires: SystemTime) -> anyhow::Result<bool> {
+    let mut nonces = self.nonces.lock().await;
+    // Expired nonces are only cleared out when they're taking up room.
+    if nonces.len() >= self.capacity {
+      let now = SystemTime::now();
+      nonces.retain(|_, expires| *expires > now);
+      if nonces.len() >= self.capacity {
+        return Ok(false);
+      }
+    }
+    nonces.insert(nonce, expires);
+    Ok(true)
+  }
+
+  async fn remove(&self, nonce: &str) -> anyhow::Result<Option<SystemTime>> {
+    Ok(self.nonces.lock().await.remove(nonce))
+  }
+}
+
+// The log is rewritten with only the outstanding nonces once it has this many
+// more lines than there are outstanding nonces.
+const COMPACT_AFTER: usize = 1_000;
+
+#[derive(Default)]
+struct NonceLog {
+  nonces: HashMap<String, u64>,
+  lines: usize,
+}
+
+// Nonces are appended to a log, as `<nonce> <expiry>` lines when issued (with
+// the expiry in seconds since the epoch), and `<nonce>` lines when redeemed, so
+// outstanding nonces survive a restart. The log is read once, and kept in
+// memory from then on.
+pub struct FileNonceStore {
+  capacity: usize,
+  path: PathBuf,
+  log: Mutex<Option<NonceLog>>,
+}
+
+impl FileNonceStore {
+  pub fn new(path: impl Into<PathBuf>, capacity: usize) -> Self {
+    Self {
+      capacity,
+      path: path.into(),
+      log: Mutex::new(None),
+    }
+  }
+
+  async fn read(&self) -> anyhow::Result<NonceLog> {
+    let data = match fs::read_to_string(&self.path).await {
+      Ok(data) => data,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+      Err(error) => return Err(error.into()),
+    };
+
+    let mut log = NonceLog::default();
+    for line in data.lines() {
+      match line.split_once(' ') {
+        Some((nonce, expires)) => {
+          log.nonces.insert(nonce.to_string(), expires.parse()?);
+        }
+        None => {
+          log.nonces.remove(line);
+        }
+      }
+      log.lines += 1;
+    }
+    Ok(log)
+  }
+
+  async fn append(&self, log: &mut NonceLog, line: String) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .await?;
+    file.write_all(format!("{}\n", line).as_bytes()).await?;
+    log.lines += 1;
+
+    if log.lines > log.nonces.len() + COMPACT_AFTER {
+      let now = seconds(SystemTime::now());
+      log.nonces.retain(|_, expires| *expires > now);
+      let lines: String = log
+        .nonces
+        .iter()
+        .map(|(nonce, expires)| format!("{} {}\n", nonce, expires))
+        .collect();
+      let temporary = self.path.with_extension("tmp");
+      fs::write(&temporary, lines).await?;
+      fs::rename(&temporary, &self.path).await?;
+      log.lines = log.nonces.len();
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl NonceStore for FileNonceStore {
+  async fn insert(&self, nonce: String, expires: SystemTime) -> anyhow::Result<bool> {
+    let mut guard = self.log.lock().await;
+    if guard.is_none() {
+      *guard = Some(self.read().await?);
+    }
+    let log = guard.as_mut().unwrap();
+
+    if log.nonces.len() >= self.capacity {
+      let now = seconds(SystemTime::now());
+      log.nonces.retain(|_, expires| *expires > now);
+      if log.nonces.len() >= self.capacity {
+        return Ok(false);
+      }
+    }
+    let expires = seconds(expires);
+    log.nonces.insert(nonce.clone(), expires);
+    self.append(log, format!("{} {}", nonce, expires)).await?;
+    Ok(true)
+  }
+
+  async fn remove(&self, nonce: &str) -> anyhow::Result<Option<SystemTime>> {
+    let mut guard = self.log.lock().await;
+    if guard.is_none() {
+      *guard = Some(self.read().await?);
+    }
+    let log = guard.as_mut().unwrap();
+
+    match log.nonces.remove(nonce) {
+      None => Ok(None),
+      Some(expires) => {
+        self.append(log, nonce.to_string()).await?;
+        Ok(Some(UNIX_EPOCH + Duration::from_secs(expires)))
+      }
+    }
+  }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs()
+}
+
+pub struct Nonce {
+  pub value: String,
+  pub expires: SystemTime,
+}
+
+#[derive(Clone)]
+pub struct Nonces {
+  pub lifetime: Duration,
+  pub store: Arc<dyn NonceStore>,
+}
+
+impl Nonces {
+  // None if the store is full.
+  pub async fn issue(&self) -> anyhow::Result<Option<Nonce>> {
+    let value = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let expires = SystemTime::now() + self.lifetime;
+    match self.store.insert(value.clone(), expires).await? {
+      true => Ok(Some(Nonce { value, expires })),
+      false => Ok(None),
+    }
+  }
+
+  // A nonce can only be redeemed once, whether or not it has expired.
+  pub async fn redeem(&self, nonce: &str) -> anyhow::Result<bool> {
+    match self.store.remove(nonce).await? {
+      Some(expires) => Ok(expires > SystemTime::now()),
+      None => Ok(false),
+    }
+  }
+}
+
+pub fn message(challenge: &[u8], nonce: &str) -> Vec<u8> {
+  let mut message = challenge.to_vec();
+  message.extend_from_slice(nonce.as_bytes());
+  message
+}
+
+// Answers requests for a nonce while the store is full.
+pub(crate) fn full() -> Response {
+  log::warn!("too many outstanding nonces, refusing to issue more");
+  let mut response = Response::new(StatusCode::ServiceUnavailable);
+  response.insert_header("Retry-After", "60");
+  response
+}
+
+#[derive(Serialize)]
+struct Challenge {
+  nonce: String,
+  expires: u64,
+  message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  typed_data: Option<serde_json::Value>,
+}
+
+#[derive(Clone)]
+pub struct ProvidesChallenge {
+  pub path: String,
+  pub challenge: Vec<u8>,
+  pub nonces: Nonces,
+  pub typed_data: Option<TypedChallenge>,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesChallenge {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    if request.method() != Method::Get || request.url().path() != self.path {
+      return Ok(next.run(request).await);
+    }
+
+    match self.nonces.issue().await {
+      Err(e) => {
+        log::error!("{:?}", &e);
+        Ok(Response::new(StatusCode::InternalServerError))
+      }
+      Ok(None) => Ok(full()),
+      Ok(Some(nonce)) => {
+        let challenge = Challenge {
+          message: String::from_utf8_lossy(&message(&self.challenge, &nonce.value)).into_owned(),
+          expires: seconds(nonce.expires),
+          typed_data: self
+            .typed_data
+            .as_ref()
+            .map(|typed_data| typed_data.to_json(&self.challenge, Some(&nonce.value))),
+          nonce: nonce.value,
+        };
+        let mut response = Response::new(StatusCode::Ok);
+        response.insert_header("Cache-Control", "no-store");
+        response.set_body(Body::from_json(&challenge)?);
+        Ok(response)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tide::http::{self, Url};
+
+  fn nonces(lifetime: Duration, store: impl NonceStore + 'static) -> Nonces {
+    Nonces {
+      lifetime,
+      store: Arc::new(store),
+    }
+  }
+
+  fn path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+      "niftygate-nonces-{}-{}.log",
+      name,
+      std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+  }
+
+  #[async_std::test]
+  async fn redeems_nonces_once() {
+    let nonces = nonces(Duration::from_secs(60), MemoryNonceStore::default());
+    let nonce = nonces.issue().await.unwrap().unwrap();
+    assert!(nonces.redeem(&nonce.value).await.unwrap());
+    assert!(!nonces.redeem(&nonce.value).await.unwrap());
+  }
+
+  #[async_std::test]
+  async fn rejects_unknown_nonces() {
+    let nonces = nonces(Duration::from_secs(60), MemoryNonceStore::default());
+    assert!(!nonces.redeem("00").await.unwrap());
+  }
+
+  #[async_std::test]
+  async fn rejects_expired_nonces() {
+    let nonces = nonces(Duration::from_secs(0), MemoryNonceStore::default());
+    let nonce = nonces.issue().await.unwrap().unwrap();
+    assert!(!nonces.redeem(&nonce.value).await.unwrap());
+  }
+
+  #[async_std::test]
+  async fn forgets_expired_nonces() {
+    let store = MemoryNonceStore::new(1);
+    let past = SystemTime::now() - Duration::from_secs(1);
+    store.insert(String::from("old"), past).await.unwrap();
+    store
+      .insert(
+        String::from("new"),
+        SystemTime::now() + Duration::from_secs(60),
+      )
+      .await
+      .unwrap();
+    assert_eq!(store.remove("old").await.unwrap(), None);
+  }
+
+  #[async_std::test]
+  async fn refuses_nonces_beyond_capacity() {
+    let nonces = nonces(Duration::from_secs(60), MemoryNonceStore::new(2));
+    let first = nonces.issue().await.unwrap().unwrap();
+    assert!(nonces.issue().await.unwrap().is_some());
+    assert!(nonces.issue().await.unwrap().is_none());
+
+    assert!(nonces.redeem(&first.value).await.unwrap());
+    assert!(nonces.issue().await.unwrap().is_some());
+  }
+
+  #[async_std::test]
+  async fn refuses_nonces_beyond_capacity_in_files() {
+    let path = path("capacity");
+    let issuing = nonces(Duration::from_secs(60), FileNonceStore::new(&path, 1));
+    assert!(issuing.issue().await.unwrap().is_some());
+    assert!(issuing.issue().await.unwrap().is_none());
+
+    // The cap counts the nonces left outstanding from before a restart.
+    let restarted = nonces(Duration::from_secs(60), FileNonceStore::new(&path, 1));
+    assert!(restarted.issue().await.unwrap().is_none());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[async_std::test]
+  async fn keeps_nonces_across_restarts() {
+    let path = path("restart");
+    let nonce = nonces(
+      Duration::from_secs(60),
+      FileNonceStore::new(&path, DEFAULT_NONCE_CAPACITY),
+    )
+    .issue()
+    .await
+    .unwrap()
+    .unwrap();
+
+    let restarted = nonces(
+      Duration::from_secs(60),
+      FileNonceStore::new(&path, DEFAULT_NONCE_CAPACITY),
+    );
+    assert!(restarted.redeem(&nonce.value).await.unwrap());
+    assert!(!restarted.redeem(&nonce.value).await.unwrap());
+
+    // Redeemed nonces stay redeemed after another restart.
+    let restarted = nonces(
+      Duration::from_secs(60),
+      FileNonceStore::new(&path, DEFAULT_NONCE_CAPACITY),
+    );
+    assert!(!restarted.redeem(&nonce.value).await.unwrap());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[async_std::test]
+  async fn appends_to_files_and_compacts_them() {
+    let path = path("compact");
+    let issuing = nonces(
+      Duration::from_secs(60),
+      FileNonceStore::new(&path, DEFAULT_NONCE_CAPACITY),
+    );
+    let kept = issuing.issue().await.unwrap().unwrap();
+    let redeemed = issuing.issue().await.unwrap().unwrap();
+    assert!(issuing.redeem(&redeemed.value).await.unwrap());
+    let lines = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(lines.lines().count(), 3);
+    assert_eq!(lines.lines().last().unwrap(), redeemed.value);
+
+    for _ in 0..COMPACT_AFTER / 2 {
+      let nonce = issuing.issue().await.unwrap().unwrap();
+      assert!(issuing.redeem(&nonce.value).await.unwrap());
+    }
+    let lines = std::fs::read_to_string(&path).unwrap();
+    assert!(lines.lines().count() < COMPACT_AFTER);
+
+    let restarted = nonces(
+      Duration::from_secs(60),
+      FileNonceStore::new(&path, DEFAULT_NONCE_CAPACITY),
+    );
+    assert!(!restarted.redeem(&redeemed.value).await.unwrap());
+    assert!(restarted.redeem(&kept.value).await.unwrap());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[async_std::test]
+  async fn rejects_expired_nonces_from_files() {
+    let path = path("expired");
+    let nonces = nonces(
+      Duration::from_secs(0),
+      FileNonceStore::new(&path, DEFAULT_NONCE_CAPACITY),
+    );
+    let nonce = nonces.issue().await.unwrap().unwrap();
+    assert!(!nonces.redeem(&nonce.value).await.unwrap());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[async_std::test]
+  async fn provides_challenges() {
+    let nonces = nonces(Duration::from_secs(60), MemoryNonceStore::default());
+    let mut server = tide::new();
+    server.with(ProvidesChallenge {
+      path: String::from("/challenge"),
+      challenge: b"Sign in: ".to_vec(),
+      nonces: nonces.clone(),
+      typed_data: None,
+    });
+
+    let request = http::Request::new(Method::Get, Url::parse("http://test/challenge").unwrap());
+    let mut response: http::Response = server.respond(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(response.header("Cache-Control").unwrap(), "no-store");
+
+    let challenge: serde_json::Value = response.body_json().await.unwrap();
+    let nonce = challenge["nonce"].as_str().unwrap();
+    assert_eq!(challenge["message"], format!("Sign in: {}", nonce));
+    assert!(nonces.redeem(nonce).await.unwrap());
+  }
+
+  #[async_std::test]
+  async fn refuses_challenges_while_full() {
+    let mut server = tide::new();
+    server.with(ProvidesChallenge {
+      path: String::from("/challenge"),
+      challenge: b"Sign in: ".to_vec(),
+      nonces: nonces(Duration::from_secs(60), MemoryNonceStore::new(1)),
+      typed_data: None,
+    });
+
+    let challenge =
+      || http::Request::new(Method::Get, Url::parse("http://test/challenge").unwrap());
+    let response: http::Response = server.respond(challenge()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    let response: http::Response = server.respond(challenge()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+    assert_eq!(response.header("Retry-After").unwrap(), "60");
+  }
+}
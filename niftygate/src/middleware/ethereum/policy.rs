@@ -0,0 +1,276 @@
+use super::{erc1155::parse_token_balance, BalanceRequirement, BalanceScale, TokenIds};
+use ethcontract::web3::types::U256;
+use serde::Deserialize;
+use std::fmt;
+use tide::{
+  http::{headers::HeaderName, StatusCode},
+  log,
+  utils::async_trait,
+  Middleware, Next, Request, Response, Result,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalancePolicy {
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub header: HeaderName,
+  pub requirement: BalanceRequirement,
+  pub scale: Option<BalanceScale>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenBalancePolicy {
+  #[serde(deserialize_with = "crate::de::header_name")]
+  pub header: HeaderName,
+  pub token_ids: TokenIds,
+  pub requirement: BalanceRequirement,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "crate::de::Policy")]
+pub enum Policy {
+  All(Vec<Policy>),
+  Any(Vec<Policy>),
+  Not(Box<Policy>),
+  Balance(BalancePolicy),
+  TokenBalance(TokenBalancePolicy),
+}
+
+#[derive(Clone, Debug)]
+pub struct Decision {
+  pub granted: bool,
+  pub branch: String,
+}
+
+impl fmt::Display for Decision {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.granted {
+      true => write!(f, "granted by {}", self.branch),
+      false => write!(f, "denied by {}", self.branch),
+    }
+  }
+}
+
+impl Policy {
+  pub fn headers(&self) -> Vec<&HeaderName> {
+    match self {
+      Policy::All(policies) | Policy::Any(policies) => policies
+        .iter()
+        .flat_map(|policy| policy.headers())
+        .collect(),
+      Policy::Not(policy) => policy.headers(),
+      Policy::Balance(policy) => vec![&policy.header],
+      Policy::TokenBalance(policy) => vec![&policy.header],
+    }
+  }
+
+  pub fn evaluate<State>(&self, request: &Request<State>) -> Decision {
+    self.evaluate_at(request, String::new())
+  }
+
+  fn evaluate_at<State>(&self, request: &Request<State>, path: String) -> Decision {
+    match self {
+      Policy::All(policies) => {
+        let path = format!("{}all", path);
+        for (index, policy) in policies.iter().enumerate() {
+          let decision = policy.evaluate_at(request, format!("{}[{}].", path, index));
+          if !decision.granted {
+            return decision;
+          }
+        }
+        Decision {
+          granted: true,
+          branch: path,
+        }
+      }
+      Policy::Any(policies) => {
+        let path = format!("{}any", path);
+        for (index, policy) in policies.iter().enumerate() {
+          let decision = policy.evaluate_at(request, format!("{}[{}].", path, index));
+          if decision.granted {
+            return decision;
+          }
+        }
+        Decision {
+          granted: false,
+          branch: path,
+        }
+      }
+      Policy::Not(policy) => {
+        let decision = policy.evaluate_at(request, format!("{}not.", path));
+        Decision {
+          granted: !decision.granted,
+          branch: decision.branch,
+        }
+      }
+      Policy::Balance(policy) => {
+        let requirement = match policy.scale {
+          Some(unit) => policy.requirement.clone().scale(unit),
+          None => policy.requirement.clone(),
+        };
+        let granted = balances(request, &policy.header)
+          .iter()
+          .any(|balance| requirement.is_met_by(balance));
+        Decision {
+          granted,
+          branch: format!("{}balance({}: {:?})", path, policy.header, requirement),
+        }
+      }
+      Policy::TokenBalance(policy) => {
+        let granted = token_balances(request, &policy.header)
+          .iter()
+          .any(|(id, balance)| {
+            policy.token_ids.contains(id) && policy.requirement.is_met_by(balance)
+          });
+        Decision {
+          granted,
+          branch: format!(
+            "{}token_balance({}: {:?} of {:?})",
+            path, policy.header, policy.requirement, policy.token_ids
+          ),
+        }
+      }
+    }
+  }
+}
+
+// Missing or malformed headers don't meet any requirement, rather than
+// rejecting the request outright, so they can't short-circuit an "any".
+fn balances<State>(request: &Request<State>, header: &HeaderName) -> Vec<U256> {
+  match request.header(header) {
+    None => vec![],
+    Some(values) => values
+      .iter()
+      .filter_map(|value| U256::from_dec_str(value.as_str()).ok())
+      .collect(),
+  }
+}
+
+fn token_balances<State>(request: &Request<State>, header: &HeaderName) -> Vec<(U256, U256)> {
+  match request.header(header) {
+    None => vec![],
+    Some(values) => values
+      .iter()
+      .filter_map(|value| parse_token_balance(value.as_str()))
+      .collect(),
+  }
+}
+
+#[derive(Clone)]
+pub struct RequiresPolicy {
+  pub decision_header: HeaderName,
+  pub policy: Policy,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequiresPolicy {
+  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    let decision = self.policy.evaluate(&request);
+    log::debug!("Policy: {}", &decision);
+
+    if decision.granted {
+      request.insert_header(&self.decision_header, decision.to_string());
+      Ok(next.run(request).await)
+    } else {
+      let mut response = Response::new(StatusCode::PaymentRequired);
+      response.insert_header(&self.decision_header, decision.to_string());
+      Ok(response)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tide::http::{self, Method, Url};
+
+  const EXAMPLE: &str = r#"
+    any = [
+      { balance = { header = "X-Balance", requirement = { at_least = 1 }, scale = "Ether" } },
+      { all = [
+        { balance = { header = "X-Tickets", requirement = { at_least = 1 } } },
+        { not = { token_balance = { header = "X-Items", token_ids = [3, 4], requirement = { at_least = 1 } } } },
+      ] },
+    ]
+  "#;
+
+  // Returns the status and the decision header, for a request with the given
+  // headers.
+  async fn decide(policy: &str, headers: &[(&str, &str)]) -> (StatusCode, String) {
+    let mut server = tide::new();
+    server.with(RequiresPolicy {
+      decision_header: HeaderName::from("X-Decision"),
+      policy: toml::from_str(policy).unwrap(),
+    });
+    server.at("*").get(|request: Request<()>| async move {
+      let mut response = Response::new(StatusCode::Ok);
+      response.insert_header("X-Decision", request.header("X-Decision").unwrap());
+      Ok(response)
+    });
+
+    let mut request = http::Request::new(Method::Get, Url::parse("http://test/a").unwrap());
+    for (name, value) in headers {
+      request.append_header(*name, *value);
+    }
+    let response: http::Response = server.respond(request).await.unwrap();
+    let decision = response.header("X-Decision").unwrap().last().to_string();
+    (response.status(), decision)
+  }
+
+  #[async_std::test]
+  async fn grants_by_the_first_branch_that_grants_an_any() {
+    let (status, decision) = decide(EXAMPLE, &[("X-Balance", "2000000000000000000")]).await;
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(
+      decision,
+      "granted by any[0].balance(x-balance: AtLeast(1000000000000000000))"
+    );
+  }
+
+  #[async_std::test]
+  async fn grants_by_an_all_when_every_branch_grants() {
+    let (status, decision) = decide(EXAMPLE, &[("X-Tickets", "1"), ("X-Items", "5=1")]).await;
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(decision, "granted by any[1].all");
+  }
+
+  #[async_std::test]
+  async fn denies_by_the_branch_that_denies_an_all() {
+    let (status, decision) = decide(
+      "all = [{ not = { token_balance = { header = \"X-Items\", token_ids = [3, 4], requirement = { at_least = 1 } } } }]",
+      &[("X-Items", "4=2")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::PaymentRequired);
+    assert_eq!(
+      decision,
+      "denied by all[0].not.token_balance(x-items: AtLeast(1) of List([3, 4]))"
+    );
+  }
+
+  #[async_std::test]
+  async fn denies_by_an_any_when_no_branch_grants() {
+    let (status, decision) = decide(EXAMPLE, &[("X-Balance", "1"), ("X-Items", "3=1")]).await;
+    assert_eq!(status, StatusCode::PaymentRequired);
+    assert_eq!(decision, "denied by any");
+  }
+
+  #[async_std::test]
+  async fn ignores_malformed_balances() {
+    let (status, _) = decide(EXAMPLE, &[("X-Balance", "lots"), ("X-Tickets", "-1")]).await;
+    assert_eq!(status, StatusCode::PaymentRequired);
+  }
+
+  #[test]
+  fn lists_headers() {
+    let policy: Policy = toml::from_str(EXAMPLE).unwrap();
+    assert_eq!(policy.headers(), vec!["x-balance", "x-tickets", "x-items"]);
+  }
+
+  #[test]
+  fn needs_exactly_one_kind_of_policy() {
+    assert!(toml::from_str::<Policy>("").is_err());
+    assert!(toml::from_str::<Policy>("all = []\nany = []").is_err());
+  }
+}
@@ -0,0 +1,36 @@
+use niftygate_contract::transport::{redact, EndpointStatus, Failover};
+use serde::Serialize;
+use tide::{
+  http::Method, utils::async_trait, Body, Middleware, Next, Request, Response, Result, StatusCode,
+};
+
+#[derive(Serialize)]
+struct Status {
+  active: String,
+  endpoints: Vec<EndpointStatus>,
+}
+
+// Reports the health of each RPC endpoint, and which one is active.
+#[derive(Clone)]
+pub struct ProvidesRpcStatus {
+  pub path: String,
+  pub failover: Failover,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesRpcStatus {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    if request.method() != Method::Get || request.url().path() != self.path {
+      return Ok(next.run(request).await);
+    }
+
+    let status = Status {
+      active: redact(&self.failover.active()),
+      endpoints: self.failover.status(),
+    };
+    let mut response = Response::new(StatusCode::Ok);
+    response.insert_header("Cache-Control", "no-store");
+    response.set_body(Body::from_json(&status)?);
+    Ok(response)
+  }
+}
@@ -0,0 +1,427 @@
+use super::{
+  account::VerifiedAccount,
+  lookup::{Lookup, Provided},
+};
+use ethcontract::web3::types::Address;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+  collections::BTreeMap,
+  fmt, result,
+  str::FromStr,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tide::{
+  http::{
+    headers::{HeaderName, AUTHORIZATION},
+    StatusCode,
+  },
+  log,
+  utils::async_trait,
+  Middleware, Next, Request, Result,
+};
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionKey {
+  pub id: String,
+  #[serde(deserialize_with = "crate::de::bytes")]
+  pub secret: Vec<u8>,
+}
+
+// Secrets are left out, so keys can be printed along with the rest of the config.
+impl fmt::Debug for SessionKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "SessionKey({:?})", self.id)
+  }
+}
+
+impl FromStr for SessionKey {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s.split_once(':') {
+      Some((id, secret)) if !id.is_empty() => Ok(Self {
+        id: id.to_string(),
+        secret: secret.as_bytes().to_vec(),
+      }),
+      _ => anyhow::bail!("expected a key like id:secret"),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionClaims {
+  pub sub: String,
+  pub aud: String,
+  pub iat: u64,
+  pub exp: u64,
+  pub headers: BTreeMap<String, Vec<String>>,
+}
+
+// The first key signs new tokens, and every key is accepted when verifying
+// them, so keys can be rotated by adding a new key to the front of the list,
+// and removing the old one once its tokens have expired. Tokens are only
+// accepted for the audience (aud) they were signed for.
+#[derive(Clone, Debug)]
+pub struct SessionKeys(pub Vec<SessionKey>);
+
+impl SessionKeys {
+  pub fn sign<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+    let key = self
+      .0
+      .first()
+      .ok_or_else(|| anyhow::anyhow!("no session keys"))?;
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some(key.id.clone());
+    Ok(jsonwebtoken::encode(
+      &header,
+      claims,
+      &EncodingKey::from_secret(&key.secret),
+    )?)
+  }
+
+  pub fn verify<T: DeserializeOwned>(&self, token: &str, audience: &str) -> anyhow::Result<T> {
+    let kid = jsonwebtoken::decode_header(token)?
+      .kid
+      .ok_or_else(|| anyhow::anyhow!("token has no key id"))?;
+    let key = self
+      .0
+      .iter()
+      .find(|key| key.id == kid)
+      .ok_or_else(|| anyhow::anyhow!("unknown key id {:?}", kid))?;
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["exp", "aud"]);
+    let data =
+      jsonwebtoken::decode::<T>(token, &DecodingKey::from_secret(&key.secret), &validation)?;
+    Ok(data.claims)
+  }
+}
+
+#[derive(Clone)]
+pub struct ProvidesSessionTokenVerification {
+  pub address_header: HeaderName,
+  pub audience: String,
+  pub cookie_name: Option<String>,
+  pub keys: SessionKeys,
+}
+
+impl ProvidesSessionTokenVerification {
+  fn token<State>(&self, request: &Request<State>) -> Option<(String, bool)> {
+    if let Some(values) = request.header(AUTHORIZATION) {
+      if let Some(token) = values.last().as_str().strip_prefix("Bearer ") {
+        return Some((token.to_string(), true));
+      }
+    }
+
+    let name = self.cookie_name.as_ref()?;
+    request
+      .cookie(name)
+      .map(|cookie| (cookie.value().to_string(), false))
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesSessionTokenVerification {
+  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    let (token, bearer) = match self.token(&request) {
+      None => return Ok(next.run(request).await),
+      Some(token) => token,
+    };
+
+    let claims: SessionClaims = match self.keys.verify(&token, &self.audience) {
+      Err(e) => {
+        log::debug!("Session Token: {}", e);
+        return Ok(next.run(request).await);
+      }
+      Ok(claims) => claims,
+    };
+
+    let address = match claims.sub.parse::<Address>() {
+      Err(_) => return Ok(next.run(request).await),
+      Ok(address) => address,
+    };
+
+    if bearer {
+      request.remove_header(AUTHORIZATION);
+    }
+
+    request.insert_header(&self.address_header, hex::encode(address));
+    for (name, values) in &claims.headers {
+      request.remove_header(name.as_str());
+      for value in values {
+        request.append_header(name.as_str(), value.as_str());
+      }
+    }
+
+    request.set_ext(VerifiedAccount(address));
+    request.set_ext(claims);
+
+    Ok(next.run(request).await)
+  }
+}
+
+#[derive(Clone)]
+pub struct ProvidesSessionToken {
+  pub address_header: HeaderName,
+  pub audience: String,
+  pub claim_headers: Vec<HeaderName>,
+  pub cookie_name: Option<String>,
+  pub keys: SessionKeys,
+  pub lifetime: Duration,
+  pub token_header: HeaderName,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesSessionToken {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    // Only requests verified by other means get a new token. Requests that
+    // already have one keep using it until it expires.
+    if request.ext::<SessionClaims>().is_some() || request.ext::<VerifiedAccount>().is_none() {
+      return Ok(next.run(request).await);
+    }
+
+    let address = match request.header(&self.address_header) {
+      None => return Ok(next.run(request).await),
+      Some(values) => values.last().as_str().to_string(),
+    };
+
+    let mut headers = BTreeMap::new();
+    for name in &self.claim_headers {
+      if let Some(values) = request.header(name) {
+        headers.insert(
+          name.to_string(),
+          values.iter().map(|value| value.to_string()).collect(),
+        );
+      }
+    }
+
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    let claims = SessionClaims {
+      sub: address,
+      aud: self.audience.clone(),
+      iat: now.as_secs(),
+      exp: (now + self.lifetime).as_secs(),
+      headers,
+    };
+
+    let token = match self.keys.sign(&claims) {
+      Err(e) => {
+        log::error!("{:?}", &e);
+        return Ok(next.run(request).await);
+      }
+      Ok(token) => token,
+    };
+
+    let mut response = next.run(request).await;
+    if response.status().is_success() {
+      response.insert_header(&self.token_header, token.as_str());
+      if let Some(name) = &self.cookie_name {
+        response.append_header(
+          "Set-Cookie",
+          format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            name,
+            token,
+            self.lifetime.as_secs()
+          ),
+        );
+      }
+    }
+
+    Ok(response)
+  }
+}
+
+// Skips the wrapped middleware for requests with a valid session token, since
+// whatever it would have provided was already restored from the token.
+#[derive(Clone)]
+pub struct UnlessSessionToken<M>(pub M);
+
+#[async_trait]
+impl<State, M> Middleware<State> for UnlessSessionToken<M>
+where
+  State: Clone + Send + Sync + 'static,
+  M: Middleware<State>,
+{
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    match request.ext::<SessionClaims>() {
+      Some(_) => Ok(next.run(request).await),
+      None => self.0.handle(request, next).await,
+    }
+  }
+}
+
+#[async_trait]
+impl<State, M> Lookup<State> for UnlessSessionToken<M>
+where
+  State: Clone + Send + Sync + 'static,
+  M: Lookup<State>,
+{
+  async fn lookup(&self, request: &Request<State>) -> result::Result<Provided, StatusCode> {
+    match request.ext::<SessionClaims>() {
+      Some(_) => Ok(vec![]),
+      None => self.0.lookup(request).await,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::ethereum::{ProvidesAccountVerification, ProvidesSignature};
+  use ethcontract::{
+    transport::DynTransport,
+    web3::signing::{Key, SecretKeyRef},
+    Web3,
+  };
+  use niftygate_contract::transport::Http;
+  use secp256k1::SecretKey;
+  use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+  fn keys(ids: &[&str]) -> SessionKeys {
+    SessionKeys(
+      ids
+        .iter()
+        .map(|id| SessionKey {
+          id: id.to_string(),
+          secret: format!("{}-secret", id).into_bytes(),
+        })
+        .collect(),
+    )
+  }
+
+  fn claims() -> SessionClaims {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs();
+    SessionClaims {
+      sub: "0x0000000000000000000000000000000000000001".to_string(),
+      aud: "site.test".to_string(),
+      iat: now,
+      exp: now + 60,
+      headers: BTreeMap::new(),
+    }
+  }
+
+  #[test]
+  fn signs_with_the_first_key() {
+    let token = keys(&["new", "old"]).sign(&claims()).unwrap();
+    let header = jsonwebtoken::decode_header(&token).unwrap();
+    assert_eq!(header.kid.as_deref(), Some("new"));
+  }
+
+  #[test]
+  fn verifies_tokens_from_any_key() {
+    let token = keys(&["old"]).sign(&claims()).unwrap();
+    let verified: SessionClaims = keys(&["new", "old"]).verify(&token, "site.test").unwrap();
+    assert_eq!(verified.sub, claims().sub);
+  }
+
+  #[test]
+  fn rejects_tokens_from_removed_keys() {
+    let token = keys(&["old"]).sign(&claims()).unwrap();
+    assert!(keys(&["new"])
+      .verify::<SessionClaims>(&token, "site.test")
+      .is_err());
+  }
+
+  #[test]
+  fn rejects_tokens_for_other_audiences() {
+    let token = keys(&["test"]).sign(&claims()).unwrap();
+    assert!(keys(&["test"])
+      .verify::<SessionClaims>(&token, "other.test")
+      .is_err());
+
+    let mut claims = serde_json::to_value(claims()).unwrap();
+    claims.as_object_mut().unwrap().remove("aud");
+    let token = keys(&["test"]).sign(&claims).unwrap();
+    assert!(keys(&["test"])
+      .verify::<SessionClaims>(&token, "site.test")
+      .is_err());
+  }
+
+  #[test]
+  fn rejects_tokens_signed_with_another_secret() {
+    let token = keys(&["new"]).sign(&claims()).unwrap();
+    let impostor = SessionKeys(vec![SessionKey {
+      id: "new".to_string(),
+      secret: b"another-secret".to_vec(),
+    }]);
+    assert!(impostor
+      .verify::<SessionClaims>(&token, "site.test")
+      .is_err());
+  }
+
+  #[test]
+  fn parses_keys() {
+    let key: SessionKey = "2022:hunter2".parse().unwrap();
+    assert_eq!(key.id, "2022");
+    assert_eq!(key.secret, b"hunter2");
+    assert!(":hunter2".parse::<SessionKey>().is_err());
+    assert!("hunter2".parse::<SessionKey>().is_err());
+  }
+
+  #[async_std::test]
+  async fn signed_requests_get_a_token() {
+    // Nothing here makes an RPC call, so the node doesn't need to exist.
+    let web3 = Web3::new(DynTransport::new(Http::new(
+      Url::parse("http://127.0.0.1:9").unwrap(),
+    )));
+    let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let address = SecretKeyRef::new(&secret_key).address();
+    let address_header = HeaderName::from("X-Web3-Account-Address");
+    let signature_header = HeaderName::from("X-Web3-Signature");
+    let nonce_header = HeaderName::from("X-Web3-Nonce");
+    let token_header = HeaderName::from("X-Web3-Session-Token");
+
+    let mut server = tide::new();
+    server.with(ProvidesSignature {
+      challenge: b"challenge".to_vec(),
+      nonce_header: nonce_header.clone(),
+      secret_key,
+      signature_header: signature_header.clone(),
+      typed_data: None,
+      web3: web3.clone(),
+    });
+    server.with(ProvidesAccountVerification {
+      address_header: address_header.clone(),
+      challenge: b"challenge".to_vec(),
+      chains: vec![],
+      claimed_address_header: None,
+      code_cache: None,
+      nonce_header,
+      nonces: None,
+      signature_header,
+      status_code: StatusCode::PaymentRequired,
+      typed_data: None,
+      web3,
+    });
+    server.with(ProvidesSessionToken {
+      address_header,
+      audience: "site.test".to_string(),
+      claim_headers: vec![],
+      cookie_name: None,
+      keys: keys(&["test"]),
+      lifetime: Duration::from_secs(60),
+      token_header: token_header.clone(),
+    });
+    server.at("/").get(|_| async { Ok("ok") });
+
+    let request = HttpRequest::new(Method::Get, Url::parse("http://localhost/").unwrap());
+    let response: HttpResponse = server.respond(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+
+    let token = response
+      .header(&token_header)
+      .expect("a session token")
+      .last()
+      .to_string();
+    let claims: SessionClaims = keys(&["test"]).verify(&token, "site.test").unwrap();
+    assert_eq!(claims.sub.parse::<Address>().unwrap(), address);
+  }
+}
@@ -9,14 +9,17 @@ pub mod prelude {
 
 use prelude::*;
 
-use ethcontract::web3::signing::SecretKeyRef;
-use tide::{utils::async_trait, Middleware, Next, Request, Result};
+use super::{nonce, typed_data::TypedChallenge};
+use ethcontract::web3::signing::{Key, SecretKeyRef};
+use tide::{utils::async_trait, Middleware, Next, Request, Response, Result, StatusCode};
 
 #[derive(Clone)]
 pub struct ProvidesSignature {
   pub challenge: Vec<u8>,
+  pub nonce_header: HeaderName,
   pub secret_key: SecretKey,
   pub signature_header: HeaderName,
+  pub typed_data: Option<TypedChallenge>,
   pub web3: DynWeb3,
 }
 
@@ -24,8 +27,34 @@ pub struct ProvidesSignature {
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesSignature {
   async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
     let key = SecretKeyRef::new(&self.secret_key);
-    let signed_data = self.web3.accounts().sign(&self.challenge, key);
-    let signature = signed_data.signature.0;
+    let nonce = request
+      .header(&self.nonce_header)
+      .map(|header_values| header_values.last().as_str().to_string());
+
+    let signature = match &self.typed_data {
+      None => {
+        let challenge = match &nonce {
+          None => self.challenge.clone(),
+          Some(nonce) => nonce::message(&self.challenge, nonce),
+        };
+        self.web3.accounts().sign(&challenge, key).signature.0
+      }
+      Some(typed_data) => {
+        let hash = typed_data.hash(&self.challenge, nonce.as_deref());
+        match key.sign(hash.as_bytes(), None) {
+          Err(e) => {
+            tide::log::error!("{:?}", &e);
+            return Ok(Response::new(StatusCode::InternalServerError));
+          }
+          Ok(signature) => {
+            let mut raw = signature.r.as_bytes().to_vec();
+            raw.extend_from_slice(signature.s.as_bytes());
+            raw.push(signature.v as u8);
+            raw
+          }
+        }
+      }
+    };
 
     request.append_header(&self.signature_header, base64::encode(signature));
 
@@ -0,0 +1,600 @@
+use super::{
+  account::VerifiedAccount,
+  nonce::{self, Nonces},
+};
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, FixedOffset, Utc};
+use ethcontract::{
+  dyns::DynWeb3,
+  web3::{
+    signing::keccak256,
+    types::{Address, Recovery},
+  },
+};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, time::Duration};
+use tide::{
+  http::{headers::HeaderName, Method},
+  log,
+  utils::async_trait,
+  Body, Middleware, Next, Request, Response, Result, StatusCode,
+};
+
+pub const NONCE_PATH: &str = "/.niftygate/nonce";
+pub const LOGIN_PATH: &str = "/.niftygate/login";
+pub const LOGOUT_PATH: &str = "/.niftygate/logout";
+
+const PREAMBLE: &str = " wants you to sign in with your Ethereum account:";
+
+// Clocks disagree a little, so messages issued slightly in the future are
+// still accepted.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+// Messages without an expiration time are only accepted for this long.
+pub const MAX_MESSAGE_AGE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug)]
+pub struct SiweMessage {
+  pub domain: String,
+  pub address: Address,
+  pub statement: Option<String>,
+  pub uri: String,
+  pub version: String,
+  pub chain_id: u64,
+  pub nonce: String,
+  pub issued_at: DateTime<FixedOffset>,
+  pub expiration_time: Option<DateTime<FixedOffset>>,
+  pub not_before: Option<DateTime<FixedOffset>>,
+  pub request_id: Option<String>,
+  pub resources: Vec<String>,
+}
+
+impl FromStr for SiweMessage {
+  type Err = anyhow::Error;
+
+  fn from_str(message: &str) -> anyhow::Result<Self> {
+    let mut lines = message.lines();
+
+    let domain = lines
+      .next()
+      .and_then(|line| line.strip_suffix(PREAMBLE))
+      .ok_or_else(|| anyhow!("missing preamble"))?
+      .to_string();
+
+    // EIP-4361 requires the address to be given with its EIP-55 checksum.
+    let address = lines
+      .next()
+      .and_then(|line| {
+        let address = line.strip_prefix("0x")?.parse::<Address>().ok()?;
+        Some(address).filter(|address| checksummed(address) == line)
+      })
+      .ok_or_else(|| anyhow!("missing, invalid, or unchecksummed address"))?;
+
+    let mut statement = None;
+    let mut uri = None;
+    let mut version = None;
+    let mut chain_id = None;
+    let mut nonce = None;
+    let mut issued_at = None;
+    let mut expiration_time = None;
+    let mut not_before = None;
+    let mut request_id = None;
+    let mut resources = vec![];
+    let mut in_resources = false;
+
+    for line in lines {
+      if line.is_empty() {
+        continue;
+      }
+
+      if in_resources {
+        match line.strip_prefix("- ") {
+          Some(resource) => {
+            resources.push(resource.to_string());
+            continue;
+          }
+          None => bail!("unexpected line after resources: {:?}", line),
+        }
+      }
+
+      if line == "Resources:" {
+        in_resources = true;
+        continue;
+      }
+
+      match line.split_once(": ") {
+        Some(("URI", value)) => uri = Some(value.to_string()),
+        Some(("Version", value)) => version = Some(value.to_string()),
+        Some(("Chain ID", value)) => chain_id = Some(value.parse()?),
+        Some(("Nonce", value)) => nonce = Some(value.to_string()),
+        Some(("Issued At", value)) => issued_at = Some(DateTime::parse_from_rfc3339(value)?),
+        Some(("Expiration Time", value)) => {
+          expiration_time = Some(DateTime::parse_from_rfc3339(value)?)
+        }
+        Some(("Not Before", value)) => not_before = Some(DateTime::parse_from_rfc3339(value)?),
+        Some(("Request ID", value)) => request_id = Some(value.to_string()),
+        _ if statement.is_none() && uri.is_none() => statement = Some(line.to_string()),
+        _ => bail!("unexpected line: {:?}", line),
+      }
+    }
+
+    Ok(Self {
+      domain,
+      address,
+      statement,
+      uri: uri.ok_or_else(|| anyhow!("missing URI"))?,
+      version: version.ok_or_else(|| anyhow!("missing Version"))?,
+      chain_id: chain_id.ok_or_else(|| anyhow!("missing Chain ID"))?,
+      nonce: nonce.ok_or_else(|| anyhow!("missing Nonce"))?,
+      issued_at: issued_at.ok_or_else(|| anyhow!("missing Issued At"))?,
+      expiration_time,
+      not_before,
+      request_id,
+      resources,
+    })
+  }
+}
+
+// The address in mixed case, as given by EIP-55.
+pub fn checksummed(address: &Address) -> String {
+  let hex = hex::encode(address);
+  let hash = keccak256(hex.as_bytes());
+  let mixed: String = hex
+    .chars()
+    .enumerate()
+    .map(
+      |(index, c)| match (hash[index / 2] >> (4 * (1 - index % 2))) & 0xf {
+        8..=15 => c.to_ascii_uppercase(),
+        _ => c,
+      },
+    )
+    .collect();
+  format!("0x{}", mixed)
+}
+
+impl SiweMessage {
+  pub fn validate(&self, domain: &str, uri: Option<&str>, chain_id: u64) -> anyhow::Result<()> {
+    let now = Utc::now();
+
+    if self.version != "1" {
+      bail!("unsupported version {}", self.version);
+    }
+    if self.domain != domain {
+      bail!("domain {} does not match {}", self.domain, domain);
+    }
+    if let Some(uri) = uri {
+      if self.uri != uri {
+        bail!("URI {} does not match {}", self.uri, uri);
+      }
+    }
+    if self.chain_id != chain_id {
+      bail!("chain id {} does not match {}", self.chain_id, chain_id);
+    }
+    let skew = chrono::Duration::from_std(MAX_CLOCK_SKEW)?;
+    if self.issued_at > now + skew {
+      bail!("message is issued in the future, at {}", self.issued_at);
+    }
+    match self.expiration_time {
+      Some(expiration_time) if expiration_time <= now => {
+        bail!("message expired at {}", expiration_time);
+      }
+      Some(_) => (),
+      None => {
+        let max_age = chrono::Duration::from_std(MAX_MESSAGE_AGE)?;
+        if self.issued_at + max_age <= now {
+          bail!(
+            "message was issued at {}, and has no expiration time",
+            self.issued_at
+          );
+        }
+      }
+    }
+    if let Some(not_before) = self.not_before {
+      if not_before > now {
+        bail!("message is not valid before {}", not_before);
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(Deserialize)]
+struct Login {
+  message: String,
+  signature: String,
+}
+
+#[derive(Serialize)]
+struct LoggedIn {
+  address: Address,
+}
+
+#[derive(Clone)]
+pub struct ProvidesSignInWithEthereum {
+  pub address_header: HeaderName,
+  pub chain_id: u64,
+  // The domain messages must be for. This is never taken from the request,
+  // since clients can set the headers it would come from.
+  pub domain: String,
+  pub uri: Option<String>,
+  pub nonces: Nonces,
+  pub session_lifetime: Duration,
+  pub web3: DynWeb3,
+}
+
+impl ProvidesSignInWithEthereum {
+  async fn nonce(&self) -> Result {
+    match self.nonces.issue().await {
+      Err(e) => {
+        log::error!("{:?}", &e);
+        Ok(Response::new(StatusCode::InternalServerError))
+      }
+      Ok(None) => Ok(nonce::full()),
+      Ok(Some(nonce)) => {
+        let mut response = Response::new(StatusCode::Ok);
+        response.insert_header("Cache-Control", "no-store");
+        response.set_body(nonce.value);
+        Ok(response)
+      }
+    }
+  }
+
+  async fn login<State: Clone + Send + Sync + 'static>(
+    &self,
+    mut request: Request<State>,
+  ) -> Result {
+    let login: Login = match request.body_json().await {
+      Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+      Ok(login) => login,
+    };
+
+    let message = match login.message.parse::<SiweMessage>() {
+      Err(e) => return Ok(rejection(StatusCode::BadRequest, e)),
+      Ok(message) => message,
+    };
+
+    if let Err(e) = message.validate(&self.domain, self.uri.as_deref(), self.chain_id) {
+      return Ok(rejection(StatusCode::Unauthorized, e));
+    }
+
+    let signature = match hex::decode(login.signature.trim_start_matches("0x")) {
+      Err(e) => return Ok(rejection(StatusCode::BadRequest, e)),
+      Ok(signature) => signature,
+    };
+
+    let recovery = match Recovery::from_raw_signature(login.message.into_bytes(), signature) {
+      Err(e) => return Ok(rejection(StatusCode::BadRequest, e)),
+      Ok(recovery) => recovery,
+    };
+
+    match self.web3.accounts().recover(recovery) {
+      Ok(address) if address == message.address => (),
+      Ok(_) => {
+        return Ok(rejection(
+          StatusCode::Unauthorized,
+          "signature does not match address",
+        ))
+      }
+      Err(e) => return Ok(rejection(StatusCode::Unauthorized, e)),
+    }
+
+    match self.nonces.redeem(&message.nonce).await {
+      Err(e) => {
+        log::error!("{:?}", &e);
+        return Ok(Response::new(StatusCode::InternalServerError));
+      }
+      Ok(false) => {
+        return Ok(rejection(
+          StatusCode::Unauthorized,
+          "unknown, used, or expired nonce",
+        ))
+      }
+      Ok(true) => (),
+    }
+
+    let lifetime = match message.expiration_time {
+      None => self.session_lifetime,
+      Some(expiration_time) => (expiration_time.with_timezone(&Utc) - Utc::now())
+        .to_std()
+        .unwrap_or_default()
+        .min(self.session_lifetime),
+    };
+
+    let session = request.session_mut();
+    session.regenerate();
+    session.insert("address", hex::encode(message.address))?;
+    session.expire_in(lifetime);
+
+    log::debug!("SIWE: signed in as {:?}", &message.address);
+
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_body(Body::from_json(&LoggedIn {
+      address: message.address,
+    })?);
+    Ok(response)
+  }
+}
+
+fn rejection(status: StatusCode, reason: impl ToString) -> Response {
+  let reason = reason.to_string();
+  log::debug!("SIWE: {}", &reason);
+  let mut response = Response::new(status);
+  response.set_body(reason);
+  response
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvidesSignInWithEthereum {
+  async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
+    match (request.method(), request.url().path()) {
+      (Method::Get, NONCE_PATH) => return self.nonce().await,
+      (Method::Post, LOGIN_PATH) => return self.login(request).await,
+      (Method::Post, LOGOUT_PATH) => {
+        request.session_mut().destroy();
+        return Ok(Response::new(StatusCode::NoContent));
+      }
+      _ => (),
+    }
+
+    let address = request
+      .session()
+      .get::<String>("address")
+      .and_then(|address| address.parse::<Address>().ok());
+
+    if let Some(address) = address {
+      request.set_ext(VerifiedAccount(address));
+      request.insert_header(&self.address_header, hex::encode(address));
+    }
+
+    Ok(next.run(request).await)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::SecondsFormat;
+
+  const ADDRESS: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+
+  fn message(issued_at: DateTime<Utc>, expiration_time: Option<DateTime<Utc>>) -> String {
+    let mut message = format!(
+      "example.com wants you to sign in with your Ethereum account:\n\
+       {}\n\
+       \n\
+       Sign in to the example.\n\
+       \n\
+       URI: https://example.com/login\n\
+       Version: 1\n\
+       Chain ID: 1\n\
+       Nonce: 32891756\n\
+       Issued At: {}",
+      ADDRESS,
+      issued_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+    if let Some(expiration_time) = expiration_time {
+      message.push_str(&format!(
+        "\nExpiration Time: {}",
+        expiration_time.to_rfc3339_opts(SecondsFormat::Secs, true)
+      ));
+    }
+    message
+  }
+
+  fn validate(message: &str) -> anyhow::Result<()> {
+    message
+      .parse::<SiweMessage>()?
+      .validate("example.com", Some("https://example.com/login"), 1)
+  }
+
+  fn minutes(minutes: i64) -> chrono::Duration {
+    chrono::Duration::minutes(minutes)
+  }
+
+  #[test]
+  fn parses_messages() {
+    let text = format!(
+      "{}\nRequest ID: abc\nResources:\n- ipfs://one\n- https://two",
+      message(Utc::now(), Some(Utc::now() + minutes(5)))
+    );
+    let parsed: SiweMessage = text.parse().unwrap();
+    assert_eq!(parsed.domain, "example.com");
+    assert_eq!(parsed.address, ADDRESS[2..].parse().unwrap());
+    assert_eq!(parsed.statement.as_deref(), Some("Sign in to the example."));
+    assert_eq!(parsed.uri, "https://example.com/login");
+    assert_eq!(parsed.version, "1");
+    assert_eq!(parsed.chain_id, 1);
+    assert_eq!(parsed.nonce, "32891756");
+    assert!(parsed.expiration_time.is_some());
+    assert_eq!(parsed.request_id.as_deref(), Some("abc"));
+    assert_eq!(parsed.resources, vec!["ipfs://one", "https://two"]);
+  }
+
+  #[test]
+  fn rejects_malformed_messages() {
+    let valid = message(Utc::now(), None);
+    assert!(valid
+      .replace(PREAMBLE, " wants you to")
+      .parse::<SiweMessage>()
+      .is_err());
+    assert!(valid
+      .replace(ADDRESS, "0x1234")
+      .parse::<SiweMessage>()
+      .is_err());
+    assert!(valid
+      .replace("Nonce", "Once")
+      .parse::<SiweMessage>()
+      .is_err());
+    assert!(valid
+      .replace("Chain ID: 1", "Chain ID: one")
+      .parse::<SiweMessage>()
+      .is_err());
+    assert!(format!("{}\nResources:\nnot a resource", valid)
+      .parse::<SiweMessage>()
+      .is_err());
+  }
+
+  #[test]
+  fn accepts_current_messages() {
+    validate(&message(Utc::now(), None)).unwrap();
+    validate(&message(
+      Utc::now() - minutes(60),
+      Some(Utc::now() + minutes(5)),
+    ))
+    .unwrap();
+  }
+
+  #[test]
+  fn rejects_mismatched_messages() {
+    let text = message(Utc::now(), None);
+    let parsed: SiweMessage = text.parse().unwrap();
+    assert!(parsed.validate("example.org", None, 1).is_err());
+    assert!(parsed
+      .validate("example.com", Some("https://example.com/"), 1)
+      .is_err());
+    assert!(parsed.validate("example.com", None, 5).is_err());
+    assert!(validate(&text.replace("Version: 1", "Version: 2")).is_err());
+  }
+
+  #[test]
+  fn rejects_expired_messages() {
+    assert!(validate(&message(
+      Utc::now() - minutes(10),
+      Some(Utc::now() - minutes(1))
+    ))
+    .is_err());
+  }
+
+  #[test]
+  fn rejects_messages_that_are_not_valid_yet() {
+    let text = format!(
+      "{}\nNot Before: {}",
+      message(Utc::now(), None),
+      (Utc::now() + minutes(5)).to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+    assert!(validate(&text).is_err());
+  }
+
+  #[test]
+  fn rejects_messages_issued_in_the_future() {
+    assert!(validate(&message(Utc::now() + minutes(5), None)).is_err());
+    assert!(validate(&message(
+      Utc::now() + minutes(5),
+      Some(Utc::now() + minutes(10))
+    ))
+    .is_err());
+    // A little clock skew is allowed.
+    validate(&message(Utc::now() + chrono::Duration::seconds(30), None)).unwrap();
+  }
+
+  #[test]
+  fn rejects_old_messages_without_an_expiration_time() {
+    assert!(validate(&message(Utc::now() - minutes(11), None)).is_err());
+    validate(&message(Utc::now() - minutes(9), None)).unwrap();
+  }
+
+  #[test]
+  fn checksums_addresses() {
+    // From the examples in EIP-55.
+    for address in &[
+      "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+      "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+      "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+      "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+    ] {
+      assert_eq!(checksummed(&address[2..].parse().unwrap()), *address);
+    }
+  }
+
+  #[test]
+  fn rejects_addresses_without_a_checksum() {
+    let valid = message(Utc::now(), None);
+    assert!(valid
+      .replace(ADDRESS, &ADDRESS.to_lowercase())
+      .parse::<SiweMessage>()
+      .is_err());
+    assert!(valid
+      .replace(ADDRESS, &ADDRESS.replace('C', "c"))
+      .parse::<SiweMessage>()
+      .is_err());
+  }
+
+  mod login {
+    use super::*;
+    use crate::middleware::ethereum::nonce::MemoryNonceStore;
+    use ethcontract::{
+      transport::DynTransport,
+      web3::signing::{Key, SecretKeyRef},
+      Web3,
+    };
+    use niftygate_contract::transport::Http;
+    use secp256k1::SecretKey;
+    use std::sync::Arc;
+    use tide::{
+      http::{self, Url},
+      sessions::{MemoryStore, SessionMiddleware},
+    };
+
+    // Signs in with a message for domain, sent with the given Host header.
+    async fn login(domain: &str, host: &str) -> StatusCode {
+      // Recovering an address doesn't make an RPC call, so the node doesn't
+      // need to exist.
+      let web3 = Web3::new(DynTransport::new(Http::new(
+        Url::parse("http://127.0.0.1:9").unwrap(),
+      )));
+      let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+      let address = SecretKeyRef::new(&secret_key).address();
+      let nonces = Nonces {
+        lifetime: Duration::from_secs(60),
+        store: Arc::new(MemoryNonceStore::default()),
+      };
+      let nonce = nonces.issue().await.unwrap().unwrap().value;
+
+      let mut server = tide::new();
+      server.with(SessionMiddleware::new(MemoryStore::new(), &[0; 32]));
+      server.with(ProvidesSignInWithEthereum {
+        address_header: HeaderName::from("X-Web3-Account-Address"),
+        chain_id: 1,
+        domain: String::from("example.com"),
+        uri: None,
+        nonces,
+        session_lifetime: Duration::from_secs(60),
+        web3: web3.clone(),
+      });
+
+      let message = message(Utc::now(), None)
+        .replacen("example.com", domain, 1)
+        .replace(ADDRESS, &checksummed(&address))
+        .replace("32891756", &nonce);
+      let signature = web3.accounts().sign(message.as_bytes(), &secret_key);
+      let mut request = http::Request::new(
+        Method::Post,
+        Url::parse(&format!("http://{}{}", host, LOGIN_PATH)).unwrap(),
+      );
+      request.insert_header("Host", host);
+      request.insert_header("X-Forwarded-Host", host);
+      request.set_body(
+        Body::from_json(&serde_json::json!({
+          "message": message,
+          "signature": format!("0x{}", hex::encode(signature.signature.0)),
+        }))
+        .unwrap(),
+      );
+      let response: http::Response = server.respond(request).await.unwrap();
+      response.status()
+    }
+
+    #[async_std::test]
+    async fn signs_in_for_the_configured_domain() {
+      assert_eq!(login("example.com", "example.com").await, StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn rejects_other_domains_whatever_the_host() {
+      assert_eq!(
+        login("phishing.test", "phishing.test").await,
+        StatusCode::Unauthorized
+      );
+    }
+  }
+}
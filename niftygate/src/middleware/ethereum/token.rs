@@ -0,0 +1,148 @@
+use anyhow::{bail, Result};
+use ethcontract::web3::types::U256;
+use serde::Deserialize;
+use std::iter;
+
+// Each id is looked up on every request, so there's a limit on how many a
+// contract can be configured with.
+pub const MAX_TOKEN_IDS: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "crate::de::TokenIds")]
+pub enum TokenIds {
+  Single(U256),
+  List(Vec<U256>),
+  Range(U256, U256),
+}
+
+impl TokenIds {
+  pub fn iter(&self) -> Box<dyn Iterator<Item = U256> + '_> {
+    match self {
+      TokenIds::Single(id) => Box::new(iter::once(*id)),
+      TokenIds::List(ids) => Box::new(ids.iter().copied()),
+      TokenIds::Range(first, last) => {
+        let last = *last;
+        Box::new(
+          iter::successors(Some(*first), move |id| match id < &last {
+            true => Some(id + 1),
+            false => None,
+          })
+          .take_while(move |id| id <= &last),
+        )
+      }
+    }
+  }
+
+  // Saturates, since a range can cover more ids than a U256 can count.
+  pub fn len(&self) -> U256 {
+    match self {
+      TokenIds::Single(_) => U256::one(),
+      TokenIds::List(ids) => U256::from(ids.len()),
+      TokenIds::Range(first, last) if first > last => U256::zero(),
+      TokenIds::Range(first, last) => (last - first).saturating_add(U256::one()),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len().is_zero()
+  }
+
+  pub fn contains(&self, id: &U256) -> bool {
+    match self {
+      TokenIds::Single(single) => single == id,
+      TokenIds::List(ids) => ids.contains(id),
+      TokenIds::Range(first, last) => first <= id && id <= last,
+    }
+  }
+
+  pub fn validate(&self, label: &str) -> Result<()> {
+    if let TokenIds::Range(first, last) = self {
+      if first > last {
+        bail!(
+          "{} range starts at {}, after it ends at {}",
+          label,
+          first,
+          last
+        );
+      }
+    }
+    if self.len() > U256::from(MAX_TOKEN_IDS) {
+      bail!(
+        "{} has {} ids, but at most {} can be checked",
+        label,
+        self.len(),
+        MAX_TOKEN_IDS
+      );
+    }
+    Ok(())
+  }
+}
+
+impl Default for TokenIds {
+  fn default() -> Self {
+    TokenIds::Single(U256::zero())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ids(token_ids: &TokenIds) -> Vec<u64> {
+    token_ids.iter().map(|id| id.as_u64()).collect()
+  }
+
+  #[test]
+  fn iterates_over_ids() {
+    assert_eq!(ids(&TokenIds::Single(7.into())), vec![7]);
+    assert_eq!(ids(&TokenIds::List(vec![9.into(), 7.into()])), vec![9, 7]);
+    assert_eq!(
+      ids(&TokenIds::Range(5.into(), 9.into())),
+      vec![5, 6, 7, 8, 9]
+    );
+    assert_eq!(ids(&TokenIds::Range(5.into(), 5.into())), vec![5]);
+    assert!(ids(&TokenIds::Range(9.into(), 5.into())).is_empty());
+  }
+
+  #[test]
+  fn iterates_up_to_the_largest_id() {
+    let token_ids = TokenIds::Range(U256::MAX - 1, U256::MAX);
+    assert_eq!(
+      token_ids.iter().collect::<Vec<U256>>(),
+      vec![U256::MAX - 1, U256::MAX]
+    );
+  }
+
+  #[test]
+  fn counts_ids_without_listing_them() {
+    assert_eq!(TokenIds::Range(5.into(), 9.into()).len(), 5.into());
+    assert_eq!(TokenIds::Range(9.into(), 5.into()).len(), U256::zero());
+    assert_eq!(TokenIds::Range(U256::zero(), U256::MAX).len(), U256::MAX);
+  }
+
+  #[test]
+  fn contains_ids_in_ranges() {
+    let token_ids = TokenIds::Range(5.into(), 9.into());
+    assert!(token_ids.contains(&5.into()));
+    assert!(token_ids.contains(&9.into()));
+    assert!(!token_ids.contains(&4.into()));
+    assert!(!token_ids.contains(&10.into()));
+  }
+
+  #[test]
+  fn rejects_backwards_ranges() {
+    assert!(TokenIds::Range(9.into(), 5.into())
+      .validate("test")
+      .is_err());
+  }
+
+  #[test]
+  fn rejects_too_many_ids() {
+    let last = U256::from(MAX_TOKEN_IDS);
+    assert!(TokenIds::Range(1.into(), last).validate("test").is_ok());
+    assert!(TokenIds::Range(0.into(), last).validate("test").is_err());
+    assert!(TokenIds::Range(0.into(), U256::exp10(18))
+      .validate("test")
+      .is_err());
+  }
+}
@@ -0,0 +1,271 @@
+use ethcontract::web3::{
+  signing::keccak256,
+  types::{Address, H256, U256},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// The challenge (and nonce, if there is one) are signed as an EIP-712 struct
+// like `Challenge(string challenge,string nonce)`, within the configured domain.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TypedChallenge {
+  pub name: Option<String>,
+  pub version: Option<String>,
+  pub chain_id: Option<u64>,
+  pub verifying_contract: Option<Address>,
+  pub primary_type: String,
+  pub challenge_field: String,
+  pub nonce_field: String,
+}
+
+impl Default for TypedChallenge {
+  fn default() -> Self {
+    Self {
+      name: Some(String::from("niftygate")),
+      version: Some(String::from("1")),
+      chain_id: None,
+      verifying_contract: None,
+      primary_type: String::from("Challenge"),
+      challenge_field: String::from("challenge"),
+      nonce_field: String::from("nonce"),
+    }
+  }
+}
+
+impl TypedChallenge {
+  fn domain_fields(&self) -> Vec<(&'static str, &'static str)> {
+    let mut fields = vec![];
+    if self.name.is_some() {
+      fields.push(("name", "string"));
+    }
+    if self.version.is_some() {
+      fields.push(("version", "string"));
+    }
+    if self.chain_id.is_some() {
+      fields.push(("chainId", "uint256"));
+    }
+    if self.verifying_contract.is_some() {
+      fields.push(("verifyingContract", "address"));
+    }
+    fields
+  }
+
+  fn message_fields(&self, nonce: Option<&str>) -> Vec<(&str, &'static str)> {
+    let mut fields = vec![(self.challenge_field.as_str(), "string")];
+    if nonce.is_some() {
+      fields.push((self.nonce_field.as_str(), "string"));
+    }
+    fields
+  }
+
+  fn type_hash(name: &str, fields: &[(&str, &str)]) -> [u8; 32] {
+    let fields = fields
+      .iter()
+      .map(|(name, kind)| format!("{} {}", kind, name))
+      .collect::<Vec<_>>()
+      .join(",");
+    keccak256(format!("{}({})", name, fields).as_bytes())
+  }
+
+  fn domain_separator(&self) -> [u8; 32] {
+    let mut encoded = Self::type_hash("EIP712Domain", &self.domain_fields()).to_vec();
+    if let Some(name) = &self.name {
+      encoded.extend_from_slice(&keccak256(name.as_bytes()));
+    }
+    if let Some(version) = &self.version {
+      encoded.extend_from_slice(&keccak256(version.as_bytes()));
+    }
+    if let Some(chain_id) = self.chain_id {
+      let mut word = [0; 32];
+      U256::from(chain_id).to_big_endian(&mut word);
+      encoded.extend_from_slice(&word);
+    }
+    if let Some(verifying_contract) = &self.verifying_contract {
+      encoded.extend_from_slice(&[0; 12]);
+      encoded.extend_from_slice(verifying_contract.as_bytes());
+    }
+    keccak256(&encoded)
+  }
+
+  pub fn hash(&self, challenge: &[u8], nonce: Option<&str>) -> H256 {
+    let mut encoded = Self::type_hash(&self.primary_type, &self.message_fields(nonce)).to_vec();
+    encoded.extend_from_slice(&keccak256(challenge));
+    if let Some(nonce) = nonce {
+      encoded.extend_from_slice(&keccak256(nonce.as_bytes()));
+    }
+    self.digest(keccak256(&encoded))
+  }
+
+  // What gets signed, for a struct (given by its hashStruct) in this domain.
+  fn digest(&self, message: [u8; 32]) -> H256 {
+    let mut digest = vec![0x19, 0x01];
+    digest.extend_from_slice(&self.domain_separator());
+    digest.extend_from_slice(&message);
+    H256(keccak256(&digest))
+  }
+
+  // The typed data, as given to `eth_signTypedData_v4`.
+  pub fn to_json(&self, challenge: &[u8], nonce: Option<&str>) -> Value {
+    let mut domain = json!({});
+    if let Some(name) = &self.name {
+      domain["name"] = json!(name);
+    }
+    if let Some(version) = &self.version {
+      domain["version"] = json!(version);
+    }
+    if let Some(chain_id) = self.chain_id {
+      domain["chainId"] = json!(chain_id);
+    }
+    if let Some(verifying_contract) = &self.verifying_contract {
+      domain["verifyingContract"] = json!(verifying_contract);
+    }
+
+    let mut message = json!({});
+    message[&self.challenge_field] = json!(String::from_utf8_lossy(challenge));
+    if let Some(nonce) = nonce {
+      message[&self.nonce_field] = json!(nonce);
+    }
+
+    let fields = |fields: Vec<(&str, &str)>| {
+      fields
+        .into_iter()
+        .map(|(name, kind)| json!({ "name": name, "type": kind }))
+        .collect::<Vec<_>>()
+    };
+
+    json!({
+      "types": {
+        "EIP712Domain": fields(self.domain_fields()),
+        &self.primary_type: fields(self.message_fields(nonce)),
+      },
+      "primaryType": self.primary_type,
+      "domain": domain,
+      "message": message,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use ethcontract::web3::signing::{Key, SecretKeyRef};
+  use secp256k1::SecretKey;
+
+  fn h256(hex: &str) -> H256 {
+    hex.parse().unwrap()
+  }
+
+  // The "Ether Mail" domain from the example in EIP-712.
+  fn ether_mail() -> TypedChallenge {
+    TypedChallenge {
+      name: Some(String::from("Ether Mail")),
+      version: Some(String::from("1")),
+      chain_id: Some(1),
+      verifying_contract: Some(
+        "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+          .parse()
+          .unwrap(),
+      ),
+      ..TypedChallenge::default()
+    }
+  }
+
+  #[test]
+  fn hashes_domains_like_the_eip712_example() {
+    assert_eq!(
+      H256(ether_mail().domain_separator()),
+      h256("0xf2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f")
+    );
+  }
+
+  #[test]
+  fn signs_like_the_eip712_example() {
+    // The Mail struct from the example, which nests a Person type, so its
+    // hashStruct is encoded here rather than by TypedChallenge.
+    let person = |name: &str, wallet: &str| {
+      let mut encoded = keccak256(b"Person(string name,address wallet)").to_vec();
+      encoded.extend_from_slice(&keccak256(name.as_bytes()));
+      encoded.extend_from_slice(&[0; 12]);
+      encoded.extend_from_slice(wallet.parse::<Address>().unwrap().as_bytes());
+      keccak256(&encoded)
+    };
+    let mut mail =
+      keccak256(b"Mail(Person from,Person to,string contents)Person(string name,address wallet)")
+        .to_vec();
+    mail.extend_from_slice(&person("Cow", "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826"));
+    mail.extend_from_slice(&person("Bob", "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB"));
+    mail.extend_from_slice(&keccak256(b"Hello, Bob!"));
+    let mail = keccak256(&mail);
+    assert_eq!(
+      H256(mail),
+      h256("0xc52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e")
+    );
+
+    let digest = ether_mail().digest(mail);
+    assert_eq!(
+      digest,
+      h256("0xbe609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+    );
+
+    // Cow's key is keccak256("cow"), and signing the digest with it gives the
+    // signature in the example, as eth_signTypedData_v4 would.
+    let secret_key = SecretKey::from_slice(&keccak256(b"cow")).unwrap();
+    let signature = SecretKeyRef::new(&secret_key)
+      .sign(digest.as_bytes(), None)
+      .unwrap();
+    assert_eq!(signature.v, 28);
+    assert_eq!(
+      signature.r,
+      h256("0x4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d")
+    );
+    assert_eq!(
+      signature.s,
+      h256("0x07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b91562")
+    );
+  }
+
+  #[test]
+  fn hashes_challenges() {
+    // Following the encoding in EIP-712, as checked against its example above.
+    let typed_data = TypedChallenge {
+      chain_id: Some(1),
+      ..TypedChallenge::default()
+    };
+    assert_eq!(
+      typed_data.hash(b"Sign in", Some("0123")),
+      h256("0x88971a63a13d111d1d4a05ad88411872463edb956a7b807b8054b60b54655692")
+    );
+    assert_ne!(
+      typed_data.hash(b"Sign in", None),
+      typed_data.hash(b"Sign in", Some("0123"))
+    );
+  }
+
+  #[test]
+  fn describes_what_is_hashed() {
+    let typed_data = TypedChallenge {
+      chain_id: Some(1),
+      ..TypedChallenge::default()
+    };
+    assert_eq!(
+      typed_data.to_json(b"Sign in", Some("0123")),
+      json!({
+        "types": {
+          "EIP712Domain": [
+            { "name": "name", "type": "string" },
+            { "name": "version", "type": "string" },
+            { "name": "chainId", "type": "uint256" },
+          ],
+          "Challenge": [
+            { "name": "challenge", "type": "string" },
+            { "name": "nonce", "type": "string" },
+          ],
+        },
+        "primaryType": "Challenge",
+        "domain": { "name": "niftygate", "version": "1", "chainId": 1 },
+        "message": { "challenge": "Sign in", "nonce": "0123" },
+      })
+    );
+  }
+}
@@ -0,0 +1,206 @@
+pub mod prelude {
+  pub use tide::{
+    http::{headers, headers::HeaderName, StatusCode},
+    Server,
+  };
+}
+
+use prelude::*;
+
+use std::str::FromStr;
+use tide::{
+  http::{self, Method, Url},
+  utils::async_trait,
+  Endpoint, Middleware, Next, Request, Response, Result,
+};
+
+pub const X_FORWARDED_METHOD: &str = "X-Forwarded-Method";
+pub const X_FORWARDED_URI: &str = "X-Forwarded-Uri";
+pub const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+pub const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+
+// Answers auth requests from another proxy (nginx auth_request, Traefik
+// ForwardAuth, Caddy forward_auth). The request is rewritten to look like the
+// original one before it is routed, so routes and token paths still apply.
+// The proxy must set every X-Forwarded-* header itself, since clients can send
+// them too.
+#[derive(Clone)]
+pub struct ForwardAuth {
+  pub server: Server<()>,
+  pub denied_status: StatusCode,
+}
+
+fn rewrite(request: &mut http::Request) -> Result<()> {
+  if let Some(method) = request.header(X_FORWARDED_METHOD) {
+    let method = Method::from_str(method.as_str())?;
+    request.set_method(method);
+  }
+
+  let uri = request
+    .header(X_FORWARDED_URI)
+    .map(|uri| uri.as_str().to_owned());
+
+  // Only paths are accepted, so the original URI can't point somewhere else.
+  if let Some(uri) = uri {
+    if uri.starts_with('/') && !uri.starts_with("//") {
+      let url = request.url().join(&uri)?;
+      *request.url_mut() = url;
+    }
+  }
+
+  // Parsed as an authority, since IPv6 addresses are full of colons.
+  if let Some(host) = request
+    .header(X_FORWARDED_HOST)
+    .map(|host| host.as_str().to_owned())
+  {
+    let authority = Url::parse(&format!("http://{}", host))?;
+    request.url_mut().set_host(authority.host_str())?;
+    request.insert_header(headers::HOST, host);
+  }
+
+  if let Some(proto) = request
+    .header(X_FORWARDED_PROTO)
+    .map(|proto| proto.as_str().to_owned())
+  {
+    // set_scheme only fails for schemes that can't replace http(s).
+    let _ = request.url_mut().set_scheme(&proto);
+  }
+
+  Ok(())
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ForwardAuth {
+  async fn handle(&self, request: Request<State>, _next: Next<'_, State>) -> Result {
+    let mut request: http::Request = request.into();
+    if rewrite(&mut request).is_err() {
+      return Ok(Response::new(StatusCode::BadRequest));
+    }
+
+    let mut response: http::Response = self.server.respond(request).await?;
+    match response.status() {
+      StatusCode::NetworkAuthenticationRequired => response.set_status(StatusCode::Unauthorized),
+      StatusCode::PaymentRequired => response.set_status(self.denied_status),
+      _ => (),
+    }
+
+    Ok(response.into())
+  }
+}
+
+// Allows the request, and hands the identity headers added by the Provides*
+// middleware back to the proxy that asked.
+#[derive(Clone)]
+pub struct ProvidesIdentity {
+  pub headers: Vec<HeaderName>,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for ProvidesIdentity {
+  async fn call(&self, request: Request<State>) -> Result {
+    let mut response = Response::new(StatusCode::Ok);
+    for header in &self.headers {
+      if let Some(values) = request.header(header) {
+        response.insert_header(header, values);
+      }
+    }
+    Ok(response)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Answers with what it was asked for, or with the status in X-Status.
+  fn forward_auth() -> ForwardAuth {
+    let mut server = tide::new();
+    server.at("*").all(|request: Request<()>| async move {
+      if let Some(status) = request.header("X-Status") {
+        let status: u16 = status.as_str().parse()?;
+        return Ok(Response::new(status));
+      }
+      let host = request.header(headers::HOST).unwrap().as_str().to_owned();
+      Ok(Response::from(format!(
+        "{} {} {}",
+        request.method(),
+        request.url(),
+        host
+      )))
+    });
+    ForwardAuth {
+      server,
+      denied_status: StatusCode::Forbidden,
+    }
+  }
+
+  async fn auth(headers: &[(&str, &str)]) -> http::Response {
+    let mut outer = tide::new();
+    outer.with(forward_auth());
+    let mut request =
+      http::Request::new(Method::Get, Url::parse("http://auth.local/auth").unwrap());
+    request.insert_header(headers::HOST, "auth.local");
+    for (name, value) in headers {
+      request.insert_header(*name, *value);
+    }
+    outer.respond(request).await.unwrap()
+  }
+
+  #[async_std::test]
+  async fn rewrites_requests_from_forwarded_headers() {
+    let mut response = auth(&[
+      (X_FORWARDED_METHOD, "POST"),
+      (X_FORWARDED_URI, "/things?id=1"),
+      (X_FORWARDED_HOST, "site.test:8443"),
+      (X_FORWARDED_PROTO, "https"),
+    ])
+    .await;
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(
+      response.body_string().await.unwrap(),
+      "POST https://site.test/things?id=1 site.test:8443"
+    );
+  }
+
+  #[async_std::test]
+  async fn rewrites_ipv6_hosts() {
+    let mut response = auth(&[(X_FORWARDED_HOST, "[::1]:8443")]).await;
+    assert_eq!(
+      response.body_string().await.unwrap(),
+      "GET http://[::1]/auth [::1]:8443"
+    );
+  }
+
+  #[async_std::test]
+  async fn only_accepts_paths() {
+    for uri in ["//other.test/things", "http://other.test/things", "things"] {
+      let mut response = auth(&[(X_FORWARDED_URI, uri)]).await;
+      assert_eq!(
+        response.body_string().await.unwrap(),
+        "GET http://auth.local/auth auth.local"
+      );
+    }
+  }
+
+  #[async_std::test]
+  async fn rejects_malformed_headers() {
+    let response = auth(&[(X_FORWARDED_METHOD, "NOT A METHOD")]).await;
+    assert_eq!(response.status(), StatusCode::BadRequest);
+    let response = auth(&[(X_FORWARDED_HOST, "site.test:port")]).await;
+    assert_eq!(response.status(), StatusCode::BadRequest);
+  }
+
+  #[async_std::test]
+  async fn maps_statuses_for_the_proxy() {
+    let cases = [
+      ("511", StatusCode::Unauthorized),
+      ("402", StatusCode::Forbidden),
+      ("401", StatusCode::Unauthorized),
+      ("200", StatusCode::Ok),
+      ("500", StatusCode::InternalServerError),
+    ];
+    for (status, expected) in cases {
+      assert_eq!(auth(&[("X-Status", status)]).await.status(), expected);
+    }
+  }
+}
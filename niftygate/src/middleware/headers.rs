@@ -4,6 +4,11 @@ pub mod prelude {
 
 use prelude::*;
 
+use std::{
+  fmt,
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+};
 use tide::{
   http::proxies::Forwarded, utils::async_trait, Middleware, Next, Request, Response, Result,
 };
@@ -53,18 +58,187 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequiresHeaders
   }
 }
 
+// An address, or a block of them (like 10.0.0.0/8).
+#[derive(Clone, Copy, PartialEq)]
+pub struct Network {
+  address: IpAddr,
+  prefix: u8,
+}
+
+impl Network {
+  pub fn contains(&self, address: IpAddr) -> bool {
+    match (self.address, address) {
+      (IpAddr::V4(network), IpAddr::V4(address)) => {
+        mask(u32::from(network).into(), 32, self.prefix)
+          == mask(u32::from(address).into(), 32, self.prefix)
+      }
+      (IpAddr::V6(network), IpAddr::V6(address)) => {
+        mask(network.into(), 128, self.prefix) == mask(address.into(), 128, self.prefix)
+      }
+      _ => false,
+    }
+  }
+}
+
+fn mask(address: u128, bits: u8, prefix: u8) -> u128 {
+  match prefix {
+    0 => 0,
+    _ => address >> (bits - prefix),
+  }
+}
+
+impl fmt::Display for Network {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.address, self.prefix)
+  }
+}
+
+impl fmt::Debug for Network {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Network({:?})", self.to_string())
+  }
+}
+
+impl FromStr for Network {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    let (address, prefix) = match s.split_once('/') {
+      Some((address, prefix)) => (address, Some(prefix)),
+      None => (s, None),
+    };
+    let address = IpAddr::from_str(address)?;
+    let bits = match address {
+      IpAddr::V4(_) => 32,
+      IpAddr::V6(_) => 128,
+    };
+    let prefix = match prefix {
+      Some(prefix) => u8::from_str(prefix)?,
+      None => bits,
+    };
+    if prefix > bits {
+      anyhow::bail!("{} is not a valid network", s);
+    }
+    Ok(Self { address, prefix })
+  }
+}
+
+// Clients can send any header they like, including the ones the Provides*
+// middleware add, so those are removed before anything can trust them. Requests
+// from trusted hops (like another proxy that removes or sets them itself) are
+// left alone.
 #[derive(Clone)]
 pub struct RemovesHeaders {
-  headers: Vec<HeaderName>,
+  pub headers: Vec<HeaderName>,
+  pub trusted_hops: Vec<Network>,
+}
+
+impl RemovesHeaders {
+  fn is_trusted<State>(&self, request: &Request<State>) -> bool {
+    match request
+      .peer_addr()
+      .and_then(|peer| SocketAddr::from_str(peer).ok())
+    {
+      Some(peer) => {
+        // IPv4 clients of an IPv6 listener show up as ::ffff:a.b.c.d.
+        let address = match peer.ip() {
+          IpAddr::V6(address) => address
+            .to_ipv4_mapped()
+            .map_or(IpAddr::V6(address), IpAddr::V4),
+          address => address,
+        };
+        self.trusted_hops.iter().any(|hop| hop.contains(address))
+      }
+      None => false,
+    }
+  }
 }
 
 #[async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for RemovesHeaders {
   async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> Result {
-    for header in &self.headers {
-      request.remove_header(header);
+    if !self.is_trusted(&request) {
+      for header in &self.headers {
+        request.remove_header(header);
+      }
     }
 
     Ok(next.run(request).await)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tide::http::{self, Method, Url};
+
+  fn network(s: &str) -> Network {
+    s.parse().unwrap()
+  }
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn contains_addresses_in_the_block() {
+    assert!(network("10.0.0.0/8").contains(ip("10.255.0.1")));
+    assert!(!network("10.0.0.0/8").contains(ip("11.0.0.1")));
+    assert!(network("192.168.1.7").contains(ip("192.168.1.7")));
+    assert!(!network("192.168.1.7").contains(ip("192.168.1.8")));
+    assert!(network("fd00::/8").contains(ip("fd12:3456::1")));
+    assert!(!network("fd00::/8").contains(ip("fe80::1")));
+  }
+
+  #[test]
+  fn contains_everything_with_an_empty_prefix() {
+    assert!(network("0.0.0.0/0").contains(ip("203.0.113.9")));
+    assert!(network("::/0").contains(ip("2001:db8::1")));
+  }
+
+  #[test]
+  fn keeps_address_families_apart() {
+    assert!(!network("0.0.0.0/0").contains(ip("::1")));
+    assert!(!network("::/0").contains(ip("127.0.0.1")));
+  }
+
+  #[test]
+  fn parses_networks() {
+    assert_eq!(network("10.1.2.3/8").to_string(), "10.1.2.3/8");
+    assert_eq!(network("::1").to_string(), "::1/128");
+    assert!("10.0.0.0/33".parse::<Network>().is_err());
+    assert!("::/129".parse::<Network>().is_err());
+    assert!("10.0.0.0/eight".parse::<Network>().is_err());
+    assert!("localhost".parse::<Network>().is_err());
+  }
+
+  async fn secret(peer: Option<&str>) -> String {
+    let mut server = tide::new();
+    server.with(RemovesHeaders {
+      headers: vec![HeaderName::from("X-Secret")],
+      trusted_hops: vec![network("10.0.0.0/8")],
+    });
+    server.at("*").get(|request: Request<()>| async move {
+      Ok(
+        request
+          .header("X-Secret")
+          .map(|values| values.last().to_string())
+          .unwrap_or_default(),
+      )
+    });
+
+    let mut request = http::Request::new(Method::Get, Url::parse("http://test/a").unwrap());
+    request.set_peer_addr(peer);
+    request.insert_header("X-Secret", "yes");
+    let mut response: http::Response = server.respond(request).await.unwrap();
+    response.body_string().await.unwrap()
+  }
+
+  #[async_std::test]
+  async fn removes_headers_unless_the_peer_is_trusted() {
+    assert_eq!(secret(Some("10.0.0.2:4000")).await, "yes");
+    assert_eq!(secret(Some("[::ffff:10.0.0.2]:4000")).await, "yes");
+    assert_eq!(secret(Some("192.0.2.1:4000")).await, "");
+    assert_eq!(secret(None).await, "");
+  }
+}
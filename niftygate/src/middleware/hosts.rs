@@ -0,0 +1,116 @@
+pub mod prelude {
+  pub use tide::{http::headers, Server};
+}
+
+use prelude::*;
+
+use std::collections::HashMap;
+use tide::{
+  http::{self, Url},
+  utils::async_trait,
+  Middleware, Next, Request, Result,
+};
+
+// Hands requests off to the server for their Host (ignoring the port). Other
+// requests, including those with a malformed Host, carry on through this server.
+#[derive(Clone)]
+pub struct VirtualHosts {
+  pub hosts: HashMap<String, Server<()>>,
+}
+
+impl VirtualHosts {
+  fn server_for<State>(&self, request: &Request<State>) -> Option<&Server<()>> {
+    // The Host header is an authority (host and port), which IPv6 addresses
+    // (like [::1]:8443) are full of colons in, so it's parsed like one.
+    let url = match request.header(headers::HOST) {
+      Some(host) => Url::parse(&format!("http://{}", host.as_str())).ok()?,
+      None => request.url().clone(),
+    };
+    if !url.username().is_empty() || url.path() != "/" || url.query().is_some() {
+      return None;
+    }
+
+    // Parsing lowercases names, and keeps IPv6 addresses in brackets, which
+    // hosts are named without.
+    let host = url.host_str()?;
+    self
+      .hosts
+      .get(host.trim_start_matches('[').trim_end_matches(']'))
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for VirtualHosts {
+  async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+    match self.server_for(&request) {
+      None => Ok(next.run(request).await),
+      Some(server) => {
+        let request: http::Request = request.into();
+        let response: http::Response = server.respond(request).await?;
+        Ok(response.into())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tide::http::{Method, StatusCode};
+
+  fn site(name: &'static str) -> Server<()> {
+    let mut site = tide::new();
+    site.at("/").get(move |_| async move { Ok(name) });
+    site
+  }
+
+  fn server() -> Server<()> {
+    let mut server = tide::new();
+    server.with(VirtualHosts {
+      hosts: vec![
+        (String::from("site.test"), site("site")),
+        (String::from("::1"), site("ipv6")),
+      ]
+      .into_iter()
+      .collect(),
+    });
+    server.at("/").get(|_| async { Ok("default") });
+    server
+  }
+
+  async fn get(host: Option<&str>) -> String {
+    let mut request = http::Request::new(Method::Get, Url::parse("http://other.test/").unwrap());
+    if let Some(host) = host {
+      request.insert_header(headers::HOST, host);
+    }
+    let mut response: http::Response = server().respond(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    response.body_string().await.unwrap()
+  }
+
+  #[async_std::test]
+  async fn dispatches_by_host() {
+    assert_eq!(get(Some("site.test")).await, "site");
+    assert_eq!(get(Some("SITE.test:8443")).await, "site");
+  }
+
+  #[async_std::test]
+  async fn dispatches_by_ipv6_address() {
+    assert_eq!(get(Some("[::1]:8443")).await, "ipv6");
+    assert_eq!(get(Some("[::1]")).await, "ipv6");
+    assert_eq!(get(Some("[::2]:8443")).await, "default");
+  }
+
+  #[async_std::test]
+  async fn serves_unknown_hosts_itself() {
+    assert_eq!(get(Some("other.test")).await, "default");
+    assert_eq!(get(None).await, "default");
+  }
+
+  #[async_std::test]
+  async fn serves_malformed_hosts_itself() {
+    assert_eq!(get(Some("evil@site.test")).await, "default");
+    assert_eq!(get(Some("site.test/path")).await, "default");
+    assert_eq!(get(Some("site.test:port")).await, "default");
+  }
+}
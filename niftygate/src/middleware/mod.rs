@@ -1,14 +1,19 @@
 pub mod authorization;
 pub mod ethereum;
+pub mod forward_auth;
 pub mod headers;
+pub mod hosts;
 pub mod proxy;
 
 pub mod prelude {
   pub use super::authorization::prelude::*;
   pub use super::headers::prelude::*;
+  pub use super::hosts::prelude::*;
   pub use super::proxy::prelude::*;
 }
 
 pub use authorization::RequiresAuthorization;
-pub use headers::{ProvidesForwardedHeader, RemovesHeaders, RequiresHeaders};
-pub use proxy::Proxy;
+pub use forward_auth::{ForwardAuth, ProvidesIdentity};
+pub use headers::{Network, ProvidesForwardedHeader, RemovesHeaders, RequiresHeaders};
+pub use hosts::VirtualHosts;
+pub use proxy::{Pool, Proxy};
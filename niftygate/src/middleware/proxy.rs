@@ -5,12 +5,66 @@ pub mod prelude {
 
 use prelude::*;
 
-use tide::{http, utils::async_trait, Middleware, Next, Request, Result};
+pub mod pool;
 
+pub use pool::{Lease, Pool, PoolOptions, Strategy};
+
+use super::ethereum::ProvidesIdentityAssertion;
+use async_std::{net::TcpStream, task};
+use async_tls::TlsConnector;
+use futures::{
+  future::{self, Either},
+  io::{self, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, Cursor},
+};
+use std::{
+  pin::Pin,
+  str::FromStr,
+  task::{Context, Poll},
+};
+use tide::{
+  http::{self, cookies::Cookie, upgrade, Body, StatusCode},
+  utils::async_trait,
+  Endpoint, Error, Middleware, Next, Request, Result,
+};
+
+// async-h1 won't read a response head longer than this either.
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+
+// RFC 7230, section 6.1. Proxy-Connection isn't standard, but is still sent.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+  "Connection",
+  "Keep-Alive",
+  "Proxy-Authenticate",
+  "Proxy-Authorization",
+  "Proxy-Connection",
+  "TE",
+  "Trailer",
+  "Transfer-Encoding",
+  "Upgrade",
+];
+
+const X_FORWARDED_PREFIX: &str = "X-Forwarded-Prefix";
+
+trait Duplex: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Duplex for T {}
+
+// Requests are sent to the backend's path, joined with the request path. A
+// strip_prefix is taken off the request path first (and passed along in
+// X-Forwarded-Prefix), and an add_prefix goes between the two. Locations and
+// cookie paths in responses are mapped back the other way.
+//
+// With a pool, each request goes to a backend picked from it, and backend is
+// only the pool's first backend.
+//
+// With an assertion, requests are signed for the path the backend is sent.
 #[derive(Clone)]
 pub struct Proxy {
   pub client: Client,
   pub backend: Url,
+  pub pool: Option<Pool>,
+  pub strip_prefix: Option<String>,
+  pub add_prefix: Option<String>,
+  pub assertion: Option<ProvidesIdentityAssertion>,
 }
 
 impl Proxy {
@@ -18,21 +72,784 @@ impl Proxy {
     Self {
       client: surf::client(),
       backend,
+      pool: None,
+      strip_prefix: None,
+      add_prefix: None,
+      assertion: None,
     }
   }
-}
 
-#[async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for Proxy {
-  async fn handle(&self, request: Request<State>, _next: Next<'_, State>) -> Result {
-    let mut request: http::Request = request.into();
+  pub fn pooled(pool: Pool) -> Self {
+    Self {
+      pool: Some(pool.clone()),
+      ..Self::new(pool.urls()[0].clone())
+    }
+  }
+
+  async fn forward<State>(&self, request: Request<State>) -> Result {
+    let request: http::Request = request.into();
+    match &self.pool {
+      None => self.send(request, None).await,
+      Some(pool) => {
+        let lease = pool.lease(request.as_ref())?;
+        let proxy = Self {
+          backend: lease.url().clone(),
+          pool: None,
+          ..self.clone()
+        };
+        proxy.send(request, Some(lease)).await
+      }
+    }
+  }
+
+  async fn send(&self, mut request: http::Request, lease: Option<Lease>) -> Result {
+    let upgrade = upgrade_protocol(&request);
+    remove_hop_by_hop(request.as_mut());
+    // async-h1 has already answered any Expect: 100-continue, and its client
+    // would take a 100 from the backend for the whole response.
+    request.remove_header(headers::EXPECT);
+
+    let (public_prefix, backend_prefix) = self.prefixes();
+    let path = request.url().path().to_owned();
+    let path = match under(&path, public_prefix) {
+      Some(rest) if !public_prefix.is_empty() => {
+        request.insert_header(X_FORWARDED_PREFIX, public_prefix);
+        rest
+      }
+      _ => &path,
+    };
+    let path = join(&backend_prefix, path);
+
     let url = request.url_mut();
     url.set_host(self.backend.host_str())?;
     url.set_port(self.backend.port_or_known_default()).unwrap();
     url.set_scheme(self.backend.scheme()).unwrap();
+    url.set_path(&path);
+
+    if let Some(assertion) = &self.assertion {
+      assertion
+        .assert(&mut request)
+        .map_err(|error| Error::new(StatusCode::InternalServerError, error))?;
+    }
+
+    if let Some(protocol) = upgrade {
+      request.insert_header(headers::CONNECTION, "upgrade");
+      request.insert_header(headers::UPGRADE, protocol);
+      return self.upgrade(request, lease).await;
+    }
 
-    let mut response: http::Response = self.client.send(request).await?.into();
-    response.remove_header(headers::CONNECTION);
+    let result = self.client.send(request).await;
+    if let Some(lease) = &lease {
+      lease.report(result.as_ref().ok().map(|response| response.status()));
+    }
+    // A backend that can't be reached is a gateway error, not a client one.
+    let mut response: http::Response = result
+      .map_err(|error| Error::from_str(StatusCode::BadGateway, error))?
+      .into();
+    self.rewrite(&mut response);
+    if let Some(lease) = lease {
+      Leased::hold(&mut response, lease);
+    }
     Ok(response.into())
   }
+
+  // The request path prefix that is replaced, and the backend path prefix that
+  // replaces it, without trailing slashes.
+  fn prefixes(&self) -> (&str, String) {
+    let public_prefix = self
+      .strip_prefix
+      .as_deref()
+      .unwrap_or_default()
+      .trim_end_matches('/');
+    let backend_prefix = format!(
+      "{}{}",
+      self.backend.path().trim_end_matches('/'),
+      self
+        .add_prefix
+        .as_deref()
+        .unwrap_or_default()
+        .trim_end_matches('/')
+    );
+    (public_prefix, backend_prefix)
+  }
+
+  // Maps a backend path back to the path clients use. Paths outside of the
+  // backend prefix are left alone.
+  fn public_path(&self, path: &str) -> String {
+    let (public_prefix, backend_prefix) = self.prefixes();
+    match under(path, &backend_prefix) {
+      Some(rest) => join(public_prefix, rest),
+      None => path.to_owned(),
+    }
+  }
+
+  // Locations on the backend are made relative, so clients stay on the proxy.
+  fn public_location(&self, location: &str) -> Option<String> {
+    let url = match Url::parse(location) {
+      Ok(url) if url.origin() == self.backend.origin() => url,
+      Ok(_) => return None,
+      Err(_) if location.starts_with('/') && !location.starts_with("//") => {
+        self.backend.join(location).ok()?
+      }
+      Err(_) => return None,
+    };
+
+    let mut public = self.public_path(url.path());
+    if let Some(query) = url.query() {
+      public = format!("{}?{}", public, query);
+    }
+    if let Some(fragment) = url.fragment() {
+      public = format!("{}#{}", public, fragment);
+    }
+    Some(public)
+  }
+
+  fn public_cookie(&self, value: &str) -> Option<String> {
+    let mut cookie = Cookie::parse(value.to_owned()).ok()?;
+    if let Some(path) = cookie.path().map(|path| self.public_path(path)) {
+      cookie.set_path(path);
+    }
+    let domain = cookie.domain().map(str::to_lowercase);
+    if domain.is_some() && domain == self.backend.host_str().map(str::to_lowercase) {
+      cookie.unset_domain();
+    }
+    Some(cookie.to_string())
+  }
+
+  fn rewrite(&self, response: &mut http::Response) {
+    remove_hop_by_hop(response.as_mut());
+
+    // async-h1's client makes up a Date header when the backend doesn't send
+    // one, with a line break in it, which would end the response head early.
+    if let Some(date) = response.header(headers::DATE) {
+      if date.as_str().contains('\n') {
+        response.remove_header(headers::DATE);
+      }
+    }
+
+    if let Some(location) = response
+      .header(headers::LOCATION)
+      .and_then(|location| self.public_location(location.as_str()))
+    {
+      response.insert_header(headers::LOCATION, location);
+    }
+
+    if let Some(values) = response.remove_header(headers::SET_COOKIE) {
+      for value in values.iter() {
+        let cookie = self
+          .public_cookie(value.as_str())
+          .unwrap_or_else(|| value.as_str().to_owned());
+        response.append_header(headers::SET_COOKIE, cookie);
+      }
+    }
+  }
+
+  // surf doesn't hand back the backend connection after a 101, so upgrades
+  // (like WebSockets) get a connection of their own, which is tunnelled to the
+  // client once both sides have switched.
+  // The lease is held until the tunnel closes (or, if the backend refused, until
+  // its response has been sent), like streamed responses.
+  async fn upgrade(&self, request: http::Request, lease: Option<Lease>) -> Result {
+    let result = handshake(request).await;
+    if let (Some(lease), Err(_)) = (&lease, &result) {
+      lease.report(None);
+    }
+    let (backend, head) = result.map_err(|mut error| {
+      error.set_status(StatusCode::BadGateway);
+      error
+    })?;
+
+    let mut response = async_h1::client::decode(Cursor::new(head.clone())).await?;
+    if let Some(lease) = &lease {
+      lease.report(Some(response.status()));
+    }
+    if response.status() != StatusCode::SwitchingProtocols {
+      // The backend said no, so this is an ordinary response (with a body).
+      response = async_h1::client::decode(Cursor::new(head).chain(backend)).await?;
+      self.rewrite(&mut response);
+      if let Some(lease) = lease {
+        Leased::hold(&mut response, lease);
+      }
+      return Ok(response.into());
+    }
+
+    let protocol = response
+      .header(headers::UPGRADE)
+      .map(|upgrade| upgrade.as_str().to_owned());
+    self.rewrite(&mut response);
+    response.insert_header(headers::CONNECTION, "upgrade");
+    if let Some(protocol) = protocol {
+      response.insert_header(headers::UPGRADE, protocol);
+    }
+    let client = response.recv_upgrade().await;
+    task::spawn(async move {
+      let _lease = lease;
+      if let Some(client) = client.await {
+        if let Err(error) = tunnel(client, backend).await {
+          tide::log::debug!("upgraded connection closed: {}", error);
+        }
+      }
+    });
+
+    Ok(response.into())
+  }
+}
+
+// Holds a lease until the response body has been sent (or dropped), so
+// streamed responses count towards least_connections while they're open.
+struct Leased {
+  body: Body,
+  _lease: Lease,
+}
+
+impl Leased {
+  fn hold(response: &mut http::Response, lease: Lease) {
+    let body = response.take_body();
+    let length = body.len();
+    let mime = body.mime().clone();
+    let mut body = Body::from_reader(
+      Self {
+        body,
+        _lease: lease,
+      },
+      length,
+    );
+    body.set_mime(mime);
+    response.set_body(body);
+  }
+}
+
+impl AsyncRead for Leased {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.body).poll_read(cx, buf)
+  }
+}
+
+impl AsyncBufRead for Leased {
+  fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+    Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+  }
+
+  fn consume(mut self: Pin<&mut Self>, amount: usize) {
+    Pin::new(&mut self.body).consume(amount)
+  }
+}
+
+// The protocol a client asked to switch to, if it asked for one.
+fn upgrade_protocol(request: &http::Request) -> Option<String> {
+  let connection = request.header(headers::CONNECTION)?;
+  let upgrade = request.header(headers::UPGRADE)?;
+
+  if connection
+    .as_str()
+    .split(',')
+    .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+  {
+    Some(upgrade.as_str().to_owned())
+  } else {
+    None
+  }
+}
+
+// Hop-by-hop headers describe a single connection, so they aren't forwarded.
+// That includes any other headers the Connection header names.
+fn remove_hop_by_hop(headers: &mut http::Headers) {
+  let mut names: Vec<HeaderName> = HOP_BY_HOP_HEADERS
+    .iter()
+    .map(|name| HeaderName::from(*name))
+    .collect();
+
+  if let Some(connection) = headers.get(headers::CONNECTION) {
+    for value in connection.iter() {
+      names.extend(
+        value
+          .as_str()
+          .split(',')
+          .filter_map(|name| HeaderName::from_str(name.trim()).ok()),
+      );
+    }
+  }
+
+  for name in names {
+    headers.remove(name);
+  }
+}
+
+// Whatever follows prefix in path, if path is prefix or starts with prefix
+// followed by a slash.
+fn under<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+  match path.strip_prefix(prefix) {
+    Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest),
+    _ => None,
+  }
+}
+
+fn join(prefix: &str, path: &str) -> String {
+  match (prefix, path) {
+    ("", "") => String::from("/"),
+    _ => format!("{}{}", prefix, path),
+  }
+}
+
+// Sends the request on a connection of its own, and reads the response head.
+async fn handshake(request: http::Request) -> Result<(Box<dyn Duplex>, Vec<u8>)> {
+  let url = request.url().clone();
+  let host = url.host_str().unwrap_or_default();
+  let port = url.port_or_known_default().unwrap_or_default();
+  let stream = TcpStream::connect((host, port)).await?;
+  let mut backend: Box<dyn Duplex> = match url.scheme() {
+    "https" => Box::new(TlsConnector::default().connect(host, stream).await?),
+    _ => Box::new(stream),
+  };
+
+  io::copy(async_h1::client::Encoder::new(request), &mut backend).await?;
+  let head = read_head(&mut backend).await?;
+  Ok((backend, head))
+}
+
+// One byte at a time, so nothing after the head (like the first WebSocket
+// frame) is read along with it.
+async fn read_head(backend: &mut Box<dyn Duplex>) -> Result<Vec<u8>> {
+  let mut head = vec![];
+  let mut byte = [0; 1];
+  while !head.ends_with(b"\r\n\r\n") {
+    if head.len() >= MAX_HEAD_LENGTH {
+      return Err(Error::from_str(
+        StatusCode::BadGateway,
+        "backend response head is too long",
+      ));
+    }
+    match backend.read(&mut byte).await? {
+      0 => {
+        return Err(Error::from_str(
+          StatusCode::BadGateway,
+          "backend closed the connection",
+        ))
+      }
+      _ => head.push(byte[0]),
+    }
+  }
+  Ok(head)
+}
+
+// The tunnel ends as soon as either side closes its end, and dropping the
+// connections is what closes them (closing a TCP stream only flushes it).
+async fn tunnel(client: upgrade::Connection, backend: Box<dyn Duplex>) -> io::Result<()> {
+  let (client_reader, mut client_writer) = client.split();
+  let (backend_reader, mut backend_writer) = backend.split();
+  let upstream = io::copy(client_reader, &mut backend_writer);
+  let downstream = io::copy(backend_reader, &mut client_writer);
+  futures::pin_mut!(upstream, downstream);
+
+  match future::select(upstream, downstream).await {
+    Either::Left((result, _)) | Either::Right((result, _)) => result.map(|_| ()),
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Proxy {
+  async fn handle(&self, request: Request<State>, _next: Next<'_, State>) -> Result {
+    self.forward(request).await
+  }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Proxy {
+  async fn call(&self, request: Request<State>) -> Result {
+    self.forward(request).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::{
+    ethereum::{AssertionSigner, SessionKey, SessionKeys},
+    hosts::VirtualHosts,
+  };
+  use async_std::{future, io::prelude::WriteExt, sync::Mutex};
+  use futures::{channel::oneshot, Future};
+  use std::{net::TcpListener, sync::Arc, time::Duration};
+  use tide::http::Method;
+
+  fn listen<State: Clone + Send + Sync + 'static>(server: tide::Server<State>) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    task::spawn(server.listen(listener));
+    url
+  }
+
+  // A backend that answers with the path it was sent, and the headers named in
+  // the request's X-Echo header.
+  fn backend() -> Url {
+    let mut server = tide::new();
+    server.at("*").all(|request: Request<()>| async move {
+      let mut body = request.url().path().to_string();
+      if let Some(names) = request.header("X-Echo") {
+        for name in names.last().as_str().split(',') {
+          let value = request.header(name).map(|values| values.last().to_string());
+          body.push_str(&format!("\n{}", value.unwrap_or_default()));
+        }
+      }
+      Ok(body)
+    });
+    listen(server)
+  }
+
+  async fn get(proxy: Proxy, path: &str, headers: &[(&str, &str)]) -> (StatusCode, String) {
+    let mut server = tide::new();
+    server.at("*").all(proxy);
+    let mut request = http::Request::new(
+      Method::Get,
+      Url::parse("http://localhost/").unwrap().join(path).unwrap(),
+    );
+    for (name, value) in headers {
+      request.insert_header(*name, *value);
+    }
+    let mut response: http::Response = server.respond(request).await.unwrap();
+    (response.status(), response.body_string().await.unwrap())
+  }
+
+  #[async_std::test]
+  async fn asserts_the_path_the_backend_is_sent() {
+    let signer = AssertionSigner::Hmac(SessionKeys(vec![SessionKey {
+      id: String::from("test"),
+      secret: b"a-secret-that-is-at-least-32-bytes-long".to_vec(),
+    }]));
+    let proxy = Proxy {
+      strip_prefix: Some(String::from("/api")),
+      add_prefix: Some(String::from("/v1")),
+      assertion: Some(ProvidesIdentityAssertion {
+        address_header: HeaderName::from("X-Web3-Account-Address"),
+        assertion_header: HeaderName::from("X-Web3-Assertion"),
+        claim_headers: vec![],
+        lifetime: Duration::from_secs(60),
+        signer: signer.clone(),
+      }),
+      ..Proxy::new(backend())
+    };
+
+    let (status, body) = get(
+      proxy,
+      "/api/things",
+      &[
+        ("X-Web3-Account-Address", "01"),
+        ("X-Echo", "X-Web3-Assertion"),
+      ],
+    )
+    .await;
+    assert_eq!(status, StatusCode::Ok);
+    let (path, token) = body.split_once('\n').unwrap();
+    assert_eq!(path, "/v1/things");
+    let assertion = signer.verifier().verify(token).unwrap();
+    assert!(assertion.is_for("GET", path));
+  }
+
+  #[async_std::test]
+  async fn holds_leases_until_the_body_is_done() {
+    let backends = vec![backend(), backend()];
+    let pool = Pool::new(
+      backends.clone(),
+      PoolOptions {
+        strategy: Strategy::LeastConnections,
+        ..PoolOptions::default()
+      },
+    )
+    .unwrap();
+    let mut server = tide::new();
+    server.at("*").all(Proxy::pooled(pool.clone()));
+    let request = || http::Request::new(Method::Get, Url::parse("http://localhost/a").unwrap());
+
+    let mut response: http::Response = server.respond(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(pool.lease(request().as_ref()).unwrap().url(), &backends[1]);
+
+    response.body_string().await.unwrap();
+    drop(response);
+    assert_eq!(pool.lease(request().as_ref()).unwrap().url(), &backends[0]);
+  }
+
+  fn prefixed() -> Proxy {
+    Proxy {
+      strip_prefix: Some(String::from("/api/")),
+      add_prefix: Some(String::from("/v1")),
+      ..Proxy::new(Url::parse("http://backend.test:8080/app/").unwrap())
+    }
+  }
+
+  #[test]
+  fn finds_paths_under_prefixes() {
+    assert_eq!(under("/api", "/api"), Some(""));
+    assert_eq!(under("/api/things", "/api"), Some("/things"));
+    assert_eq!(under("/apis", "/api"), None);
+    assert_eq!(under("/other", "/api"), None);
+    assert_eq!(under("/anything", ""), Some("/anything"));
+  }
+
+  #[test]
+  fn joins_prefixes_and_paths() {
+    assert_eq!(join("", ""), "/");
+    assert_eq!(join("/api", ""), "/api");
+    assert_eq!(join("", "/things"), "/things");
+    assert_eq!(join("/api", "/things"), "/api/things");
+  }
+
+  #[test]
+  fn maps_backend_locations_to_public_ones() {
+    let proxy = prefixed();
+    assert_eq!(
+      proxy.public_location("http://backend.test:8080/app/v1/things?page=2#top"),
+      Some(String::from("/api/things?page=2#top"))
+    );
+    assert_eq!(proxy.public_location("/app/v1"), Some(String::from("/api")));
+    assert_eq!(
+      proxy.public_location("/elsewhere"),
+      Some(String::from("/elsewhere"))
+    );
+    assert_eq!(proxy.public_location("http://other.test/app/v1"), None);
+    assert_eq!(proxy.public_location("//other.test/app/v1"), None);
+    assert_eq!(proxy.public_location("things"), None);
+  }
+
+  #[test]
+  fn maps_backend_cookies_to_public_ones() {
+    let proxy = prefixed();
+    assert_eq!(
+      proxy.public_cookie("id=1; Path=/app/v1/things; Domain=Backend.Test"),
+      Some(String::from("id=1; Path=/api/things"))
+    );
+    assert_eq!(
+      proxy.public_cookie("id=1; Domain=example.test"),
+      Some(String::from("id=1; Domain=example.test"))
+    );
+    assert_eq!(proxy.public_cookie("not a cookie"), None);
+  }
+
+  #[async_std::test]
+  async fn sends_requests_under_the_backend_prefix() {
+    let proxy = Proxy {
+      strip_prefix: Some(String::from("/api")),
+      add_prefix: Some(String::from("/v1")),
+      ..Proxy::new(backend().join("/app/").unwrap())
+    };
+    let echo = [("X-Echo", "X-Forwarded-Prefix")];
+
+    let (_, body) = get(proxy.clone(), "/api/things", &echo).await;
+    assert_eq!(body, "/app/v1/things\n/api");
+    let (_, body) = get(proxy, "/other", &echo).await;
+    assert_eq!(body, "/app/v1/other\n");
+  }
+
+  // A body that sends its first part, then waits for the receiver before
+  // sending the second.
+  struct HeldBack {
+    first: Option<&'static [u8]>,
+    rest: Option<oneshot::Receiver<()>>,
+    second: &'static [u8],
+  }
+
+  impl AsyncRead for HeldBack {
+    fn poll_read(
+      mut self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+      if let Some(first) = self.first.take() {
+        return Pin::new(&mut &*first).poll_read(cx, buf);
+      }
+      if let Some(rest) = &mut self.rest {
+        futures::ready!(Pin::new(rest).poll(cx)).ok();
+        self.rest = None;
+      }
+      let mut second = self.second;
+      let read = futures::ready!(Pin::new(&mut second).poll_read(cx, buf))?;
+      self.second = second;
+      Poll::Ready(Ok(read))
+    }
+  }
+
+  // A backend that echoes whatever is sent on an upgraded connection, refuses
+  // upgrades to /refused, and streams /stream (sending the rest of the body
+  // once the sender is dropped).
+  fn upgrading_backend(rest: oneshot::Receiver<()>) -> Url {
+    let rest = Arc::new(Mutex::new(Some(rest)));
+    let mut server = tide::with_state(rest);
+    server.at("/echo").get(|_| async {
+      let mut response = http::Response::new(StatusCode::SwitchingProtocols);
+      response.insert_header(headers::CONNECTION, "upgrade");
+      response.insert_header(headers::UPGRADE, "websocket");
+      let upgrade = response.recv_upgrade().await;
+      task::spawn(async move {
+        if let Some(connection) = upgrade.await {
+          let (reader, mut writer) = connection.split();
+          let _ = io::copy(reader, &mut writer).await;
+        }
+      });
+      Ok(tide::Response::from(response))
+    });
+    server.at("/refused").get(|_| async { Ok("not upgraded") });
+    server.at("/stream").get(
+      |request: Request<Arc<Mutex<Option<oneshot::Receiver<()>>>>>| async move {
+        let rest = request.state().lock().await.take().unwrap();
+        let body = HeldBack {
+          first: Some(b"first\n"),
+          rest: Some(rest),
+          second: b"second\n",
+        };
+        let mut response = tide::Response::new(StatusCode::Ok);
+        response.set_body(Body::from_reader(io::BufReader::new(body), None));
+        response.set_content_type("text/event-stream");
+        Ok(response)
+      },
+    );
+
+    listen(server)
+  }
+
+  // Serves the proxy for site.test, through VirtualHosts like the application
+  // does, and connects to it.
+  async fn connect(proxy: Proxy) -> TcpStream {
+    let mut site = tide::new();
+    site.at("*").all(proxy);
+    let mut server = tide::new();
+    server.with(VirtualHosts {
+      hosts: vec![(String::from("site.test"), site)]
+        .into_iter()
+        .collect(),
+    });
+    let url = listen(server);
+    TcpStream::connect(url.socket_addrs(|| None).unwrap()[0])
+      .await
+      .unwrap()
+  }
+
+  // Reads from the stream until it has read something ending with suffix.
+  async fn read_until(stream: &mut TcpStream, suffix: &[u8]) -> Vec<u8> {
+    let mut read = vec![];
+    let mut byte = [0; 1];
+    while !read.ends_with(suffix) {
+      match future::timeout(Duration::from_secs(5), stream.read(&mut byte)).await {
+        Ok(Ok(1)) => read.push(byte[0]),
+        result => panic!(
+          "read {:?}, then {:?}",
+          String::from_utf8_lossy(&read),
+          result
+        ),
+      }
+    }
+    read
+  }
+
+  async fn read_text(stream: &mut TcpStream, suffix: &[u8]) -> String {
+    String::from_utf8(read_until(stream, suffix).await).unwrap()
+  }
+
+  const UPGRADE: &[u8] = b"Host: site.test\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+
+  #[async_std::test]
+  async fn tunnels_upgraded_connections() {
+    let (_, rest) = oneshot::channel();
+    let mut client = connect(Proxy::new(upgrading_backend(rest))).await;
+    client.write_all(b"GET /echo HTTP/1.1\r\n").await.unwrap();
+    client.write_all(UPGRADE).await.unwrap();
+
+    let head = read_text(&mut client, b"\r\n\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(
+      head.to_lowercase().contains("upgrade: websocket"),
+      "{}",
+      head
+    );
+
+    // A masked text frame saying "hello", which is passed along as it is.
+    let frame = [
+      0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+    for _ in 0..2 {
+      client.write_all(&frame).await.unwrap();
+      let echoed = read_until(&mut client, &frame[frame.len() - 4..]).await;
+      assert_eq!(echoed, frame);
+    }
+  }
+
+  #[async_std::test]
+  async fn passes_refused_upgrades_along() {
+    let (_, rest) = oneshot::channel();
+    let mut client = connect(Proxy::new(upgrading_backend(rest))).await;
+    client
+      .write_all(b"GET /refused HTTP/1.1\r\n")
+      .await
+      .unwrap();
+    client.write_all(UPGRADE).await.unwrap();
+
+    let response = read_text(&mut client, b"not upgraded").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+  }
+
+  #[async_std::test]
+  async fn streams_bodies_as_they_come() {
+    let (send_rest, rest) = oneshot::channel::<()>();
+    let mut client = connect(Proxy::new(upgrading_backend(rest))).await;
+    client
+      .write_all(b"GET /stream HTTP/1.1\r\nHost: site.test\r\n\r\n")
+      .await
+      .unwrap();
+
+    // The first chunk arrives while the backend is still holding back the rest.
+    let head = read_text(&mut client, b"first\n").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert!(head.contains("text/event-stream"), "{}", head);
+    drop(send_rest);
+    read_until(&mut client, b"second\n").await;
+  }
+
+  #[async_std::test]
+  async fn holds_leases_for_refused_upgrades_until_the_body_is_done() {
+    let backends = vec![
+      upgrading_backend(oneshot::channel().1),
+      upgrading_backend(oneshot::channel().1),
+    ];
+    let pool = Pool::new(
+      backends.clone(),
+      PoolOptions {
+        strategy: Strategy::LeastConnections,
+        ..PoolOptions::default()
+      },
+    )
+    .unwrap();
+    let mut server = tide::new();
+    server.at("*").all(Proxy::pooled(pool.clone()));
+    let request = || {
+      let mut request =
+        http::Request::new(Method::Get, Url::parse("http://localhost/refused").unwrap());
+      request.insert_header(headers::CONNECTION, "Upgrade");
+      request.insert_header(headers::UPGRADE, "websocket");
+      request
+    };
+
+    let mut response: http::Response = server.respond(request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(pool.lease(request().as_ref()).unwrap().url(), &backends[1]);
+
+    assert_eq!(response.body_string().await.unwrap(), "not upgraded");
+    drop(response);
+    assert_eq!(pool.lease(request().as_ref()).unwrap().url(), &backends[0]);
+  }
+
+  #[test]
+  fn drops_broken_dates() {
+    let proxy = Proxy::new(Url::parse("http://backend.test/").unwrap());
+    let mut response = http::Response::new(StatusCode::Ok);
+    response.insert_header(headers::DATE, "Mon, 1 Jan 2001\n00:00:00 GMT");
+    proxy.rewrite(&mut response);
+    assert!(response.header(headers::DATE).is_none());
+
+    response.insert_header(headers::DATE, "Mon, 01 Jan 2001 00:00:00 GMT");
+    proxy.rewrite(&mut response);
+    assert!(response.header(headers::DATE).is_some());
+  }
 }
@@ -0,0 +1,431 @@
+use super::prelude::*;
+
+use async_std::{future::timeout, task};
+use futures::future::join_all;
+use serde::Deserialize;
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+  sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
+use strum::{AsRefStr, EnumString};
+use tide::{
+  http::{self, StatusCode},
+  Error, Result,
+};
+
+// How a backend is picked for each request. AddressHash sends each verified
+// address to the same backend for as long as that backend is available, and
+// falls back to round robin for requests without one.
+#[derive(AsRefStr, Clone, Copy, Debug, Default, Deserialize, EnumString, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Strategy {
+  #[default]
+  RoundRobin,
+  LeastConnections,
+  AddressHash,
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+  pub strategy: Strategy,
+  pub address_header: HeaderName,
+  pub check_path: Option<String>,
+  pub check_interval: Duration,
+  pub check_timeout: Duration,
+  pub max_failures: u32,
+  pub ejection_time: Duration,
+}
+
+impl Default for PoolOptions {
+  fn default() -> Self {
+    Self {
+      strategy: Strategy::RoundRobin,
+      address_header: HeaderName::from("X-Web3-Account-Address"),
+      check_path: None,
+      check_interval: Duration::from_secs(10),
+      check_timeout: Duration::from_secs(2),
+      max_failures: 3,
+      ejection_time: Duration::from_secs(30),
+    }
+  }
+}
+
+struct Backend {
+  url: Url,
+  healthy: AtomicBool,
+  connections: AtomicUsize,
+  failures: AtomicU32,
+  ejected_until: RwLock<Option<Instant>>,
+}
+
+impl Backend {
+  fn new(url: Url) -> Self {
+    Self {
+      url,
+      healthy: AtomicBool::new(true),
+      connections: AtomicUsize::new(0),
+      failures: AtomicU32::new(0),
+      ejected_until: RwLock::new(None),
+    }
+  }
+
+  fn is_available(&self, now: Instant) -> bool {
+    let ejected = match *self.ejected_until.read().unwrap() {
+      Some(until) => now < until,
+      None => false,
+    };
+    self.healthy.load(Ordering::Acquire) && !ejected
+  }
+
+  async fn check(&self, client: &Client, options: &PoolOptions) {
+    let path = match &options.check_path {
+      Some(path) => path,
+      None => return,
+    };
+    // Like requests, the check path is under the backend's path.
+    let mut url = self.url.clone();
+    url.set_path(&format!(
+      "{}{}",
+      self.url.path().trim_end_matches('/'),
+      path
+    ));
+
+    // The body is read even though it isn't used, so the connection can be
+    // reused for the next check.
+    let check = async {
+      let mut response = client.get(url).send().await?;
+      response.body_bytes().await?;
+      Ok::<_, surf::Error>(response.status())
+    };
+
+    let healthy = match timeout(options.check_timeout, check).await {
+      Ok(Ok(status)) if status.is_success() => true,
+      Ok(Ok(status)) => {
+        tide::log::debug!("health check for {} returned {}", self.url, status);
+        false
+      }
+      Ok(Err(error)) => {
+        tide::log::debug!("health check failed for {}: {}", self.url, error);
+        false
+      }
+      Err(_) => {
+        tide::log::debug!("health check timed out for {}", self.url);
+        false
+      }
+    };
+
+    if self.healthy.swap(healthy, Ordering::AcqRel) != healthy {
+      match healthy {
+        true => tide::log::info!("backend {} is healthy again", self.url),
+        false => tide::log::warn!("backend {} failed its health check", self.url),
+      }
+    }
+  }
+}
+
+struct Backends {
+  backends: Vec<Backend>,
+  client: Client,
+  next: AtomicUsize,
+  options: PoolOptions,
+}
+
+impl Backends {
+  async fn check(&self) {
+    join_all(
+      self
+        .backends
+        .iter()
+        .map(|backend| backend.check(&self.client, &self.options)),
+    )
+    .await;
+  }
+
+  fn round_robin(&self, available: &[usize]) -> usize {
+    available[self.next.fetch_add(1, Ordering::Relaxed) % available.len()]
+  }
+
+  // Rendezvous hashing, so a backend going away only moves the addresses that
+  // were on it.
+  fn address_hash(&self, available: &[usize], address: &str) -> usize {
+    let address = address.to_lowercase();
+    *available
+      .iter()
+      .max_by_key(|&&index| {
+        let mut hasher = DefaultHasher::new();
+        address.hash(&mut hasher);
+        self.backends[index].url.as_str().hash(&mut hasher);
+        hasher.finish()
+      })
+      .unwrap()
+  }
+}
+
+// A set of backends to spread requests across. Backends are taken out of the
+// pool while they fail active health checks (if check_path is set), and for
+// ejection_time after max_failures requests to them fail in a row.
+#[derive(Clone)]
+pub struct Pool {
+  backends: Arc<Backends>,
+}
+
+impl Pool {
+  pub fn new(urls: Vec<Url>, options: PoolOptions) -> anyhow::Result<Self> {
+    if urls.is_empty() {
+      anyhow::bail!("pool needs at least one backend");
+    }
+
+    let check_interval = options.check_interval;
+    let checks = options.check_path.is_some();
+    let backends = Arc::new(Backends {
+      backends: urls.into_iter().map(Backend::new).collect(),
+      client: surf::client(),
+      next: AtomicUsize::new(0),
+      options,
+    });
+
+    if checks {
+      let weak = Arc::downgrade(&backends);
+      task::spawn(async move {
+        loop {
+          match weak.upgrade() {
+            Some(backends) => backends.check().await,
+            None => break,
+          }
+          task::sleep(check_interval).await;
+        }
+      });
+    }
+
+    Ok(Self { backends })
+  }
+
+  pub fn urls(&self) -> Vec<Url> {
+    self
+      .backends
+      .backends
+      .iter()
+      .map(|backend| backend.url.clone())
+      .collect()
+  }
+
+  // Picks a backend for a request, or fails with a 503 if none are available.
+  pub fn lease(&self, headers: &http::Headers) -> Result<Lease> {
+    let now = Instant::now();
+    let backends = &self.backends;
+    let available: Vec<usize> = (0..backends.backends.len())
+      .filter(|&index| backends.backends[index].is_available(now))
+      .collect();
+
+    if available.is_empty() {
+      return Err(Error::from_str(
+        StatusCode::ServiceUnavailable,
+        "no backends are available",
+      ));
+    }
+
+    let index = match backends.options.strategy {
+      Strategy::RoundRobin => backends.round_robin(&available),
+      Strategy::LeastConnections => *available
+        .iter()
+        .min_by_key(|&&index| backends.backends[index].connections.load(Ordering::Acquire))
+        .unwrap(),
+      Strategy::AddressHash => match headers.get(&backends.options.address_header) {
+        Some(address) => backends.address_hash(&available, address.last().as_str()),
+        None => backends.round_robin(&available),
+      },
+    };
+
+    backends.backends[index]
+      .connections
+      .fetch_add(1, Ordering::AcqRel);
+    Ok(Lease {
+      backends: backends.clone(),
+      index,
+    })
+  }
+}
+
+// A backend picked for a request, which counts as a connection to it until the
+// lease is dropped.
+pub struct Lease {
+  backends: Arc<Backends>,
+  index: usize,
+}
+
+impl Lease {
+  fn backend(&self) -> &Backend {
+    &self.backends.backends[self.index]
+  }
+
+  pub fn url(&self) -> &Url {
+    &self.backend().url
+  }
+
+  // Requests that get a response (other than a gateway error) reset the
+  // backend's failure count.
+  pub fn report(&self, status: Option<StatusCode>) {
+    let backend = self.backend();
+    let failed = match status {
+      Some(StatusCode::BadGateway)
+      | Some(StatusCode::ServiceUnavailable)
+      | Some(StatusCode::GatewayTimeout)
+      | None => true,
+      Some(_) => false,
+    };
+
+    if !failed {
+      backend.failures.store(0, Ordering::Release);
+      return;
+    }
+
+    let options = &self.backends.options;
+    if backend.failures.fetch_add(1, Ordering::AcqRel) + 1 >= options.max_failures {
+      backend.failures.store(0, Ordering::Release);
+      *backend.ejected_until.write().unwrap() = Some(Instant::now() + options.ejection_time);
+      tide::log::warn!(
+        "ejected backend {} for {} after {} failures",
+        backend.url,
+        humantime::format_duration(options.ejection_time),
+        options.max_failures
+      );
+    }
+  }
+}
+
+impl Drop for Lease {
+  fn drop(&mut self) {
+    self.backend().connections.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn urls() -> Vec<Url> {
+    vec![
+      Url::parse("http://a.test/").unwrap(),
+      Url::parse("http://b.test/").unwrap(),
+    ]
+  }
+
+  fn pool(strategy: Strategy, max_failures: u32) -> Pool {
+    Pool::new(
+      urls(),
+      PoolOptions {
+        strategy,
+        max_failures,
+        ..PoolOptions::default()
+      },
+    )
+    .unwrap()
+  }
+
+  fn request() -> http::Request {
+    http::Request::new(http::Method::Get, Url::parse("http://localhost/").unwrap())
+  }
+
+  fn lease(pool: &Pool) -> Lease {
+    pool.lease(request().as_ref()).unwrap()
+  }
+
+  #[test]
+  fn needs_a_backend() {
+    assert!(Pool::new(vec![], PoolOptions::default()).is_err());
+  }
+
+  #[test]
+  fn takes_turns() {
+    let pool = pool(Strategy::RoundRobin, 3);
+    let picked: Vec<Url> = (0..4).map(|_| lease(&pool).url().clone()).collect();
+    assert_eq!(picked, [urls(), urls()].concat());
+  }
+
+  #[test]
+  fn picks_the_backend_with_the_least_connections() {
+    let pool = pool(Strategy::LeastConnections, 3);
+    let first = lease(&pool);
+    assert_eq!(first.url(), &urls()[0]);
+    assert_eq!(lease(&pool).url(), &urls()[1]);
+    drop(first);
+    assert_eq!(lease(&pool).url(), &urls()[0]);
+  }
+
+  #[test]
+  fn sends_each_address_to_the_same_backend() {
+    let pool = pool(Strategy::AddressHash, 3);
+    let mut request = request();
+    request.insert_header(
+      "X-Web3-Account-Address",
+      "00000000000000000000000000000000000000aa",
+    );
+    let first = pool.lease(request.as_ref()).unwrap().url().clone();
+    for _ in 0..4 {
+      assert_eq!(pool.lease(request.as_ref()).unwrap().url(), &first);
+    }
+    request.insert_header(
+      "X-Web3-Account-Address",
+      "00000000000000000000000000000000000000AA",
+    );
+    assert_eq!(pool.lease(request.as_ref()).unwrap().url(), &first);
+  }
+
+  #[test]
+  fn ejects_backends_after_failing_in_a_row() {
+    let pool = pool(Strategy::LeastConnections, 2);
+    lease(&pool).report(None);
+    assert_eq!(lease(&pool).url(), &urls()[0]);
+    lease(&pool).report(Some(StatusCode::BadGateway));
+    for _ in 0..4 {
+      assert_eq!(lease(&pool).url(), &urls()[1]);
+    }
+  }
+
+  #[test]
+  fn resets_failures_after_a_response() {
+    let pool = pool(Strategy::LeastConnections, 2);
+    lease(&pool).report(None);
+    lease(&pool).report(Some(StatusCode::NotFound));
+    lease(&pool).report(Some(StatusCode::ServiceUnavailable));
+    assert_eq!(lease(&pool).url(), &urls()[0]);
+  }
+
+  #[test]
+  fn fails_without_available_backends() {
+    let pool = Pool::new(
+      urls()[..1].to_vec(),
+      PoolOptions {
+        max_failures: 1,
+        ..PoolOptions::default()
+      },
+    )
+    .unwrap();
+    lease(&pool).report(None);
+    let error = pool.lease(request().as_ref()).err().unwrap();
+    assert_eq!(error.status(), StatusCode::ServiceUnavailable);
+  }
+
+  #[test]
+  fn takes_ejected_backends_back() {
+    let pool = Pool::new(
+      urls()[..1].to_vec(),
+      PoolOptions {
+        max_failures: 1,
+        ejection_time: Duration::from_millis(10),
+        ..PoolOptions::default()
+      },
+    )
+    .unwrap();
+    lease(&pool).report(None);
+    assert!(pool.lease(request().as_ref()).is_err());
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(pool.lease(request().as_ref()).is_ok());
+  }
+}
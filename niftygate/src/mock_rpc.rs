@@ -0,0 +1,89 @@
+// A JSON-RPC node for tests, answering each call with a handler, and keeping
+// track of what it was asked.
+use serde_json::{json, Value};
+use std::{
+  net::TcpListener,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tide::{http::Url, Body, Request};
+
+type Handler = dyn Fn(&str, &[Value]) -> Result<Value, Value> + Send + Sync;
+
+#[derive(Default)]
+pub struct Log {
+  pub methods: Vec<String>,
+  pub batches: Vec<usize>,
+  in_flight: usize,
+  pub max_in_flight: usize,
+}
+
+#[derive(Clone)]
+struct Node {
+  delay: Duration,
+  handler: Arc<Handler>,
+  log: Arc<Mutex<Log>>,
+}
+
+impl Node {
+  fn answer(&self, call: &Value) -> Value {
+    let method = call["method"].as_str().unwrap_or_default();
+    let params = call["params"].as_array().cloned().unwrap_or_default();
+    self.log.lock().unwrap().methods.push(method.to_string());
+    match (self.handler)(method, &params) {
+      Ok(result) => json!({"jsonrpc": "2.0", "id": call["id"], "result": result}),
+      Err(error) => json!({"jsonrpc": "2.0", "id": call["id"], "error": error}),
+    }
+  }
+}
+
+pub struct MockRpc {
+  pub url: Url,
+  pub log: Arc<Mutex<Log>>,
+}
+
+pub fn serve<F>(delay: Duration, handler: F) -> MockRpc
+where
+  F: Fn(&str, &[Value]) -> Result<Value, Value> + Send + Sync + 'static,
+{
+  let log = Arc::new(Mutex::new(Log::default()));
+  let node = Node {
+    delay,
+    handler: Arc::new(handler),
+    log: log.clone(),
+  };
+
+  let mut server = tide::with_state(node);
+  server
+    .at("/")
+    .post(|mut request: Request<Node>| async move {
+      let body: Value = request.body_json().await?;
+      let node = request.state();
+      {
+        let mut log = node.log.lock().unwrap();
+        log.in_flight += 1;
+        log.max_in_flight = log.max_in_flight.max(log.in_flight);
+        if let Value::Array(calls) = &body {
+          log.batches.push(calls.len());
+        }
+      }
+      async_std::task::sleep(node.delay).await;
+      let response = match &body {
+        Value::Array(calls) => Value::Array(calls.iter().map(|call| node.answer(call)).collect()),
+        call => node.answer(call),
+      };
+      node.log.lock().unwrap().in_flight -= 1;
+      Body::from_json(&response)
+    });
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+  async_std::task::spawn(server.listen(listener));
+
+  MockRpc { url, log }
+}
+
+// Encodes a word, as returned by eth_call.
+pub fn word(bytes: &[u8]) -> String {
+  format!("0x{:0>64}", hex::encode(bytes))
+}